@@ -3,15 +3,12 @@ use std::net::SocketAddr;
 use std::str::FromStr;
 use std::sync::Arc;
 use tokio::signal;
-use trusttunnel::authentication::jwt::JwtAuth;
-use trusttunnel::authentication::mixed::MixedAuth;
-use trusttunnel::authentication::registry_based::CredentialsAuth;
-use trusttunnel::authentication::{AuthProvider, Authenticator, ProxyBasicAuthenticator};
+use trusttunnel::authentication::password;
 use trusttunnel::client_config;
 use trusttunnel::core::Core;
 use trusttunnel::settings::{AuthMode, Settings};
 use trusttunnel::shutdown::Shutdown;
-use trusttunnel::{log_utils, settings};
+use trusttunnel::{authentication, log_utils, settings};
 
 const VERSION_STRING: &str = env!("CARGO_PKG_VERSION");
 const VERSION_PARAM_NAME: &str = "v_e_r_s_i_o_n_do_not_change_this_name_it_will_break";
@@ -24,6 +21,7 @@ const ADDRESS_PARAM_NAME: &str = "address";
 const CUSTOM_SNI_PARAM_NAME: &str = "custom_sni";
 const CLIENT_RANDOM_PREFIX_PARAM_NAME: &str = "client_random_prefix";
 const FORMAT_PARAM_NAME: &str = "format";
+const CLIENT_PASSWORD_PARAM_NAME: &str = "client_password";
 const SENTRY_DSN_PARAM_NAME: &str = "sentry_dsn";
 const THREADS_NUM_PARAM_NAME: &str = "threads_num";
 
@@ -66,6 +64,30 @@ fn increase_fd_limit() {
 #[cfg(not(unix))]
 fn increase_fd_limit() {}
 
+fn reload_settings(core: &Core, settings_path: &str) -> Result<(), String> {
+    let content = std::fs::read_to_string(settings_path)
+        .map_err(|e| format!("Couldn't read the settings file: {}", e))?;
+    let mut settings: Settings =
+        toml::from_str(&content).map_err(|e| format!("Couldn't parse the settings file: {}", e))?;
+    settings.set_source_files(&content);
+    let authenticator = authentication::from_settings(&settings)
+        .map_err(|e| format!("Couldn't create authenticator: {}", e))?;
+
+    core.reload_settings(settings, authenticator)
+        .map_err(|e| format!("Couldn't apply new settings: {}", e))
+}
+
+fn reload_tls_hosts_settings(core: &Core, tls_hosts_settings_path: &str) -> Result<(), String> {
+    let tls_hosts_settings: settings::TlsHostsSettings = toml::from_str(
+        &std::fs::read_to_string(tls_hosts_settings_path)
+            .map_err(|e| format!("Couldn't read the TLS hosts settings file: {}", e))?,
+    )
+    .map_err(|e| format!("Couldn't parse the TLS hosts settings file: {}", e))?;
+
+    core.reload_tls_hosts_settings(tls_hosts_settings)
+        .map_err(|e| format!("Couldn't apply new TLS hosts settings: {}", e))
+}
+
 fn main() {
     let args = clap::Command::new("VPN endpoint")
         .args(&[
@@ -100,7 +122,7 @@ fn main() {
             clap::Arg::new(SETTINGS_PARAM_NAME)
                 .action(clap::ArgAction::Set)
                 .required_unless_present(VERSION_PARAM_NAME)
-                .help("Path to a settings file"),
+                .help("Path to a settings file. Sending SIGHUP to the process causes reloading the settings."),
             clap::Arg::new(TLS_HOSTS_SETTINGS_PARAM_NAME)
                 .action(clap::ArgAction::Set)
                 .required_unless_present(VERSION_PARAM_NAME)
@@ -130,6 +152,11 @@ fn main() {
                 .short('r')
                 .long("client-random-prefix")
                 .help("TLS client random hex prefix for connection filtering. Must have a corresponding rule in rules.toml."),
+            clap::Arg::new(CLIENT_PASSWORD_PARAM_NAME)
+                .action(clap::ArgAction::Set)
+                .requires(CLIENT_CONFIG_PARAM_NAME)
+                .long("client-password")
+                .help("Password of the exported client. Required if the credentials file stores the password hash."),
             clap::Arg::new(FORMAT_PARAM_NAME)
                 .action(clap::ArgAction::Set)
                 .requires(CLIENT_CONFIG_PARAM_NAME)
@@ -185,10 +212,11 @@ fn main() {
     increase_fd_limit();
 
     let settings_path = args.get_one::<String>(SETTINGS_PARAM_NAME).unwrap();
-    let settings: Settings = toml::from_str(
-        &std::fs::read_to_string(settings_path).expect("Couldn't read the settings file"),
-    )
-    .expect("Couldn't parse the settings file");
+    let settings_content =
+        std::fs::read_to_string(settings_path).expect("Couldn't read the settings file");
+    let mut settings: Settings =
+        toml::from_str(&settings_content).expect("Couldn't parse the settings file");
+    settings.set_source_files(&settings_content);
 
     if settings.get_clients().is_empty()
         && matches!(
@@ -215,6 +243,34 @@ fn main() {
 
     if args.contains_id(CLIENT_CONFIG_PARAM_NAME) {
         let username = args.get_one::<String>(CLIENT_CONFIG_PARAM_NAME).unwrap();
+        let mut clients = settings.get_clients().clone();
+        if let Some(client) = clients.iter_mut().find(|x| x.username == *username) {
+            if let Some(warning) = client.validity_warning() {
+                eprintln!(
+                    "Warning: {}, the endpoint will reject the exported configuration",
+                    warning
+                );
+            }
+            if password::Format::of(&client.password) != password::Format::Plain {
+                match args.get_one::<String>(CLIENT_PASSWORD_PARAM_NAME) {
+                    Some(x) if password::verify(&client.password, x) => client.password = x.clone(),
+                    Some(_) => {
+                        eprintln!(
+                            "Error: the password does not match the hash of '{}'",
+                            username
+                        );
+                        std::process::exit(1);
+                    }
+                    None => {
+                        eprintln!(
+                            "Error: the password of '{}' is stored as a hash, pass it with --client-password",
+                            username
+                        );
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
         let addresses: Vec<SocketAddr> = args
             .get_many::<String>(ADDRESS_PARAM_NAME)
             .expect("At least one address should be specified")
@@ -284,7 +340,7 @@ fn main() {
         let client_config = client_config::build(
             username,
             addresses,
-            settings.get_clients(),
+            &clients,
             &tls_hosts_settings,
             custom_sni,
             client_random_prefix,
@@ -331,46 +387,8 @@ fn main() {
     };
 
     let shutdown = Shutdown::new();
-    let authenticator: Option<Arc<dyn Authenticator>> = {
-        let auth_provider: Option<Box<dyn AuthProvider>> = match *settings.get_auth().get_mode() {
-            AuthMode::Credentials => {
-                if settings.get_clients().is_empty() {
-                    None
-                } else {
-                    Some(Box::new(CredentialsAuth::new(settings.get_clients())))
-                }
-            }
-            AuthMode::Jwt => {
-                let jwt_settings = settings
-                    .get_auth()
-                    .get_jwt()
-                    .as_ref()
-                    .expect("[auth.jwt] must be configured for mode=jwt");
-                Some(Box::new(
-                    JwtAuth::from_config(&jwt_settings.to_auth_config())
-                        .expect("Couldn't initialize JWT auth"),
-                ))
-            }
-            AuthMode::Mixed => {
-                let jwt_settings = settings
-                    .get_auth()
-                    .get_jwt()
-                    .as_ref()
-                    .expect("[auth.jwt] must be configured for mode=mixed");
-                Some(Box::new(MixedAuth::new(
-                    Box::new(
-                        JwtAuth::from_config(&jwt_settings.to_auth_config())
-                            .expect("Couldn't initialize JWT auth"),
-                    ),
-                    Box::new(CredentialsAuth::new(settings.get_clients())),
-                )))
-            }
-        };
-
-        auth_provider.map(|provider| {
-            Arc::new(ProxyBasicAuthenticator::new(provider)) as Arc<dyn Authenticator>
-        })
-    };
+    let authenticator =
+        authentication::from_settings(&settings).expect("Couldn't create authenticator");
     let core = Arc::new(
         Core::new(
             settings,
@@ -386,7 +404,8 @@ fn main() {
         async move { core.listen().await }
     };
 
-    let reload_tls_hosts_task = {
+    let reload_task = {
+        let settings_path = settings_path.clone();
         let tls_hosts_settings_path = tls_hosts_settings_path.clone();
         async move {
             let mut sighup_listener = signal::unix::signal(signal::unix::SignalKind::hangup())
@@ -394,17 +413,18 @@ fn main() {
 
             loop {
                 sighup_listener.recv().await;
-                info!("Reloading TLS hosts settings");
 
-                let tls_hosts_settings: settings::TlsHostsSettings = toml::from_str(
-                    &std::fs::read_to_string(&tls_hosts_settings_path)
-                        .expect("Couldn't read the TLS hosts settings file"),
-                )
-                .expect("Couldn't parse the TLS hosts settings file");
+                info!("Reloading settings");
+                match reload_settings(&core, &settings_path) {
+                    Ok(()) => info!("Settings are successfully reloaded"),
+                    Err(e) => error!("{}. Keeping the current settings.", e),
+                }
 
-                core.reload_tls_hosts_settings(tls_hosts_settings)
-                    .expect("Couldn't apply new settings");
-                info!("TLS hosts settings are successfully reloaded");
+                info!("Reloading TLS hosts settings");
+                match reload_tls_hosts_settings(&core, &tls_hosts_settings_path) {
+                    Ok(()) => info!("TLS hosts settings are successfully reloaded"),
+                    Err(e) => error!("{}. Keeping the current TLS hosts settings.", e),
+                }
             }
         }
     };
@@ -425,8 +445,8 @@ fn main() {
                     1
                 }
             },
-            _ = reload_tls_hosts_task => {
-                error!("Error while reloading TLS hosts");
+            _ = reload_task => {
+                error!("Error while reloading settings");
                 1
             },
             _ = interrupt_task => {
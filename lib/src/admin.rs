@@ -0,0 +1,983 @@
+use crate::authentication::password;
+use crate::authentication::registry_based::Client;
+use crate::http1_codec::Http1Codec;
+use crate::http_codec::HttpCodec;
+use crate::settings::{AuthMode, Settings};
+use crate::{authentication, core, http_codec, log_id, log_utils, pipe, rules, utils};
+use bytes::{Bytes, BytesMut};
+use http::{Method, StatusCode};
+use ipnet::IpNet;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use toml_edit::{value, ArrayOfTables, Document, Item, Table};
+
+const LOG_FMT: &str = "ADMIN={}";
+const SESSIONS_PATH: &str = "sessions";
+const CREDENTIALS_PATH: &str = "credentials";
+const RULES_PATH: &str = "rules";
+const BANS_PATH: &str = "bans";
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Serializes the modifications, so that the files are saved in the order
+/// the modifications are applied
+static MODIFICATION_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// An administration request processing result
+struct Reply {
+    status: StatusCode,
+    body: Bytes,
+}
+
+#[derive(Deserialize)]
+struct AddCredentialRequest {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+struct CredentialInfo<'a> {
+    username: &'a str,
+}
+
+#[derive(Serialize)]
+struct ErrorInfo {
+    error: String,
+}
+
+pub(crate) async fn listen(
+    context: Arc<core::Context>,
+    log_chain: log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    let (mut shutdown_notification, _shutdown_completion) = {
+        let shutdown = context.shutdown.lock().unwrap();
+        (shutdown.notification_handler(), shutdown.completion_guard())
+    };
+
+    tokio::select! {
+        x = shutdown_notification.wait() => {
+            match x {
+                Ok(_) => Ok(()),
+                Err(e) => Err(io::Error::new(ErrorKind::Other, format!("{}", e))),
+            }
+        }
+        x = listen_inner(context, log_chain) => x,
+    }
+}
+
+async fn listen_inner(
+    context: Arc<core::Context>,
+    log_chain: log_utils::IdChain<u64>,
+) -> io::Result<()> {
+    // The admin listener is not affected by settings reloading
+    let settings = context.settings();
+    let admin_settings = match settings.admin.as_ref() {
+        None => return Ok(()),
+        Some(x) => x,
+    };
+
+    let token = match std::env::var(&admin_settings.token_env) {
+        Ok(x) if !x.is_empty() => Arc::new(x),
+        _ => {
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                format!(
+                    "Token environment variable is not set or empty: {}",
+                    admin_settings.token_env
+                ),
+            ))
+        }
+    };
+
+    let next_id = AtomicU64::default();
+    let listener = TcpListener::bind(admin_settings.address).await?;
+    info!(
+        "Listening to admin API requests on {}",
+        admin_settings.address
+    );
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let log_id = log_chain.extended(log_utils::IdItem::new(
+            LOG_FMT,
+            next_id.fetch_add(1, Ordering::Relaxed),
+        ));
+        log_id!(trace, log_id, "New connection from {}", peer);
+        let context = context.clone();
+        let settings = settings.clone();
+        let token = token.clone();
+        tokio::spawn(async move { handle_request(context, settings, token, stream, log_id).await });
+    }
+}
+
+async fn handle_request(
+    context: Arc<core::Context>,
+    settings: Arc<Settings>,
+    token: Arc<String>,
+    io: TcpStream,
+    log_id: log_utils::IdChain<u64>,
+) {
+    let timeout = settings.admin.as_ref().unwrap().request_timeout;
+    let mut codec = Http1Codec::new(settings, io, log_id.clone());
+    let stream = match tokio::time::timeout(timeout, codec.listen()).await {
+        Ok(Ok(Some(x))) => {
+            log_id!(trace, log_id, "Got request: {:?}", x.request().request());
+            x
+        }
+        Ok(Ok(None)) => {
+            log_id!(debug, log_id, "Connection closed immediately");
+            return;
+        }
+        Ok(Err(e)) => {
+            log_id!(debug, log_id, "Listen failed: {}", e);
+            return;
+        }
+        Err(_elapsed) => {
+            log_id!(
+                debug,
+                log_id,
+                "Didn't receive any request during configured period"
+            );
+            return;
+        }
+    };
+
+    let dispatch = async {
+        match codec.listen().await {
+            Ok(Some(x)) => log_id!(
+                debug,
+                log_id,
+                "Got unexpected request while processing previous: {:?}",
+                x.request().request(),
+            ),
+            Ok(None) => (),
+            Err(e) => log_id!(debug, log_id, "IO error during processing: {}", e),
+        }
+    };
+
+    let handle = async {
+        let (request, respond) = stream.split();
+        let version = request.request().version;
+        let reply =
+            match tokio::time::timeout(timeout, handle_stream(&context, &token, request, &log_id))
+                .await
+            {
+                Ok(x) => x,
+                Err(_elapsed) => Reply::error(StatusCode::REQUEST_TIMEOUT, "Request timed out"),
+            };
+
+        if let Err(e) = send_reply(respond, version, reply).await {
+            log_id!(debug, log_id, "Failed to send response: {}", e);
+        }
+    };
+
+    tokio::select! {
+        _ = dispatch => (),
+        _ = handle => (),
+    }
+
+    if let Err(e) = codec.graceful_shutdown().await {
+        log_id!(debug, log_id, "Failed to shutdown HTTP session: {}", e);
+    }
+}
+
+async fn handle_stream(
+    context: &core::Context,
+    token: &str,
+    request: Box<dyn http_codec::PendingRequest>,
+    log_id: &log_utils::IdChain<u64>,
+) -> Reply {
+    if !is_authorized(request.request(), token) {
+        log_id!(debug, log_id, "Unauthorized request");
+        return Reply::error(StatusCode::UNAUTHORIZED, "Invalid or missing bearer token");
+    }
+
+    let method = request.request().method.clone();
+    let path = request.request().uri.path().to_string();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        (Method::GET, [SESSIONS_PATH]) => Reply::json(StatusCode::OK, &context.sessions.list()),
+        (Method::DELETE, [SESSIONS_PATH, id]) => kick_session(context, id),
+        (Method::GET, [CREDENTIALS_PATH]) => list_credentials(context),
+        (Method::POST, [CREDENTIALS_PATH]) => match read_json(request).await {
+            Ok(x) => add_credential(context, x).await,
+            Err(e) => e,
+        },
+        (Method::DELETE, [CREDENTIALS_PATH, username]) => match percent_decode(username) {
+            Some(x) => remove_credential(context, &x).await,
+            None => Reply::error(StatusCode::BAD_REQUEST, "Malformed username"),
+        },
+        (Method::GET, [RULES_PATH]) => list_rules(context),
+        (Method::PUT, [RULES_PATH]) => match read_json(request).await {
+            Ok(x) => replace_rules(context, x).await,
+            Err(e) => e,
+        },
+        (Method::GET, [BANS_PATH]) => Reply::json(StatusCode::OK, &context.bans.list()),
+        (Method::DELETE, [BANS_PATH, "ip", ip]) => unban_ip(context, ip),
+        (Method::DELETE, [BANS_PATH, "username", username]) => match percent_decode(username) {
+            Some(x) => unban_user(context, &x),
+            None => Reply::error(StatusCode::BAD_REQUEST, "Malformed username"),
+        },
+        (_, [SESSIONS_PATH] | [SESSIONS_PATH, _])
+        | (_, [CREDENTIALS_PATH] | [CREDENTIALS_PATH, _])
+        | (_, [RULES_PATH])
+        | (_, [BANS_PATH] | [BANS_PATH, "ip" | "username", _]) => {
+            Reply::error(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
+        }
+        (_, x) => {
+            log_id!(debug, log_id, "Unexpected path: {}", x.join("/"));
+            Reply::error(StatusCode::NOT_FOUND, "Not found")
+        }
+    }
+}
+
+fn is_authorized(request: &http_codec::RequestHeaders, token: &str) -> bool {
+    request
+        .headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .is_some_and(|x| utils::constant_time_eq(x.trim().as_bytes(), token.as_bytes()))
+}
+
+fn kick_session(context: &core::Context, id: &str) -> Reply {
+    let id = match id.parse() {
+        Ok(x) => x,
+        Err(_) => return Reply::error(StatusCode::BAD_REQUEST, "Malformed session ID"),
+    };
+
+    if context.sessions.kick(id) {
+        Reply::json(StatusCode::OK, &())
+    } else {
+        Reply::error(StatusCode::NOT_FOUND, "Session not found")
+    }
+}
+
+fn unban_ip(context: &core::Context, ip: &str) -> Reply {
+    let ip = match ip.parse() {
+        Ok(x) => x,
+        Err(_) => return Reply::error(StatusCode::BAD_REQUEST, "Malformed IP address"),
+    };
+
+    if context.bans.unban_ip(ip) {
+        Reply::json(StatusCode::OK, &())
+    } else {
+        Reply::error(StatusCode::NOT_FOUND, "IP address not found")
+    }
+}
+
+fn unban_user(context: &core::Context, username: &str) -> Reply {
+    if context.bans.unban_user(username) {
+        Reply::json(StatusCode::OK, &())
+    } else {
+        Reply::error(StatusCode::NOT_FOUND, "User not found")
+    }
+}
+
+fn list_credentials(context: &core::Context) -> Reply {
+    let settings = context.settings();
+    let list: Vec<_> = settings
+        .clients
+        .iter()
+        .map(|x| CredentialInfo {
+            username: &x.username,
+        })
+        .collect();
+    Reply::json(StatusCode::OK, &list)
+}
+
+async fn add_credential(context: &core::Context, request: AddCredentialRequest) -> Reply {
+    if request.username.is_empty() {
+        return Reply::error(StatusCode::BAD_REQUEST, "Username cannot be empty");
+    }
+    if request.username.contains(':') {
+        return Reply::error(StatusCode::BAD_REQUEST, "Username cannot contain colon");
+    }
+
+    // Keep the plain text passwords out of memory, the hashes are accepted as is
+    let password = match password::Format::of(&request.password) {
+        password::Format::Plain => password::hash(&request.password),
+        _ => request.password,
+    };
+
+    modify_credentials(context, |clients| {
+        match clients.iter_mut().find(|x| x.username == request.username) {
+            Some(x) => x.password = password,
+            None => clients.push(Client {
+                username: request.username,
+                password,
+                ..Default::default()
+            }),
+        }
+        Ok(())
+    })
+    .await
+}
+
+async fn remove_credential(context: &core::Context, username: &str) -> Reply {
+    modify_credentials(context, |clients| {
+        let len = clients.len();
+        clients.retain(|x| x.username != username);
+        if clients.len() == len {
+            return Err(Reply::error(StatusCode::NOT_FOUND, "User not found"));
+        }
+        Ok(())
+    })
+    .await
+}
+
+async fn modify_credentials<F>(context: &core::Context, f: F) -> Reply
+where
+    F: FnOnce(&mut Vec<Client>) -> Result<(), Reply>,
+{
+    let _guard = MODIFICATION_LOCK.lock().await;
+    let mut reply = None;
+    let mut save = None;
+    let result = context.modify_settings(|settings, authenticator| {
+        if matches!(
+            settings.auth.mode,
+            AuthMode::Jwt | AuthMode::Http | AuthMode::Radius
+        ) {
+            reply = Some(Reply::error(
+                StatusCode::CONFLICT,
+                "Credentials are not used in JWT, HTTP and RADIUS authentication modes",
+            ));
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        if let Err(e) = f(&mut settings.clients) {
+            reply = Some(e);
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        if let Err(e) = settings.validate() {
+            reply = Some(Reply::error(StatusCode::CONFLICT, format!("{:?}", e)));
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        // The endpoint does not start with a credentials file without clients
+        if settings.credentials_path.is_some() && settings.clients.is_empty() {
+            reply = Some(Reply::error(
+                StatusCode::CONFLICT,
+                "The last client cannot be removed from the credentials file",
+            ));
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        *authenticator = authentication::from_settings(settings)?;
+        save = settings
+            .credentials_path
+            .clone()
+            .map(|path| (path, settings.clients.clone()));
+        Ok(())
+    });
+
+    match (result, reply, save) {
+        (Ok(()), _, Some((path, clients))) => {
+            save_file(move || save_credentials(&path, &clients)).await
+        }
+        (Ok(()), _, None) => Reply::json(StatusCode::OK, &()),
+        (Err(_), Some(reply), _) => reply,
+        (Err(e), None, _) => Reply::error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+fn list_rules(context: &core::Context) -> Reply {
+    let settings = context.settings();
+    Reply::json(
+        StatusCode::OK,
+        &settings
+            .rules_engine
+            .as_ref()
+            .map(rules::RulesEngine::config)
+            .cloned()
+            .unwrap_or_default(),
+    )
+}
+
+async fn replace_rules(context: &core::Context, config: rules::RulesConfig) -> Reply {
+    for (i, rule) in config.rule.iter().enumerate() {
+        if let Err(e) = validate_rule(rule) {
+            return Reply::error(StatusCode::BAD_REQUEST, format!("Rule #{}: {}", i + 1, e));
+        }
+    }
+    for (i, rule) in config.egress.iter().enumerate() {
+        if let Err(e) = validate_egress_rule(rule) {
+            return Reply::error(
+                StatusCode::BAD_REQUEST,
+                format!("Egress rule #{}: {}", i + 1, e),
+            );
+        }
+    }
+    for (i, rule) in config.route.iter().enumerate() {
+        if let Err(e) = validate_route_rule(rule) {
+            return Reply::error(
+                StatusCode::BAD_REQUEST,
+                format!("Routing rule #{}: {}", i + 1, e),
+            );
+        }
+    }
+
+    let _guard = MODIFICATION_LOCK.lock().await;
+    let mut reply = None;
+    let mut save = None;
+    let result = context.modify_settings(|settings, _| {
+        save = settings
+            .rules_path
+            .clone()
+            .map(|path| (path, config.clone()));
+        settings.rules_engine = Some(rules::RulesEngine::from_config(config));
+        // Routing rules must refer to the configured forwarders
+        if let Err(e) = settings.validate() {
+            reply = Some(Reply::error(StatusCode::BAD_REQUEST, format!("{:?}", e)));
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        Ok(())
+    });
+
+    match (result, reply, save) {
+        (Ok(()), _, Some((path, config))) => save_file(move || save_rules(&path, &config)).await,
+        (Ok(()), _, None) => Reply::json(StatusCode::OK, &()),
+        (Err(_), Some(reply), _) => reply,
+        (Err(e), None, _) => Reply::error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+fn validate_rule(rule: &rules::Rule) -> Result<(), String> {
+    validate_cidr(rule.cidr.as_deref())?;
+    validate_pattern("sni", rule.sni.as_deref())?;
+    validate_name("username", rule.username.as_deref())?;
+    validate_name("group", rule.group.as_deref())?;
+    validate_name("user_agent", rule.user_agent.as_deref())?;
+
+    if let Some(x) = &rule.client_random_prefix {
+        let (prefix, mask) = match x.split_once('/') {
+            Some((p, m)) => (p, Some(m)),
+            None => (x.as_str(), None),
+        };
+        hex::decode(prefix).map_err(|e| format!("Invalid client random prefix {}: {}", x, e))?;
+        if let Some(mask) = mask {
+            hex::decode(mask).map_err(|e| format!("Invalid client random mask {}: {}", x, e))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_egress_rule(rule: &rules::EgressRule) -> Result<(), String> {
+    validate_pattern("domain", rule.domain.as_deref())?;
+    validate_cidr(rule.cidr.as_deref())?;
+    validate_ports(rule.ports.as_deref())?;
+    validate_name("username", rule.username.as_deref())?;
+    validate_name("group", rule.group.as_deref())
+}
+
+fn validate_route_rule(rule: &rules::RouteRule) -> Result<(), String> {
+    validate_name("username", rule.username.as_deref())?;
+    validate_pattern("sni", rule.sni.as_deref())?;
+    validate_pattern("domain", rule.domain.as_deref())?;
+    validate_cidr(rule.cidr.as_deref())?;
+    validate_ports(rule.ports.as_deref())?;
+    validate_name("forwarder", Some(&rule.forwarder))
+}
+
+fn validate_cidr(cidr: Option<&str>) -> Result<(), String> {
+    if let Some(x) = cidr {
+        x.parse::<IpNet>()
+            .map_err(|e| format!("Invalid CIDR {}: {}", x, e))?;
+    }
+    Ok(())
+}
+
+fn validate_ports(ports: Option<&str>) -> Result<(), String> {
+    if let Some(x) = ports {
+        rules::parse_port_ranges(x).ok_or_else(|| format!("Invalid ports {}", x))?;
+    }
+    Ok(())
+}
+
+/// Check a host name pattern, see [`rules::EgressRule::domain`]
+fn validate_pattern(field: &str, pattern: Option<&str>) -> Result<(), String> {
+    match pattern {
+        Some(x) if x.trim_start_matches(['.', '*']).is_empty() => {
+            Err(format!("Invalid {} pattern: {:?}", field, x))
+        }
+        Some(x) if x.contains(char::is_whitespace) => {
+            Err(format!("Invalid {} pattern: {:?}", field, x))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn validate_name(field: &str, name: Option<&str>) -> Result<(), String> {
+    match name {
+        Some("") => Err(format!("Empty {}", field)),
+        _ => Ok(()),
+    }
+}
+
+/// Update the credentials file with the changed clients, keeping the rest of it intact
+fn save_credentials(path: &str, clients: &[Client]) -> io::Result<()> {
+    let mut document = match std::fs::read_to_string(path) {
+        Ok(x) => x
+            .parse::<Document>()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?,
+        Err(e) if e.kind() == ErrorKind::NotFound => Document::new(),
+        Err(e) => return Err(e),
+    };
+    if !document.contains_key("client") {
+        document["client"] = Item::ArrayOfTables(ArrayOfTables::new());
+    }
+    let tables = document["client"]
+        .as_array_of_tables_mut()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Not an array of clients"))?;
+
+    let username = |x: &Table| x.get("username").and_then(Item::as_str).map(String::from);
+    tables.retain(|x| username(x).is_some_and(|u| clients.iter().any(|c| c.username == u)));
+    for client in clients {
+        let existing = tables
+            .iter()
+            .position(|x| username(x).as_deref() == Some(&client.username));
+        match existing.and_then(|i| tables.get_mut(i)) {
+            Some(x) if x.get("password").and_then(Item::as_str) == Some(&client.password) => (),
+            Some(x) => x["password"] = value(&client.password),
+            None => tables.push(Table::from_iter([
+                ("username", &client.username),
+                ("password", &client.password),
+            ])),
+        }
+    }
+
+    write_atomically(path, document.to_string())
+        .map_err(|e| io::Error::new(e.kind(), format!("Couldn't save credentials: {}", e)))
+}
+
+/// Replace the rules file with the new rules
+fn save_rules(path: &str, config: &rules::RulesConfig) -> io::Result<()> {
+    fn table<'a>(fields: impl IntoIterator<Item = (&'a str, Option<&'a str>)>) -> Table {
+        fields
+            .into_iter()
+            .filter_map(|(k, v)| v.map(|v| (k, v)))
+            .collect()
+    }
+    let action = |x: &rules::RuleAction| match x {
+        rules::RuleAction::Allow => "allow",
+        rules::RuleAction::Deny => "deny",
+    };
+    let protocol = |x: &rules::EgressProtocol| match x {
+        rules::EgressProtocol::Tcp => "tcp",
+        rules::EgressProtocol::Udp => "udp",
+    };
+
+    let mut document = Document::new();
+    document["rule"] = Item::ArrayOfTables(
+        config
+            .rule
+            .iter()
+            .map(|x| {
+                table([
+                    ("cidr", x.cidr.as_deref()),
+                    ("client_random_prefix", x.client_random_prefix.as_deref()),
+                    ("sni", x.sni.as_deref()),
+                    ("username", x.username.as_deref()),
+                    ("group", x.group.as_deref()),
+                    ("user_agent", x.user_agent.as_deref()),
+                    ("action", Some(action(&x.action))),
+                ])
+            })
+            .collect(),
+    );
+    document["egress"] = Item::ArrayOfTables(
+        config
+            .egress
+            .iter()
+            .map(|x| {
+                table([
+                    ("domain", x.domain.as_deref()),
+                    ("cidr", x.cidr.as_deref()),
+                    ("ports", x.ports.as_deref()),
+                    ("protocol", x.protocol.as_ref().map(protocol)),
+                    ("username", x.username.as_deref()),
+                    ("group", x.group.as_deref()),
+                    ("action", Some(action(&x.action))),
+                ])
+            })
+            .collect(),
+    );
+    document["route"] = Item::ArrayOfTables(
+        config
+            .route
+            .iter()
+            .map(|x| {
+                table([
+                    ("username", x.username.as_deref()),
+                    ("sni", x.sni.as_deref()),
+                    ("domain", x.domain.as_deref()),
+                    ("cidr", x.cidr.as_deref()),
+                    ("ports", x.ports.as_deref()),
+                    ("protocol", x.protocol.as_ref().map(protocol)),
+                    ("forwarder", Some(x.forwarder.as_str())),
+                ])
+            })
+            .collect(),
+    );
+
+    write_atomically(path, document.to_string())
+        .map_err(|e| io::Error::new(e.kind(), format!("Couldn't save rules: {}", e)))
+}
+
+/// Save the applied modification on the blocking thread pool, outside of the settings lock
+async fn save_file<F>(f: F) -> Reply
+where
+    F: FnOnce() -> io::Result<()> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(Ok(())) => Reply::json(StatusCode::OK, &()),
+        Ok(Err(e)) => Reply::error(StatusCode::INTERNAL_SERVER_ERROR, e),
+        Err(e) => Reply::error(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Write the file through a temporary one, so that a reload never sees it half-written
+fn write_atomically(path: &str, content: String) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    std::fs::write(&tmp_path, content)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+async fn read_json<T: DeserializeOwned>(
+    request: Box<dyn http_codec::PendingRequest>,
+) -> Result<T, Reply> {
+    let content_length = request
+        .request()
+        .headers
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<usize>().ok())
+        .ok_or_else(|| Reply::error(StatusCode::LENGTH_REQUIRED, "Content length is required"))?;
+    if content_length > MAX_BODY_SIZE {
+        return Err(Reply::error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Request body is too large",
+        ));
+    }
+
+    let mut source = request.finalize();
+    let mut body = BytesMut::with_capacity(content_length);
+    while body.len() < content_length {
+        match source.read().await {
+            Ok(pipe::Data::Chunk(chunk)) => {
+                source
+                    .consume(chunk.len())
+                    .map_err(|e| Reply::error(StatusCode::BAD_REQUEST, e))?;
+                body.extend_from_slice(&chunk);
+            }
+            Ok(pipe::Data::Eof) => {
+                return Err(Reply::error(
+                    StatusCode::BAD_REQUEST,
+                    "Unexpected end of request body",
+                ))
+            }
+            Err(e) => return Err(Reply::error(StatusCode::BAD_REQUEST, e)),
+        }
+    }
+    body.truncate(content_length);
+
+    serde_json::from_slice(&body).map_err(|e| Reply::error(StatusCode::BAD_REQUEST, e))
+}
+
+async fn send_reply(
+    respond: Box<dyn http_codec::PendingRespond>,
+    version: http::Version,
+    reply: Reply,
+) -> io::Result<()> {
+    let response = http::Response::builder()
+        .version(version)
+        .status(reply.status)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .header(http::header::CONTENT_LENGTH, reply.body.len())
+        .body(())
+        .unwrap()
+        .into_parts()
+        .0;
+
+    let mut sink = respond.send_response(response, false)?.into_pipe_sink();
+    sink.write_all(reply.body).await?;
+    sink.eof()
+}
+
+/// Decode a percent-encoded path segment
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hi = (iter.next()? as char).to_digit(16)?;
+            let lo = (iter.next()? as char).to_digit(16)?;
+            bytes.push((hi * 16 + lo) as u8);
+        } else {
+            bytes.push(b);
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+impl Reply {
+    fn json<T: Serialize + ?Sized>(status: StatusCode, x: &T) -> Self {
+        match serde_json::to_vec(x) {
+            Ok(body) => Self {
+                status,
+                body: Bytes::from(body),
+            },
+            Err(e) => Self::error(StatusCode::INTERNAL_SERVER_ERROR, e),
+        }
+    }
+
+    fn error<E: Display>(status: StatusCode, e: E) -> Self {
+        Self {
+            status,
+            body: Bytes::from(
+                serde_json::to_vec(&ErrorInfo {
+                    error: e.to_string(),
+                })
+                .unwrap_or_default(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings;
+    use serde::de::value::{Error as ValueError, StringDeserializer};
+
+    #[test]
+    fn decode_percent_encoded() {
+        assert_eq!(percent_decode("abc").as_deref(), Some("abc"));
+        assert_eq!(percent_decode("a%20b%2Fc").as_deref(), Some("a b/c"));
+        assert_eq!(percent_decode("a%2"), None);
+        assert_eq!(percent_decode("a%zz"), None);
+    }
+
+    #[test]
+    fn bearer_token_authorization() {
+        let make_request = |header: Option<&str>| {
+            let mut builder = http::Request::builder().uri("/sessions");
+            if let Some(x) = header {
+                builder = builder.header(http::header::AUTHORIZATION, x);
+            }
+            builder.body(()).unwrap().into_parts().0
+        };
+
+        assert!(is_authorized(
+            &make_request(Some("Bearer secret")),
+            "secret"
+        ));
+        assert!(!is_authorized(
+            &make_request(Some("Bearer wrong")),
+            "secret"
+        ));
+        assert!(!is_authorized(
+            &make_request(Some("Basic secret")),
+            "secret"
+        ));
+        assert!(!is_authorized(&make_request(None), "secret"));
+    }
+
+    #[test]
+    fn rule_validation() {
+        let make_rule = |cidr: Option<&str>, prefix: Option<&str>| rules::Rule {
+            cidr: cidr.map(String::from),
+            client_random_prefix: prefix.map(String::from),
+            sni: None,
+            username: None,
+            group: None,
+            user_agent: None,
+            action: rules::RuleAction::Deny,
+        };
+
+        assert!(validate_rule(&make_rule(Some("10.0.0.0/8"), Some("aabb/ff00"))).is_ok());
+        assert!(validate_rule(&make_rule(Some("10.0.0.0/33"), None)).is_err());
+        assert!(validate_rule(&make_rule(None, Some("xyz"))).is_err());
+        assert!(validate_rule(&make_rule(None, Some("aabb/zz"))).is_err());
+        assert!(validate_rule(&rules::Rule {
+            sni: Some(".".to_string()),
+            ..make_rule(None, None)
+        })
+        .is_err());
+        assert!(validate_rule(&rules::Rule {
+            group: Some(String::new()),
+            ..make_rule(None, None)
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn egress_and_route_rule_validation() {
+        let egress: rules::EgressRule = serde_json::from_str(
+            r#"{"domain": "*.example.com", "cidr": "10.0.0.0/8", "ports": "25,6881-6889", "group": "staff", "action": "deny"}"#,
+        )
+        .unwrap();
+        assert!(validate_egress_rule(&egress).is_ok());
+        for invalid in [
+            rules::EgressRule {
+                domain: Some("*".to_string()),
+                ..egress.clone()
+            },
+            rules::EgressRule {
+                cidr: Some("10.0.0.0/33".to_string()),
+                ..egress.clone()
+            },
+            rules::EgressRule {
+                ports: Some("25-x".to_string()),
+                ..egress.clone()
+            },
+            rules::EgressRule {
+                username: Some(String::new()),
+                ..egress.clone()
+            },
+        ] {
+            assert!(validate_egress_rule(&invalid).is_err(), "{:?}", invalid);
+        }
+
+        let route: rules::RouteRule = serde_json::from_str(
+            r#"{"sni": ".vpn.example.com", "domain": "example.org", "ports": "443", "forwarder": "office"}"#,
+        )
+        .unwrap();
+        assert!(validate_route_rule(&route).is_ok());
+        for invalid in [
+            rules::RouteRule {
+                sni: Some("vpn example".to_string()),
+                ..route.clone()
+            },
+            rules::RouteRule {
+                cidr: Some("192.0.2.0/40".to_string()),
+                ..route.clone()
+            },
+            rules::RouteRule {
+                ports: Some("70000".to_string()),
+                ..route.clone()
+            },
+            rules::RouteRule {
+                forwarder: String::new(),
+                ..route.clone()
+            },
+        ] {
+            assert!(validate_route_rule(&invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn credentials_saving() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("credentials.toml");
+        let path = path.to_str().unwrap();
+        std::fs::write(
+            path,
+            r#"# Managed by the admin API
+[[client]]
+username = "a"
+password = "1"
+groups = ["staff"]
+
+[[client]]
+username = "b"
+password = "2"
+"#,
+        )
+        .unwrap();
+
+        let client = |username: &str, password: &str| Client {
+            username: username.to_string(),
+            password: password.to_string(),
+            ..Default::default()
+        };
+        save_credentials(path, &[client("a", "3"), client("c", "4")]).unwrap();
+
+        let content = std::fs::read_to_string(path).unwrap();
+        assert!(
+            content.starts_with("# Managed by the admin API"),
+            "{}",
+            content
+        );
+        let clients =
+            settings::deserialize_clients(StringDeserializer::<ValueError>::new(path.to_string()))
+                .unwrap();
+        let clients: Vec<_> = clients
+            .iter()
+            .map(|x| (x.username.as_str(), x.password.as_str(), x.groups.clone()))
+            .collect();
+        assert_eq!(
+            clients,
+            vec![("a", "3", vec!["staff".to_string()]), ("c", "4", vec![])]
+        );
+    }
+
+    #[test]
+    fn rules_saving() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("rules.toml");
+        let path = path.to_str().unwrap();
+
+        let config: rules::RulesConfig = serde_json::from_str(
+            r#"{
+                "rule": [{"cidr": "10.0.0.0/8", "group": "staff", "action": "allow"}],
+                "egress": [{"domain": ".example.com", "ports": "25,465", "protocol": "tcp", "action": "deny"}],
+                "route": [{"username": "a", "cidr": "192.0.2.0/24", "forwarder": "office"}]
+            }"#,
+        )
+        .unwrap();
+        save_rules(path, &config).unwrap();
+
+        let engine =
+            settings::deserialize_rules(StringDeserializer::<ValueError>::new(path.to_string()))
+                .unwrap()
+                .unwrap();
+        assert_eq!(
+            serde_json::to_value(engine.config()).unwrap(),
+            serde_json::to_value(&config).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn rules_applied_before_saving() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::builder()
+            .listen_address("127.0.0.1:443")
+            .unwrap()
+            .listen_protocols(settings::ListenProtocolSettings {
+                http2: Some(settings::Http2Settings::builder().build()),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        settings.rules_path = Some(
+            temp_dir
+                .path()
+                .join("missing")
+                .join("rules.toml")
+                .to_str()
+                .unwrap()
+                .to_string(),
+        );
+        let context = core::Context::with_settings(settings);
+
+        let config: rules::RulesConfig =
+            serde_json::from_str(r#"{"rule": [{"cidr": "10.0.0.0/8", "action": "deny"}]}"#)
+                .unwrap();
+        let reply = replace_rules(&context, config).await;
+        assert_eq!(reply.status, StatusCode::INTERNAL_SERVER_ERROR);
+        let settings = context.settings();
+        assert_eq!(
+            settings.rules_engine.as_ref().unwrap().config().rule.len(),
+            1
+        );
+    }
+}
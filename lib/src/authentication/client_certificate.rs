@@ -0,0 +1,225 @@
+use crate::settings::{CertificateUsernameField, ClientCertificateSettings};
+use crate::utils;
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerifier,
+};
+use rustls::{Certificate, RootCertStore};
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::SystemTime;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Verifies the client certificates of a TLS host against its CA bundle
+/// and maps them to the usernames
+pub struct ClientCertificateAuth {
+    verifier: Arc<dyn ClientCertVerifier>,
+    required: bool,
+    username_field: CertificateUsernameField,
+}
+
+impl ClientCertificateAuth {
+    pub fn new(settings: &ClientCertificateSettings) -> io::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for cert in utils::load_certs(&settings.ca_path)? {
+            roots.add(&cert).map_err(|e| {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid client CA certificate: {}", e),
+                )
+            })?;
+        }
+
+        Ok(Self {
+            verifier: if settings.required {
+                AllowAnyAuthenticatedClient::new(roots).boxed()
+            } else {
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed()
+            },
+            required: settings.required,
+            username_field: settings.username_field,
+        })
+    }
+
+    /// The verifier for the rustls server configuration
+    pub(crate) fn verifier(&self) -> Arc<dyn ClientCertVerifier> {
+        self.verifier.clone()
+    }
+
+    pub(crate) fn is_required(&self) -> bool {
+        self.required
+    }
+
+    /// Verify the certificate chain received from a client outside of rustls
+    /// and get the username.
+    /// The end-entity certificate goes first.
+    /// Returns [`None`] if the client did not present a certificate.
+    pub(crate) fn verify(&self, chain: &[Certificate]) -> Result<Option<String>, String> {
+        let (end_entity, intermediates) = match chain.split_first() {
+            Some(x) => x,
+            None if self.required => return Err("No client certificate".to_string()),
+            None => return Ok(None),
+        };
+
+        self.verifier
+            .verify_client_cert(end_entity, intermediates, SystemTime::now())
+            .map_err(|e| format!("Invalid client certificate: {}", e))?;
+        self.username(chain)
+    }
+
+    /// Get the username from a chain verified during the handshake.
+    /// Returns [`None`] if the client did not present a certificate.
+    pub(crate) fn username(&self, chain: &[Certificate]) -> Result<Option<String>, String> {
+        let end_entity = match chain.first() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let (_, cert) = X509Certificate::from_der(&end_entity.0)
+            .map_err(|e| format!("Malformed client certificate: {}", e))?;
+        let username = match self.username_field {
+            CertificateUsernameField::CommonName => cert
+                .subject()
+                .iter_common_name()
+                .next()
+                .and_then(|x| x.as_str().ok())
+                .map(String::from),
+            field => cert
+                .subject_alternative_name()
+                .ok()
+                .flatten()
+                .and_then(|san| {
+                    san.value
+                        .general_names
+                        .iter()
+                        .find_map(|name| match (field, name) {
+                            (CertificateUsernameField::SanEmail, GeneralName::RFC822Name(x))
+                            | (CertificateUsernameField::SanDns, GeneralName::DNSName(x))
+                            | (CertificateUsernameField::SanUri, GeneralName::URI(x)) => {
+                                Some(x.to_string())
+                            }
+                            _ => None,
+                        })
+                }),
+        };
+
+        match username.filter(|x| !x.is_empty()) {
+            Some(x) => Ok(Some(x)),
+            None => Err(format!(
+                "Client certificate has no {:?} to take the username from",
+                self.username_field
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{
+        BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair, SanType,
+    };
+    use std::io::Write;
+
+    struct Ca {
+        cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    impl Ca {
+        fn new() -> Self {
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "Test CA");
+            let key = KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key).unwrap();
+            Self { cert, key }
+        }
+
+        fn issue(&self, common_name: Option<&str>, sans: Vec<SanType>) -> Certificate {
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.distinguished_name = DistinguishedName::new();
+            if let Some(x) = common_name {
+                params.distinguished_name.push(DnType::CommonName, x);
+            }
+            params.subject_alt_names = sans;
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+            Certificate(cert.der().to_vec())
+        }
+    }
+
+    fn make_auth(
+        ca: &Ca,
+        required: bool,
+        username_field: CertificateUsernameField,
+    ) -> (ClientCertificateAuth, tempfile::NamedTempFile) {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(ca.cert.pem().as_bytes()).unwrap();
+        let auth = ClientCertificateAuth::new(&ClientCertificateSettings {
+            ca_path: file.path().to_str().unwrap().to_string(),
+            required,
+            username_field,
+        })
+        .unwrap();
+        (auth, file)
+    }
+
+    #[test]
+    fn chain_verification() {
+        let ca = Ca::new();
+        let (auth, _file) = make_auth(&ca, false, CertificateUsernameField::CommonName);
+
+        let cert = ca.issue(Some("alice"), vec![]);
+        assert_eq!(auth.verify(&[cert]), Ok(Some("alice".to_string())));
+        assert_eq!(auth.verify(&[]), Ok(None));
+
+        let foreign = Ca::new().issue(Some("alice"), vec![]);
+        assert!(auth.verify(&[foreign]).is_err());
+
+        let (auth, _file) = make_auth(&ca, true, CertificateUsernameField::CommonName);
+        assert!(auth.is_required());
+        assert!(auth.verify(&[]).is_err());
+    }
+
+    #[test]
+    fn username_fields() {
+        let ca = Ca::new();
+        let cert = ca.issue(
+            Some("alice"),
+            vec![
+                SanType::DnsName("alice.example.org".try_into().unwrap()),
+                SanType::Rfc822Name("alice@example.org".try_into().unwrap()),
+                SanType::URI("spiffe://example.org/alice".try_into().unwrap()),
+            ],
+        );
+
+        for (field, expected) in [
+            (CertificateUsernameField::CommonName, "alice"),
+            (CertificateUsernameField::SanEmail, "alice@example.org"),
+            (CertificateUsernameField::SanDns, "alice.example.org"),
+            (
+                CertificateUsernameField::SanUri,
+                "spiffe://example.org/alice",
+            ),
+        ] {
+            let (auth, _file) = make_auth(&ca, false, field);
+            assert_eq!(
+                auth.username(&[cert.clone()]),
+                Ok(Some(expected.to_string())),
+                "{:?}",
+                field
+            );
+        }
+
+        let (auth, _file) = make_auth(&ca, false, CertificateUsernameField::SanEmail);
+        let cert = ca.issue(Some("bob"), vec![]);
+        assert!(auth.username(&[cert]).is_err());
+        let (auth, _file) = make_auth(&ca, false, CertificateUsernameField::CommonName);
+        let cert = ca.issue(None, vec![]);
+        assert!(auth.username(&[cert]).is_err());
+    }
+}
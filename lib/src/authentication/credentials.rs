@@ -1 +1 @@
-pub use crate::authentication::registry_based::{Client, CredentialsAuth};
+pub use crate::authentication::registry_based::{AccountStatus, Client, CredentialsAuth};
@@ -0,0 +1,268 @@
+use crate::authentication::{AsyncAuthProvider, AuthError};
+use crate::{http_client, net_utils};
+use async_trait::async_trait;
+use bytes::Bytes;
+use ring::digest;
+use rustls::ClientConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The expired entries are purged once the cache grows to this size
+const MAX_CACHE_SIZE: usize = 10000;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HttpAuthConfig {
+    /// `http` or `https` URL the credentials are POSTed to
+    pub url: String,
+    pub request_timeout_secs: u64,
+    /// How long a passed check is cached, 0 disables the caching
+    pub positive_cache_ttl_secs: u64,
+    /// How long a rejected check is cached, 0 disables the caching
+    pub negative_cache_ttl_secs: u64,
+}
+
+/// The result of a check and its expiration time
+type CacheEntry = (Result<(), AuthError>, Instant);
+
+#[derive(Serialize)]
+struct Request<'a> {
+    username: &'a str,
+    password: &'a str,
+}
+
+/// Checks the credentials with an external HTTP service.
+/// The credentials are sent as a JSON object `{"username": "...", "password": "..."}`,
+/// where the password may also be a token.
+/// A `2xx` response passes the client, `401` and `403` reject it, and anything else
+/// is considered an internal error. The internal errors are not cached.
+pub struct HttpAuth {
+    url: http::Uri,
+    tls_config: Option<Arc<ClientConfig>>,
+    request_timeout: Duration,
+    positive_cache_ttl: Duration,
+    negative_cache_ttl: Duration,
+    /// The results by the digest of the credentials, so the passwords are not kept in memory
+    cache: Mutex<HashMap<[u8; 32], CacheEntry>>,
+}
+
+impl HttpAuth {
+    pub fn from_config(config: &HttpAuthConfig) -> Result<Self, AuthError> {
+        let url: http::Uri = config.url.parse().map_err(|e| {
+            log::error!("Invalid authentication service URL {}: {}", config.url, e);
+            AuthError::Internal
+        })?;
+        let tls_config = match url.scheme_str() {
+            Some("https") => Some(net_utils::make_client_tls_config().map_err(|e| {
+                log::error!("Failed to make TLS configuration: {}", e);
+                AuthError::Internal
+            })?),
+            _ => None,
+        };
+
+        Ok(Self {
+            url,
+            tls_config,
+            request_timeout: Duration::from_secs(config.request_timeout_secs),
+            positive_cache_ttl: Duration::from_secs(config.positive_cache_ttl_secs),
+            negative_cache_ttl: Duration::from_secs(config.negative_cache_ttl_secs),
+            cache: Default::default(),
+        })
+    }
+
+    async fn query(&self, username: &str, password: &str) -> Result<(), AuthError> {
+        let body =
+            serde_json::to_vec(&Request { username, password }).map_err(|_| AuthError::Internal)?;
+        let request = http::Request::post(self.url.clone())
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Bytes::from(body))
+            .map_err(|_| AuthError::Internal)?;
+
+        let response = http_client::send(request, self.tls_config.as_ref(), self.request_timeout)
+            .await
+            .map_err(|e| {
+                log::warn!("Authentication service request failed: {}", e);
+                AuthError::Internal
+            })?;
+        match response.status() {
+            x if x.is_success() => Ok(()),
+            http::StatusCode::UNAUTHORIZED | http::StatusCode::FORBIDDEN => {
+                Err(AuthError::InvalidCredentials)
+            }
+            x => {
+                log::warn!("Unexpected authentication service response: {}", x);
+                Err(AuthError::Internal)
+            }
+        }
+    }
+
+    fn cache_key(username: &str, password: &str) -> [u8; 32] {
+        let mut context = digest::Context::new(&digest::SHA256);
+        context.update(username.as_bytes());
+        context.update(&[0]);
+        context.update(password.as_bytes());
+        let mut key = [0; 32];
+        key.copy_from_slice(context.finish().as_ref());
+        key
+    }
+}
+
+#[async_trait]
+impl AsyncAuthProvider for HttpAuth {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<(), AuthError> {
+        let key = Self::cache_key(username, password);
+        let now = Instant::now();
+        if let Some((result, _)) = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(&key)
+            .filter(|(_, expires)| *expires > now)
+        {
+            return *result;
+        }
+
+        let result = self.query(username, password).await;
+        let ttl = match result {
+            Ok(()) => self.positive_cache_ttl,
+            Err(AuthError::Internal) => Duration::ZERO,
+            Err(_) => self.negative_cache_ttl,
+        };
+        if !ttl.is_zero() {
+            let mut cache = self.cache.lock().unwrap();
+            if cache.len() >= MAX_CACHE_SIZE {
+                let now = Instant::now();
+                cache.retain(|_, (_, expires)| *expires > now);
+                if cache.len() >= MAX_CACHE_SIZE {
+                    cache.clear();
+                }
+            }
+            cache.insert(key, (result, Instant::now() + ttl));
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Passes `alice:secret`, rejects the other users, and fails for `broken`
+    fn run_service() -> (SocketAddr, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let server = hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(
+            hyper::service::make_service_fn(move |_| {
+                let counter = counter.clone();
+                async move {
+                    Ok::<_, Infallible>(hyper::service::service_fn(
+                        move |request: http::Request<hyper::Body>| {
+                            let counter = counter.clone();
+                            async move {
+                                counter.fetch_add(1, Ordering::Relaxed);
+                                let body = hyper::body::to_bytes(request.into_body()).await?;
+                                let body: serde_json::Value =
+                                    serde_json::from_slice(&body).unwrap();
+                                let status =
+                                    match (body["username"].as_str(), body["password"].as_str()) {
+                                        (Some("alice"), Some("secret")) => {
+                                            http::StatusCode::NO_CONTENT
+                                        }
+                                        (Some("broken"), _) => http::StatusCode::BAD_GATEWAY,
+                                        _ => http::StatusCode::FORBIDDEN,
+                                    };
+                                let mut response = http::Response::new(hyper::Body::empty());
+                                *response.status_mut() = status;
+                                Ok::<_, hyper::Error>(response)
+                            }
+                        },
+                    ))
+                }
+            }),
+        );
+        let address = server.local_addr();
+        tokio::spawn(server);
+        (address, requests)
+    }
+
+    fn config(address: SocketAddr) -> HttpAuthConfig {
+        HttpAuthConfig {
+            url: format!("http://{}/auth", address),
+            request_timeout_secs: 5,
+            positive_cache_ttl_secs: 3600,
+            negative_cache_ttl_secs: 3600,
+        }
+    }
+
+    #[tokio::test]
+    async fn service_responses() {
+        let (address, requests) = run_service();
+        let auth = HttpAuth::from_config(&HttpAuthConfig {
+            positive_cache_ttl_secs: 0,
+            negative_cache_ttl_secs: 0,
+            ..config(address)
+        })
+        .unwrap();
+
+        assert_eq!(auth.authenticate("alice", "secret").await, Ok(()));
+        assert_eq!(
+            auth.authenticate("alice", "wrong").await,
+            Err(AuthError::InvalidCredentials)
+        );
+        assert_eq!(
+            auth.authenticate("broken", "secret").await,
+            Err(AuthError::Internal)
+        );
+        assert_eq!(auth.authenticate("alice", "secret").await, Ok(()));
+        assert_eq!(requests.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn cached_results() {
+        let (address, requests) = run_service();
+        let auth = HttpAuth::from_config(&config(address)).unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(auth.authenticate("alice", "secret").await, Ok(()));
+            assert_eq!(
+                auth.authenticate("alice", "wrong").await,
+                Err(AuthError::InvalidCredentials)
+            );
+        }
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+
+        // The internal errors are not cached
+        for _ in 0..2 {
+            assert_eq!(
+                auth.authenticate("broken", "secret").await,
+                Err(AuthError::Internal)
+            );
+        }
+        assert_eq!(requests.load(Ordering::Relaxed), 4);
+
+        // Expired entries are queried again
+        for (_, expires) in auth.cache.lock().unwrap().values_mut() {
+            *expires = Instant::now();
+        }
+        assert_eq!(auth.authenticate("alice", "secret").await, Ok(()));
+        assert_eq!(requests.load(Ordering::Relaxed), 5);
+    }
+
+    #[tokio::test]
+    async fn unreachable_service() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+
+        let auth = HttpAuth::from_config(&config(address)).unwrap();
+        assert_eq!(
+            auth.authenticate("alice", "secret").await,
+            Err(AuthError::Internal)
+        );
+    }
+}
@@ -1,22 +1,97 @@
-use crate::authentication::{AuthError, AuthProvider};
+use crate::authentication::{AsyncAuthProvider, AuthError};
+use async_trait::async_trait;
 
+/// Tries the providers in order until one of them passes the client
 pub struct MixedAuth {
-    jwt: Box<dyn AuthProvider>,
-    credentials: Box<dyn AuthProvider>,
+    providers: Vec<Box<dyn AsyncAuthProvider>>,
 }
 
 impl MixedAuth {
-    pub fn new(jwt: Box<dyn AuthProvider>, credentials: Box<dyn AuthProvider>) -> Self {
-        Self { jwt, credentials }
+    pub fn new(providers: Vec<Box<dyn AsyncAuthProvider>>) -> Self {
+        Self { providers }
     }
 }
 
-impl AuthProvider for MixedAuth {
-    fn authenticate(&self, username: &str, password: &str) -> Result<(), AuthError> {
-        if self.jwt.authenticate(username, password).is_ok() {
-            return Ok(());
+#[async_trait]
+impl AsyncAuthProvider for MixedAuth {
+    /// Returns the error of the last provider if all of them reject the client,
+    /// or the internal error if any of them failed, as it might have passed the client
+    async fn authenticate(&self, username: &str, password: &str) -> Result<(), AuthError> {
+        let mut result = Err(AuthError::InvalidCredentials);
+        let mut failed = false;
+        for provider in &self.providers {
+            result = provider.authenticate(username, password).await;
+            match result {
+                Ok(()) => return result,
+                Err(AuthError::Internal) => failed = true,
+                Err(_) => (),
+            }
         }
 
-        self.credentials.authenticate(username, password)
+        match failed {
+            true => Err(AuthError::Internal),
+            false => result,
+        }
+    }
+
+    /// Every provider with an account registry must accept the client
+    async fn check_account(&self, username: &str) -> Result<(), AuthError> {
+        for provider in &self.providers {
+            provider.check_account(username).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::{AuthProvider, BlockingAuthProvider};
+
+    struct User(&'static str, AuthError);
+
+    impl AuthProvider for User {
+        fn authenticate(&self, username: &str, _: &str) -> Result<(), AuthError> {
+            (username == self.0).then_some(()).ok_or(self.1)
+        }
+    }
+
+    #[tokio::test]
+    async fn providers_chain() {
+        let auth = MixedAuth::new(vec![
+            Box::new(BlockingAuthProvider::new(User(
+                "a",
+                AuthError::InvalidToken,
+            ))),
+            Box::new(BlockingAuthProvider::new(User(
+                "b",
+                AuthError::InvalidCredentials,
+            ))),
+        ]);
+
+        assert_eq!(auth.authenticate("a", "").await, Ok(()));
+        assert_eq!(auth.authenticate("b", "").await, Ok(()));
+        assert_eq!(
+            auth.authenticate("c", "").await,
+            Err(AuthError::InvalidCredentials)
+        );
+        assert_eq!(
+            MixedAuth::new(vec![]).authenticate("a", "").await,
+            Err(AuthError::InvalidCredentials)
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_provider() {
+        let auth = MixedAuth::new(vec![
+            Box::new(BlockingAuthProvider::new(User("a", AuthError::Internal))),
+            Box::new(BlockingAuthProvider::new(User(
+                "b",
+                AuthError::InvalidCredentials,
+            ))),
+        ]);
+
+        assert_eq!(auth.authenticate("b", "").await, Ok(()));
+        assert_eq!(auth.authenticate("c", "").await, Err(AuthError::Internal));
     }
 }
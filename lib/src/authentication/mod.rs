@@ -1,13 +1,29 @@
+pub mod client_certificate;
 pub mod credentials;
+pub mod http_auth;
 pub mod jwt;
 pub mod mixed;
+pub mod password;
+pub mod radius_auth;
 pub mod registry_based;
 
 use crate::log_id;
 use crate::log_utils;
+use crate::metrics;
+use crate::settings::{AuthMode, HttpAuthSettings, RadiusSettings, Settings};
+use async_trait::async_trait;
 use base64::engine::general_purpose::STANDARD as BASE64_ENGINE;
 use base64::Engine;
+use http_auth::HttpAuth;
+use jwt::JwtAuth;
+use mixed::MixedAuth;
+use radius_auth::RadiusAuth;
+use registry_based::CredentialsAuth;
 use std::borrow::Cow;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
 
 /// Authentication request source
 #[derive(Debug, Clone, PartialEq)]
@@ -17,6 +33,9 @@ pub enum Source<'this> {
     /// A client tries to authenticate using
     /// [the basic authentication scheme](https://datatracker.ietf.org/doc/html/rfc7617)
     ProxyBasic(Cow<'this, str>),
+    /// A client presented a TLS certificate verified against the CA bundle of the host.
+    /// Contains the username mapped from the certificate.
+    ClientCertificate(Cow<'this, str>),
 }
 
 /// Authentication procedure status
@@ -26,41 +45,185 @@ pub enum Status {
     Pass,
     /// Failure
     Reject,
+    /// The check could not be completed, e.g., the authentication service failed or
+    /// did not respond in time. The client is rejected, but this does not count as
+    /// its failed attempt.
+    Unavailable,
 }
 
 /// The authenticator abstract interface
+#[async_trait]
 pub trait Authenticator: Send + Sync {
+    /// Authenticate client.
+    /// Dropping the returned future stops awaiting the result, but the checks running
+    /// on the blocking thread pool, see [`BlockingAuthProvider`], still run to completion.
+    async fn authenticate(&self, source: &Source<'_>, log_id: &log_utils::IdChain<u64>) -> Status;
+}
+
+/// The synchronous authenticator interface, every implementation is an [`Authenticator`].
+/// The check is called directly on the runtime thread, so it must not block for long:
+/// it holds up the other clients and can't be interrupted by the authentication timeout.
+pub trait BlockingAuthenticator: Send + Sync {
     /// Authenticate client
     fn authenticate(&self, source: &Source<'_>, log_id: &log_utils::IdChain<u64>) -> Status;
 }
 
-#[derive(Debug)]
+#[async_trait]
+impl<T: BlockingAuthenticator> Authenticator for T {
+    async fn authenticate(&self, source: &Source<'_>, log_id: &log_utils::IdChain<u64>) -> Status {
+        BlockingAuthenticator::authenticate(self, source, log_id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AuthError {
     InvalidCredentials,
+    /// The token is malformed
     InvalidToken,
     InvalidAuthHeader,
     Internal,
+    /// The token is signed with an algorithm not allowed by the configuration
+    UnsupportedAlgorithm,
+    /// None of the verification keys matches the token signature
+    InvalidSignature,
+    /// A required claim is absent
+    MissingClaim,
+    /// A claim has an unexpected type
+    InvalidClaim,
+    /// The `exp` claim is in the past
+    TokenExpired,
+    /// The `nbf` claim is in the future
+    TokenNotYetValid,
+    /// The `iat` claim is in the future
+    TokenIssuedInFuture,
+    /// The `iat` claim is older than the configured maximum token age
+    TokenTooOld,
+    /// The `iss` claim does not match the configured issuer
+    InvalidIssuer,
+    /// The `aud` claim does not contain the configured audience
+    InvalidAudience,
+    /// The username claim does not match the username of the client
+    UsernameMismatch,
+    /// The `jti` claim is in the denylist
+    TokenRevoked,
+    /// The authentication took longer than the configured timeout
+    Timeout,
+    /// The client IP address or username is blocked after failed authentication attempts
+    Blocked,
+    /// The client account is disabled
+    AccountDisabled,
+    /// The validity period of the client account has not started yet
+    AccountNotYetValid,
+    /// The validity period of the client account is over
+    AccountExpired,
 }
 
+impl AuthError {
+    /// The reason label for logs and metrics
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthError::InvalidCredentials => "invalid_credentials",
+            AuthError::InvalidToken => "invalid_token",
+            AuthError::InvalidAuthHeader => "invalid_auth_header",
+            AuthError::Internal => "internal",
+            AuthError::UnsupportedAlgorithm => "unsupported_algorithm",
+            AuthError::InvalidSignature => "invalid_signature",
+            AuthError::MissingClaim => "missing_claim",
+            AuthError::InvalidClaim => "invalid_claim",
+            AuthError::TokenExpired => "token_expired",
+            AuthError::TokenNotYetValid => "token_not_yet_valid",
+            AuthError::TokenIssuedInFuture => "token_issued_in_future",
+            AuthError::TokenTooOld => "token_too_old",
+            AuthError::InvalidIssuer => "invalid_issuer",
+            AuthError::InvalidAudience => "invalid_audience",
+            AuthError::UsernameMismatch => "username_mismatch",
+            AuthError::TokenRevoked => "token_revoked",
+            AuthError::Timeout => "timeout",
+            AuthError::Blocked => "blocked",
+            AuthError::AccountDisabled => "account_disabled",
+            AuthError::AccountNotYetValid => "account_not_yet_valid",
+            AuthError::AccountExpired => "account_expired",
+        }
+    }
+}
+
+/// The credentials check not involving I/O, see [`BlockingAuthProvider`]
 pub trait AuthProvider: Send + Sync {
     fn authenticate(&self, username: &str, password: &str) -> Result<(), AuthError>;
+
+    /// Check the account of a client authenticated without a password, i.e., with
+    /// a TLS client certificate. The providers without an account registry accept any client.
+    fn check_account(&self, _username: &str) -> Result<(), AuthError> {
+        Ok(())
+    }
+}
+
+/// The credentials check which may consult an external service
+#[async_trait]
+pub trait AsyncAuthProvider: Send + Sync {
+    /// The check is cancelled if the returned future is dropped, unless the implementation
+    /// hands it over to another task like [`BlockingAuthProvider`] does
+    async fn authenticate(&self, username: &str, password: &str) -> Result<(), AuthError>;
+
+    /// See [`AuthProvider::check_account`]
+    async fn check_account(&self, _username: &str) -> Result<(), AuthError> {
+        Ok(())
+    }
+}
+
+/// Adapts an [`AuthProvider`] to [`AsyncAuthProvider`].
+/// The checks are run on the blocking thread pool as the password hashing and
+/// the signature verification may take a while. A started check can't be cancelled,
+/// it runs to completion even if the client is rejected on the timeout.
+pub struct BlockingAuthProvider<P> {
+    inner: Arc<P>,
+}
+
+impl<P: AuthProvider + 'static> BlockingAuthProvider<P> {
+    pub fn new(provider: P) -> Self {
+        Self {
+            inner: Arc::new(provider),
+        }
+    }
+}
+
+#[async_trait]
+impl<P: AuthProvider + 'static> AsyncAuthProvider for BlockingAuthProvider<P> {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<(), AuthError> {
+        let provider = self.inner.clone();
+        let username = username.to_string();
+        let password = password.to_string();
+        tokio::task::spawn_blocking(move || provider.authenticate(&username, &password))
+            .await
+            .unwrap_or(Err(AuthError::Internal))
+    }
+
+    async fn check_account(&self, username: &str) -> Result<(), AuthError> {
+        self.inner.check_account(username)
+    }
 }
 
 pub struct ProxyBasicAuthenticator {
-    provider: Box<dyn AuthProvider>,
+    provider: Box<dyn AsyncAuthProvider>,
 }
 
 impl ProxyBasicAuthenticator {
-    pub fn new(provider: Box<dyn AuthProvider>) -> Self {
+    pub fn new(provider: Box<dyn AsyncAuthProvider>) -> Self {
         Self { provider }
     }
 }
 
+#[async_trait]
 impl Authenticator for ProxyBasicAuthenticator {
-    fn authenticate(&self, source: &Source<'_>, log_id: &log_utils::IdChain<u64>) -> Status {
+    async fn authenticate(&self, source: &Source<'_>, log_id: &log_utils::IdChain<u64>) -> Status {
         let basic = match source {
             Source::ProxyBasic(value) => value,
-            _ => return Status::Reject,
+            // The certificate chain is verified during the TLS handshake,
+            // but the account of the user it is mapped to may be inactive
+            Source::ClientCertificate(username) => {
+                return Self::on_result(self.provider.check_account(username).await, log_id)
+            }
+            Source::Sni(_) => return Status::Reject,
         };
 
         let decoded = match BASE64_ENGINE.decode(basic.as_ref()) {
@@ -83,11 +246,21 @@ impl Authenticator for ProxyBasicAuthenticator {
             None => return Status::Reject,
         };
 
-        match self.provider.authenticate(username, password) {
+        Self::on_result(self.provider.authenticate(username, password).await, log_id)
+    }
+}
+
+impl ProxyBasicAuthenticator {
+    fn on_result(result: Result<(), AuthError>, log_id: &log_utils::IdChain<u64>) -> Status {
+        match result {
             Ok(()) => Status::Pass,
             Err(err) => {
-                log_id!(debug, log_id, "Authentication rejected: {:?}", err);
-                Status::Reject
+                log_id!(debug, log_id, "Authentication rejected: {}", err.as_str());
+                metrics::add_auth_failure(err);
+                match err {
+                    AuthError::Internal | AuthError::Timeout => Status::Unavailable,
+                    _ => Status::Reject,
+                }
             }
         }
     }
@@ -98,6 +271,218 @@ impl Source<'_> {
         match self {
             Source::Sni(x) => Source::Sni(Cow::Owned(x.into_owned())),
             Source::ProxyBasic(x) => Source::ProxyBasic(Cow::Owned(x.into_owned())),
+            Source::ClientCertificate(x) => Source::ClientCertificate(Cow::Owned(x.into_owned())),
+        }
+    }
+
+    /// Extract the username from the authentication info.
+    /// Returns [`None`] if the source does not carry the username in a known format.
+    pub fn username(&self) -> Option<String> {
+        match self {
+            Source::Sni(_) => None,
+            Source::ProxyBasic(x) => BASE64_ENGINE
+                .decode(x.as_ref())
+                .ok()
+                .and_then(|x| String::from_utf8(x).ok())
+                .and_then(|x| x.split_once(':').map(|(u, _)| u.to_string()))
+                .filter(|x| !x.is_empty()),
+            Source::ClientCertificate(x) => Some(x.to_string()),
         }
     }
 }
+
+/// Authenticate the client within the timeout, the client is rejected on its expiration
+pub(crate) async fn authenticate_with_timeout(
+    authenticator: &dyn Authenticator,
+    source: &Source<'_>,
+    timeout: Duration,
+    log_id: &log_utils::IdChain<u64>,
+) -> Status {
+    match tokio::time::timeout(timeout, authenticator.authenticate(source, log_id)).await {
+        Ok(x) => x,
+        Err(_) => {
+            log_id!(
+                debug,
+                log_id,
+                "Authentication rejected: {}",
+                AuthError::Timeout.as_str()
+            );
+            metrics::add_auth_failure(AuthError::Timeout);
+            Status::Unavailable
+        }
+    }
+}
+
+/// Make the authenticator according to the authentication mode and the client credentials
+/// from the settings.
+/// Returns [`None`] if the credentials mode is configured without any client.
+pub fn from_settings(settings: &Settings) -> io::Result<Option<Arc<dyn Authenticator>>> {
+    let make_jwt_auth = || -> io::Result<JwtAuth> {
+        let jwt_settings = settings.auth.jwt.as_ref().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::Other,
+                "[auth.jwt] must be configured for JWT and mixed modes",
+            )
+        })?;
+        JwtAuth::from_config(&jwt_settings.to_auth_config()).map_err(|e| {
+            io::Error::new(
+                ErrorKind::Other,
+                format!("Couldn't initialize JWT auth: {:?}", e),
+            )
+        })
+    };
+
+    let make_http_auth = |http_settings: &HttpAuthSettings| -> io::Result<HttpAuth> {
+        HttpAuth::from_config(&http_settings.to_auth_config()).map_err(|e| {
+            io::Error::new(
+                ErrorKind::Other,
+                format!("Couldn't initialize HTTP auth: {:?}", e),
+            )
+        })
+    };
+
+    let make_radius_auth = |radius_settings: &RadiusSettings| -> io::Result<RadiusAuth> {
+        RadiusAuth::from_config(&radius_settings.to_auth_config()).map_err(|e| {
+            io::Error::new(
+                ErrorKind::Other,
+                format!("Couldn't initialize RADIUS auth: {:?}", e),
+            )
+        })
+    };
+
+    let provider: Option<Box<dyn AsyncAuthProvider>> = match settings.auth.mode {
+        AuthMode::Credentials if settings.clients.is_empty() => None,
+        AuthMode::Credentials => Some(Box::new(BlockingAuthProvider::new(CredentialsAuth::new(
+            &settings.clients,
+        )))),
+        AuthMode::Jwt => Some(Box::new(BlockingAuthProvider::new(make_jwt_auth()?))),
+        AuthMode::Http => {
+            let http_settings = settings.auth.http.as_ref().ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::Other,
+                    "[auth.http] must be configured for HTTP mode",
+                )
+            })?;
+            Some(Box::new(make_http_auth(http_settings)?))
+        }
+        AuthMode::Radius => {
+            let radius_settings = settings.auth.radius.as_ref().ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::Other,
+                    "[auth.radius] must be configured for RADIUS mode",
+                )
+            })?;
+            Some(Box::new(make_radius_auth(radius_settings)?))
+        }
+        AuthMode::Mixed => {
+            let mut providers: Vec<Box<dyn AsyncAuthProvider>> = vec![
+                Box::new(BlockingAuthProvider::new(make_jwt_auth()?)),
+                Box::new(BlockingAuthProvider::new(CredentialsAuth::new(
+                    &settings.clients,
+                ))),
+            ];
+            if let Some(x) = &settings.auth.http {
+                providers.push(Box::new(make_http_auth(x)?));
+            }
+            if let Some(x) = &settings.auth.radius {
+                providers.push(Box::new(make_radius_auth(x)?));
+            }
+            Some(Box::new(MixedAuth::new(providers)))
+        }
+    };
+
+    Ok(provider.map(|x| Arc::new(ProxyBasicAuthenticator::new(x)) as Arc<dyn Authenticator>))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    struct Fixed(&'static str);
+
+    impl AuthProvider for Fixed {
+        fn authenticate(&self, _: &str, password: &str) -> Result<(), AuthError> {
+            (password == self.0)
+                .then_some(())
+                .ok_or(AuthError::InvalidCredentials)
+        }
+    }
+
+    struct Failing;
+
+    impl AuthProvider for Failing {
+        fn authenticate(&self, _: &str, _: &str) -> Result<(), AuthError> {
+            Err(AuthError::Internal)
+        }
+    }
+
+    struct Slow(Arc<AtomicBool>);
+
+    #[async_trait]
+    impl AsyncAuthProvider for Slow {
+        async fn authenticate(&self, _: &str, _: &str) -> Result<(), AuthError> {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            self.0.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    fn basic(credentials: &str) -> Source<'static> {
+        Source::ProxyBasic(BASE64_ENGINE.encode(credentials).into())
+    }
+
+    #[tokio::test]
+    async fn blocking_provider() {
+        let auth = ProxyBasicAuthenticator::new(Box::new(BlockingAuthProvider::new(Fixed("b"))));
+        let log_id = log_utils::IdChain::empty();
+
+        assert!(auth.authenticate(&basic("a:b"), &log_id).await == Status::Pass);
+        assert!(auth.authenticate(&basic("a:c"), &log_id).await == Status::Reject);
+        assert!(auth.authenticate(&basic("a"), &log_id).await == Status::Reject);
+        assert!(auth.authenticate(&Source::Sni("a".into()), &log_id).await == Status::Reject);
+        let source = Source::ClientCertificate("a".into());
+        assert!(auth.authenticate(&source, &log_id).await == Status::Pass);
+        assert_eq!(source.username().as_deref(), Some("a"));
+
+        let auth = ProxyBasicAuthenticator::new(Box::new(BlockingAuthProvider::new(Failing)));
+        assert!(auth.authenticate(&basic("a:b"), &log_id).await == Status::Unavailable);
+    }
+
+    struct Constant(Status);
+
+    impl BlockingAuthenticator for Constant {
+        fn authenticate(&self, _: &Source<'_>, _: &log_utils::IdChain<u64>) -> Status {
+            self.0.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn blocking_authenticator() {
+        let log_id = log_utils::IdChain::empty();
+        for status in [Status::Pass, Status::Reject, Status::Unavailable] {
+            let auth: Arc<dyn Authenticator> = Arc::new(Constant(status.clone()));
+            assert!(auth.authenticate(&basic("a:b"), &log_id).await == status);
+        }
+    }
+
+    #[tokio::test]
+    async fn authentication_timeout() {
+        let completed = Arc::new(AtomicBool::new(false));
+        let auth = ProxyBasicAuthenticator::new(Box::new(Slow(completed.clone())));
+        let log_id = log_utils::IdChain::empty();
+
+        let status =
+            authenticate_with_timeout(&auth, &basic("a:b"), Duration::from_millis(50), &log_id)
+                .await;
+        assert!(status == Status::Unavailable);
+        // The timed out asynchronous check is cancelled
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!completed.load(Ordering::Relaxed));
+
+        let status =
+            authenticate_with_timeout(&auth, &basic("a:b"), Duration::from_secs(5), &log_id).await;
+        assert!(status == Status::Pass);
+        assert!(completed.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,224 @@
+//! The client passwords of the credentials file.
+//! A password is stored either in plain text or as a hash: an argon2 or scrypt
+//! [PHC string](https://github.com/P-H-C/phc-string-format/blob/master/phc-sf-spec.md),
+//! or a bcrypt (`$2a$`, `$2b$`, `$2x$`, `$2y$`) hash.
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use ring::rand::SecureRandom;
+use subtle::ConstantTimeEq;
+
+const BCRYPT_PREFIXES: [&str; 4] = ["$2a$", "$2b$", "$2x$", "$2y$"];
+
+/// The way a password is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Plain,
+    Argon2,
+    Scrypt,
+    Bcrypt,
+}
+
+impl Format {
+    /// Detect the format of a stored password by its prefix
+    pub fn of(stored: &str) -> Self {
+        if stored.starts_with("$argon2") {
+            Format::Argon2
+        } else if stored.starts_with("$scrypt$") {
+            Format::Scrypt
+        } else if BCRYPT_PREFIXES.iter().any(|x| stored.starts_with(x)) {
+            Format::Bcrypt
+        } else {
+            Format::Plain
+        }
+    }
+}
+
+/// Check that a stored password hash is well-formed
+pub(crate) fn validate(stored: &str) -> Result<(), String> {
+    match Format::of(stored) {
+        Format::Plain => Ok(()),
+        Format::Argon2 => {
+            let hash = parse_phc(stored)?;
+            argon2::Algorithm::try_from(hash.algorithm).map_err(|e| e.to_string())?;
+            argon2::Params::try_from(&hash)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }
+        Format::Scrypt => scrypt::Params::try_from(&parse_phc(stored)?)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        Format::Bcrypt => stored
+            .parse::<bcrypt::HashParts>()
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+    }
+}
+
+fn parse_phc(stored: &str) -> Result<PasswordHash<'_>, String> {
+    let hash = PasswordHash::new(stored).map_err(|e| e.to_string())?;
+    match hash.hash {
+        Some(_) => Ok(hash),
+        None => Err("no hash value".into()),
+    }
+}
+
+/// Check the password against the stored one.
+/// The plain text passwords are compared in constant time.
+pub fn verify(stored: &str, password: &str) -> bool {
+    match Format::of(stored) {
+        Format::Plain => stored.as_bytes().ct_eq(password.as_bytes()).into(),
+        Format::Argon2 => PasswordHash::new(stored)
+            .and_then(|x| Argon2::default().verify_password(password.as_bytes(), &x))
+            .is_ok(),
+        Format::Scrypt => PasswordHash::new(stored)
+            .and_then(|x| scrypt::Scrypt.verify_password(password.as_bytes(), &x))
+            .is_ok(),
+        Format::Bcrypt => bcrypt::verify(password, stored).unwrap_or(false),
+    }
+}
+
+/// Hash the password with argon2id and the default parameters
+pub fn hash(password: &str) -> String {
+    Argon2::default()
+        .hash_password(password.as_bytes(), &random_salt())
+        .expect("Default parameters are valid")
+        .to_string()
+}
+
+/// Make a hash of a random password with the scheme and the parameters of the stored one.
+/// Verifying a password against it takes as long as against the stored one, so
+/// the unknown users are not told apart from the known ones by the response time.
+pub(crate) fn dummy(stored: &str) -> String {
+    let salt = random_salt();
+    let password = salt.as_str().as_bytes();
+    let result = match Format::of(stored) {
+        Format::Plain => Ok(salt.to_string()),
+        Format::Argon2 => dummy_phc(&Argon2::default(), stored, password, &salt),
+        Format::Scrypt => dummy_phc(&scrypt::Scrypt, stored, password, &salt),
+        Format::Bcrypt => stored
+            .parse::<bcrypt::HashParts>()
+            .map_err(|e| e.to_string())
+            .and_then(|x| bcrypt::hash(password, x.get_cost()).map_err(|e| e.to_string())),
+    };
+    result.unwrap_or_else(|e| {
+        debug!("Falling back to the default dummy hash: {}", e);
+        hash(salt.as_str())
+    })
+}
+
+fn dummy_phc<H>(
+    hasher: &H,
+    stored: &str,
+    password: &[u8],
+    salt: &SaltString,
+) -> Result<String, String>
+where
+    H: PasswordHasher,
+    for<'a> H::Params: TryFrom<&'a PasswordHash<'a>>,
+{
+    let stored = parse_phc(stored)?;
+    let params = H::Params::try_from(&stored).map_err(|_| "invalid parameters".to_string())?;
+    hasher
+        .hash_password_customized(
+            password,
+            Some(stored.algorithm),
+            stored.version,
+            params,
+            salt,
+        )
+        .map(|x| x.to_string())
+        .map_err(|e| e.to_string())
+}
+
+fn random_salt() -> SaltString {
+    let mut salt = [0; 16];
+    ring::rand::SystemRandom::new()
+        .fill(&mut salt)
+        .expect("Couldn't generate salt");
+    SaltString::encode_b64(&salt).expect("Salt length is valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_formats() {
+        let salt = SaltString::encode_b64(b"0123456789abcdef").unwrap();
+        let argon2 = Argon2::new(
+            argon2::Algorithm::Argon2id,
+            argon2::Version::V0x13,
+            argon2::Params::new(64, 1, 1, None).unwrap(),
+        )
+        .hash_password(b"secret", &salt)
+        .unwrap()
+        .to_string();
+        let scrypt = scrypt::Scrypt
+            .hash_password_customized(
+                b"secret",
+                None,
+                None,
+                scrypt::Params::new(4, 8, 1, 32).unwrap(),
+                &salt,
+            )
+            .unwrap()
+            .to_string();
+        let bcrypt = bcrypt::hash("secret", 4).unwrap();
+
+        for (stored, format) in [
+            (argon2.as_str(), Format::Argon2),
+            (scrypt.as_str(), Format::Scrypt),
+            (bcrypt.as_str(), Format::Bcrypt),
+            ("secret", Format::Plain),
+        ] {
+            assert_eq!(Format::of(stored), format, "{}", stored);
+            assert!(validate(stored).is_ok(), "{}", stored);
+            assert!(verify(stored, "secret"), "{}", stored);
+            assert!(!verify(stored, "secret1"), "{}", stored);
+            assert!(!verify(stored, ""), "{}", stored);
+        }
+
+        assert!(validate("$argon2id$v=19$garbage").is_err());
+        assert!(validate("$argon2id$v=19$m=19456,t=2,p=1$c2FsdHNhbHQ").is_err());
+        assert!(validate(&argon2.replace("argon2id", "argon2x")).is_err());
+        assert!(validate(&scrypt.replace("ln=4", "ln=100")).is_err());
+        assert!(validate("$2b$04$short").is_err());
+        assert!(!verify("$argon2id$v=19$garbage", "$argon2id$v=19$garbage"));
+    }
+
+    #[test]
+    fn dummy_hashes() {
+        let salt = SaltString::encode_b64(b"0123456789abcdef").unwrap();
+        let argon2 = Argon2::new(
+            argon2::Algorithm::Argon2i,
+            argon2::Version::V0x10,
+            argon2::Params::new(64, 1, 1, None).unwrap(),
+        )
+        .hash_password(b"secret", &salt)
+        .unwrap()
+        .to_string();
+        let bcrypt = bcrypt::hash("secret", 4).unwrap();
+
+        let x = dummy(&argon2);
+        assert!(x.starts_with("$argon2i$v=16$m=64,t=1,p=1$"), "{}", x);
+        assert!(!verify(&x, "secret"));
+
+        let x = dummy(&bcrypt);
+        assert!(x.starts_with(&bcrypt[..7]), "{}", x);
+        assert!(!verify(&x, "secret"));
+
+        assert_eq!(Format::of(&dummy("secret")), Format::Plain);
+        assert_eq!(Format::of(&dummy("$argon2id$garbage")), Format::Argon2);
+    }
+
+    #[test]
+    fn hash_roundtrip() {
+        let stored = hash("secret");
+        assert_eq!(Format::of(&stored), Format::Argon2);
+        assert!(stored.starts_with("$argon2id$"));
+        assert!(verify(&stored, "secret"));
+        assert!(!verify(&stored, "Secret"));
+        assert_ne!(hash("secret"), stored);
+    }
+}
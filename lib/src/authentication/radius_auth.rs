@@ -0,0 +1,91 @@
+use crate::authentication::{AsyncAuthProvider, AuthError};
+use crate::radius;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RadiusAuthConfig {
+    /// `host:port` of the RADIUS server
+    pub server: String,
+    /// The name of the environment variable containing the shared secret
+    pub secret_env: String,
+    /// The `NAS-Identifier` attribute of the requests
+    pub nas_identifier: String,
+    /// Timeout of a single request attempt
+    pub timeout_secs: u64,
+    /// Number of the request retransmissions on timeout
+    pub retries: usize,
+}
+
+/// Checks the credentials with a RADIUS server using PAP:
+/// an `Access-Accept` response passes the client, an `Access-Reject` rejects it,
+/// and a failed exchange is considered an internal error
+pub struct RadiusAuth {
+    client: radius::Client,
+}
+
+impl RadiusAuth {
+    pub fn from_config(config: &RadiusAuthConfig) -> Result<Self, AuthError> {
+        let client = radius::Client::new(config).map_err(|e| {
+            log::error!("Failed to make RADIUS client: {}", e);
+            AuthError::Internal
+        })?;
+
+        Ok(Self { client })
+    }
+}
+
+#[async_trait]
+impl AsyncAuthProvider for RadiusAuth {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<(), AuthError> {
+        match self.client.authenticate(username, password).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(AuthError::InvalidCredentials),
+            Err(e) => {
+                log::warn!("RADIUS authentication request failed: {}", e);
+                Err(AuthError::Internal)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::radius::test_server;
+
+    #[tokio::test]
+    async fn server_responses() {
+        let server = test_server::run("radius_auth_responses").await;
+        let auth = RadiusAuth::from_config(&server.config()).unwrap();
+
+        assert_eq!(auth.authenticate("alice", "secret").await, Ok(()));
+        assert_eq!(
+            auth.authenticate("alice", "wrong").await,
+            Err(AuthError::InvalidCredentials)
+        );
+        assert_eq!(
+            auth.authenticate("bob", "secret").await,
+            Err(AuthError::InvalidCredentials)
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_server() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        std::env::set_var("RADIUS_AUTH_UNREACHABLE", "x");
+        let auth = RadiusAuth::from_config(&RadiusAuthConfig {
+            server: socket.local_addr().unwrap().to_string(),
+            secret_env: "RADIUS_AUTH_UNREACHABLE".into(),
+            nas_identifier: "test".into(),
+            timeout_secs: 1,
+            retries: 0,
+        })
+        .unwrap();
+
+        assert_eq!(
+            auth.authenticate("alice", "secret").await,
+            Err(AuthError::Internal)
+        );
+    }
+}
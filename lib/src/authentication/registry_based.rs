@@ -1,21 +1,72 @@
 use crate::authentication::{
-    AuthError, AuthProvider, Authenticator, ProxyBasicAuthenticator, Source, Status,
+    password, AuthError, AuthProvider, Authenticator, BlockingAuthProvider,
+    ProxyBasicAuthenticator, Source, Status,
 };
 use crate::log_utils;
+use crate::settings::QuotaPeriod;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use once_cell::sync::OnceCell;
 use serde::Deserialize;
 use std::collections::HashMap;
 
 /// A client descriptor
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone, Default)]
 pub struct Client {
     /// The client username
     pub username: String,
-    /// The client password
+    /// The client password in plain text or as a hash, see [`password`]
     pub password: String,
+    /// The groups the client belongs to
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// The maximum upload rate in bytes per second, overrides the default limit
+    #[serde(default)]
+    pub upload_bytes_per_sec: Option<u64>,
+    /// The maximum download rate in bytes per second, overrides the default limit
+    #[serde(default)]
+    pub download_bytes_per_sec: Option<u64>,
+    /// The number of bytes the client may transfer within [`Self::quota_period`].
+    /// Unlimited if not set.
+    #[serde(default)]
+    pub quota_bytes: Option<u64>,
+    /// The period after which the quota usage is reset
+    #[serde(default)]
+    pub quota_period: QuotaPeriod,
+    /// The moment before which the client is rejected
+    #[serde(default)]
+    pub not_before: Option<DateTime<Utc>>,
+    /// The moment starting from which the client is rejected
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Whether the account is suspended
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+/// The state of a client account at some moment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountStatus {
+    Active,
+    Disabled,
+    NotYetValid,
+    Expired,
 }
 
 pub struct CredentialsAuth {
-    clients: HashMap<String, String>,
+    clients: HashMap<String, Account>,
+    /// The stored password the dummy one is made after, see [`password::dummy`]
+    dummy_template: String,
+    /// The password the unknown users are checked against.
+    /// Made on the first use not to slow down the settings reloads.
+    dummy_password: OnceCell<String>,
+}
+
+struct Account {
+    password: String,
+    not_before: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+    disabled: bool,
 }
 
 /// Backward-compatible wrapper for previous authenticator type.
@@ -26,33 +77,341 @@ pub struct RegistryBasedAuthenticator {
 impl RegistryBasedAuthenticator {
     pub fn new(clients: &[Client]) -> Self {
         Self {
-            inner: ProxyBasicAuthenticator::new(Box::new(CredentialsAuth::new(clients))),
+            inner: ProxyBasicAuthenticator::new(Box::new(BlockingAuthProvider::new(
+                CredentialsAuth::new(clients),
+            ))),
         }
     }
 }
 
+#[async_trait]
 impl Authenticator for RegistryBasedAuthenticator {
-    fn authenticate(&self, source: &Source<'_>, log_id: &log_utils::IdChain<u64>) -> Status {
-        self.inner.authenticate(source, log_id)
+    async fn authenticate(&self, source: &Source<'_>, log_id: &log_utils::IdChain<u64>) -> Status {
+        self.inner.authenticate(source, log_id).await
+    }
+}
+
+impl Client {
+    /// Get the state of the account at the moment
+    pub fn status(&self, now: DateTime<Utc>) -> AccountStatus {
+        account_status(self.disabled, self.not_before, self.expires_at, now)
+    }
+
+    /// Describe why the endpoint currently rejects the account.
+    /// Returns [`None`] if the account is active.
+    pub fn validity_warning(&self) -> Option<String> {
+        match self.status(Utc::now()) {
+            AccountStatus::Active => None,
+            AccountStatus::Disabled => Some(format!("the account '{}' is disabled", self.username)),
+            AccountStatus::NotYetValid => Some(format!(
+                "the account '{}' is not valid until {}",
+                self.username,
+                self.not_before.unwrap_or_default()
+            )),
+            AccountStatus::Expired => Some(format!(
+                "the account '{}' expired at {}",
+                self.username,
+                self.expires_at.unwrap_or_default()
+            )),
+        }
+    }
+}
+
+impl AccountStatus {
+    /// The error the client is rejected with, [`None`] for an active account
+    fn as_error(&self) -> Option<AuthError> {
+        match self {
+            AccountStatus::Active => None,
+            AccountStatus::Disabled => Some(AuthError::AccountDisabled),
+            AccountStatus::NotYetValid => Some(AuthError::AccountNotYetValid),
+            AccountStatus::Expired => Some(AuthError::AccountExpired),
+        }
     }
 }
 
+fn account_status(
+    disabled: bool,
+    not_before: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> AccountStatus {
+    if disabled {
+        AccountStatus::Disabled
+    } else if not_before.is_some_and(|x| now < x) {
+        AccountStatus::NotYetValid
+    } else if expires_at.is_some_and(|x| now >= x) {
+        AccountStatus::Expired
+    } else {
+        AccountStatus::Active
+    }
+}
+
+/// Parse a moment of an account validity window.
+/// Accepts an RFC 3339 timestamp, or a date and time without an offset, or a date,
+/// the last two are treated as UTC.
+pub fn parse_timestamp(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(x) = DateTime::parse_from_rfc3339(s) {
+        return Ok(x.with_timezone(&Utc));
+    }
+    if let Some(x) = ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"]
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+    {
+        return Ok(x.and_utc());
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map(|x| x.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        .map_err(|_| format!("Invalid timestamp: {}", s))
+}
+
 impl CredentialsAuth {
     pub fn new(clients: &[Client]) -> Self {
         Self {
             clients: clients
                 .iter()
-                .map(|x| (x.username.clone(), x.password.clone()))
+                .map(|x| {
+                    (
+                        x.username.clone(),
+                        Account {
+                            password: x.password.clone(),
+                            not_before: x.not_before,
+                            expires_at: x.expires_at,
+                            disabled: x.disabled,
+                        },
+                    )
+                })
                 .collect(),
+            // The hashed passwords are the slow ones to mimic
+            dummy_template: clients
+                .iter()
+                .find(|x| password::Format::of(&x.password) != password::Format::Plain)
+                .or(clients.first())
+                .map(|x| x.password.clone())
+                .unwrap_or_default(),
+            dummy_password: OnceCell::new(),
+        }
+    }
+
+    fn authenticate_at(
+        &self,
+        username: &str,
+        password: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), AuthError> {
+        let account = self.clients.get(username);
+        // An unknown user is checked too, so that it takes as long as a known one
+        let stored = match account {
+            Some(x) => x.password.as_str(),
+            None => self
+                .dummy_password
+                .get_or_init(|| password::dummy(&self.dummy_template)),
+        };
+        let valid = password::verify(stored, password);
+        let account = account.ok_or(AuthError::InvalidCredentials)?;
+
+        // An inactive account is rejected whatever the password not to disclose
+        // whether the password was right
+        Self::check_status(username, account, now)?;
+        match valid {
+            true => Ok(()),
+            false => Err(AuthError::InvalidCredentials),
+        }
+    }
+
+    fn check_account_at(&self, username: &str, now: DateTime<Utc>) -> Result<(), AuthError> {
+        let account = self
+            .clients
+            .get(username)
+            .ok_or(AuthError::InvalidCredentials)?;
+        Self::check_status(username, account, now)
+    }
+
+    fn check_status(
+        username: &str,
+        account: &Account,
+        now: DateTime<Utc>,
+    ) -> Result<(), AuthError> {
+        let status = account_status(
+            account.disabled,
+            account.not_before,
+            account.expires_at,
+            now,
+        );
+        match status.as_error() {
+            None => Ok(()),
+            Some(e) => {
+                debug!("Rejecting account {}: {:?}", username, status);
+                Err(e)
+            }
         }
     }
 }
 
 impl AuthProvider for CredentialsAuth {
     fn authenticate(&self, username: &str, password: &str) -> Result<(), AuthError> {
-        match self.clients.get(username) {
-            Some(expected_password) if expected_password == password => Ok(()),
-            _ => Err(AuthError::InvalidCredentials),
+        self.authenticate_at(username, password, Utc::now())
+    }
+
+    /// The client must be registered and its account must be active
+    fn check_account(&self, username: &str) -> Result<(), AuthError> {
+        self.check_account_at(username, Utc::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_client(username: &str) -> Client {
+        Client {
+            username: username.to_string(),
+            password: "secret".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn validity_window() {
+        let now = parse_timestamp("2025-06-01T12:00:00Z").unwrap();
+        let auth = CredentialsAuth::new(&[
+            Client {
+                not_before: Some(parse_timestamp("2025-05-01").unwrap()),
+                expires_at: Some(parse_timestamp("2025-07-01").unwrap()),
+                ..make_client("trial")
+            },
+            Client {
+                expires_at: Some(now),
+                ..make_client("expired")
+            },
+            Client {
+                not_before: Some(parse_timestamp("2025-06-01T12:00:01Z").unwrap()),
+                ..make_client("future")
+            },
+            Client {
+                disabled: true,
+                ..make_client("disabled")
+            },
+        ]);
+
+        assert_eq!(auth.authenticate_at("trial", "secret", now), Ok(()));
+        assert_eq!(
+            auth.authenticate_at("expired", "secret", now),
+            Err(AuthError::AccountExpired)
+        );
+        assert_eq!(
+            auth.authenticate_at("future", "secret", now),
+            Err(AuthError::AccountNotYetValid)
+        );
+        assert_eq!(
+            auth.authenticate_at("disabled", "secret", now),
+            Err(AuthError::AccountDisabled)
+        );
+        // The result does not depend on the password
+        assert_eq!(
+            auth.authenticate_at("expired", "wrong", now),
+            Err(AuthError::AccountExpired)
+        );
+        assert_eq!(
+            auth.authenticate_at("trial", "wrong", now),
+            Err(AuthError::InvalidCredentials)
+        );
+    }
+
+    #[test]
+    fn unknown_user_checked_against_dummy() {
+        use argon2::password_hash::{PasswordHasher, SaltString};
+
+        let salt = SaltString::encode_b64(b"0123456789abcdef").unwrap();
+        let stored = argon2::Argon2::new(
+            argon2::Algorithm::Argon2id,
+            argon2::Version::V0x13,
+            argon2::Params::new(64, 1, 1, None).unwrap(),
+        )
+        .hash_password(b"secret", &salt)
+        .unwrap()
+        .to_string();
+        let auth = CredentialsAuth::new(&[
+            make_client("plain"),
+            Client {
+                password: stored,
+                ..make_client("hashed")
+            },
+        ]);
+
+        assert_eq!(
+            auth.authenticate("unknown", "secret"),
+            Err(AuthError::InvalidCredentials)
+        );
+        let dummy = auth.dummy_password.get().unwrap();
+        assert!(
+            dummy.starts_with("$argon2id$v=19$m=64,t=1,p=1$"),
+            "{}",
+            dummy
+        );
+        assert_eq!(auth.authenticate("hashed", "secret"), Ok(()));
+    }
+
+    #[test]
+    fn account_check() {
+        let now = parse_timestamp("2025-06-01T12:00:00Z").unwrap();
+        let auth = CredentialsAuth::new(&[
+            make_client("active"),
+            Client {
+                disabled: true,
+                ..make_client("disabled")
+            },
+            Client {
+                expires_at: Some(now),
+                ..make_client("expired")
+            },
+        ]);
+
+        assert_eq!(auth.check_account_at("active", now), Ok(()));
+        assert_eq!(
+            auth.check_account_at("disabled", now),
+            Err(AuthError::AccountDisabled)
+        );
+        assert_eq!(
+            auth.check_account_at("expired", now),
+            Err(AuthError::AccountExpired)
+        );
+        assert_eq!(
+            auth.check_account_at("unknown", now),
+            Err(AuthError::InvalidCredentials)
+        );
+    }
+
+    #[tokio::test]
+    async fn disabled_certificate_user() {
+        let auth = RegistryBasedAuthenticator::new(&[
+            make_client("active"),
+            Client {
+                disabled: true,
+                ..make_client("disabled")
+            },
+        ]);
+        let log_id = log_utils::IdChain::empty();
+        let certificate = |x: &'static str| Source::ClientCertificate(x.into());
+
+        assert!(auth.authenticate(&certificate("active"), &log_id).await == Status::Pass);
+        assert!(auth.authenticate(&certificate("disabled"), &log_id).await == Status::Reject);
+        assert!(auth.authenticate(&certificate("unknown"), &log_id).await == Status::Reject);
+    }
+
+    #[test]
+    fn timestamp_formats() {
+        let expected = DateTime::parse_from_rfc3339("2025-06-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        for s in [
+            "2025-06-01T00:00:00Z",
+            "2025-06-01T03:00:00+03:00",
+            "2025-06-01T00:00:00",
+            "2025-06-01 00:00:00.000",
+            "2025-06-01",
+        ] {
+            assert_eq!(parse_timestamp(s), Ok(expected), "{}", s);
         }
+        assert!(parse_timestamp("tomorrow").is_err());
     }
 }
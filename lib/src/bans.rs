@@ -0,0 +1,385 @@
+use crate::authentication::{AuthError, Authenticator, Source, Status};
+use crate::settings::{BruteForceSettings, Settings};
+use crate::{authentication, log_id, log_utils, metrics};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// The tracker of the failed authentication attempts.
+/// Blocks the client IP addresses and the usernames according to [`BruteForceSettings`].
+#[derive(Default)]
+pub(crate) struct Bans {
+    entries: Mutex<HashMap<Key, Entry>>,
+}
+
+#[derive(Clone, Hash, PartialEq, Eq)]
+enum Key {
+    Ip(IpAddr),
+    Username(String),
+}
+
+struct Entry {
+    /// The number of failures since the last ban or the failure window expiration
+    failures: u32,
+    last_failure: Instant,
+    blocked_until: Option<Instant>,
+    /// Whether the block is a ban, not a backoff
+    banned: bool,
+}
+
+/// The snapshot of a block state
+#[derive(Serialize)]
+pub(crate) struct BanInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Whether the client is banned or just waits for the backoff period to expire
+    pub banned: bool,
+    /// Seconds until the block expires
+    pub expires_in_secs: u64,
+}
+
+impl Bans {
+    /// Authenticate a client unless its IP address or username is blocked,
+    /// and account the result.
+    /// The checks failed on the authentication side, e.g., timed out, are not accounted,
+    /// so that an unavailable authentication service does not get the clients blocked.
+    pub async fn authenticate(
+        &self,
+        settings: &Settings,
+        authenticator: &dyn Authenticator,
+        source: &Source<'_>,
+        client_ip: IpAddr,
+        log_id: &log_utils::IdChain<u64>,
+    ) -> Status {
+        let username = source.username();
+        if self.is_ip_blocked(client_ip)
+            || username.as_deref().is_some_and(|x| self.is_user_blocked(x))
+        {
+            log_id!(
+                debug,
+                log_id,
+                "Rejecting blocked client: ip={} username={:?}",
+                client_ip,
+                username
+            );
+            metrics::add_auth_failure(AuthError::Blocked);
+            return Status::Reject;
+        }
+
+        let status = authentication::authenticate_with_timeout(
+            authenticator,
+            source,
+            settings.auth.timeout,
+            log_id,
+        )
+        .await;
+        match (&status, &settings.brute_force) {
+            (Status::Pass, _) => {
+                if let Some(x) = &username {
+                    self.add_success(x);
+                }
+            }
+            (Status::Reject, Some(x)) => {
+                self.add_failure(x, client_ip, username.as_deref(), Instant::now())
+            }
+            (Status::Reject, None) | (Status::Unavailable, _) => (),
+        }
+        status
+    }
+
+    pub fn is_ip_blocked(&self, ip: IpAddr) -> bool {
+        self.is_blocked(&Key::Ip(ip), Instant::now())
+    }
+
+    pub fn is_user_blocked(&self, username: &str) -> bool {
+        self.is_blocked(&Key::Username(username.to_string()), Instant::now())
+    }
+
+    /// Get the snapshot of the active blocks
+    pub fn list(&self) -> Vec<BanInfo> {
+        let now = Instant::now();
+        let mut list: Vec<_> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(key, entry)| {
+                let expires_in = entry.blocked_until?.checked_duration_since(now)?;
+                let (ip, username) = match key {
+                    Key::Ip(x) => (Some(*x), None),
+                    Key::Username(x) => (None, Some(x.clone())),
+                };
+                Some(BanInfo {
+                    ip,
+                    username,
+                    banned: entry.banned,
+                    expires_in_secs: expires_in.as_secs(),
+                })
+            })
+            .collect();
+        list.sort_unstable_by_key(|x| std::cmp::Reverse(x.expires_in_secs));
+        list
+    }
+
+    /// Lift the block of the IP address and forget its failures.
+    /// Returns `false` if there is no such address.
+    pub fn unban_ip(&self, ip: IpAddr) -> bool {
+        self.entries.lock().unwrap().remove(&Key::Ip(ip)).is_some()
+    }
+
+    /// Lift the block of the username and forget its failures.
+    /// Returns `false` if there is no such username.
+    pub fn unban_user(&self, username: &str) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .remove(&Key::Username(username.to_string()))
+            .is_some()
+    }
+
+    fn is_blocked(&self, key: &Key, now: Instant) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(key)
+            .and_then(|x| x.blocked_until)
+            .is_some_and(|x| x > now)
+    }
+
+    fn add_success(&self, username: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .remove(&Key::Username(username.to_string()));
+    }
+
+    fn add_failure(
+        &self,
+        settings: &BruteForceSettings,
+        ip: IpAddr,
+        username: Option<&str>,
+        now: Instant,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        let keys = std::iter::once(Key::Ip(ip)).chain(username.map(|x| Key::Username(x.into())));
+        for key in keys {
+            if !entries.contains_key(&key) {
+                entries.retain(|_, x| !x.is_stale(settings, now));
+            }
+
+            let entry = entries.entry(key.clone()).or_insert_with(|| Entry {
+                failures: 0,
+                last_failure: now,
+                blocked_until: None,
+                banned: false,
+            });
+            if entry.is_stale(settings, now) {
+                entry.failures = 0;
+                entry.banned = false;
+            }
+
+            entry.failures += 1;
+            entry.last_failure = now;
+            if entry.failures >= settings.ban_after_failures {
+                entry.failures = 0;
+                entry.banned = true;
+                entry.blocked_until = Some(now + settings.ban_duration);
+                warn!(
+                    "Banned {} for {:?} after failed authentication attempts",
+                    key, settings.ban_duration
+                );
+                metrics::add_auth_ban(key.kind());
+            } else if entry.failures >= settings.backoff_after_failures {
+                let exponent = entry.failures - settings.backoff_after_failures;
+                let backoff = settings
+                    .initial_backoff
+                    .checked_mul(2_u32.saturating_pow(exponent))
+                    .map_or(settings.ban_duration, |x| x.min(settings.ban_duration));
+                entry.banned = false;
+                entry.blocked_until = Some(now + backoff);
+            }
+        }
+    }
+}
+
+impl Entry {
+    /// Whether the entry is neither blocked nor has recent failures
+    fn is_stale(&self, settings: &BruteForceSettings, now: Instant) -> bool {
+        self.blocked_until.is_none_or(|x| x <= now)
+            && now.saturating_duration_since(self.last_failure) >= settings.failure_window
+    }
+}
+
+impl Key {
+    fn kind(&self) -> &'static str {
+        match self {
+            Key::Ip(_) => "ip",
+            Key::Username(_) => "username",
+        }
+    }
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Key::Ip(x) => write!(f, "IP address {}", x),
+            Key::Username(x) => write!(f, "username {}", x),
+        }
+    }
+}
+
+impl BanInfo {
+    pub fn kind(&self) -> &'static str {
+        if self.ip.is_some() {
+            "ip"
+        } else {
+            "username"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    fn make_settings() -> BruteForceSettings {
+        BruteForceSettings::builder()
+            .backoff_after_failures(2)
+            .initial_backoff(Duration::from_secs(1))
+            .ban_after_failures(5)
+            .ban_duration(Duration::from_secs(60))
+            .failure_window(Duration::from_secs(30))
+            .build()
+            .unwrap()
+    }
+
+    struct Fixed(Status);
+
+    #[async_trait::async_trait]
+    impl Authenticator for Fixed {
+        async fn authenticate(&self, _: &Source<'_>, _: &log_utils::IdChain<u64>) -> Status {
+            self.0.clone()
+        }
+    }
+
+    fn blocked_until(bans: &Bans, key: &Key) -> Option<Instant> {
+        bans.entries.lock().unwrap().get(key)?.blocked_until
+    }
+
+    #[test]
+    fn backoff_then_ban() {
+        let settings = make_settings();
+        let bans = Bans::default();
+        let ip = Key::Ip(IP);
+        let start = Instant::now();
+
+        bans.add_failure(&settings, IP, None, start);
+        assert!(!bans.is_blocked(&ip, start));
+
+        // The backoff doubles on each failure
+        for (i, backoff) in [1, 2, 4].into_iter().enumerate() {
+            let now = start + Duration::from_secs(i as u64 * 10);
+            bans.add_failure(&settings, IP, None, now);
+            assert_eq!(
+                blocked_until(&bans, &ip),
+                Some(now + Duration::from_secs(backoff))
+            );
+            assert!(bans.is_blocked(&ip, now));
+            assert!(!bans.is_blocked(&ip, now + Duration::from_secs(backoff)));
+        }
+
+        let now = start + Duration::from_secs(30);
+        bans.add_failure(&settings, IP, None, now);
+        assert!(bans.is_blocked(&ip, now + Duration::from_secs(59)));
+        assert!(!bans.is_blocked(&ip, now + Duration::from_secs(60)));
+        assert_eq!(bans.list().len(), 1);
+        assert!(bans.list()[0].banned);
+
+        // The counter starts over after the ban
+        let now = now + Duration::from_secs(60);
+        bans.add_failure(&settings, IP, None, now);
+        assert!(!bans.is_blocked(&ip, now));
+    }
+
+    #[tokio::test]
+    async fn unavailable_not_counted() {
+        let mut settings = Settings::default();
+        settings.brute_force = Some(make_settings());
+        let bans = Bans::default();
+        let source = Source::ClientCertificate("alice".into());
+        let log_id = log_utils::IdChain::empty();
+
+        for _ in 0..settings.brute_force.as_ref().unwrap().ban_after_failures {
+            let status = bans
+                .authenticate(&settings, &Fixed(Status::Unavailable), &source, IP, &log_id)
+                .await;
+            assert!(status == Status::Unavailable);
+        }
+        assert!(bans.entries.lock().unwrap().is_empty());
+
+        let status = bans
+            .authenticate(&settings, &Fixed(Status::Reject), &source, IP, &log_id)
+            .await;
+        assert!(status == Status::Reject);
+        // The IP address and the username
+        assert_eq!(bans.entries.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn failures_expire() {
+        let settings = make_settings();
+        let bans = Bans::default();
+        let ip = Key::Ip(IP);
+        let start = Instant::now();
+
+        bans.add_failure(&settings, IP, None, start);
+        let now = start + Duration::from_secs(30);
+        bans.add_failure(&settings, IP, None, now);
+        assert!(!bans.is_blocked(&ip, now));
+        bans.add_failure(&settings, IP, None, now);
+        assert!(bans.is_blocked(&ip, now));
+    }
+
+    #[test]
+    fn usernames_tracked_separately() {
+        let settings = make_settings();
+        let bans = Bans::default();
+        let now = Instant::now();
+        let other_ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+        bans.add_failure(&settings, IP, Some("alice"), now);
+        bans.add_failure(&settings, other_ip, Some("alice"), now);
+        assert!(bans.is_blocked(&Key::Username("alice".into()), now));
+        assert!(!bans.is_blocked(&Key::Ip(IP), now));
+        assert!(!bans.is_blocked(&Key::Ip(other_ip), now));
+
+        bans.add_success("alice");
+        assert!(!bans.is_blocked(&Key::Username("alice".into()), now));
+    }
+
+    #[test]
+    fn manual_unban() {
+        let settings = make_settings();
+        let bans = Bans::default();
+        for _ in 0..settings.ban_after_failures {
+            bans.add_failure(&settings, IP, Some("alice"), Instant::now());
+        }
+        assert!(bans.is_ip_blocked(IP));
+        assert!(bans.is_user_blocked("alice"));
+
+        assert!(bans.unban_ip(IP));
+        assert!(!bans.unban_ip(IP));
+        assert!(!bans.is_ip_blocked(IP));
+        assert!(bans.unban_user("alice"));
+        assert!(!bans.is_user_blocked("alice"));
+        assert!(bans.list().is_empty());
+    }
+}
@@ -0,0 +1,475 @@
+use crate::cert_verification::PinnedKeyVerifier;
+use crate::direct_forwarder::DirectForwarder;
+use crate::forwarder::{Forwarder, IcmpMultiplexer, UdpMultiplexer};
+use crate::http_downstream::{DatagramDecoder, DatagramEncoder};
+use crate::metrics::OutboundTcpSocketCounter;
+use crate::net_utils::TcpDestination;
+use crate::settings::{CascadeForwarderSettings, Http2Settings};
+use crate::{
+    core, datagram_pipe, downstream, forwarder, http2_codec, http_downstream, http_udp_codec,
+    log_id, log_utils, net_utils, pipe, tunnel,
+};
+use async_trait::async_trait;
+use base64::Engine;
+use bytes::Bytes;
+use h2::client::SendRequest;
+use h2::{RecvStream, SendStream};
+use once_cell::sync::OnceCell;
+use rustls::ClientConfig;
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
+
+pub(crate) struct CascadeForwarder {
+    context: Arc<core::Context>,
+    /// The name of the forwarder in [`Settings.forwarders`](crate::settings::Settings),
+    /// [`None`] for the default one
+    name: Option<String>,
+    /// The snapshot of the settings taken on the forwarder creation
+    settings: Arc<CascadeForwarderSettings>,
+    /// Authenticates the datagram multiplexers
+    direct: DirectForwarder,
+}
+
+/// Keeps the HTTP/2 sessions to the next endpoints shared by all the clients,
+/// one per cascade forwarder
+#[derive(Default)]
+pub(crate) struct SessionPool {
+    /// Each session is established under its own lock, so an unreachable next endpoint
+    /// does not stall the other forwarders
+    sessions: std::sync::Mutex<HashMap<Option<String>, SessionSlot>>,
+}
+
+type SessionSlot = Arc<Mutex<Option<Session>>>;
+
+struct Session {
+    /// The settings the session was established with
+    settings: CascadeForwarderSettings,
+    sender: SendRequest<Bytes>,
+    connection: JoinHandle<()>,
+}
+
+struct TcpConnector {
+    context: Arc<core::Context>,
+    name: Option<String>,
+    settings: Arc<CascadeForwarderSettings>,
+}
+
+struct StreamRx {
+    source: Box<dyn pipe::Source>,
+    _metrics_guard: OutboundTcpSocketCounter,
+}
+
+/// The UDP "connections" are tracked by the next endpoint
+struct DatagramPipeShared;
+
+/// The multiplexer is created synchronously, so the `_udp2` stream is opened
+/// in the background and its halves wait for it on the first use
+enum Deferred<T> {
+    Opening(oneshot::Receiver<io::Result<T>>),
+    Open(T),
+    Failed,
+}
+
+struct DatagramSource {
+    stream: Deferred<Box<dyn datagram_pipe::Source<Output = forwarder::UdpDatagramReadStatus>>>,
+    id: log_utils::IdChain<u64>,
+}
+
+struct DatagramSink {
+    stream: Deferred<Box<dyn datagram_pipe::Sink<Input = downstream::UdpDatagram>>>,
+}
+
+impl CascadeForwarder {
+    pub fn new(
+        context: Arc<core::Context>,
+        name: Option<String>,
+        settings: Arc<CascadeForwarderSettings>,
+    ) -> Self {
+        Self {
+            direct: DirectForwarder::new(context.clone(), &Default::default()),
+            context,
+            name,
+            settings,
+        }
+    }
+}
+
+impl Forwarder for CascadeForwarder {
+    fn tcp_connector(&self) -> Box<dyn forwarder::TcpConnector> {
+        Box::new(TcpConnector {
+            context: self.context.clone(),
+            name: self.name.clone(),
+            settings: self.settings.clone(),
+        })
+    }
+
+    fn datagram_mux_authenticator(&self) -> Box<dyn forwarder::DatagramMultiplexerAuthenticator> {
+        self.direct.datagram_mux_authenticator()
+    }
+
+    fn make_udp_datagram_multiplexer(
+        &self,
+        id: log_utils::IdChain<u64>,
+        meta: forwarder::UdpMultiplexerMeta,
+    ) -> io::Result<UdpMultiplexer> {
+        let (source_tx, source_rx) = oneshot::channel();
+        let (sink_tx, sink_rx) = oneshot::channel();
+
+        let context = self.context.clone();
+        let name = self.name.clone();
+        let settings = self.settings.clone();
+        let task_id = id.clone();
+        tokio::spawn(async move {
+            let id = task_id;
+            let opened = tokio::time::timeout(
+                context.settings().connection_establishment_timeout,
+                open_datagram_stream(&context, &name, &settings, meta.user_agent.as_deref(), &id),
+            )
+            .await
+            .unwrap_or(Err(tunnel::ConnectionError::Timeout));
+
+            match opened {
+                Ok((source, sink)) => {
+                    let _ = source_tx.send(Ok(source));
+                    let _ = sink_tx.send(Ok(sink));
+                }
+                Err(e) => {
+                    log_id!(
+                        debug,
+                        id,
+                        "Failed to open UDP stream to next endpoint: {}",
+                        e
+                    );
+                    let _ = source_tx.send(Err(io::Error::new(ErrorKind::Other, e.to_string())));
+                    let _ = sink_tx.send(Err(io::Error::new(ErrorKind::Other, e.to_string())));
+                }
+            }
+        });
+
+        Ok((
+            Arc::new(DatagramPipeShared),
+            Box::new(DatagramSource {
+                stream: Deferred::Opening(source_rx),
+                id,
+            }),
+            Box::new(DatagramSink {
+                stream: Deferred::Opening(sink_rx),
+            }),
+        ))
+    }
+
+    fn make_icmp_datagram_multiplexer(
+        &self,
+        _id: log_utils::IdChain<u64>,
+    ) -> io::Result<Option<IcmpMultiplexer>> {
+        // The next endpoint can't be asked to carry ICMP, and sending it from this one
+        // would reveal the entry endpoint address
+        Ok(None)
+    }
+}
+
+#[async_trait]
+impl forwarder::TcpConnector for TcpConnector {
+    async fn connect(
+        self: Box<Self>,
+        id: log_utils::IdChain<u64>,
+        meta: forwarder::TcpConnectionMeta,
+    ) -> Result<(Box<dyn pipe::Source>, Box<dyn pipe::Sink>), tunnel::ConnectionError> {
+        let authority = match &meta.destination {
+            TcpDestination::Address(x) => x.to_string(),
+            TcpDestination::HostName((host, port)) => format!("{}:{}", host, port),
+        };
+
+        let request = make_request(&self.settings, &authority, meta.user_agent.as_deref())?;
+        let (rx, tx) = self
+            .context
+            .cascade_sessions
+            .open_stream(
+                &self.name,
+                &self.settings,
+                request,
+                self.context.settings().connection_establishment_timeout,
+                &id,
+            )
+            .await?;
+
+        let (source, sink) = http2_codec::make_client_stream(rx, tx, id);
+        Ok((
+            Box::new(StreamRx {
+                source,
+                _metrics_guard: self.context.metrics.clone().outbound_tcp_socket_counter(),
+            }),
+            sink.into_pipe_sink(),
+        ))
+    }
+}
+
+impl SessionPool {
+    /// Send a request on the session to the next endpoint, establishing the session
+    /// if there is none or the settings have changed since it was established
+    async fn open_stream(
+        &self,
+        name: &Option<String>,
+        settings: &CascadeForwarderSettings,
+        request: http::Request<()>,
+        handshake_timeout: Duration,
+        id: &log_utils::IdChain<u64>,
+    ) -> Result<(RecvStream, SendStream<Bytes>), tunnel::ConnectionError> {
+        let slot = self
+            .sessions
+            .lock()
+            .unwrap()
+            .entry(name.clone())
+            .or_default()
+            .clone();
+        let sender = {
+            let mut session = slot.lock().await;
+            match session.as_ref() {
+                Some(x) if x.settings == *settings && !x.connection.is_finished() => {
+                    x.sender.clone()
+                }
+                _ => {
+                    log_id!(
+                        debug,
+                        id,
+                        "Establishing session to next endpoint: {}",
+                        settings.address
+                    );
+                    let established = tokio::time::timeout(handshake_timeout, handshake(settings))
+                        .await
+                        .unwrap_or(Err(tunnel::ConnectionError::Timeout))?;
+                    let sender = established.sender.clone();
+                    *session = Some(established);
+                    sender
+                }
+            }
+        };
+
+        log_id!(
+            trace,
+            id,
+            "Sending request to next endpoint: {}",
+            request.uri()
+        );
+        let (response, tx) = sender
+            .ready()
+            .await
+            .and_then(|mut x| x.send_request(request, false))
+            .map_err(h2_to_connection_error)?;
+        let (response, rx) = response.await.map_err(h2_to_connection_error)?.into_parts();
+
+        log_id!(trace, id, "Next endpoint responded: {}", response.status);
+        if !response.status.is_success() {
+            return Err(http_downstream::response_to_tunnel_error(&response));
+        }
+
+        Ok((rx, tx))
+    }
+}
+
+#[async_trait]
+impl pipe::Source for StreamRx {
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.source.id()
+    }
+
+    async fn read(&mut self) -> io::Result<pipe::Data> {
+        self.source.read().await
+    }
+
+    fn consume(&mut self, size: usize) -> io::Result<()> {
+        self.source.consume(size)
+    }
+}
+
+#[async_trait]
+impl forwarder::UdpDatagramPipeShared for DatagramPipeShared {
+    async fn on_new_udp_connection(&self, _: &downstream::UdpDatagramMeta) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn on_connection_closed(&self, _: &forwarder::UdpDatagramMeta) {}
+}
+
+impl<T> Deferred<T> {
+    async fn get(&mut self) -> io::Result<&mut T> {
+        if let Self::Opening(rx) = self {
+            match rx.await {
+                Ok(Ok(x)) => *self = Self::Open(x),
+                Ok(Err(e)) => {
+                    *self = Self::Failed;
+                    return Err(e);
+                }
+                Err(_) => *self = Self::Failed,
+            }
+        }
+
+        match self {
+            Self::Open(x) => Ok(x),
+            Self::Opening(_) | Self::Failed => Err(io::Error::new(
+                ErrorKind::Other,
+                "Stream to next endpoint is not open",
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl datagram_pipe::Source for DatagramSource {
+    type Output = forwarder::UdpDatagramReadStatus;
+
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.id.clone()
+    }
+
+    async fn read(&mut self) -> io::Result<Self::Output> {
+        self.stream.get().await?.read().await
+    }
+}
+
+#[async_trait]
+impl datagram_pipe::Sink for DatagramSink {
+    type Input = downstream::UdpDatagram;
+
+    async fn write(&mut self, datagram: Self::Input) -> io::Result<datagram_pipe::SendStatus> {
+        self.stream.get().await?.write(datagram).await
+    }
+}
+
+async fn open_datagram_stream(
+    context: &core::Context,
+    name: &Option<String>,
+    settings: &CascadeForwarderSettings,
+    user_agent: Option<&str>,
+    id: &log_utils::IdChain<u64>,
+) -> Result<
+    (
+        Box<dyn datagram_pipe::Source<Output = forwarder::UdpDatagramReadStatus>>,
+        Box<dyn datagram_pipe::Sink<Input = downstream::UdpDatagram>>,
+    ),
+    tunnel::ConnectionError,
+> {
+    let request = make_request(settings, http_downstream::UDP_AUTHORITY, user_agent)?;
+    let (rx, tx) = context
+        .cascade_sessions
+        .open_stream(
+            name,
+            settings,
+            request,
+            context.settings().connection_establishment_timeout,
+            id,
+        )
+        .await?;
+
+    let (source, sink) = http2_codec::make_client_stream(rx, tx, id.clone());
+    Ok((
+        Box::new(DatagramDecoder::new(
+            source,
+            Box::new(http_udp_codec::ClientDecoder::new(id.clone())),
+        )),
+        Box::new(DatagramEncoder::new(
+            Box::<http_udp_codec::ClientEncoder>::default(),
+            sink.into_datagram_sink(),
+        )),
+    ))
+}
+
+/// Open a new session to the next endpoint
+async fn handshake(
+    settings: &CascadeForwarderSettings,
+) -> Result<Session, tunnel::ConnectionError> {
+    let stream = TcpStream::connect(settings.address)
+        .await
+        .map_err(tunnel::ConnectionError::Io)?;
+    stream
+        .set_nodelay(true)
+        .map_err(tunnel::ConnectionError::Io)?;
+
+    let server_name = rustls::ServerName::try_from(settings.server_name.as_str())
+        .map_err(|e| tunnel::ConnectionError::Other(format!("Invalid server name: {}", e)))?;
+    let stream = tokio_rustls::TlsConnector::from(
+        tls_config(settings).map_err(tunnel::ConnectionError::Io)?,
+    )
+    .connect(server_name, stream)
+    .await
+    .map_err(tunnel::ConnectionError::Io)?;
+
+    let (sender, connection) = h2::client::Builder::new()
+        .initial_connection_window_size(Http2Settings::default_initial_connection_window_size())
+        .initial_window_size(Http2Settings::default_initial_stream_window_size())
+        .handshake(stream)
+        .await
+        .map_err(h2_to_connection_error)?;
+
+    Ok(Session {
+        settings: settings.clone(),
+        sender,
+        connection: tokio::spawn(async move {
+            let _ = connection.await;
+        }),
+    })
+}
+
+/// The certificate of the next endpoint is verified either against the pins,
+/// or against the system CAs which are loaded once for all the sessions
+fn tls_config(settings: &CascadeForwarderSettings) -> io::Result<Arc<ClientConfig>> {
+    static SYSTEM_CAS: OnceCell<Arc<ClientConfig>> = OnceCell::new();
+
+    let alpn = vec![net_utils::HTTP2_ALPN.as_bytes().to_vec()];
+    if !settings.certificate_pins.is_empty() {
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(PinnedKeyVerifier::new(
+                settings.certificate_pins.clone(),
+            )))
+            .with_no_client_auth();
+        config.alpn_protocols = alpn;
+        return Ok(Arc::new(config));
+    }
+
+    SYSTEM_CAS
+        .get_or_try_init(|| {
+            let mut config = ClientConfig::clone(net_utils::make_client_tls_config()?.as_ref());
+            config.alpn_protocols = alpn;
+            Ok(Arc::new(config))
+        })
+        .cloned()
+}
+
+fn make_request(
+    settings: &CascadeForwarderSettings,
+    authority: &str,
+    user_agent: Option<&str>,
+) -> Result<http::Request<()>, tunnel::ConnectionError> {
+    let password = std::env::var(&settings.password_env).map_err(|e| {
+        tunnel::ConnectionError::Other(format!("Couldn't read {}: {}", settings.password_env, e))
+    })?;
+    let credentials = base64::engine::general_purpose::STANDARD
+        .encode(format!("{}:{}", settings.username, password));
+
+    let mut request = http::Request::builder()
+        .method(http::Method::CONNECT)
+        .uri(authority)
+        .header(
+            http::header::PROXY_AUTHORIZATION,
+            format!("Basic {}", credentials),
+        );
+    // The value coming from the client may be not representable in a header
+    if let Some(x) = user_agent.and_then(|x| http::HeaderValue::from_str(x).ok()) {
+        request = request.header(http::header::USER_AGENT, x);
+    }
+
+    request
+        .body(())
+        .map_err(|e| tunnel::ConnectionError::Other(e.to_string()))
+}
+
+fn h2_to_connection_error(e: h2::Error) -> tunnel::ConnectionError {
+    tunnel::ConnectionError::Io(http2_codec::h2_to_io_error(e))
+}
@@ -1,8 +1,10 @@
 use crate::utils;
-use rustls::client::ServerCertVerifier;
+use base64::Engine;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
 use rustls::{Certificate, RootCertStore, ServerName};
 use std::io;
 use std::sync::Arc;
+use x509_parser::prelude::{FromDer, X509Certificate};
 
 /// Checks if certificate chain would be verifiable by system CAs.
 ///
@@ -102,6 +104,59 @@ impl CertificateVerifier {
     }
 }
 
+/// Accepts a server certificate if its public key matches one of the pins
+/// regardless of the issuer. The signature of the handshake is still verified
+/// with the key, so the server must possess the pinned key.
+pub(crate) struct PinnedKeyVerifier {
+    pins: Vec<String>,
+}
+
+impl PinnedKeyVerifier {
+    /// Create a verifier from the base64-encoded SHA-256 digests of the public keys
+    pub fn new(pins: Vec<String>) -> Self {
+        Self { pins }
+    }
+}
+
+impl ServerCertVerifier for PinnedKeyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let pin = public_key_pin(&end_entity.0).map_err(rustls::Error::General)?;
+        if self.pins.contains(&pin) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "Public key is not pinned: {}",
+                pin
+            )))
+        }
+    }
+}
+
+/// Compute the pin of a DER-encoded certificate: the base64-encoded SHA-256 digest
+/// of its `SubjectPublicKeyInfo`. The same as the output of:
+///
+/// ```text
+/// openssl x509 -in cert.pem -pubkey -noout | openssl pkey -pubin -outform der \
+///     | openssl dgst -sha256 -binary | base64
+/// ```
+pub fn public_key_pin(certificate: &[u8]) -> Result<String, String> {
+    let (_, certificate) = X509Certificate::from_der(certificate)
+        .map_err(|e| format!("Failed to parse certificate: {}", e))?;
+    let digest = ring::digest::digest(
+        &ring::digest::SHA256,
+        certificate.tbs_certificate.subject_pki.raw,
+    );
+    Ok(base64::engine::general_purpose::STANDARD.encode(digest))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
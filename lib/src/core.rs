@@ -1,23 +1,32 @@
+use crate::bans::Bans;
+use crate::cascade_forwarder::{CascadeForwarder, SessionPool};
 use crate::direct_forwarder::DirectForwarder;
 use crate::forwarder::Forwarder;
 use crate::http1_codec::Http1Codec;
 use crate::http2_codec::Http2Codec;
 use crate::http3_codec::Http3Codec;
 use crate::http_codec::HttpCodec;
+use crate::http_connect_forwarder::HttpConnectForwarder;
 use crate::http_downstream::HttpDownstream;
 use crate::icmp_forwarder::IcmpForwarder;
 use crate::metrics::Metrics;
 use crate::net_utils::PeerAddr;
 use crate::quic_multiplexer::{QuicMultiplexer, QuicSocket};
+use crate::quota::Quotas;
+use crate::rate_limit::RateLimiters;
+use crate::routing_forwarder::RoutingForwarder;
+use crate::sessions::Sessions;
 use crate::settings::{ForwardProtocolSettings, Settings};
 use crate::shutdown::Shutdown;
 use crate::socks5_forwarder::Socks5Forwarder;
+use crate::socks5_pool::UpstreamPools;
 use crate::tls_demultiplexer::TlsDemux;
 use crate::tls_listener::{TlsAcceptor, TlsListener};
 use crate::tunnel::Tunnel;
 use crate::{
-    authentication, http_ping_handler, http_speedtest_handler, log_id, log_utils, metrics,
-    net_utils, reverse_proxy, rules, settings, tls_demultiplexer, tunnel,
+    admin, authentication, dns, http_downstream, http_ping_handler, http_speedtest_handler, log_id,
+    log_utils, metrics, net_utils, quota, radius, reverse_proxy, rules, settings,
+    tls_demultiplexer, tunnel,
 };
 use socket2::SockRef;
 use std::io;
@@ -36,6 +45,12 @@ pub enum Error {
     TlsDemultiplexer(String),
     /// Metrics module initialization failed
     Metrics(String),
+    /// Data quota state loading failed
+    Quota(String),
+    /// DNS resolver initialization failed
+    Dns(String),
+    /// RADIUS accounting client initialization failed
+    Radius(String),
 }
 
 pub struct Core {
@@ -69,8 +84,9 @@ impl FatalIoError {
 }
 
 pub(crate) struct Context {
-    pub settings: Arc<Settings>,
-    pub authenticator: Option<Arc<dyn authentication::Authenticator>>,
+    /// The part of the context which may be replaced on [`Core::reload_settings()`].
+    /// Must be accessed through [`Context::settings()`] and [`Context::authenticator()`].
+    reloadable: RwLock<ReloadableContext>,
     tls_demux: Arc<RwLock<TlsDemux>>,
     pub icmp_forwarder: Option<Arc<IcmpForwarder>>,
     pub shutdown: Arc<Mutex<Shutdown>>,
@@ -78,12 +94,76 @@ pub(crate) struct Context {
     /// to the main Core::listen() loop.
     /// Spawned tasks report errors via Context::report_fatal_io_error().
     fatal_error: watch::Sender<Option<FatalIoError>>,
+    /// Marked as changed each time the settings or the authenticator are replaced
+    settings_reload: watch::Sender<()>,
     pub metrics: Arc<Metrics>,
+    /// The registry of the active tunnels
+    pub sessions: Arc<Sessions>,
+    /// The per-user bandwidth limiters
+    pub rate_limiters: RateLimiters,
+    /// The per-user data usage counters
+    pub quotas: Quotas,
+    /// The failed authentication attempts tracker
+    pub bans: Arc<Bans>,
+    /// Resolves the host names of the peers
+    pub resolver: Box<dyn dns::Resolver>,
+    /// Answers the DNS queries intercepted in the tunneled UDP traffic.
+    /// Present only if the interception is enabled.
+    pub dns_filter: Option<dns::filter::DnsFilter>,
+    /// Reports the sessions to the RADIUS accounting server.
+    /// Present only if the accounting is enabled.
+    pub accounting: Option<Arc<radius::Accounting>>,
+    /// The session to the next endpoint shared by the cascade forwarders
+    pub cascade_sessions: SessionPool,
+    /// The pools of the upstream proxies shared by the SOCKS5 forwarders
+    pub socks5_pools: UpstreamPools,
     next_client_id: Arc<AtomicU64>,
     next_tunnel_id: Arc<AtomicU64>,
 }
 
+struct ReloadableContext {
+    settings: Arc<Settings>,
+    authenticator: Option<Arc<dyn authentication::Authenticator>>,
+}
+
 impl Context {
+    /// Get the snapshot of the current settings
+    pub(crate) fn settings(&self) -> Arc<Settings> {
+        self.reloadable.read().unwrap().settings.clone()
+    }
+
+    /// Get the current authenticator
+    pub(crate) fn authenticator(&self) -> Option<Arc<dyn authentication::Authenticator>> {
+        self.reloadable.read().unwrap().authenticator.clone()
+    }
+
+    /// Replace the current settings and authenticator with the modified copies.
+    /// The modifications are dropped if `f` fails.
+    pub(crate) fn modify_settings<F>(&self, f: F) -> io::Result<()>
+    where
+        F: FnOnce(
+            &mut Settings,
+            &mut Option<Arc<dyn authentication::Authenticator>>,
+        ) -> io::Result<()>,
+    {
+        let mut reloadable = self.reloadable.write().unwrap();
+        let mut settings = Settings::clone(&reloadable.settings);
+        let mut authenticator = reloadable.authenticator.clone();
+        f(&mut settings, &mut authenticator)?;
+
+        *reloadable = ReloadableContext {
+            settings: Arc::new(settings),
+            authenticator,
+        };
+        self.settings_reload.send_replace(());
+        Ok(())
+    }
+
+    /// Subscribe to the replacements of the settings and the authenticator
+    pub(crate) fn subscribe_settings_reload(&self) -> watch::Receiver<()> {
+        self.settings_reload.subscribe()
+    }
+
     pub(crate) fn report_fatal_io_error(&self, e: &io::Error) {
         let _ = self.fatal_error.send(Some(FatalIoError::from_io_error(e)));
     }
@@ -115,11 +195,34 @@ impl Core {
         let settings = Arc::new(settings);
 
         let (fatal_error, _fatal_error_rx) = watch::channel(None);
+        // The metrics settings are not affected by settings reloading
+        let metrics =
+            Metrics::new(settings.metrics.as_ref()).map_err(|e| Error::Metrics(e.to_string()))?;
+        let quotas = match settings.quota.as_ref() {
+            None => Default::default(),
+            Some(x) => Quotas::load(&x.state_file).map_err(|e| {
+                Error::Quota(format!("Failed to load state from {}: {}", x.state_file, e))
+            })?,
+        };
+        // The resolver settings are not affected by settings reloading
+        let resolver =
+            dns::make_resolver(settings.dns.as_ref()).map_err(|e| Error::Dns(e.to_string()))?;
+        let dns_filter = match &settings.dns {
+            None => None,
+            Some(x) => dns::filter::DnsFilter::new(x).map_err(|e| Error::Dns(e.to_string()))?,
+        };
+        // The accounting settings are not affected by settings reloading
+        let accounting = match &settings.auth.radius {
+            None => None,
+            Some(x) => radius::Accounting::new(x).map_err(|e| Error::Radius(e.to_string()))?,
+        };
 
         Ok(Self {
             context: Arc::new(Context {
-                settings: settings.clone(),
-                authenticator,
+                reloadable: RwLock::new(ReloadableContext {
+                    settings: settings.clone(),
+                    authenticator,
+                }),
                 tls_demux: Arc::new(RwLock::new(
                     TlsDemux::new(&settings, &tls_hosts_settings)
                         .map_err(|e| Error::TlsDemultiplexer(e.to_string()))?,
@@ -131,7 +234,17 @@ impl Core {
                 },
                 shutdown,
                 fatal_error,
-                metrics: Metrics::new().map_err(|e| Error::Metrics(e.to_string()))?,
+                settings_reload: watch::channel(()).0,
+                metrics,
+                sessions: Default::default(),
+                rate_limiters: Default::default(),
+                quotas,
+                bans: Default::default(),
+                resolver,
+                dns_filter,
+                accounting,
+                cascade_sessions: Default::default(),
+                socks5_pools: Default::default(),
                 next_client_id: Default::default(),
                 next_tunnel_id: Default::default(),
             }),
@@ -164,6 +277,12 @@ impl Core {
                 .map_err(|e| io::Error::new(e.kind(), format!("Metrics listener failure: {}", e)))
         };
 
+        let listen_admin = async {
+            admin::listen(self.context.clone(), log_utils::IdChain::empty())
+                .await
+                .map_err(|e| io::Error::new(e.kind(), format!("Admin listener failure: {}", e)))
+        };
+
         let (mut shutdown_notification, _shutdown_completion) = {
             let shutdown = self.context.shutdown.lock().unwrap();
             (
@@ -176,6 +295,12 @@ impl Core {
 
         let mut fatal_error_rx = self.context.fatal_error.subscribe();
 
+        // Spawned separately to be able to save the state after the listeners are stopped
+        tokio::spawn(quota::run_saver(
+            self.context.clone(),
+            log_utils::IdChain::empty(),
+        ));
+
         tokio::select! {
             x = shutdown_notification.wait() => {
                 x.map_err(|e| io::Error::new(ErrorKind::Other, format!("{}", e)))
@@ -187,11 +312,12 @@ impl Core {
                 },
                 Err(_) => Err(io::Error::new(ErrorKind::Other, "Fatal error channel is unexpectedly closed")),
             },
-            x = futures::future::try_join4(
+            x = futures::future::try_join5(
                 listen_tcp,
                 listen_udp,
                 listen_icmp,
                 listen_metrics,
+                listen_admin,
             ) => x.map(|_| ()),
         }
     }
@@ -212,12 +338,67 @@ impl Core {
             })?;
         }
 
-        *demux = TlsDemux::new(&self.context.settings, &settings)?;
+        *demux = TlsDemux::new(&self.context.settings(), &settings)?;
+        Ok(())
+    }
+
+    /// Reload the endpoint settings.
+    /// The new settings and authenticator are applied to the connections and requests
+    /// accepted after the call, the established tunnels keep running unless their clients
+    /// fail the re-authentication with the new authenticator.
+    /// The listen address, the set of the listen protocols and the reverse proxy presence
+    /// cannot be changed without restarting the endpoint, such settings are rejected.
+    pub fn reload_settings(
+        &self,
+        settings: Settings,
+        authenticator: Option<Arc<dyn authentication::Authenticator>>,
+    ) -> io::Result<()> {
+        if !settings.is_built() {
+            settings.validate().map_err(|e| {
+                io::Error::new(
+                    ErrorKind::Other,
+                    format!("Settings validation failure: {:?}", e),
+                )
+            })?;
+        }
+
+        let mut reloadable = self.context.reloadable.write().unwrap();
+        let current = &reloadable.settings;
+        if settings.listen_address != current.listen_address {
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                format!(
+                    "Listen address cannot be changed without restart: current={} new={}",
+                    current.listen_address, settings.listen_address
+                ),
+            ));
+        }
+        if !settings
+            .listen_protocols
+            .has_same_protocols(&current.listen_protocols)
+        {
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                "Set of listen protocols cannot be changed without restart",
+            ));
+        }
+        if settings.reverse_proxy.is_some() != current.reverse_proxy.is_some() {
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                "Reverse proxy cannot be enabled or disabled without restart",
+            ));
+        }
+
+        *reloadable = ReloadableContext {
+            settings: Arc::new(settings),
+            authenticator,
+        };
+        self.context.settings_reload.send_replace(());
         Ok(())
     }
 
     async fn listen_tcp(&self) -> io::Result<()> {
-        let settings = self.context.settings.clone();
+        let settings = self.context.settings();
         let has_tcp_based_codec =
             settings.listen_protocols.http1.is_some() || settings.listen_protocols.http2.is_some();
 
@@ -240,12 +421,15 @@ impl Core {
                 Ok((s, a))
             }) {
                 Ok((stream, addr)) => {
-                    if has_tcp_based_codec {
-                        log_id!(debug, client_id, "New TCP client: {}", addr);
-                        (stream, addr)
-                    } else {
+                    if !has_tcp_based_codec {
                         continue; // accept just for pings
                     }
+                    if self.context.bans.is_ip_blocked(addr.ip()) {
+                        log_id!(debug, client_id, "Dropping blocked TCP client: {}", addr);
+                        continue;
+                    }
+                    log_id!(debug, client_id, "New TCP client: {}", addr);
+                    (stream, addr)
                 }
                 Err(e) => {
                     log_id!(debug, client_id, "TCP connection failed: {}", e);
@@ -258,7 +442,7 @@ impl Core {
                 let tls_listener = tls_listener.clone();
                 async move {
                     log_id!(trace, client_id, "Starting TLS handshake");
-                    let handshake_timeout = context.settings.tls_handshake_timeout;
+                    let handshake_timeout = context.settings().tls_handshake_timeout;
                     match tokio::time::timeout(handshake_timeout, tls_listener.listen(stream))
                         .await
                         .unwrap_or_else(|_| Err(io::Error::from(ErrorKind::TimedOut)))
@@ -288,7 +472,7 @@ impl Core {
     }
 
     async fn listen_udp(&self) -> io::Result<()> {
-        let settings = self.context.settings.clone();
+        let settings = self.context.settings();
         if settings.listen_protocols.quic.is_none() {
             return Ok(());
         }
@@ -300,6 +484,7 @@ impl Core {
             settings,
             socket,
             self.context.tls_demux.clone(),
+            self.context.bans.clone(),
             self.context.next_client_id.clone(),
         )?;
 
@@ -353,18 +538,8 @@ impl Core {
             "TLS SNI: {}",
             net_utils::scrub_sni(sni.to_string())
         );
-        // Apply connection filtering rules
-        if let Err(deny_reason) = Self::evaluate_connection_rules(
-            &context,
-            Some(client_ip),
-            acceptor.client_random().as_deref(),
-            &client_id,
-        ) {
-            return Err((client_id, deny_reason));
-        }
-
-        let core_settings = context.settings.clone();
-        let tls_connection_meta = match context
+        let core_settings = context.settings();
+        let mut tls_connection_meta = match context
             .tls_demux
             .read()
             .unwrap()
@@ -391,6 +566,18 @@ impl Core {
             tls_connection_meta
         );
 
+        // Apply connection filtering rules
+        let client_random = acceptor.client_random();
+        if let Err(deny_reason) = Self::evaluate_connection_rules(
+            &context,
+            Some(client_ip),
+            client_random.as_deref(),
+            &tls_connection_meta.sni,
+            &client_id,
+        ) {
+            return Err((client_id, deny_reason));
+        }
+
         log_id!(
             trace,
             client_id,
@@ -398,11 +585,12 @@ impl Core {
             tls_connection_meta.protocol
         );
         let stream = match tokio::time::timeout(
-            context.settings.tls_handshake_timeout,
+            core_settings.tls_handshake_timeout,
             acceptor.accept(
                 tls_connection_meta.protocol,
-                tls_connection_meta.cert_chain,
-                tls_connection_meta.key,
+                tls_connection_meta.cert_chain.clone(),
+                tls_connection_meta.key.clone(),
+                tls_connection_meta.client_auth.as_deref(),
                 &client_id,
             ),
         )
@@ -422,6 +610,13 @@ impl Core {
                 ));
             }
         };
+        if let Some(client_auth) = &tls_connection_meta.client_auth {
+            let chain = stream.get_ref().1.peer_certificates().unwrap_or_default();
+            tls_connection_meta.client_cert_username = match client_auth.username(chain) {
+                Ok(x) => x,
+                Err(e) => return Err((client_id, e)),
+            };
+        }
 
         log_id!(
             trace,
@@ -431,14 +626,14 @@ impl Core {
         );
         match tls_connection_meta.channel {
             net_utils::Channel::Tunnel => {
-                let tunnel_id = client_id.extended(log_utils::IdItem::new(
-                    log_utils::TUNNEL_ID_FMT,
-                    context.next_tunnel_id.fetch_add(1, Ordering::Relaxed),
-                ));
+                let tunnel_num = context.next_tunnel_id.fetch_add(1, Ordering::Relaxed);
+                let tunnel_id = client_id
+                    .extended(log_utils::IdItem::new(log_utils::TUNNEL_ID_FMT, tunnel_num));
                 log_id!(trace, tunnel_id, "Creating tunnel");
                 Self::on_tunnel_request(
                     context,
                     tls_connection_meta.protocol,
+                    client_ip,
                     match Self::make_tcp_http_codec(
                         tls_connection_meta.protocol,
                         core_settings,
@@ -450,8 +645,10 @@ impl Core {
                             return Err((client_id, format!("Failed to create HTTP codec: {}", e)))
                         }
                     },
+                    tls_connection_meta.authentication_source(),
                     tls_connection_meta.sni,
-                    tls_connection_meta.sni_auth_creds,
+                    client_random,
+                    tunnel_num,
                     tunnel_id,
                 )
                 .await
@@ -470,7 +667,7 @@ impl Core {
                             return Err((client_id, format!("Failed to create HTTP codec: {}", e)))
                         }
                     },
-                    context.settings.tls_handshake_timeout,
+                    context.settings().tls_handshake_timeout,
                     client_id,
                 )
                 .await
@@ -489,7 +686,7 @@ impl Core {
                             return Err((client_id, format!("Failed to create HTTP codec: {}", e)))
                         }
                     },
-                    context.settings.tls_handshake_timeout,
+                    context.settings().tls_handshake_timeout,
                     client_id,
                 )
                 .await
@@ -523,6 +720,14 @@ impl Core {
         socket: QuicSocket,
         client_id: log_utils::IdChain<u64>,
     ) {
+        let tls_connection_meta = socket.tls_connection_meta();
+        log_id!(
+            debug,
+            client_id,
+            "Connection meta: {:?}",
+            tls_connection_meta
+        );
+
         // Apply connection filtering rules
         let client_ip = socket.peer_addr().ok().map(|addr| addr.ip());
         let client_random = Some(socket.client_random());
@@ -531,36 +736,42 @@ impl Core {
             &context,
             client_ip,
             client_random.as_deref(),
+            &tls_connection_meta.sni,
             &client_id,
         ) {
             log_id!(debug, client_id, "{}", deny_reason);
             return; // Drop the connection
         }
 
-        let tls_connection_meta = socket.tls_connection_meta();
-        log_id!(
-            debug,
-            client_id,
-            "Connection meta: {:?}",
-            tls_connection_meta
-        );
-
         match tls_connection_meta.channel {
             net_utils::Channel::Tunnel => {
-                let tunnel_id = client_id.extended(log_utils::IdItem::new(
-                    log_utils::TUNNEL_ID_FMT,
-                    context.next_tunnel_id.fetch_add(1, Ordering::Relaxed),
-                ));
+                let tunnel_num = context.next_tunnel_id.fetch_add(1, Ordering::Relaxed);
+                let tunnel_id = client_id
+                    .extended(log_utils::IdItem::new(log_utils::TUNNEL_ID_FMT, tunnel_num));
 
                 let sni = tls_connection_meta.sni.clone();
-                let sni_auth_creds = tls_connection_meta.sni_auth_creds.clone();
+                let connection_auth = tls_connection_meta.authentication_source();
+                let client_ip = match client_ip {
+                    Some(x) => x,
+                    None => {
+                        log_id!(
+                            debug,
+                            tunnel_id,
+                            "Dropping tunnel due to unknown peer address"
+                        );
+                        return;
+                    }
+                };
 
                 Self::on_tunnel_request(
                     context,
                     tls_connection_meta.protocol,
+                    client_ip,
                     Box::new(Http3Codec::new(socket, tunnel_id.clone())),
+                    connection_auth,
                     sni,
-                    sni_auth_creds,
+                    client_random,
+                    tunnel_num,
                     tunnel_id,
                 )
                 .await
@@ -569,7 +780,7 @@ impl Core {
                 http_ping_handler::listen(
                     context.shutdown.clone(),
                     Box::new(Http3Codec::new(socket, client_id.clone())),
-                    context.settings.tls_handshake_timeout,
+                    context.settings().tls_handshake_timeout,
                     client_id,
                 )
                 .await
@@ -578,7 +789,7 @@ impl Core {
                 http_speedtest_handler::listen(
                     context.shutdown.clone(),
                     Box::new(Http3Codec::new(socket, client_id.clone())),
-                    context.settings.tls_handshake_timeout,
+                    context.settings().tls_handshake_timeout,
                     client_id,
                 )
                 .await
@@ -602,11 +813,17 @@ impl Core {
         context: &Arc<Context>,
         client_ip: Option<std::net::IpAddr>,
         client_random: Option<&[u8]>,
+        sni: &str,
         log_id: &log_utils::IdChain<u64>,
     ) -> Result<(), String> {
-        if let Some(rules_engine) = &context.settings.rules_engine {
+        if let Some(rules_engine) = &context.settings().rules_engine {
             if let Some(ip) = client_ip {
-                let rule_result = rules_engine.evaluate(&ip, client_random);
+                let connection = rules::ConnectionInfo {
+                    client_ip: ip,
+                    client_random,
+                    sni: Some(sni),
+                };
+                let rule_result = rules_engine.evaluate(&connection, None);
                 match rule_result {
                     rules::RuleEvaluation::Deny => {
                         log_id!(
@@ -632,26 +849,90 @@ impl Core {
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn on_tunnel_request(
         context: Arc<Context>,
         protocol: tls_demultiplexer::Protocol,
+        client_ip: std::net::IpAddr,
         codec: Box<dyn HttpCodec>,
+        connection_auth: Option<authentication::Source<'static>>,
         server_name: String,
-        sni_auth_creds: Option<String>,
+        client_random: Option<Vec<u8>>,
+        tunnel_num: u64,
         tunnel_id: log_utils::IdChain<u64>,
     ) {
         let _metrics_guard = Metrics::client_sessions_counter(context.metrics.clone(), protocol);
+        let settings = context.settings();
+        let session = match context.sessions.clone().register(
+            tunnel_num,
+            client_ip,
+            protocol,
+            server_name.clone(),
+            settings.limits.max_sessions_per_ip,
+        ) {
+            Some(x) => x,
+            None => {
+                let err = tunnel::ConnectionError::LimitExceeded(
+                    "Too many sessions from the client address".to_string(),
+                );
+                log_id!(debug, tunnel_id, "{}", err);
+                http_downstream::reject_session(
+                    codec,
+                    settings.client_listener_timeout,
+                    err,
+                    &tunnel_id,
+                )
+                .await;
+                return;
+            }
+        };
+        let _accounting = context
+            .accounting
+            .clone()
+            .map(|x| x.start(session.session()));
 
-        let authentication_policy = match context.authenticator.as_ref().zip(sni_auth_creds) {
-            None => tunnel::AuthenticationPolicy::Default,
-            Some((authenticator, credentials)) => {
-                let auth = authentication::Source::Sni(credentials.into());
-                match authenticator.authenticate(&auth, &tunnel_id) {
+        let authentication_policy = match (context.authenticator(), connection_auth) {
+            (_, None) | (None, Some(authentication::Source::Sni(_))) => {
+                tunnel::AuthenticationPolicy::Default
+            }
+            (authenticator, Some(auth)) => {
+                let status = match authenticator {
+                    Some(x) => {
+                        context
+                            .bans
+                            .authenticate(&settings, x.as_ref(), &auth, client_ip, &tunnel_id)
+                            .await
+                    }
+                    // The client certificate is verified during the TLS handshake
+                    None => authentication::Status::Pass,
+                };
+                match status {
                     authentication::Status::Pass => {
+                        let allowed = auth.username().is_none_or(|x| {
+                            context.sessions.set_username(
+                                &session,
+                                &x,
+                                settings.limits.max_sessions_per_user,
+                            )
+                        });
+                        if !allowed {
+                            let err = tunnel::ConnectionError::LimitExceeded(
+                                "Too many sessions of the user".to_string(),
+                            );
+                            log_id!(debug, tunnel_id, "{}", err);
+                            http_downstream::reject_session(
+                                codec,
+                                settings.client_listener_timeout,
+                                err,
+                                &tunnel_id,
+                            )
+                            .await;
+                            return;
+                        }
                         tunnel::AuthenticationPolicy::Authenticated(auth)
                     }
-                    authentication::Status::Reject => {
-                        log_id!(debug, tunnel_id, "SNI authentication failed");
+                    authentication::Status::Reject | authentication::Status::Unavailable => {
+                        log_id!(debug, tunnel_id, "Connection authentication failed");
                         return;
                     }
                 }
@@ -664,6 +945,8 @@ impl Core {
             Box::new(HttpDownstream::new(context.clone(), codec, server_name)),
             Self::make_forwarder(context),
             authentication_policy,
+            session.session(),
+            client_random,
             tunnel_id.clone(),
         );
 
@@ -695,9 +978,41 @@ impl Core {
     }
 
     fn make_forwarder(context: Arc<Context>) -> Box<dyn Forwarder> {
-        match &context.settings.forward_protocol {
-            ForwardProtocolSettings::Direct(_) => Box::new(DirectForwarder::new(context)),
-            ForwardProtocolSettings::Socks5(_) => Box::new(Socks5Forwarder::new(context)),
+        let settings = context.settings();
+        let default =
+            Self::make_protocol_forwarder(context.clone(), None, &settings.forward_protocol);
+        if settings.forwarders.is_empty() {
+            return default;
+        }
+
+        let named = settings
+            .forwarders
+            .iter()
+            .map(|(name, x)| {
+                let forwarder =
+                    Self::make_protocol_forwarder(context.clone(), Some(name.clone()), x);
+                (name.clone(), forwarder)
+            })
+            .collect();
+        Box::new(RoutingForwarder::new(context, default, named))
+    }
+
+    fn make_protocol_forwarder(
+        context: Arc<Context>,
+        name: Option<String>,
+        settings: &ForwardProtocolSettings,
+    ) -> Box<dyn Forwarder> {
+        match settings {
+            ForwardProtocolSettings::Direct(x) => Box::new(DirectForwarder::new(context, x)),
+            ForwardProtocolSettings::Socks5(x) => {
+                Box::new(Socks5Forwarder::new(context, name, Arc::new(x.clone())))
+            }
+            ForwardProtocolSettings::HttpConnect(x) => {
+                Box::new(HttpConnectForwarder::new(context, Arc::new(x.clone())))
+            }
+            ForwardProtocolSettings::Cascade(x) => {
+                Box::new(CascadeForwarder::new(context, name, Arc::new(x.clone())))
+            }
         }
     }
 }
@@ -705,18 +1020,37 @@ impl Core {
 #[cfg(test)]
 impl Default for Context {
     fn default() -> Self {
-        let settings = Arc::new(Settings::default());
+        Self::with_settings(Settings::default())
+    }
+}
+
+#[cfg(test)]
+impl Context {
+    pub(crate) fn with_settings(settings: Settings) -> Self {
+        let settings = Arc::new(settings);
         let (fatal_error, _fatal_error_rx) = watch::channel(None);
         Self {
-            settings: settings.clone(),
-            authenticator: None,
+            reloadable: RwLock::new(ReloadableContext {
+                settings: settings.clone(),
+                authenticator: None,
+            }),
             tls_demux: Arc::new(RwLock::new(
                 TlsDemux::new(&settings, &settings::TlsHostsSettings::default()).unwrap(),
             )),
             icmp_forwarder: None,
             shutdown: Shutdown::new(),
             fatal_error,
-            metrics: Metrics::new().unwrap(),
+            settings_reload: watch::channel(()).0,
+            metrics: Metrics::new(settings.metrics.as_ref()).unwrap(),
+            sessions: Default::default(),
+            rate_limiters: Default::default(),
+            quotas: Default::default(),
+            bans: Default::default(),
+            resolver: Box::new(dns::SystemResolver),
+            dns_filter: None,
+            accounting: None,
+            cascade_sessions: Default::default(),
+            socks5_pools: Default::default(),
             next_client_id: Default::default(),
             next_tunnel_id: Default::default(),
         }
@@ -1,4 +1,6 @@
 use crate::forwarder::{Forwarder, IcmpMultiplexer, UdpMultiplexer};
+use crate::settings::DirectForwarderSettings;
+use crate::source_address::SourcePool;
 use crate::tcp_forwarder::TcpForwarder;
 use crate::{authentication, core, forwarder, log_utils, tunnel, udp_forwarder};
 use async_trait::async_trait;
@@ -8,17 +10,25 @@ use std::sync::Arc;
 
 pub(crate) struct DirectForwarder {
     context: Arc<core::Context>,
+    /// The source addresses of the outbound sockets, if configured
+    sources: Option<Arc<SourcePool>>,
 }
 
 impl DirectForwarder {
-    pub fn new(context: Arc<core::Context>) -> Self {
-        Self { context }
+    pub fn new(context: Arc<core::Context>, settings: &DirectForwarderSettings) -> Self {
+        Self {
+            context,
+            sources: SourcePool::new(settings),
+        }
     }
 }
 
 impl Forwarder for DirectForwarder {
     fn tcp_connector(&self) -> Box<dyn forwarder::TcpConnector> {
-        Box::new(TcpForwarder::new(self.context.clone()))
+        Box::new(TcpForwarder::with_sources(
+            self.context.clone(),
+            self.sources.clone(),
+        ))
     }
 
     fn datagram_mux_authenticator(&self) -> Box<dyn forwarder::DatagramMultiplexerAuthenticator> {
@@ -43,9 +53,13 @@ impl Forwarder for DirectForwarder {
     fn make_udp_datagram_multiplexer(
         &self,
         id: log_utils::IdChain<u64>,
-        _: forwarder::UdpMultiplexerMeta,
+        meta: forwarder::UdpMultiplexerMeta,
     ) -> io::Result<UdpMultiplexer> {
-        udp_forwarder::make_multiplexer(self.context.clone(), id)
+        let sources = self.sources.as_ref().map(|x| {
+            let username = meta.auth.as_ref().and_then(|x| x.username());
+            x.selector(username.as_deref(), &meta.client_address)
+        });
+        udp_forwarder::make_multiplexer(self.context.clone(), id, sources)
     }
 
     fn make_icmp_datagram_multiplexer(
@@ -0,0 +1,296 @@
+//! Answering the DNS queries intercepted in the tunneled UDP traffic
+
+use crate::core::Context;
+use crate::dns::message;
+use crate::metrics::DnsQueryResult;
+use crate::settings::{DnsBlockingMode, DnsSettings};
+use crate::{log_id, log_utils};
+use std::collections::HashSet;
+use std::io;
+use std::net::IpAddr;
+
+/// The time to live of the answers for the blocked names
+const BLOCKED_TTL: u32 = 60;
+
+/// Answers the intercepted queries, the ones for the blocked names are answered
+/// by the filter itself
+pub(crate) struct DnsFilter {
+    blocklist: Blocklist,
+    blocking_mode: DnsBlockingMode,
+}
+
+#[derive(Default)]
+struct Blocklist {
+    /// Blocked along with the subdomains
+    blocked: HashSet<String>,
+    /// Blocked without the subdomains
+    blocked_exact: HashSet<String>,
+    /// Exceptions from the blocked names, along with the subdomains
+    allowed: HashSet<String>,
+}
+
+impl DnsFilter {
+    /// Make the filter if the interception is enabled
+    pub fn new(settings: &DnsSettings) -> io::Result<Option<Self>> {
+        if !settings.intercept {
+            return Ok(None);
+        }
+
+        let mut blocklist = Blocklist::default();
+        for path in &settings.blocklists {
+            let content = std::fs::read_to_string(path).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Failed to read blocklist {}: {}", path, e),
+                )
+            })?;
+            blocklist.add_rules(&content);
+        }
+
+        Ok(Some(Self {
+            blocklist,
+            blocking_mode: settings.blocking_mode,
+        }))
+    }
+
+    fn blocked_response(&self, query: &message::Message) -> Vec<u8> {
+        match self.blocking_mode {
+            DnsBlockingMode::Nxdomain => {
+                message::build_response(query, message::RCODE_NAME_ERROR, &[])
+            }
+            DnsBlockingMode::Unspecified => {
+                let qtype = query.questions.first().map(|x| x.qtype);
+                let answer = match qtype {
+                    Some(message::TYPE_A) => vec![0; 4],
+                    Some(message::TYPE_AAAA) => vec![0; 16],
+                    _ => vec![],
+                };
+                let answers = qtype
+                    .filter(|_| !answer.is_empty())
+                    .map(|x| (x, BLOCKED_TTL, answer.as_slice()));
+                message::build_response(query, message::RCODE_NO_ERROR, answers.as_slice())
+            }
+        }
+    }
+}
+
+impl Blocklist {
+    fn add_rules(&mut self, content: &str) {
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(['#', '!', '[']) {
+                continue;
+            }
+
+            if let Some(x) = line.strip_prefix("@@||") {
+                if let Some(x) = adblock_domain(x) {
+                    self.allowed.insert(x);
+                }
+            } else if let Some(x) = line.strip_prefix("||") {
+                if let Some(x) = adblock_domain(x) {
+                    self.blocked.insert(x);
+                }
+            } else {
+                let line = line.split('#').next().unwrap_or_default();
+                let mut tokens = line.split_whitespace();
+                match tokens.next() {
+                    Some(x) if x.parse::<IpAddr>().is_ok() => {
+                        self.blocked_exact.extend(
+                            tokens
+                                .filter(|x| !is_local_name(x))
+                                .filter_map(normalize_name),
+                        );
+                    }
+                    Some(x) => self.blocked.extend(normalize_name(x)),
+                    None => (),
+                }
+            }
+        }
+    }
+
+    fn is_blocked(&self, name: &str) -> bool {
+        let any_suffix = |set: &HashSet<String>| {
+            let mut x = name;
+            loop {
+                if set.contains(x) {
+                    break true;
+                }
+                match x.split_once('.') {
+                    Some((_, parent)) => x = parent,
+                    None => break false,
+                }
+            }
+        };
+
+        if !any_suffix(&self.allowed) {
+            self.blocked_exact.contains(name) || any_suffix(&self.blocked)
+        } else {
+            false
+        }
+    }
+}
+
+/// Get the response to the intercepted query.
+/// Returns [`None`] if the datagram is not a query and must be dropped.
+pub(crate) async fn answer(
+    context: &Context,
+    query: &[u8],
+    username: Option<&str>,
+    log_id: &log_utils::IdChain<u64>,
+) -> Option<Vec<u8>> {
+    let filter = context.dns_filter.as_ref()?;
+    let parsed = match message::parse(query) {
+        Ok(x) if !x.is_response() && x.questions.len() == 1 => x,
+        Ok(_) => {
+            log_id!(debug, log_id, "Dropping unexpected DNS message");
+            return None;
+        }
+        Err(e) => {
+            log_id!(debug, log_id, "Dropping malformed DNS query: {}", e);
+            return None;
+        }
+    };
+
+    let name = &parsed.questions[0].name;
+    if filter.blocklist.is_blocked(name) {
+        log_id!(debug, log_id, "Blocked DNS query: {}", name);
+        context
+            .metrics
+            .add_dns_query(username, DnsQueryResult::Blocked);
+        return Some(filter.blocked_response(&parsed));
+    }
+
+    match context.resolver.exchange(query, log_id).await {
+        Ok(x) => {
+            context
+                .metrics
+                .add_dns_query(username, DnsQueryResult::Allowed);
+            Some(x)
+        }
+        Err(e) => {
+            log_id!(
+                debug,
+                log_id,
+                "Failed to answer DNS query: {} error={}",
+                name,
+                e
+            );
+            context
+                .metrics
+                .add_dns_query(username, DnsQueryResult::Failed);
+            Some(message::build_response(
+                &parsed,
+                message::RCODE_SERVER_FAILURE,
+                &[],
+            ))
+        }
+    }
+}
+
+/// Get the domain from the adblock rule body: `example.com^`
+fn adblock_domain(rule: &str) -> Option<String> {
+    let domain = rule
+        .strip_suffix('^')
+        .or_else(|| rule.strip_suffix("^$important"))?;
+    if domain.contains(['*', '/', '$', '^']) {
+        return None;
+    }
+    normalize_name(domain)
+}
+
+fn normalize_name(name: &str) -> Option<String> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    (!name.is_empty()
+        && name
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '.' || x == '_'))
+    .then(|| name.to_ascii_lowercase())
+}
+
+fn is_local_name(name: &str) -> bool {
+    matches!(
+        name,
+        "localhost"
+            | "localhost.localdomain"
+            | "local"
+            | "broadcasthost"
+            | "ip6-localhost"
+            | "ip6-loopback"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocklist_formats() {
+        let mut blocklist = Blocklist::default();
+        blocklist.add_rules(
+            r#"
+# hosts file
+127.0.0.1 localhost
+0.0.0.0 ads.example.com tracker.example.com # comment
+! adblock
+[Adblock Plus 2.0]
+||doubleclick.net^
+||Analytics.Example.ORG^
+@@||good.doubleclick.net^
+||example.net^$third-party
+||*.wildcard.com^
+# plain list
+malware.test
+"#,
+        );
+
+        assert!(blocklist.is_blocked("ads.example.com"));
+        assert!(blocklist.is_blocked("tracker.example.com"));
+        assert!(!blocklist.is_blocked("sub.ads.example.com"));
+        assert!(!blocklist.is_blocked("example.com"));
+        assert!(!blocklist.is_blocked("localhost"));
+
+        assert!(blocklist.is_blocked("doubleclick.net"));
+        assert!(blocklist.is_blocked("ad.doubleclick.net"));
+        assert!(!blocklist.is_blocked("good.doubleclick.net"));
+        assert!(!blocklist.is_blocked("x.good.doubleclick.net"));
+        assert!(blocklist.is_blocked("analytics.example.org"));
+        assert!(!blocklist.is_blocked("example.net"));
+        assert!(!blocklist.is_blocked("a.wildcard.com"));
+
+        assert!(blocklist.is_blocked("malware.test"));
+        assert!(blocklist.is_blocked("a.malware.test"));
+    }
+
+    #[test]
+    fn blocked_responses() {
+        let query = |qtype| {
+            message::parse(&message::build_query(1, "ads.example.com", qtype).unwrap()).unwrap()
+        };
+        let filter = |blocking_mode| DnsFilter {
+            blocklist: Default::default(),
+            blocking_mode,
+        };
+
+        let response = message::parse(
+            &filter(DnsBlockingMode::Nxdomain).blocked_response(&query(message::TYPE_A)),
+        )
+        .unwrap();
+        assert_eq!(response.id, 1);
+        assert_eq!(response.rcode(), message::RCODE_NAME_ERROR);
+        assert!(response.answers.is_empty());
+
+        let filter = filter(DnsBlockingMode::Unspecified);
+        let response =
+            message::parse(&filter.blocked_response(&query(message::TYPE_AAAA))).unwrap();
+        assert_eq!(response.rcode(), message::RCODE_NO_ERROR);
+        assert_eq!(
+            response.addresses().collect::<Vec<_>>(),
+            vec![("::".parse().unwrap(), BLOCKED_TTL)]
+        );
+
+        // No answer records for the non-address types
+        let response = message::parse(&filter.blocked_response(&query(16))).unwrap();
+        assert_eq!(response.rcode(), message::RCODE_NO_ERROR);
+        assert!(response.answers.is_empty());
+    }
+}
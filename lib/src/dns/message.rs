@@ -0,0 +1,314 @@
+//! Encoding and decoding of the [DNS messages](https://datatracker.ietf.org/doc/html/rfc1035#section-4)
+
+use std::io;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+
+pub(crate) const TYPE_A: u16 = 1;
+pub(crate) const TYPE_AAAA: u16 = 28;
+pub(crate) const CLASS_IN: u16 = 1;
+
+pub(crate) const RCODE_NO_ERROR: u8 = 0;
+pub(crate) const RCODE_SERVER_FAILURE: u8 = 2;
+pub(crate) const RCODE_NAME_ERROR: u8 = 3;
+pub(crate) const RCODE_NOT_IMPLEMENTED: u8 = 4;
+
+const HEADER_LENGTH: usize = 12;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const FLAG_RECURSION_AVAILABLE: u16 = 0x0080;
+const MAX_NAME_LENGTH: usize = 255;
+const MAX_LABEL_LENGTH: usize = 63;
+/// Limits the number of compression pointers followed while reading a name
+const MAX_POINTERS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Message {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Question {
+    /// Lowercase, without the trailing dot
+    pub name: String,
+    pub qtype: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Record {
+    pub rtype: u16,
+    pub ttl: u32,
+    pub data: Vec<u8>,
+}
+
+impl Message {
+    pub fn is_response(&self) -> bool {
+        self.flags & FLAG_RESPONSE != 0
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.flags & FLAG_TRUNCATED != 0
+    }
+
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0x000f) as u8
+    }
+
+    /// Get the addresses from the answer records of the type `A` and `AAAA`
+    pub fn addresses(&self) -> impl Iterator<Item = (IpAddr, u32)> + '_ {
+        self.answers
+            .iter()
+            .filter_map(|x| match (x.rtype, x.data.len()) {
+                (TYPE_A, 4) => Some((
+                    IpAddr::from(<[u8; 4]>::try_from(x.data.as_slice()).unwrap()),
+                    x.ttl,
+                )),
+                (TYPE_AAAA, 16) => Some((
+                    IpAddr::from(<[u8; 16]>::try_from(x.data.as_slice()).unwrap()),
+                    x.ttl,
+                )),
+                _ => None,
+            })
+    }
+}
+
+/// Build a recursive query for the records of the type `qtype` of the name
+pub(crate) fn build_query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(HEADER_LENGTH + name.len() + 6);
+    buffer.extend_from_slice(&id.to_be_bytes());
+    buffer.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    // 1 question, no answer, authority and additional records
+    buffer.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    write_name(&mut buffer, name)?;
+    buffer.extend_from_slice(&qtype.to_be_bytes());
+    buffer.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(buffer)
+}
+
+/// Build a response to the query with the answer records of the name of its first question
+pub(crate) fn build_response(query: &Message, rcode: u8, answers: &[(u16, u32, &[u8])]) -> Vec<u8> {
+    let flags = FLAG_RESPONSE
+        | FLAG_RECURSION_AVAILABLE
+        | (query.flags & FLAG_RECURSION_DESIRED)
+        | u16::from(rcode & 0x0f);
+    let question = query.questions.first();
+    let mut buffer = Vec::with_capacity(512);
+    buffer.extend_from_slice(&query.id.to_be_bytes());
+    buffer.extend_from_slice(&flags.to_be_bytes());
+    buffer.extend_from_slice(&u16::from(question.is_some()).to_be_bytes());
+    buffer.extend_from_slice(&(question.map_or(0, |_| answers.len()) as u16).to_be_bytes());
+    buffer.extend_from_slice(&[0, 0, 0, 0]);
+
+    if let Some(question) = question {
+        // The name is read from a message, so it is valid
+        let _ = write_name(&mut buffer, &question.name);
+        buffer.extend_from_slice(&question.qtype.to_be_bytes());
+        buffer.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+        for (rtype, ttl, data) in answers {
+            // Pointer to the question name
+            buffer.extend_from_slice(&[0xc0, HEADER_LENGTH as u8]);
+            buffer.extend_from_slice(&rtype.to_be_bytes());
+            buffer.extend_from_slice(&CLASS_IN.to_be_bytes());
+            buffer.extend_from_slice(&ttl.to_be_bytes());
+            buffer.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            buffer.extend_from_slice(data);
+        }
+    }
+
+    buffer
+}
+
+/// Parse a message. The authority and additional sections are skipped.
+pub(crate) fn parse(buffer: &[u8]) -> io::Result<Message> {
+    if buffer.len() < HEADER_LENGTH {
+        return Err(invalid_data("Message is too short"));
+    }
+
+    let id = read_u16(buffer, 0)?;
+    let flags = read_u16(buffer, 2)?;
+    let questions_num = read_u16(buffer, 4)?;
+    let answers_num = read_u16(buffer, 6)?;
+
+    let mut offset = HEADER_LENGTH;
+    let mut questions = Vec::with_capacity(questions_num.min(4) as usize);
+    for _ in 0..questions_num {
+        let name = read_name(buffer, &mut offset)?;
+        questions.push(Question {
+            name,
+            qtype: read_u16(buffer, offset)?,
+        });
+        offset += 4;
+    }
+
+    let mut answers = Vec::with_capacity(answers_num.min(16) as usize);
+    for _ in 0..answers_num {
+        read_name(buffer, &mut offset)?;
+        let rtype = read_u16(buffer, offset)?;
+        let ttl = (u32::from(read_u16(buffer, offset + 4)?) << 16)
+            | u32::from(read_u16(buffer, offset + 6)?);
+        let length = read_u16(buffer, offset + 8)? as usize;
+        offset += 10;
+        let data = buffer
+            .get(offset..offset + length)
+            .ok_or_else(|| invalid_data("Record data is out of bounds"))?;
+        offset += length;
+        answers.push(Record {
+            rtype,
+            ttl,
+            data: data.to_vec(),
+        });
+    }
+
+    Ok(Message {
+        id,
+        flags,
+        questions,
+        answers,
+    })
+}
+
+fn write_name(buffer: &mut Vec<u8>, name: &str) -> io::Result<()> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.len() + 1 > MAX_NAME_LENGTH {
+        return Err(invalid_input("Name is too long"));
+    }
+
+    if !name.is_empty() {
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > MAX_LABEL_LENGTH {
+                return Err(invalid_input("Invalid name label length"));
+            }
+            buffer.push(label.len() as u8);
+            buffer.extend_from_slice(label.as_bytes());
+        }
+    }
+    buffer.push(0);
+    Ok(())
+}
+
+/// Read a possibly compressed name and move the offset past it
+fn read_name(buffer: &[u8], offset: &mut usize) -> io::Result<String> {
+    let mut name = String::new();
+    let mut position = *offset;
+    let mut pointers = 0;
+    loop {
+        let length = *buffer
+            .get(position)
+            .ok_or_else(|| invalid_data("Name is out of bounds"))? as usize;
+        match length & 0xc0 {
+            0x00 if length == 0 => {
+                if pointers == 0 {
+                    *offset = position + 1;
+                }
+                break;
+            }
+            0x00 => {
+                let label = buffer
+                    .get(position + 1..position + 1 + length)
+                    .ok_or_else(|| invalid_data("Name label is out of bounds"))?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.extend(label.iter().map(|x| x.to_ascii_lowercase() as char));
+                if name.len() >= MAX_NAME_LENGTH {
+                    return Err(invalid_data("Name is too long"));
+                }
+                position += 1 + length;
+            }
+            0xc0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return Err(invalid_data("Too many name compression pointers"));
+                }
+                if pointers == 1 {
+                    *offset = position + 2;
+                }
+                position = (read_u16(buffer, position)? & 0x3fff) as usize;
+            }
+            _ => return Err(invalid_data("Unsupported name label type")),
+        }
+    }
+
+    Ok(name)
+}
+
+fn read_u16(buffer: &[u8], offset: usize) -> io::Result<u16> {
+    buffer
+        .get(offset..offset + 2)
+        .map(|x| u16::from_be_bytes([x[0], x[1]]))
+        .ok_or_else(|| invalid_data("Message is truncated"))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_roundtrip() {
+        let query = build_query(0x1234, "Example.COM.", TYPE_AAAA).unwrap();
+        let message = parse(&query).unwrap();
+        assert_eq!(message.id, 0x1234);
+        assert!(!message.is_response());
+        assert_eq!(
+            message.questions,
+            vec![Question {
+                name: "example.com".to_string(),
+                qtype: TYPE_AAAA,
+            }]
+        );
+
+        assert!(build_query(0, "a..b", TYPE_A).is_err());
+        assert!(build_query(0, &"a".repeat(64), TYPE_A).is_err());
+    }
+
+    #[test]
+    fn response_with_compressed_names() {
+        let query = parse(&build_query(7, "www.example.com", TYPE_A).unwrap()).unwrap();
+        let response = build_response(
+            &query,
+            RCODE_NO_ERROR,
+            &[(TYPE_A, 60, &[1, 2, 3, 4]), (TYPE_A, 30, &[5, 6, 7, 8])],
+        );
+
+        let message = parse(&response).unwrap();
+        assert!(message.is_response());
+        assert!(!message.is_truncated());
+        assert_eq!(message.rcode(), RCODE_NO_ERROR);
+        assert_eq!(message.answers.len(), 2);
+        assert_eq!(
+            message.addresses().collect::<Vec<_>>(),
+            vec![
+                (IpAddr::from([1, 2, 3, 4]), 60),
+                (IpAddr::from([5, 6, 7, 8]), 30)
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_messages() {
+        assert!(parse(&[0; 4]).is_err());
+
+        let mut query = build_query(1, "example.com", TYPE_A).unwrap();
+        query.truncate(query.len() - 3);
+        assert!(parse(&query).is_err());
+
+        // A name pointing to itself
+        let mut looped = build_query(1, "", TYPE_A).unwrap();
+        looped[HEADER_LENGTH] = 0xc0;
+        looped.insert(HEADER_LENGTH + 1, HEADER_LENGTH as u8);
+        assert!(parse(&looped).is_err());
+    }
+}
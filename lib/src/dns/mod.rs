@@ -0,0 +1,464 @@
+pub(crate) mod filter;
+pub(crate) mod message;
+pub(crate) mod upstream;
+
+use crate::settings::DnsSettings;
+use crate::{log_id, log_utils, net_utils};
+use async_trait::async_trait;
+use futures::future;
+use futures::future::Either;
+use ring::rand::SecureRandom;
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use upstream::{Protocol, Upstream, UpstreamAddress};
+
+/// How long the answer of one address family is awaited after the other one is received
+/// ([RFC 8305](https://datatracker.ietf.org/doc/html/rfc8305#section-3))
+const RESOLUTION_DELAY: Duration = Duration::from_millis(50);
+/// The time to live of the cached empty answers
+const NEGATIVE_TTL: Duration = Duration::from_secs(30);
+/// The upper bound of the time to live of the cached answers
+const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// The time to live of the answers of the system resolver
+const SYSTEM_RESOLVER_TTL: u32 = 60;
+
+/// Resolves the host names of the peers
+#[async_trait]
+pub(crate) trait Resolver: Send + Sync {
+    /// Resolve the host name to the IPv4 and IPv6 addresses
+    async fn resolve(
+        &self,
+        name: &str,
+        log_id: &log_utils::IdChain<u64>,
+    ) -> io::Result<Vec<IpAddr>>;
+
+    /// Get the response to the raw DNS query
+    async fn exchange(&self, query: &[u8], log_id: &log_utils::IdChain<u64>)
+        -> io::Result<Vec<u8>>;
+}
+
+/// Resolves through the system resolver
+pub(crate) struct SystemResolver;
+
+/// Resolves through the configured upstream servers with caching the answers
+pub(crate) struct UpstreamResolver {
+    /// Tried in order until one of them answers
+    upstreams: Vec<Upstream>,
+    query_timeout: Duration,
+    cache: Cache,
+    random: ring::rand::SystemRandom,
+}
+
+/// The LRU cache of the answers, the entries expire according to their time to live
+struct Cache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// Key is the name and the record type
+    entries: HashMap<(String, u16), CacheEntry>,
+    /// Incremented on each access to order the entries by the last use
+    clock: u64,
+}
+
+struct CacheEntry {
+    addresses: Vec<IpAddr>,
+    expires_at: Instant,
+    last_used: u64,
+}
+
+/// Make the resolver according to the settings
+pub(crate) fn make_resolver(settings: Option<&DnsSettings>) -> io::Result<Box<dyn Resolver>> {
+    match settings {
+        None => Ok(Box::new(SystemResolver)),
+        Some(x) => Ok(Box::new(UpstreamResolver::new(x)?)),
+    }
+}
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(
+        &self,
+        name: &str,
+        _log_id: &log_utils::IdChain<u64>,
+    ) -> io::Result<Vec<IpAddr>> {
+        Ok(tokio::net::lookup_host((name, 0))
+            .await?
+            .map(|x| x.ip())
+            .collect())
+    }
+
+    /// Only the address queries are supported
+    async fn exchange(
+        &self,
+        query: &[u8],
+        log_id: &log_utils::IdChain<u64>,
+    ) -> io::Result<Vec<u8>> {
+        let query = message::parse(query)?;
+        let question = match query.questions.as_slice() {
+            [x] if x.qtype == message::TYPE_A || x.qtype == message::TYPE_AAAA => x,
+            _ => {
+                return Ok(message::build_response(
+                    &query,
+                    message::RCODE_NOT_IMPLEMENTED,
+                    &[],
+                ))
+            }
+        };
+
+        let answers: Vec<_> = self
+            .resolve(&question.name, log_id)
+            .await?
+            .into_iter()
+            .filter(|x| x.is_ipv4() == (question.qtype == message::TYPE_A))
+            .map(|x| match x {
+                IpAddr::V4(x) => x.octets().to_vec(),
+                IpAddr::V6(x) => x.octets().to_vec(),
+            })
+            .collect();
+        Ok(message::build_response(
+            &query,
+            message::RCODE_NO_ERROR,
+            &answers
+                .iter()
+                .map(|x| (question.qtype, SYSTEM_RESOLVER_TTL, x.as_slice()))
+                .collect::<Vec<_>>(),
+        ))
+    }
+}
+
+impl UpstreamResolver {
+    pub fn new(settings: &DnsSettings) -> io::Result<Self> {
+        let addresses = settings
+            .upstreams
+            .iter()
+            .map(|x| UpstreamAddress::parse(x))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+
+        let tls_config = addresses
+            .iter()
+            .any(|x| matches!(x.protocol, Protocol::Tls | Protocol::Https))
+            .then(net_utils::make_client_tls_config)
+            .transpose()?;
+
+        Ok(Self {
+            upstreams: addresses
+                .into_iter()
+                .map(|x| Upstream::new(x, tls_config.as_ref()))
+                .collect::<io::Result<_>>()?,
+            query_timeout: settings.query_timeout,
+            cache: Cache::new(settings.cache_size),
+            random: ring::rand::SystemRandom::new(),
+        })
+    }
+
+    /// Get the addresses of the record type from the cache or the upstreams
+    async fn lookup(
+        &self,
+        name: &str,
+        rtype: u16,
+        log_id: &log_utils::IdChain<u64>,
+    ) -> io::Result<Vec<IpAddr>> {
+        if let Some(x) = self.cache.get(name, rtype) {
+            log_id!(trace, log_id, "DNS cache hit: {} type={}", name, rtype);
+            return Ok(x);
+        }
+
+        let response = self.query(name, rtype, log_id).await?;
+        let mut ttl = None;
+        let addresses: Vec<_> = response
+            .addresses()
+            .filter(|(ip, _)| match rtype {
+                message::TYPE_A => ip.is_ipv4(),
+                _ => ip.is_ipv6(),
+            })
+            .map(|(ip, x)| {
+                ttl = Some(ttl.map_or(x, |ttl: u32| ttl.min(x)));
+                ip
+            })
+            .collect();
+
+        let ttl = ttl.map_or(NEGATIVE_TTL, |x| Duration::from_secs(x.into()));
+        self.cache
+            .insert(name, rtype, addresses.clone(), ttl.min(MAX_TTL));
+        Ok(addresses)
+    }
+
+    async fn query(
+        &self,
+        name: &str,
+        rtype: u16,
+        log_id: &log_utils::IdChain<u64>,
+    ) -> io::Result<message::Message> {
+        let query = message::build_query(0, name, rtype)?;
+        self.send(&query, log_id).await.map(|(_, x)| x)
+    }
+
+    /// Send the query to the upstreams in order until one of them answers it.
+    /// The upstreams see a random query ID, the response gets the original one back.
+    async fn send(
+        &self,
+        query: &[u8],
+        log_id: &log_utils::IdChain<u64>,
+    ) -> io::Result<(Vec<u8>, message::Message)> {
+        let parsed = message::parse(query)?;
+        let mut query = query.to_vec();
+        self.random
+            .fill(&mut query[..2])
+            .map_err(|_| io::Error::new(ErrorKind::Other, "Failed to generate query ID"))?;
+
+        let mut last_error = None;
+        for upstream in &self.upstreams {
+            let error =
+                match tokio::time::timeout(self.query_timeout, upstream.exchange(&query)).await {
+                    Ok(Ok(mut response)) => match message::parse(&response) {
+                        Ok(x) if !x.is_response() || x.questions != parsed.questions => {
+                            io::Error::new(ErrorKind::InvalidData, "Response does not match query")
+                        }
+                        Ok(x)
+                            if x.rcode() == message::RCODE_NO_ERROR
+                                || x.rcode() == message::RCODE_NAME_ERROR =>
+                        {
+                            response[..2].copy_from_slice(&parsed.id.to_be_bytes());
+                            return Ok((response, message::Message { id: parsed.id, ..x }));
+                        }
+                        Ok(x) => io::Error::new(
+                            ErrorKind::Other,
+                            format!("Unexpected response code: {}", x.rcode()),
+                        ),
+                        Err(e) => e,
+                    },
+                    Ok(Err(e)) => e,
+                    Err(_) => io::Error::from(ErrorKind::TimedOut),
+                };
+            log_id!(
+                debug,
+                log_id,
+                "DNS query failed: upstream={} questions={:?} error={}",
+                upstream,
+                parsed.questions,
+                error
+            );
+            last_error = Some(error);
+        }
+
+        Err(last_error.unwrap_or_else(|| io::Error::new(ErrorKind::Other, "No upstreams")))
+    }
+}
+
+#[async_trait]
+impl Resolver for UpstreamResolver {
+    async fn resolve(
+        &self,
+        name: &str,
+        log_id: &log_utils::IdChain<u64>,
+    ) -> io::Result<Vec<IpAddr>> {
+        if let Ok(x) = name.parse::<IpAddr>() {
+            return Ok(vec![x]);
+        }
+
+        let name = name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase();
+        let v4 = Box::pin(self.lookup(&name, message::TYPE_A, log_id));
+        let v6 = Box::pin(self.lookup(&name, message::TYPE_AAAA, log_id));
+
+        // Do not hold the connection establishment for long if one of the families
+        // is answered, but wait for the other one if the first answer is unusable
+        let await_other =
+            |first: &io::Result<Vec<IpAddr>>| first.as_ref().is_ok_and(|x| !x.is_empty());
+        let (v4, v6) = match future::select(v4, v6).await {
+            Either::Left((v4, v6)) => {
+                let v6 = if await_other(&v4) {
+                    tokio::time::timeout(RESOLUTION_DELAY, v6)
+                        .await
+                        .unwrap_or_else(|_| Ok(vec![]))
+                } else {
+                    v6.await
+                };
+                (v4, v6)
+            }
+            Either::Right((v6, v4)) => {
+                let v4 = if await_other(&v6) {
+                    tokio::time::timeout(RESOLUTION_DELAY, v4)
+                        .await
+                        .unwrap_or_else(|_| Ok(vec![]))
+                } else {
+                    v4.await
+                };
+                (v4, v6)
+            }
+        };
+
+        match (v4, v6) {
+            (Err(e), Err(_)) => Err(e),
+            (v4, v6) => Ok(v6
+                .unwrap_or_default()
+                .into_iter()
+                .chain(v4.unwrap_or_default())
+                .collect()),
+        }
+    }
+
+    async fn exchange(
+        &self,
+        query: &[u8],
+        log_id: &log_utils::IdChain<u64>,
+    ) -> io::Result<Vec<u8>> {
+        self.send(query, log_id).await.map(|(x, _)| x)
+    }
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Default::default(),
+        }
+    }
+
+    fn get(&self, name: &str, rtype: u16) -> Option<Vec<IpAddr>> {
+        let mut state = self.state.lock().unwrap();
+        let key = (name.to_string(), rtype);
+        let entry = state.entries.get(&key)?;
+        if entry.expires_at <= Instant::now() {
+            state.entries.remove(&key);
+            return None;
+        }
+
+        state.clock += 1;
+        let clock = state.clock;
+        let entry = state.entries.get_mut(&key).unwrap();
+        entry.last_used = clock;
+        Some(entry.addresses.clone())
+    }
+
+    fn insert(&self, name: &str, rtype: u16, addresses: Vec<IpAddr>, ttl: Duration) {
+        if self.capacity == 0 || ttl.is_zero() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let key = (name.to_string(), rtype);
+        if !state.entries.contains_key(&key) && state.entries.len() >= self.capacity {
+            let now = Instant::now();
+            state.entries.retain(|_, x| x.expires_at > now);
+            if state.entries.len() >= self.capacity {
+                let oldest = state
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, x)| x.last_used)
+                    .map(|(k, _)| k.clone());
+                if let Some(x) = oldest {
+                    state.entries.remove(&x);
+                }
+            }
+        }
+
+        state.clock += 1;
+        let last_used = state.clock;
+        state.entries.insert(
+            key,
+            CacheEntry {
+                addresses,
+                expires_at: Instant::now() + ttl,
+                last_used,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::net::UdpSocket;
+
+    #[test]
+    fn cache_eviction() {
+        let cache = Cache::new(2);
+        let ip = |x| vec![IpAddr::from([x, 0, 0, 1])];
+        cache.insert("a", message::TYPE_A, ip(1), Duration::from_secs(60));
+        cache.insert("b", message::TYPE_A, ip(2), Duration::from_secs(60));
+        assert_eq!(cache.get("a", message::TYPE_A), Some(ip(1)));
+
+        // "b" is the least recently used one
+        cache.insert("c", message::TYPE_A, ip(3), Duration::from_secs(60));
+        assert_eq!(cache.get("b", message::TYPE_A), None);
+        assert_eq!(cache.get("a", message::TYPE_A), Some(ip(1)));
+        assert_eq!(cache.get("c", message::TYPE_A), Some(ip(3)));
+        assert_eq!(cache.get("c", message::TYPE_AAAA), None);
+
+        cache.insert("a", message::TYPE_A, ip(1), Duration::from_nanos(1));
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(cache.get("a", message::TYPE_A), None);
+    }
+
+    /// Run a server answering the `A` queries with 192.0.2.1 and the `AAAA` ones with
+    /// an empty answer. Returns the server address and the number of the received queries.
+    async fn run_udp_server() -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        tokio::spawn(async move {
+            let mut buffer = vec![0; 512];
+            loop {
+                let (n, peer) = socket.recv_from(&mut buffer).await.unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+                let query = message::parse(&buffer[..n]).unwrap();
+                let mut response = buffer[..n].to_vec();
+                response[2] |= 0x80;
+                if query.questions[0].qtype == message::TYPE_A {
+                    response[7] = 1;
+                    response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                    response.extend_from_slice(&[192, 0, 2, 1]);
+                }
+                socket.send_to(&response, peer).await.unwrap();
+            }
+        });
+        (address, queries)
+    }
+
+    #[tokio::test]
+    async fn resolve_through_upstream() {
+        let (address, queries) = run_udp_server().await;
+        let settings = DnsSettings::builder()
+            .upstreams(vec![
+                // Nothing listens on the discard port, the next upstream is used
+                "udp://127.0.0.1:9".to_string(),
+                format!("udp://{}", address),
+            ])
+            .query_timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let resolver = UpstreamResolver::new(&settings).unwrap();
+        let log_id = log_utils::IdChain::empty();
+
+        let expected = vec![IpAddr::from([192, 0, 2, 1])];
+        assert_eq!(
+            resolver.resolve("Example.com.", &log_id).await.unwrap(),
+            expected
+        );
+        assert_eq!(queries.load(Ordering::Relaxed), 2);
+
+        // Answered from the cache
+        assert_eq!(
+            resolver.resolve("example.com", &log_id).await.unwrap(),
+            expected
+        );
+        assert_eq!(queries.load(Ordering::Relaxed), 2);
+
+        assert_eq!(
+            resolver.resolve("2001:db8::1", &log_id).await.unwrap(),
+            vec!["2001:db8::1".parse::<IpAddr>().unwrap()]
+        );
+    }
+}
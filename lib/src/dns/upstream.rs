@@ -0,0 +1,444 @@
+//! The transports to the upstream DNS servers
+
+use crate::dns::message;
+use bytes::Bytes;
+use rustls::{ClientConfig, ServerName};
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio_rustls::TlsConnector;
+
+/// The maximum size of a DNS message
+const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+/// The maximum number of idle TCP and TLS connections kept for reuse per upstream
+const MAX_IDLE_CONNECTIONS: usize = 4;
+const DOH_CONTENT_TYPE: &str = "application/dns-message";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Protocol {
+    /// Plain DNS over UDP with the fallback to TCP on truncated responses
+    Udp,
+    /// Plain DNS over TCP
+    Tcp,
+    /// [DNS-over-TLS](https://datatracker.ietf.org/doc/html/rfc7858)
+    Tls,
+    /// [DNS-over-HTTPS](https://datatracker.ietf.org/doc/html/rfc8484)
+    Https,
+}
+
+/// The parsed upstream server specification
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct UpstreamAddress {
+    pub protocol: Protocol,
+    /// An IP address, or a host name in case of the encrypted protocols
+    pub host: String,
+    pub port: u16,
+    /// The request path in case of [`Protocol::Https`]
+    pub path: String,
+}
+
+pub(crate) struct Upstream {
+    address: UpstreamAddress,
+    transport: Transport,
+}
+
+enum Transport {
+    Udp(SocketAddr),
+    Stream(StreamTransport),
+    Https(HttpsTransport),
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Exchanges the length-prefixed messages over TCP or TLS connections
+struct StreamTransport {
+    tls: Option<(TlsConnector, ServerName)>,
+    idle: Mutex<Vec<Box<dyn Stream>>>,
+}
+
+struct HttpsTransport {
+    connector: TlsConnector,
+    server_name: ServerName,
+    uri: http::Uri,
+    sender: tokio::sync::Mutex<Option<h2::client::SendRequest<Bytes>>>,
+}
+
+impl UpstreamAddress {
+    /// Parse the upstream specification in one of the forms:
+    /// * `8.8.8.8`, `udp://8.8.8.8:53`
+    /// * `tcp://8.8.8.8`
+    /// * `tls://dns.google`, `tls://8.8.8.8:853`
+    /// * `https://dns.google/dns-query`
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (protocol, rest) = match spec.split_once("://") {
+            None => (Protocol::Udp, spec),
+            Some(("udp", x)) => (Protocol::Udp, x),
+            Some(("tcp", x)) => (Protocol::Tcp, x),
+            Some(("tls", x)) => (Protocol::Tls, x),
+            Some(("https", x)) => (Protocol::Https, x),
+            Some((x, _)) => return Err(format!("Unsupported upstream protocol: {}", x)),
+        };
+
+        let (authority, path) = match (protocol, rest.find('/')) {
+            (Protocol::Https, Some(i)) => (&rest[..i], &rest[i..]),
+            (Protocol::Https, None) => (rest, "/dns-query"),
+            (_, None) => (rest, ""),
+            (_, Some(_)) => return Err(format!("Unexpected path in upstream: {}", spec)),
+        };
+
+        let default_port = match protocol {
+            Protocol::Udp | Protocol::Tcp => 53,
+            Protocol::Tls => 853,
+            Protocol::Https => 443,
+        };
+        let (host, port) = if let Ok(x) = authority.parse::<SocketAddr>() {
+            (x.ip().to_string(), x.port())
+        } else if let Ok(x) = authority
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+        {
+            (x.to_string(), default_port)
+        } else {
+            match authority.rsplit_once(':') {
+                None => (authority.to_string(), default_port),
+                Some((host, port)) => (
+                    host.to_string(),
+                    port.parse()
+                        .map_err(|_| format!("Invalid port in upstream: {}", spec))?,
+                ),
+            }
+        };
+
+        if host.is_empty() {
+            return Err(format!("Host is not set in upstream: {}", spec));
+        }
+        if matches!(protocol, Protocol::Udp | Protocol::Tcp) && host.parse::<IpAddr>().is_err() {
+            return Err(format!(
+                "Plain DNS upstream must be an IP address: {}",
+                spec
+            ));
+        }
+        if matches!(protocol, Protocol::Tls | Protocol::Https)
+            && ServerName::try_from(host.as_str()).is_err()
+        {
+            return Err(format!("Invalid server name in upstream: {}", spec));
+        }
+
+        Ok(Self {
+            protocol,
+            host,
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl Display for UpstreamAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let scheme = match self.protocol {
+            Protocol::Udp => "udp",
+            Protocol::Tcp => "tcp",
+            Protocol::Tls => "tls",
+            Protocol::Https => "https",
+        };
+        match self.host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => {
+                write!(f, "{}://[{}]:{}{}", scheme, self.host, self.port, self.path)
+            }
+            _ => write!(f, "{}://{}:{}{}", scheme, self.host, self.port, self.path),
+        }
+    }
+}
+
+impl Upstream {
+    /// Create an upstream.
+    /// The TLS configuration is required for the encrypted protocols.
+    pub fn new(
+        address: UpstreamAddress,
+        tls_config: Option<&Arc<ClientConfig>>,
+    ) -> io::Result<Self> {
+        let tls = || -> io::Result<(Arc<ClientConfig>, ServerName)> {
+            let config = tls_config
+                .cloned()
+                .ok_or_else(|| io::Error::new(ErrorKind::Other, "TLS is not configured"))?;
+            let server_name = ServerName::try_from(address.host.as_str())
+                .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+            Ok((config, server_name))
+        };
+
+        let transport = match address.protocol {
+            Protocol::Udp => Transport::Udp(socket_address(&address)?),
+            Protocol::Tcp => Transport::Stream(StreamTransport {
+                tls: None,
+                idle: Default::default(),
+            }),
+            Protocol::Tls => {
+                let (config, server_name) = tls()?;
+                Transport::Stream(StreamTransport {
+                    tls: Some((TlsConnector::from(config), server_name)),
+                    idle: Default::default(),
+                })
+            }
+            Protocol::Https => {
+                let (config, server_name) = tls()?;
+                let mut config = ClientConfig::clone(&config);
+                config.alpn_protocols = vec![b"h2".to_vec()];
+                Transport::Https(HttpsTransport {
+                    connector: TlsConnector::from(Arc::new(config)),
+                    server_name,
+                    uri: address
+                        .to_string()
+                        .parse()
+                        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("{}", e)))?,
+                    sender: Default::default(),
+                })
+            }
+        };
+
+        Ok(Self { address, transport })
+    }
+
+    /// Send the query and receive the response to it
+    pub async fn exchange(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        match &self.transport {
+            Transport::Udp(peer) => {
+                let response = exchange_udp(*peer, query).await?;
+                if message::parse(&response)?.is_truncated() {
+                    let mut stream = TcpStream::connect(peer).await?;
+                    exchange_stream(&mut stream, query).await
+                } else {
+                    Ok(response)
+                }
+            }
+            Transport::Stream(x) => x.exchange(&self.address, query).await,
+            Transport::Https(x) => x.exchange(&self.address, query).await,
+        }
+    }
+}
+
+impl Display for Upstream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.address.fmt(f)
+    }
+}
+
+impl StreamTransport {
+    async fn exchange(&self, address: &UpstreamAddress, query: &[u8]) -> io::Result<Vec<u8>> {
+        // An idle connection may have been closed by the server in the meantime,
+        // so a failure on it is retried on a new one
+        let reused = self.idle.lock().unwrap().pop();
+        if let Some(mut stream) = reused {
+            if let Ok(x) = exchange_stream(&mut stream, query).await {
+                self.release(stream);
+                return Ok(x);
+            }
+        }
+
+        let stream = connect_tcp(address).await?;
+        let mut stream: Box<dyn Stream> = match &self.tls {
+            None => Box::new(stream),
+            Some((connector, server_name)) => {
+                Box::new(connector.connect(server_name.clone(), stream).await?)
+            }
+        };
+        let response = exchange_stream(&mut stream, query).await?;
+        self.release(stream);
+        Ok(response)
+    }
+
+    fn release(&self, stream: Box<dyn Stream>) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(stream);
+        }
+    }
+}
+
+impl HttpsTransport {
+    async fn exchange(&self, address: &UpstreamAddress, query: &[u8]) -> io::Result<Vec<u8>> {
+        let reused = self.sender.lock().await.clone();
+        if let Some(sender) = reused {
+            match self.send(sender, query).await {
+                Ok(x) => return Ok(x),
+                Err(_) => *self.sender.lock().await = None,
+            }
+        }
+
+        let sender = {
+            let mut guard = self.sender.lock().await;
+            match guard.as_ref() {
+                Some(x) => x.clone(),
+                None => {
+                    let x = self.connect(address).await?;
+                    *guard = Some(x.clone());
+                    x
+                }
+            }
+        };
+        self.send(sender, query).await
+    }
+
+    async fn connect(
+        &self,
+        address: &UpstreamAddress,
+    ) -> io::Result<h2::client::SendRequest<Bytes>> {
+        let stream = connect_tcp(address).await?;
+        let stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await?;
+        let (sender, connection) = h2::client::handshake(stream).await.map_err(h2_to_io)?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("DNS-over-HTTPS connection failure: {}", e);
+            }
+        });
+        Ok(sender)
+    }
+
+    async fn send(
+        &self,
+        sender: h2::client::SendRequest<Bytes>,
+        query: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let mut sender = sender.ready().await.map_err(h2_to_io)?;
+        let request = http::Request::post(self.uri.clone())
+            .header(http::header::CONTENT_TYPE, DOH_CONTENT_TYPE)
+            .header(http::header::ACCEPT, DOH_CONTENT_TYPE)
+            .body(())
+            .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?;
+        let (response, mut stream) = sender.send_request(request, false).map_err(h2_to_io)?;
+        stream
+            .send_data(Bytes::copy_from_slice(query), true)
+            .map_err(h2_to_io)?;
+
+        let response = response.await.map_err(h2_to_io)?;
+        if response.status() != http::StatusCode::OK {
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                format!("Unexpected response status: {}", response.status()),
+            ));
+        }
+
+        let mut body = response.into_body();
+        let mut content = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(h2_to_io)?;
+            let _ = body.flow_control().release_capacity(chunk.len());
+            content.extend_from_slice(&chunk);
+            if content.len() > MAX_MESSAGE_SIZE {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Response is too long",
+                ));
+            }
+        }
+
+        Ok(content)
+    }
+}
+
+fn socket_address(address: &UpstreamAddress) -> io::Result<SocketAddr> {
+    address
+        .host
+        .parse::<IpAddr>()
+        .map(|x| SocketAddr::new(x, address.port))
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e.to_string()))
+}
+
+/// Connect to the upstream. The host name of an encrypted upstream is resolved with
+/// the system resolver.
+async fn connect_tcp(address: &UpstreamAddress) -> io::Result<TcpStream> {
+    match socket_address(address) {
+        Ok(x) => TcpStream::connect(x).await,
+        Err(_) => TcpStream::connect((address.host.as_str(), address.port)).await,
+    }
+}
+
+async fn exchange_udp(peer: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let bind_address = match peer {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(bind_address).await?;
+    socket.connect(peer).await?;
+    socket.send(query).await?;
+
+    let mut buffer = vec![0; MAX_MESSAGE_SIZE];
+    loop {
+        let n = socket.recv(&mut buffer).await?;
+        // Skip the stray datagrams
+        if n >= 2 && buffer[..2] == query[..2] {
+            buffer.truncate(n);
+            return Ok(buffer);
+        }
+    }
+}
+
+async fn exchange_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    query: &[u8],
+) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(2 + query.len());
+    buffer.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(query);
+    stream.write_all(&buffer).await?;
+    stream.flush().await?;
+
+    loop {
+        let length = stream.read_u16().await? as usize;
+        buffer.resize(length, 0);
+        stream.read_exact(&mut buffer).await?;
+        // Skip the responses to the timed out queries
+        if length >= 2 && buffer[..2] == query[..2] {
+            return Ok(buffer);
+        }
+    }
+}
+
+fn h2_to_io(error: h2::Error) -> io::Error {
+    if error.is_io() {
+        return error.into_io().unwrap();
+    }
+    io::Error::new(ErrorKind::Other, error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_upstreams() {
+        let parse = |x| UpstreamAddress::parse(x).map(|x| x.to_string());
+        assert_eq!(parse("8.8.8.8").unwrap(), "udp://8.8.8.8:53");
+        assert_eq!(parse("udp://8.8.8.8:5353").unwrap(), "udp://8.8.8.8:5353");
+        assert_eq!(
+            parse("tcp://[2001:db8::1]").unwrap(),
+            "tcp://[2001:db8::1]:53"
+        );
+        assert_eq!(parse("2001:db8::1").unwrap(), "udp://[2001:db8::1]:53");
+        assert_eq!(parse("tls://dns.google").unwrap(), "tls://dns.google:853");
+        assert_eq!(parse("tls://1.1.1.1:8853").unwrap(), "tls://1.1.1.1:8853");
+        assert_eq!(
+            parse("https://dns.google").unwrap(),
+            "https://dns.google:443/dns-query"
+        );
+        assert_eq!(
+            parse("https://dns.example:8443/resolve").unwrap(),
+            "https://dns.example:8443/resolve"
+        );
+
+        assert!(parse("dns.google").is_err());
+        assert!(parse("quic://dns.google").is_err());
+        assert!(parse("tls://dns.google:port").is_err());
+        assert!(parse("tcp://1.1.1.1/dns-query").is_err());
+        assert!(parse("https://").is_err());
+    }
+}
@@ -108,6 +108,9 @@ pub(crate) trait Downstream: Send {
     /// Shut down the downstream connection gracefully
     async fn graceful_shutdown(&mut self) -> io::Result<()>;
 
+    /// Close the downstream connection telling the client that it must authenticate again
+    async fn shutdown_unauthenticated(&mut self) -> io::Result<()>;
+
     /// Get the downstream protocol
     fn protocol(&self) -> Protocol;
 
@@ -252,6 +252,10 @@ where
         self.transport_stream.shutdown().await
     }
 
+    async fn shutdown_with_error(&mut self, _code: u32) -> io::Result<()> {
+        self.transport_stream.shutdown().await
+    }
+
     fn protocol(&self) -> Protocol {
         Protocol::Http1
     }
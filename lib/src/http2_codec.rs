@@ -187,6 +187,31 @@ impl<IO: AsyncRead + AsyncWrite + Send + Unpin> HttpCodec for Http2Codec<IO> {
         }
     }
 
+    async fn shutdown_with_error(&mut self, code: u32) -> io::Result<()> {
+        let session = match &mut self.state {
+            State::Handshake(_) => return Ok(()),
+            State::Established(s) => s,
+        };
+
+        log_id!(
+            trace,
+            self.parent_id_chain,
+            "H2 shutting down with error: {}",
+            code
+        );
+        session.abrupt_shutdown(Reason::from(code));
+
+        loop {
+            match session.accept().await {
+                None => break Ok(()),
+                Some(Err(e)) if e.is_io() => break Err(e.into_io().unwrap()),
+                // The GOAWAY frame carrying the error is sent
+                Some(Err(_)) => break Ok(()),
+                Some(Ok(_)) => continue,
+            }
+        }
+    }
+
     fn protocol(&self) -> Protocol {
         Protocol::Http2
     }
@@ -380,7 +405,23 @@ impl http_codec::DroppingSink for RespondStream {
     }
 }
 
-fn h2_to_io_error(e: h2::Error) -> io::Error {
+/// Wrap the halves of a stream opened by the endpoint itself on an HTTP/2 session
+/// to another endpoint
+pub(crate) fn make_client_stream(
+    rx: RecvStream,
+    tx: SendStream<Bytes>,
+    id: log_utils::IdChain<u64>,
+) -> (
+    Box<dyn pipe::Source>,
+    Box<dyn http_codec::RespondedStreamSink>,
+) {
+    (
+        Box::new(RequestStream { rx, id: id.clone() }),
+        Box::new(RespondStream { tx, id }),
+    )
+}
+
+pub(crate) fn h2_to_io_error(e: h2::Error) -> io::Error {
     let reason = e.reason();
     if reason.as_ref().is_none_or(|r| *r == Reason::NO_ERROR) {
         return io::Error::from(ErrorKind::UnexpectedEof);
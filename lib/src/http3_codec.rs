@@ -307,6 +307,10 @@ impl HttpCodec for Http3Codec {
         self.socket.graceful_shutdown()
     }
 
+    async fn shutdown_with_error(&mut self, code: u32) -> io::Result<()> {
+        self.socket.shutdown_with_error(code as u64)
+    }
+
     fn protocol(&self) -> Protocol {
         Protocol::Http3
     }
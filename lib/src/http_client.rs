@@ -0,0 +1,161 @@
+//! A minimal HTTP/1.1 client for the requests of the endpoint itself,
+//! like fetching the authentication keys
+
+use bytes::{Bytes, BytesMut};
+use hyper::body::HttpBody;
+use rustls::ClientConfig;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+/// Responses with longer bodies are rejected
+const MAX_RESPONSE_BODY_SIZE: usize = 1024 * 1024;
+
+/// Send the request over a new connection and read the whole response.
+/// The `https` requests are sent only if `tls_config` is set.
+pub(crate) async fn send(
+    request: http::Request<Bytes>,
+    tls_config: Option<&Arc<ClientConfig>>,
+    timeout: Duration,
+) -> io::Result<http::Response<Bytes>> {
+    tokio::time::timeout(timeout, send_inner(request, tls_config))
+        .await
+        .map_err(|_| io::Error::new(ErrorKind::TimedOut, "HTTP request timed out"))?
+}
+
+async fn send_inner(
+    request: http::Request<Bytes>,
+    tls_config: Option<&Arc<ClientConfig>>,
+) -> io::Result<http::Response<Bytes>> {
+    let (mut parts, body) = request.into_parts();
+    let uri = parts.uri.clone();
+    let authority = uri
+        .authority()
+        .ok_or_else(|| invalid_input("URI without host"))?;
+    let host = authority
+        .host()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let is_tls = match uri.scheme_str() {
+        Some("http") => false,
+        Some("https") => true,
+        _ => return Err(invalid_input("Unsupported URI scheme")),
+    };
+    let port = authority
+        .port_u16()
+        .unwrap_or(if is_tls { 443 } else { 80 });
+
+    parts.uri = uri
+        .path_and_query()
+        .map_or("/", |x| x.as_str())
+        .parse()
+        .map_err(|e| invalid_input(&format!("Invalid URI path: {}", e)))?;
+    if !parts.headers.contains_key(http::header::HOST) {
+        parts.headers.insert(
+            http::header::HOST,
+            http::HeaderValue::from_str(authority.as_str())
+                .map_err(|e| invalid_input(&e.to_string()))?,
+        );
+    }
+    let request = http::Request::from_parts(parts, hyper::Body::from(body));
+
+    let stream = TcpStream::connect((host, port)).await?;
+    if !is_tls {
+        return exchange(stream, request).await;
+    }
+
+    let tls_config = tls_config.ok_or_else(|| invalid_input("TLS is not configured"))?;
+    let server_name = rustls::ServerName::try_from(host)
+        .map_err(|e| invalid_input(&format!("Invalid server name: {}", e)))?;
+    let stream = tokio_rustls::TlsConnector::from(tls_config.clone())
+        .connect(server_name, stream)
+        .await?;
+    exchange(stream, request).await
+}
+
+async fn exchange<S>(
+    stream: S,
+    request: http::Request<hyper::Body>,
+) -> io::Result<http::Response<Bytes>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::handshake(stream)
+        .await
+        .map_err(hyper_to_io_error)?;
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+
+    let (parts, mut body) = sender
+        .send_request(request)
+        .await
+        .map_err(hyper_to_io_error)?
+        .into_parts();
+    let mut content = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(hyper_to_io_error)?;
+        if content.len() + chunk.len() > MAX_RESPONSE_BODY_SIZE {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Response body is too long",
+            ));
+        }
+        content.extend_from_slice(&chunk);
+    }
+
+    Ok(http::Response::from_parts(parts, content.freeze()))
+}
+
+fn hyper_to_io_error(e: hyper::Error) -> io::Error {
+    io::Error::new(ErrorKind::Other, e.to_string())
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    #[tokio::test]
+    async fn get_from_local_server() {
+        let server = hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(
+            hyper::service::make_service_fn(|_| async {
+                Ok::<_, Infallible>(hyper::service::service_fn(
+                    |request: http::Request<hyper::Body>| async move {
+                        let body = format!(
+                            "{} {}",
+                            request.uri(),
+                            request.headers()[http::header::HOST].to_str().unwrap()
+                        );
+                        Ok::<_, Infallible>(http::Response::new(hyper::Body::from(body)))
+                    },
+                ))
+            }),
+        );
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        let request = http::Request::get(format!("http://{}/keys?x=1", address))
+            .body(Bytes::new())
+            .unwrap();
+        let response = send(request, None, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(
+            response.body().as_ref(),
+            format!("/keys?x=1 {}", address).as_bytes()
+        );
+
+        let request = http::Request::get(format!("https://{}/", address))
+            .body(Bytes::new())
+            .unwrap();
+        assert!(send(request, None, Duration::from_secs(5)).await.is_err());
+    }
+}
@@ -8,6 +8,9 @@ use std::io;
 use std::io::ErrorKind;
 use std::net::IpAddr;
 
+/// The session error code telling the client to re-authenticate, see the protocol specification
+pub(crate) const AUTHENTICATION_REQUIRED_ERROR_CODE: u32 = 0x1F;
+
 pub(crate) type RequestHeaders = http::request::Parts;
 pub(crate) type ResponseHeaders = http::response::Parts;
 
@@ -162,6 +165,10 @@ pub(crate) trait HttpCodec: Send {
     /// Shut down the HTTP session gracefully
     async fn graceful_shutdown(&mut self) -> io::Result<()>;
 
+    /// Close the HTTP session with the error code, the active streams are terminated.
+    /// The protocols without session error codes are just shut down.
+    async fn shutdown_with_error(&mut self, code: u32) -> io::Result<()>;
+
     /// Get the codec protocol
     fn protocol(&self) -> Protocol;
 }
@@ -197,6 +204,10 @@ impl HttpCodec for SingleRequestCodec {
         Ok(())
     }
 
+    async fn shutdown_with_error(&mut self, _code: u32) -> io::Result<()> {
+        self.graceful_shutdown().await
+    }
+
     fn protocol(&self) -> Protocol {
         self.protocol
     }
@@ -0,0 +1,581 @@
+use crate::direct_forwarder::DirectForwarder;
+use crate::forwarder::{Forwarder, IcmpMultiplexer, UdpMultiplexer};
+use crate::metrics::OutboundTcpSocketCounter;
+use crate::net_utils::TcpDestination;
+use crate::settings::{HttpConnectForwarderSettings, HttpConnectProtocol};
+use crate::{authentication, core, forwarder, log_id, log_utils, net_utils, pipe, tunnel};
+use async_trait::async_trait;
+use base64::Engine;
+use bytes::Bytes;
+use futures::future;
+use hyper::client::conn::SendRequest;
+use hyper::upgrade::Upgraded;
+use once_cell::sync::OnceCell;
+use rustls::ClientConfig;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+
+/// The number of data chunks queued for sending through a tunnel
+const WRITE_QUEUE_CAPACITY: usize = 16;
+
+/// The headers carrying the identity of the VPN client if
+/// [`HttpConnectForwarderSettings.forward_identity`] is enabled
+const DOMAIN_HEADER: &str = "X-Tunnel-Domain";
+const CLIENT_ADDRESS_HEADER: &str = "X-Tunnel-Client-Address";
+const USER_AGENT_HEADER: &str = "X-Tunnel-User-Agent";
+const AUTH_METHOD_HEADER: &str = "X-Tunnel-Auth-Method";
+const USERNAME_HEADER: &str = "X-Tunnel-Username";
+
+type SharedConnection = Arc<Mutex<Option<SendRequest<hyper::Body>>>>;
+
+pub(crate) struct HttpConnectForwarder {
+    context: Arc<core::Context>,
+    /// The snapshot of the settings taken on the forwarder creation
+    settings: Arc<HttpConnectForwarderSettings>,
+    /// The CONNECT method carries TCP only, so UDP and ICMP are routed directly
+    /// if [`HttpConnectForwarderSettings.direct_datagrams`] is enabled
+    direct: DirectForwarder,
+    /// The HTTP/2 connection to the proxy shared by the connections of the session
+    h2_connection: SharedConnection,
+}
+
+struct TcpConnector {
+    context: Arc<core::Context>,
+    settings: Arc<HttpConnectForwarderSettings>,
+    h2_connection: SharedConnection,
+}
+
+struct StreamRx {
+    rx: ReadHalf<Upgraded>,
+    id: log_utils::IdChain<u64>,
+    _metrics_guard: OutboundTcpSocketCounter,
+}
+
+/// The upgraded connection is not writable synchronously, so the data is queued
+/// and sent by a separate task
+struct StreamTx {
+    queue: Option<mpsc::Sender<Bytes>>,
+    writer: Option<JoinHandle<io::Result<()>>>,
+    id: log_utils::IdChain<u64>,
+}
+
+impl HttpConnectForwarder {
+    pub fn new(context: Arc<core::Context>, settings: Arc<HttpConnectForwarderSettings>) -> Self {
+        Self {
+            direct: DirectForwarder::new(context.clone(), &Default::default()),
+            context,
+            settings,
+            h2_connection: Default::default(),
+        }
+    }
+}
+
+impl Forwarder for HttpConnectForwarder {
+    fn tcp_connector(&self) -> Box<dyn forwarder::TcpConnector> {
+        Box::new(TcpConnector {
+            context: self.context.clone(),
+            settings: self.settings.clone(),
+            h2_connection: self.h2_connection.clone(),
+        })
+    }
+
+    fn datagram_mux_authenticator(&self) -> Box<dyn forwarder::DatagramMultiplexerAuthenticator> {
+        self.direct.datagram_mux_authenticator()
+    }
+
+    fn make_udp_datagram_multiplexer(
+        &self,
+        id: log_utils::IdChain<u64>,
+        meta: forwarder::UdpMultiplexerMeta,
+    ) -> io::Result<UdpMultiplexer> {
+        if !self.settings.direct_datagrams {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "UDP is not forwarded through HTTP CONNECT proxy",
+            ));
+        }
+        self.direct.make_udp_datagram_multiplexer(id, meta)
+    }
+
+    fn make_icmp_datagram_multiplexer(
+        &self,
+        id: log_utils::IdChain<u64>,
+    ) -> io::Result<Option<IcmpMultiplexer>> {
+        if !self.settings.direct_datagrams {
+            return Ok(None);
+        }
+        self.direct.make_icmp_datagram_multiplexer(id)
+    }
+}
+
+#[async_trait]
+impl forwarder::TcpConnector for TcpConnector {
+    async fn connect(
+        self: Box<Self>,
+        id: log_utils::IdChain<u64>,
+        meta: forwarder::TcpConnectionMeta,
+    ) -> Result<(Box<dyn pipe::Source>, Box<dyn pipe::Sink>), tunnel::ConnectionError> {
+        let settings = self.settings.as_ref();
+        let request = make_request(settings, &meta).map_err(tunnel::ConnectionError::Other)?;
+
+        log_id!(
+            trace,
+            id,
+            "Sending CONNECT request to proxy: {}",
+            settings.address
+        );
+        // The HTTP/1.1 connection must stay alive until the upgrade is completed
+        let mut h1_connection = None;
+        let response = match settings.protocol {
+            HttpConnectProtocol::Http1 => h1_connection
+                .insert(handshake(settings).await?)
+                .send_request(request),
+            HttpConnectProtocol::Http2 => {
+                let mut connection = self.h2_connection.lock().await;
+                let is_ready = match connection.as_mut() {
+                    Some(x) => future::poll_fn(|cx| x.poll_ready(cx)).await.is_ok(),
+                    None => false,
+                };
+                if !is_ready {
+                    log_id!(trace, id, "Establishing HTTP/2 connection to proxy");
+                    *connection = Some(handshake(settings).await?);
+                }
+                connection.as_mut().unwrap().send_request(request)
+            }
+        }
+        .await
+        .map_err(hyper_to_connection_error)?;
+
+        match response.status() {
+            x if x.is_success() => (),
+            http::StatusCode::PROXY_AUTHENTICATION_REQUIRED => {
+                return Err(tunnel::ConnectionError::Authentication(
+                    "Proxy authentication required".into(),
+                ))
+            }
+            http::StatusCode::BAD_GATEWAY => return Err(tunnel::ConnectionError::HostUnreachable),
+            http::StatusCode::GATEWAY_TIMEOUT => return Err(tunnel::ConnectionError::Timeout),
+            x => {
+                return Err(tunnel::ConnectionError::Other(format!(
+                    "Proxy responded with {}",
+                    x
+                )))
+            }
+        }
+
+        let stream = hyper::upgrade::on(response)
+            .await
+            .map_err(hyper_to_connection_error)?;
+        let metrics_guard = self.context.metrics.clone().outbound_tcp_socket_counter();
+        Ok(pipe_from_stream(stream, id, metrics_guard))
+    }
+}
+
+#[async_trait]
+impl pipe::Source for StreamRx {
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.id.clone()
+    }
+
+    async fn read(&mut self) -> io::Result<pipe::Data> {
+        const READ_CHUNK_SIZE: usize = 64 * 1024;
+        let mut buffer = Vec::with_capacity(READ_CHUNK_SIZE);
+
+        match self.rx.read_buf(&mut buffer).await? {
+            0 => Ok(pipe::Data::Eof),
+            _ => Ok(pipe::Data::Chunk(Bytes::from(buffer))),
+        }
+    }
+
+    fn consume(&mut self, _size: usize) -> io::Result<()> {
+        // do nothing
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl pipe::Sink for StreamTx {
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.id.clone()
+    }
+
+    fn write(&mut self, data: Bytes) -> io::Result<Bytes> {
+        if data.is_empty() {
+            return Ok(data);
+        }
+
+        match self.queue()?.try_send(data) {
+            Ok(_) => Ok(Bytes::new()),
+            Err(mpsc::error::TrySendError::Full(data)) => Ok(data),
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(writer_closed()),
+        }
+    }
+
+    fn eof(&mut self) -> io::Result<()> {
+        // The writer shuts the connection down after sending the queued data
+        self.queue = None;
+        Ok(())
+    }
+
+    async fn wait_writable(&mut self) -> io::Result<()> {
+        self.queue()?
+            .reserve()
+            .await
+            .map(drop)
+            .map_err(|_| writer_closed())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        if self.queue.is_some() {
+            return self.wait_writable().await;
+        }
+
+        match self.writer.take() {
+            Some(x) => x
+                .await
+                .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl StreamTx {
+    fn queue(&self) -> io::Result<&mpsc::Sender<Bytes>> {
+        self.queue
+            .as_ref()
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Already shut down".to_string()))
+    }
+}
+
+fn pipe_from_stream(
+    stream: Upgraded,
+    id: log_utils::IdChain<u64>,
+    metrics_guard: OutboundTcpSocketCounter,
+) -> (Box<dyn pipe::Source>, Box<dyn pipe::Sink>) {
+    let (rx, tx) = tokio::io::split(stream);
+    let (queue_tx, queue_rx) = mpsc::channel(WRITE_QUEUE_CAPACITY);
+    (
+        Box::new(StreamRx {
+            rx,
+            id: id.clone(),
+            _metrics_guard: metrics_guard,
+        }),
+        Box::new(StreamTx {
+            queue: Some(queue_tx),
+            writer: Some(tokio::spawn(write_queued(queue_rx, tx))),
+            id,
+        }),
+    )
+}
+
+async fn write_queued(
+    mut queue: mpsc::Receiver<Bytes>,
+    mut tx: WriteHalf<Upgraded>,
+) -> io::Result<()> {
+    while let Some(chunk) = queue.recv().await {
+        tx.write_all(&chunk).await?;
+    }
+    tx.shutdown().await
+}
+
+/// Open a new connection to the proxy
+async fn handshake(
+    settings: &HttpConnectForwarderSettings,
+) -> Result<SendRequest<hyper::Body>, tunnel::ConnectionError> {
+    let stream = TcpStream::connect(settings.address)
+        .await
+        .map_err(tunnel::ConnectionError::Io)?;
+    stream
+        .set_nodelay(true)
+        .map_err(tunnel::ConnectionError::Io)?;
+    if !settings.tls {
+        return start_connection(stream, settings.protocol).await;
+    }
+
+    let server_name = match &settings.tls_server_name {
+        Some(x) => rustls::ServerName::try_from(x.as_str())
+            .map_err(|e| tunnel::ConnectionError::Other(format!("Invalid server name: {}", e)))?,
+        None => rustls::ServerName::IpAddress(settings.address.ip()),
+    };
+    let stream = tokio_rustls::TlsConnector::from(
+        tls_config(settings.protocol).map_err(tunnel::ConnectionError::Io)?,
+    )
+    .connect(server_name, stream)
+    .await
+    .map_err(tunnel::ConnectionError::Io)?;
+    start_connection(stream, settings.protocol).await
+}
+
+async fn start_connection<S>(
+    stream: S,
+    protocol: HttpConnectProtocol,
+) -> Result<SendRequest<hyper::Body>, tunnel::ConnectionError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, connection) = hyper::client::conn::Builder::new()
+        .http2_only(protocol == HttpConnectProtocol::Http2)
+        .handshake(stream)
+        .await
+        .map_err(hyper_to_connection_error)?;
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+
+    Ok(sender)
+}
+
+/// The system CAs are loaded once for all the forwarders
+fn tls_config(protocol: HttpConnectProtocol) -> io::Result<Arc<ClientConfig>> {
+    static HTTP1: OnceCell<Arc<ClientConfig>> = OnceCell::new();
+    static HTTP2: OnceCell<Arc<ClientConfig>> = OnceCell::new();
+
+    let (cell, alpn) = match protocol {
+        HttpConnectProtocol::Http1 => (&HTTP1, b"http/1.1".as_slice()),
+        HttpConnectProtocol::Http2 => (&HTTP2, b"h2".as_slice()),
+    };
+    cell.get_or_try_init(|| {
+        let mut config = ClientConfig::clone(net_utils::make_client_tls_config()?.as_ref());
+        config.alpn_protocols = vec![alpn.to_vec()];
+        Ok(Arc::new(config))
+    })
+    .cloned()
+}
+
+fn make_request(
+    settings: &HttpConnectForwarderSettings,
+    meta: &forwarder::TcpConnectionMeta,
+) -> Result<http::Request<hyper::Body>, String> {
+    let authority = match &meta.destination {
+        TcpDestination::Address(x) => x.to_string(),
+        TcpDestination::HostName((host, port)) => format!("{}:{}", host, port),
+    };
+
+    let mut request = http::Request::builder()
+        .method(http::Method::CONNECT)
+        .uri(authority.as_str());
+    if settings.protocol == HttpConnectProtocol::Http1 {
+        request = request.header(http::header::HOST, authority.as_str());
+    }
+
+    if let Some(username) = &settings.username {
+        let password = match &settings.password_env {
+            Some(x) => std::env::var(x).map_err(|e| format!("Couldn't read {}: {}", x, e))?,
+            None => String::new(),
+        };
+        let credentials =
+            base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, password));
+        request = request.header(
+            http::header::PROXY_AUTHORIZATION,
+            format!("Basic {}", credentials),
+        );
+    }
+
+    if settings.forward_identity {
+        for (name, value) in identity_headers(meta) {
+            // The values coming from the client may be not representable in a header
+            match http::HeaderValue::from_str(&value) {
+                Ok(x) => request = request.header(name, x),
+                Err(_) => continue,
+            }
+        }
+    }
+
+    request
+        .body(hyper::Body::empty())
+        .map_err(|e| e.to_string())
+}
+
+fn identity_headers(meta: &forwarder::TcpConnectionMeta) -> Vec<(&'static str, String)> {
+    let mut headers = vec![
+        (DOMAIN_HEADER, meta.tls_domain.clone()),
+        (CLIENT_ADDRESS_HEADER, meta.client_address.to_string()),
+    ];
+
+    if let Some(user_agent) = &meta.user_agent {
+        headers.push((USER_AGENT_HEADER, user_agent.clone()));
+    }
+
+    if let Some(auth) = &meta.auth {
+        let method = match auth {
+            authentication::Source::Sni(_) => "sni",
+            authentication::Source::ProxyBasic(_) => "basic",
+            authentication::Source::ClientCertificate(_) => "client_certificate",
+        };
+        headers.push((AUTH_METHOD_HEADER, method.to_string()));
+        if let Some(username) = auth.username() {
+            headers.push((USERNAME_HEADER, username));
+        }
+    }
+
+    headers
+}
+
+fn writer_closed() -> io::Error {
+    io::Error::new(ErrorKind::BrokenPipe, "Connection to proxy is closed")
+}
+
+fn hyper_to_connection_error(e: hyper::Error) -> tunnel::ConnectionError {
+    tunnel::ConnectionError::Io(io::Error::new(ErrorKind::Other, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    /// Run a proxy echoing the tunneled data back if the request carries
+    /// the expected credentials and the identity of the client
+    fn run_proxy(protocol: HttpConnectProtocol) -> SocketAddr {
+        let server = hyper::Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .http2_only(protocol == HttpConnectProtocol::Http2)
+            .serve(hyper::service::make_service_fn(|_| async {
+                Ok::<_, Infallible>(hyper::service::service_fn(
+                    |request: http::Request<hyper::Body>| async move {
+                        let header = |name| {
+                            request
+                                .headers()
+                                .get(name)
+                                .and_then(|x| x.to_str().ok())
+                                .unwrap_or_default()
+                        };
+                        let status = if request.method() != http::Method::CONNECT
+                            || request.uri() != "example.org:443"
+                        {
+                            http::StatusCode::BAD_REQUEST
+                        } else if header(http::header::PROXY_AUTHORIZATION.as_str())
+                            != "Basic ZW5kcG9pbnQ6"
+                        {
+                            http::StatusCode::PROXY_AUTHENTICATION_REQUIRED
+                        } else if header(USERNAME_HEADER) != "alice"
+                            || header(AUTH_METHOD_HEADER) != "basic"
+                            || header(CLIENT_ADDRESS_HEADER) != "203.0.113.1"
+                            || header(DOMAIN_HEADER) != "vpn.example.org"
+                        {
+                            http::StatusCode::FORBIDDEN
+                        } else {
+                            tokio::spawn(async move {
+                                let stream = hyper::upgrade::on(request).await.unwrap();
+                                let (mut rx, mut tx) = tokio::io::split(stream);
+                                tokio::io::copy(&mut rx, &mut tx).await.unwrap();
+                                tx.shutdown().await.unwrap();
+                            });
+                            http::StatusCode::OK
+                        };
+
+                        let mut response = http::Response::new(hyper::Body::empty());
+                        *response.status_mut() = status;
+                        Ok::<_, Infallible>(response)
+                    },
+                ))
+            }));
+        let address = server.local_addr();
+        tokio::spawn(server);
+        address
+    }
+
+    fn make_connector(
+        address: SocketAddr,
+        protocol: HttpConnectProtocol,
+        username: &str,
+    ) -> Box<dyn forwarder::TcpConnector> {
+        let proxy = HttpConnectForwarderSettings::builder()
+            .server_address(address)
+            .unwrap()
+            .protocol(protocol)
+            .basic_auth(username.into(), None)
+            .forward_identity(true)
+            .build()
+            .unwrap();
+        let context = Arc::new(core::Context::default());
+        HttpConnectForwarder::new(context, Arc::new(proxy)).tcp_connector()
+    }
+
+    fn make_meta() -> forwarder::TcpConnectionMeta {
+        forwarder::TcpConnectionMeta {
+            client_address: "203.0.113.1".parse().unwrap(),
+            destination: TcpDestination::HostName(("example.org".into(), 443)),
+            auth: Some(authentication::Source::ProxyBasic(
+                // alice:secret
+                "YWxpY2U6c2VjcmV0".into(),
+            )),
+            tls_domain: "vpn.example.org".into(),
+            user_agent: Some("test".into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn tunnel_through_proxy() {
+        for protocol in [HttpConnectProtocol::Http1, HttpConnectProtocol::Http2] {
+            let connector = make_connector(run_proxy(protocol), protocol, "endpoint");
+            let (mut source, mut sink) = match connector
+                .connect(log_utils::IdChain::empty(), make_meta())
+                .await
+            {
+                Ok(x) => x,
+                Err(e) => panic!("{:?}: {}", protocol, e),
+            };
+
+            sink.write_all(Bytes::from_static(b"ping")).await.unwrap();
+            match source.read().await.unwrap() {
+                pipe::Data::Chunk(x) => assert_eq!(x.as_ref(), b"ping"),
+                pipe::Data::Eof => panic!("{:?}: unexpected EOF", protocol),
+            }
+            sink.eof().unwrap();
+            sink.flush().await.unwrap();
+            assert!(matches!(source.read().await.unwrap(), pipe::Data::Eof));
+        }
+    }
+
+    #[tokio::test]
+    async fn proxy_rejects_credentials() {
+        let protocol = HttpConnectProtocol::Http1;
+        let connector = make_connector(run_proxy(protocol), protocol, "stranger");
+        match connector
+            .connect(log_utils::IdChain::empty(), make_meta())
+            .await
+        {
+            Ok(_) => panic!("Expected the request to be rejected"),
+            Err(e) => assert!(matches!(e, tunnel::ConnectionError::Authentication(_))),
+        }
+    }
+
+    #[tokio::test]
+    async fn datagrams_rejected_by_default() {
+        let make_forwarder = |direct_datagrams| {
+            let proxy = HttpConnectForwarderSettings::builder()
+                .server_address("127.0.0.1:3128")
+                .unwrap()
+                .direct_datagrams(direct_datagrams)
+                .build()
+                .unwrap();
+            HttpConnectForwarder::new(Arc::new(core::Context::default()), Arc::new(proxy))
+        };
+        let meta = forwarder::UdpMultiplexerMeta {
+            client_address: "203.0.113.1".parse().unwrap(),
+            auth: None,
+            tls_domain: "vpn.example.org".into(),
+            user_agent: None,
+        };
+
+        let forwarder = make_forwarder(false);
+        match forwarder.make_udp_datagram_multiplexer(log_utils::IdChain::empty(), meta.clone()) {
+            Ok(_) => panic!("Expected UDP to be rejected"),
+            Err(e) => assert_eq!(e.kind(), ErrorKind::Unsupported),
+        }
+        assert!(forwarder
+            .make_icmp_datagram_multiplexer(log_utils::IdChain::empty())
+            .unwrap()
+            .is_none());
+
+        assert!(make_forwarder(true)
+            .make_udp_datagram_multiplexer(log_utils::IdChain::empty(), meta)
+            .is_ok());
+    }
+}
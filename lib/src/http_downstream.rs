@@ -19,7 +19,7 @@ use std::net::IpAddr;
 use std::sync::Arc;
 
 const HEALTH_CHECK_AUTHORITY: &str = "_check";
-const UDP_AUTHORITY: &str = "_udp2";
+pub(crate) const UDP_AUTHORITY: &str = "_udp2";
 const ICMP_AUTHORITY: &str = "_icmp";
 
 const AUTHORIZATION_FAILURE_STATUS_CODE: StatusCode = StatusCode::PROXY_AUTHENTICATION_REQUIRED;
@@ -27,6 +27,8 @@ const AUTHORIZATION_FAILURE_EXTRA_HEADER: (&str, &str) =
     ("proxy-authenticate", "Basic realm=Authorization Required");
 
 const BAD_STATUS_CODE: StatusCode = StatusCode::BAD_GATEWAY;
+const LIMIT_EXCEEDED_STATUS_CODE: StatusCode = StatusCode::TOO_MANY_REQUESTS;
+const FORBIDDEN_STATUS_CODE: StatusCode = StatusCode::FORBIDDEN;
 const WARNING_HEADER_NAME: &str = "X-Warning";
 const DNS_WARNING_HEADER_NAME: &str = "X-Adguard-Vpn-Error";
 
@@ -47,12 +49,12 @@ struct DatagramMultiplexer {
     id: log_utils::IdChain<u64>,
 }
 
-struct DatagramEncoder<D> {
+pub(crate) struct DatagramEncoder<D> {
     encoder: Box<dyn http_datagram_codec::Encoder<Datagram = D>>,
     sink: Box<dyn http_codec::DroppingSink>,
 }
 
-struct DatagramDecoder<D> {
+pub(crate) struct DatagramDecoder<D> {
     source: Box<dyn pipe::Source>,
     decoder: Box<dyn http_datagram_codec::Decoder<Datagram = D>>,
     pending_bytes: LinkedList<Bytes>,
@@ -66,7 +68,7 @@ struct PendingRequest {
 impl HttpDownstream {
     pub fn new(context: Arc<core::Context>, codec: Box<dyn HttpCodec>, tls_domain: String) -> Self {
         Self {
-            request_demux: HttpDemux::new(context.settings.clone()),
+            request_demux: HttpDemux::new(context.settings()),
             context,
             codec,
             tls_domain,
@@ -123,7 +125,7 @@ impl Downstream for HttpDownstream {
                         http_ping_handler::listen(
                             context.shutdown.clone(),
                             Box::new(http_codec::stream_into_codec(stream, protocol)),
-                            context.settings.tls_handshake_timeout,
+                            context.settings().tls_handshake_timeout,
                             stream_id,
                         )
                         .await
@@ -135,7 +137,7 @@ impl Downstream for HttpDownstream {
                         http_speedtest_handler::listen(
                             context.shutdown.clone(),
                             Box::new(http_codec::stream_into_codec(stream, protocol)),
-                            context.settings.tls_handshake_timeout,
+                            context.settings().tls_handshake_timeout,
                             stream_id,
                         )
                         .await
@@ -164,6 +166,12 @@ impl Downstream for HttpDownstream {
         self.codec.graceful_shutdown().await
     }
 
+    async fn shutdown_unauthenticated(&mut self) -> io::Result<()> {
+        self.codec
+            .shutdown_with_error(http_codec::AUTHENTICATION_REQUIRED_ERROR_CODE)
+            .await
+    }
+
     fn protocol(&self) -> Protocol {
         self.codec.protocol()
     }
@@ -295,26 +303,24 @@ impl downstream::PendingRequest for DatagramMultiplexer {
         let (source, sink) = self.stream.split();
         match authority.as_str() {
             UDP_AUTHORITY => Ok(downstream::DatagramPipeHalves::Udp(
-                Box::new(DatagramDecoder {
-                    source: source.finalize(),
-                    decoder: Box::new(http_udp_codec::Decoder::new(self.id.clone())),
-                    pending_bytes: Default::default(),
-                }),
-                Box::new(DatagramEncoder {
-                    sink: sink.send_ok_response(false)?.into_datagram_sink(),
-                    encoder: Box::<http_udp_codec::Encoder>::default(),
-                }),
+                Box::new(DatagramDecoder::new(
+                    source.finalize(),
+                    Box::new(http_udp_codec::Decoder::new(self.id.clone())),
+                )),
+                Box::new(DatagramEncoder::new(
+                    Box::<http_udp_codec::Encoder>::default(),
+                    sink.send_ok_response(false)?.into_datagram_sink(),
+                )),
             )),
             ICMP_AUTHORITY => Ok(downstream::DatagramPipeHalves::Icmp(
-                Box::new(DatagramDecoder {
-                    source: source.finalize(),
-                    decoder: Box::new(http_icmp_codec::Decoder::new()),
-                    pending_bytes: Default::default(),
-                }),
-                Box::new(DatagramEncoder {
-                    sink: sink.send_ok_response(false)?.into_datagram_sink(),
-                    encoder: Box::<http_icmp_codec::Encoder>::default(),
-                }),
+                Box::new(DatagramDecoder::new(
+                    source.finalize(),
+                    Box::new(http_icmp_codec::Decoder::new()),
+                )),
+                Box::new(DatagramEncoder::new(
+                    Box::<http_icmp_codec::Encoder>::default(),
+                    sink.send_ok_response(false)?.into_datagram_sink(),
+                )),
             )),
             _ => unreachable!(),
         }
@@ -335,6 +341,28 @@ impl downstream::PendingDatagramMultiplexerRequest for DatagramMultiplexer {
     }
 }
 
+impl<D> DatagramDecoder<D> {
+    pub fn new(
+        source: Box<dyn pipe::Source>,
+        decoder: Box<dyn http_datagram_codec::Decoder<Datagram = D>>,
+    ) -> Self {
+        Self {
+            source,
+            decoder,
+            pending_bytes: Default::default(),
+        }
+    }
+}
+
+impl<D> DatagramEncoder<D> {
+    pub fn new(
+        encoder: Box<dyn http_datagram_codec::Encoder<Datagram = D>>,
+        sink: Box<dyn http_codec::DroppingSink>,
+    ) -> Self {
+        Self { encoder, sink }
+    }
+}
+
 impl<D> downstream::StreamId for DatagramDecoder<D> {
     fn id(&self) -> log_utils::IdChain<u64> {
         self.source.id()
@@ -394,10 +422,47 @@ impl<D: Send> datagram_pipe::Sink for DatagramEncoder<D> {
 fn tunnel_error_to_status_code(error: &tunnel::ConnectionError) -> StatusCode {
     match error {
         tunnel::ConnectionError::Authentication(_) => AUTHORIZATION_FAILURE_STATUS_CODE,
+        // Not an authentication failure, the client must not be asked to re-authenticate
+        tunnel::ConnectionError::QuotaExceeded | tunnel::ConnectionError::LimitExceeded(_) => {
+            LIMIT_EXCEEDED_STATUS_CODE
+        }
+        tunnel::ConnectionError::EgressDenied | tunnel::ConnectionError::RuleDenied => {
+            FORBIDDEN_STATUS_CODE
+        }
         _ => BAD_STATUS_CODE,
     }
 }
 
+/// The reverse of [`tunnel_error_to_status_code`] and [`tunnel_error_to_warn_header`]
+/// for the responses of another endpoint
+pub(crate) fn response_to_tunnel_error(
+    response: &http_codec::ResponseHeaders,
+) -> tunnel::ConnectionError {
+    let warning = response
+        .headers
+        .get(WARNING_HEADER_NAME)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default();
+    let code = warning.split(' ').next().unwrap_or_default();
+
+    match (response.status, code) {
+        (LIMIT_EXCEEDED_STATUS_CODE, "320") => tunnel::ConnectionError::QuotaExceeded,
+        (LIMIT_EXCEEDED_STATUS_CODE, _) => tunnel::ConnectionError::LimitExceeded(warning.into()),
+        (FORBIDDEN_STATUS_CODE, "330") => tunnel::ConnectionError::EgressDenied,
+        (FORBIDDEN_STATUS_CODE, _) => tunnel::ConnectionError::RuleDenied,
+        (BAD_STATUS_CODE, "301") => tunnel::ConnectionError::HostUnreachable,
+        (BAD_STATUS_CODE, "302") => tunnel::ConnectionError::Timeout,
+        (BAD_STATUS_CODE, "310") => tunnel::ConnectionError::DnsNonroutable,
+        (BAD_STATUS_CODE, "311") => tunnel::ConnectionError::DnsLoopback,
+        // A rejection of the credentials of this endpoint is not the client's fault,
+        // so it must not be asked to re-authenticate
+        (status, _) => tunnel::ConnectionError::Other(format!(
+            "Endpoint responded with {} {}",
+            status, warning
+        )),
+    }
+}
+
 fn tunnel_error_to_warn_header(
     error: &tunnel::ConnectionError,
     hostname: &str,
@@ -425,6 +490,18 @@ fn tunnel_error_to_warn_header(
             (DNS_WARNING_HEADER_NAME.to_string(), hostname.to_string()),
             (WARNING_HEADER_NAME.to_string(), format!("311 - {}", error)),
         ],
+        tunnel::ConnectionError::QuotaExceeded => {
+            vec![(WARNING_HEADER_NAME.to_string(), format!("320 - {}", error))]
+        }
+        tunnel::ConnectionError::LimitExceeded(_) => {
+            vec![(WARNING_HEADER_NAME.to_string(), format!("321 - {}", error))]
+        }
+        tunnel::ConnectionError::EgressDenied => {
+            vec![(WARNING_HEADER_NAME.to_string(), format!("330 - {}", error))]
+        }
+        tunnel::ConnectionError::RuleDenied => {
+            vec![(WARNING_HEADER_NAME.to_string(), format!("331 - {}", error))]
+        }
         tunnel::ConnectionError::Other(_) => vec![(
             WARNING_HEADER_NAME.to_string(),
             "300 - Connection failed for some reason".to_string(),
@@ -432,6 +509,26 @@ fn tunnel_error_to_warn_header(
     }
 }
 
+/// Reject the first request of a session which is not allowed to be established
+/// and close the session
+pub(crate) async fn reject_session(
+    mut codec: Box<dyn HttpCodec>,
+    timeout: std::time::Duration,
+    error: tunnel::ConnectionError,
+    log_id: &log_utils::IdChain<u64>,
+) {
+    match tokio::time::timeout(timeout, codec.listen()).await {
+        Ok(Ok(Some(stream))) => fail_request_with_error(stream, error),
+        Ok(Ok(None)) => return,
+        Ok(Err(e)) => log_id!(debug, log_id, "Failed to receive request: {}", e),
+        Err(_) => log_id!(debug, log_id, "Request receive timeout"),
+    }
+
+    if let Err(e) = codec.graceful_shutdown().await {
+        log_id!(debug, log_id, "Failed to shut down session: {}", e);
+    }
+}
+
 fn fail_request(
     stream: Box<dyn http_codec::Stream>,
     status: StatusCode,
@@ -454,3 +551,36 @@ fn request_hostname(request: &dyn http_codec::PendingRequest) -> &str {
         .map(http::uri::Authority::as_str)
         .unwrap_or_default()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tunnel_error_round_trip() {
+        for error in [
+            tunnel::ConnectionError::QuotaExceeded,
+            tunnel::ConnectionError::LimitExceeded("Too many sessions".into()),
+            tunnel::ConnectionError::EgressDenied,
+            tunnel::ConnectionError::RuleDenied,
+            tunnel::ConnectionError::HostUnreachable,
+            tunnel::ConnectionError::Timeout,
+        ] {
+            let mut response =
+                http::Response::builder().status(tunnel_error_to_status_code(&error));
+            for (name, value) in tunnel_error_to_warn_header(&error, "example.org") {
+                response = response.header(name, value);
+            }
+            let (response, _) = response.body(()).unwrap().into_parts();
+
+            let decoded = response_to_tunnel_error(&response);
+            assert_eq!(
+                std::mem::discriminant(&decoded),
+                std::mem::discriminant(&error),
+                "{} {}",
+                error,
+                decoded
+            );
+        }
+    }
+}
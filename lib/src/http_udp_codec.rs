@@ -11,6 +11,10 @@
 //! |  Length  | Source address | Source port | Destination address | Destination port | App name len (L) | App name | Payload |
 //! | 4 bytes  |  16 bytes      | 2 bytes     |  16 bytes           | 2 bytes          | 1 byte           | L bytes  | N bytes |
 //! +----------+----------------+-------------+---------------------+------------------+------------------+----------+---------+
+//!
+//! If the endpoint acts as a client of another endpoint, the formats are swapped:
+//! [`ClientEncoder`] produces the incoming format and [`ClientDecoder`] consumes
+//! the outgoing one.
 
 use crate::{downstream, forwarder, http_datagram_codec, log_id, log_utils, net_utils};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
@@ -40,12 +44,21 @@ pub(crate) struct Decoder {
     source: Option<SocketAddr>,
     destination: Option<SocketAddr>,
     app_name: Option<String>,
+    /// Whether the packets carry the `App name` field
+    with_app_name: bool,
     id: log_utils::IdChain<u64>,
 }
 
 #[derive(Default)]
 pub(crate) struct Encoder {}
 
+/// Decodes the packets sent by another endpoint
+pub(crate) struct ClientDecoder(Decoder);
+
+/// Encodes the packets sent to another endpoint
+#[derive(Default)]
+pub(crate) struct ClientEncoder {}
+
 impl Decoder {
     pub fn new(id: log_utils::IdChain<u64>) -> Self {
         Self {
@@ -55,10 +68,19 @@ impl Decoder {
             source: None,
             destination: None,
             app_name: None,
+            with_app_name: true,
             id,
         }
     }
 
+    const fn fixed_header_size(&self) -> usize {
+        if self.with_app_name {
+            UDPPKT_IN_FIXED_HEADER_NO_LENGTH_SIZE
+        } else {
+            UDPPKT_OUT_FIXED_HEADER_NO_LENGTH_SIZE
+        }
+    }
+
     fn decode_chunk_once(&mut self, mut data: Bytes) -> (Option<downstream::UdpDatagram>, Bytes) {
         match self.state {
             RecvState::Length => (None, self.process_client_length(data)),
@@ -86,7 +108,7 @@ impl Decoder {
         };
 
         self.total_length = raw_length.get_u32() as usize;
-        if self.total_length >= UDPPKT_IN_FIXED_HEADER_NO_LENGTH_SIZE {
+        if self.total_length >= self.fixed_header_size() {
             self.state = RecvState::FixedHeader;
         } else {
             log_id!(
@@ -102,11 +124,11 @@ impl Decoder {
     }
 
     fn process_client_fixed_header(&mut self, data: Bytes) -> Bytes {
-        let (mut header, tail) =
-            match self.buffered_read(data, UDPPKT_IN_FIXED_HEADER_NO_LENGTH_SIZE) {
-                Some(x) => x,
-                None => return Bytes::new(),
-            };
+        let header_size = self.fixed_header_size();
+        let (mut header, tail) = match self.buffered_read(data, header_size) {
+            Some(x) => x,
+            None => return Bytes::new(),
+        };
 
         self.source = Some(SocketAddr::new(
             net_utils::get_fixed_size_ip(&mut header),
@@ -116,7 +138,11 @@ impl Decoder {
             net_utils::get_fixed_size_ip(&mut header),
             header.get_u16(),
         ));
-        let app_name_length = header.get_u8() as usize;
+        let app_name_length = match self.with_app_name {
+            true => header.get_u8() as usize,
+            false => 0,
+        };
+
         if self.total_length > MAX_UDP_IN_PAYLOAD_SIZE - app_name_length {
             log_id!(
                 debug,
@@ -124,9 +150,10 @@ impl Decoder {
                 "Too large UDP packet length ({}) - dropping it",
                 self.total_length
             );
-            self.state =
-                RecvState::Dropping(self.total_length - UDPPKT_IN_FIXED_HEADER_NO_LENGTH_SIZE);
-        } else if self.total_length >= UDPPKT_IN_FIXED_HEADER_NO_LENGTH_SIZE + app_name_length {
+            self.state = RecvState::Dropping(self.total_length - header_size);
+        } else if !self.with_app_name {
+            self.state = RecvState::Payload(self.total_length - header_size);
+        } else if self.total_length >= header_size + app_name_length {
             self.state = RecvState::AppName(app_name_length);
         } else {
             log_id!(
@@ -135,8 +162,7 @@ impl Decoder {
                 "UDP packet length ({}) is less than header size - dropping it",
                 self.total_length
             );
-            self.state =
-                RecvState::Dropping(self.total_length - UDPPKT_IN_FIXED_HEADER_NO_LENGTH_SIZE);
+            self.state = RecvState::Dropping(self.total_length - header_size);
         }
 
         tail
@@ -263,6 +289,63 @@ impl http_datagram_codec::Encoder for Encoder {
     }
 }
 
+impl ClientDecoder {
+    pub fn new(id: log_utils::IdChain<u64>) -> Self {
+        Self(Decoder {
+            with_app_name: false,
+            ..Decoder::new(id)
+        })
+    }
+}
+
+impl http_datagram_codec::Decoder for ClientDecoder {
+    type Datagram = forwarder::UdpDatagramReadStatus;
+
+    fn decode_chunk(&mut self, data: Bytes) -> http_datagram_codec::DecodeResult<Self::Datagram> {
+        match self.0.decode_chunk(data) {
+            http_datagram_codec::DecodeResult::WantMore => {
+                http_datagram_codec::DecodeResult::WantMore
+            }
+            http_datagram_codec::DecodeResult::Complete(datagram, tail) => {
+                http_datagram_codec::DecodeResult::Complete(
+                    forwarder::UdpDatagramReadStatus::Read(forwarder::UdpDatagram {
+                        meta: forwarder::UdpDatagramMeta::from(&datagram.meta),
+                        payload: datagram.payload,
+                    }),
+                    tail,
+                )
+            }
+        }
+    }
+}
+
+impl http_datagram_codec::Encoder for ClientEncoder {
+    type Datagram = downstream::UdpDatagram;
+
+    fn encode_packet(&self, datagram: &Self::Datagram) -> Option<Bytes> {
+        let app_name = datagram.meta.app_name.as_deref().unwrap_or_default();
+        if app_name.len() > u8::MAX as usize {
+            return None;
+        }
+
+        let total_length =
+            UDPPKT_IN_FIXED_HEADER_NO_LENGTH_SIZE + app_name.len() + datagram.payload.len();
+        let mut encoded = BytesMut::with_capacity(UDPPKT_LENGTH_SIZE + total_length);
+
+        encoded.put_u32(total_length as u32);
+        net_utils::put_fixed_size_ip(&mut encoded, &datagram.meta.source.ip());
+        encoded.put_u16(datagram.meta.source.port());
+        net_utils::put_fixed_size_ip(&mut encoded, &datagram.meta.destination.ip());
+        encoded.put_u16(datagram.meta.destination.port());
+        encoded.put_u8(app_name.len() as u8);
+        encoded.extend_from_slice(app_name.as_bytes());
+
+        encoded.extend_from_slice(&datagram.payload);
+
+        Some(encoded.freeze())
+    }
+}
+
 #[derive(Debug, Default)]
 enum RecvState {
     /// Waiting for the `Length` field
@@ -280,10 +363,11 @@ enum RecvState {
 
 #[cfg(test)]
 mod tests {
-    use crate::http_datagram_codec::{DecodeResult, Decoder};
+    use crate::http_datagram_codec::{DecodeResult, Decoder, Encoder};
     use crate::http_udp_codec;
     use crate::http_udp_codec::UDPPKT_IN_FIXED_HEADER_NO_LENGTH_SIZE;
     use crate::log_utils::IdChain;
+    use crate::{downstream, forwarder};
     use bytes::{BufMut, Bytes};
     use std::net::Ipv4Addr;
 
@@ -325,4 +409,56 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn client_round_trip() {
+        let source = (Ipv4Addr::LOCALHOST, 1234).into();
+        let destination = (Ipv4Addr::BROADCAST, 9876).into();
+
+        let encoded = http_udp_codec::ClientEncoder::default()
+            .encode_packet(&downstream::UdpDatagram {
+                meta: downstream::UdpDatagramMeta {
+                    source,
+                    destination,
+                    app_name: Some("test".into()),
+                },
+                payload: Bytes::from_static(b"request"),
+            })
+            .unwrap();
+        let mut decoder = http_udp_codec::Decoder::new(IdChain::empty());
+        match decoder.decode_chunk(encoded) {
+            DecodeResult::WantMore => unreachable!(),
+            DecodeResult::Complete(datagram, tail) => {
+                assert_eq!(datagram.meta.source, source);
+                assert_eq!(datagram.meta.destination, destination);
+                assert_eq!(datagram.meta.app_name.as_deref(), Some("test"));
+                assert_eq!(&datagram.payload, b"request".as_slice());
+                assert_eq!(tail.len(), 0);
+            }
+        }
+
+        let encoded = http_udp_codec::Encoder::default()
+            .encode_packet(&forwarder::UdpDatagram {
+                meta: forwarder::UdpDatagramMeta {
+                    source: destination,
+                    destination: source,
+                },
+                payload: Bytes::from_static(b"response"),
+            })
+            .unwrap();
+        let mut decoder = http_udp_codec::ClientDecoder::new(IdChain::empty());
+        // Feed the packet in parts to check the reassembly
+        let (head, tail) = (encoded.slice(..10), encoded.slice(10..));
+        assert!(matches!(decoder.decode_chunk(head), DecodeResult::WantMore));
+        match decoder.decode_chunk(tail) {
+            DecodeResult::WantMore => unreachable!(),
+            DecodeResult::Complete(forwarder::UdpDatagramReadStatus::Read(datagram), tail) => {
+                assert_eq!(datagram.meta.source, destination);
+                assert_eq!(datagram.meta.destination, source);
+                assert_eq!(&datagram.payload, b"response".as_slice());
+                assert_eq!(tail.len(), 0);
+            }
+            DecodeResult::Complete(..) => unreachable!(),
+        }
+    }
 }
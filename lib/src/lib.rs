@@ -14,14 +14,20 @@ pub mod settings;
 pub mod shutdown;
 pub mod utils;
 
+mod admin;
+mod bans;
+mod cascade_forwarder;
 mod datagram_pipe;
 mod direct_forwarder;
+mod dns;
 mod downstream;
 mod forwarder;
 mod http1_codec;
 mod http2_codec;
 mod http3_codec;
+mod http_client;
 mod http_codec;
+mod http_connect_forwarder;
 mod http_datagram_codec;
 mod http_demultiplexer;
 mod http_downstream;
@@ -35,9 +41,16 @@ mod icmp_utils;
 mod metrics;
 mod pipe;
 mod quic_multiplexer;
+mod quota;
+mod radius;
+mod rate_limit;
 mod reverse_proxy;
+mod routing_forwarder;
+mod sessions;
 mod socks5_client;
 mod socks5_forwarder;
+mod socks5_pool;
+mod source_address;
 mod tcp_forwarder;
 mod tls_demultiplexer;
 mod tls_listener;
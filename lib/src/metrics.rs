@@ -1,18 +1,47 @@
+use crate::authentication::AuthError;
+use crate::bans::BanInfo;
 use crate::http1_codec::Http1Codec;
 use crate::http_codec::HttpCodec;
+use crate::settings::{MetricsSettings, Settings};
 use crate::tls_demultiplexer::Protocol;
 use crate::{core, http_codec, log_id, log_utils};
 use bytes::Bytes;
+use once_cell::sync::Lazy;
 use prometheus::Encoder;
+use std::collections::HashSet;
 use std::io;
 use std::io::ErrorKind;
+use std::net::SocketAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tokio::net::{TcpListener, TcpStream};
 
 const LOG_FMT: &str = "METRICS={}";
 const HEALTH_CHECK_PATH: &str = "/health-check";
 const METRICS_PATH: &str = "/metrics";
+/// The label of the users exceeding the per-user metrics cardinality limit
+const OTHER_USERS_LABEL: &str = "other";
+
+/// Lives in the default registry as the authenticators are built without
+/// access to the [`Metrics`] instance
+static AUTH_FAILURES: Lazy<prometheus::IntCounterVec> = Lazy::new(|| {
+    prometheus::register_int_counter_vec!(
+        "auth_failures_total",
+        "Total number of rejected client authentication attempts",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+/// Lives in the default registry along with [`AUTH_FAILURES`]
+static AUTH_BANS: Lazy<prometheus::IntCounterVec> = Lazy::new(|| {
+    prometheus::register_int_counter_vec!(
+        "auth_bans_total",
+        "Total number of client IP addresses and usernames banned after failed authentication attempts",
+        &["kind"]
+    )
+    .unwrap()
+});
 
 pub(crate) struct Metrics {
     _registry: prometheus::Registry,
@@ -21,6 +50,56 @@ pub(crate) struct Metrics {
     outbound_traffic: prometheus::IntCounterVec,
     outbound_tcp_sockets: prometheus::IntGauge,
     outbound_udp_sockets: prometheus::IntGauge,
+    quota_remaining: prometheus::IntGaugeVec,
+    dns_queries: prometheus::IntCounterVec,
+    auth_blocked: prometheus::IntGaugeVec,
+    socks5_upstream_up: prometheus::IntGaugeVec,
+    socks5_upstream_connections: prometheus::IntGaugeVec,
+    socks5_upstream_failures: prometheus::IntCounterVec,
+    /// Present only if the per-user metrics are enabled
+    user_metrics: Option<UserMetrics>,
+}
+
+struct UserMetrics {
+    max_users: usize,
+    /// The users having their own label
+    known_users: Mutex<HashSet<String>>,
+    sessions: prometheus::IntGaugeVec,
+    inbound_traffic: prometheus::IntCounterVec,
+    outbound_traffic: prometheus::IntCounterVec,
+    tcp_connections: prometheus::IntGaugeVec,
+    udp_connections: prometheus::IntGaugeVec,
+    dns_queries: prometheus::IntCounterVec,
+}
+
+/// The outcome of an intercepted DNS query
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum DnsQueryResult {
+    /// Forwarded to the resolver and answered
+    Allowed,
+    /// Answered by the blocklist filter
+    Blocked,
+    /// The resolver failed to answer
+    Failed,
+}
+
+/// Traffic counters bound to a user
+#[derive(Clone)]
+pub(crate) struct UserTrafficCounter {
+    inbound: prometheus::IntCounter,
+    outbound: prometheus::IntCounter,
+}
+
+/// The metrics bound to an upstream proxy of a SOCKS5 forwarder
+pub(crate) struct Socks5UpstreamMetrics {
+    up: prometheus::IntGauge,
+    connections: prometheus::IntGauge,
+    failures: prometheus::IntCounter,
+}
+
+/// Decrements the bound per-user gauge on drop
+pub(crate) struct UserGaugeGuard {
+    gauge: prometheus::IntGauge,
 }
 
 pub(crate) struct ClientSessionsCounter {
@@ -37,8 +116,12 @@ pub(crate) struct OutboundUdpSocketCounter {
 }
 
 impl Metrics {
-    pub fn new() -> io::Result<Arc<Self>> {
+    pub fn new(settings: Option<&MetricsSettings>) -> io::Result<Arc<Self>> {
         let registry = prometheus::Registry::new();
+        let user_metrics = match settings {
+            Some(x) if x.per_user => Some(UserMetrics::new(x.max_users, &registry)?),
+            _ => None,
+        };
         Ok(Arc::new(Self {
             client_sessions: prometheus::register_int_gauge_vec_with_registry!(
                 "client_sessions",
@@ -73,6 +156,49 @@ impl Metrics {
                 registry,
             )
             .map_err(prometheus_to_io_error)?,
+            quota_remaining: prometheus::register_int_gauge_vec_with_registry!(
+                "user_quota_remaining_bytes",
+                "Number of bytes left in the data quota of a user",
+                &["username"],
+                registry,
+            )
+            .map_err(prometheus_to_io_error)?,
+            dns_queries: prometheus::register_int_counter_vec_with_registry!(
+                "dns_queries_total",
+                "Total number of intercepted DNS queries",
+                &["result"],
+                registry,
+            )
+            .map_err(prometheus_to_io_error)?,
+            auth_blocked: prometheus::register_int_gauge_vec_with_registry!(
+                "auth_blocked_clients",
+                "Number of client IP addresses and usernames blocked after failed authentication attempts",
+                &["kind", "state"],
+                registry,
+            )
+            .map_err(prometheus_to_io_error)?,
+            socks5_upstream_up: prometheus::register_int_gauge_vec_with_registry!(
+                "socks5_upstream_up",
+                "Whether an upstream SOCKS5 proxy is in the pool (1) or ejected (0)",
+                &["forwarder", "upstream"],
+                registry,
+            )
+            .map_err(prometheus_to_io_error)?,
+            socks5_upstream_connections: prometheus::register_int_gauge_vec_with_registry!(
+                "socks5_upstream_connections",
+                "Number of active connections through an upstream SOCKS5 proxy",
+                &["forwarder", "upstream"],
+                registry,
+            )
+            .map_err(prometheus_to_io_error)?,
+            socks5_upstream_failures: prometheus::register_int_counter_vec_with_registry!(
+                "socks5_upstream_failures_total",
+                "Total number of failed connection attempts and health probes of an upstream SOCKS5 proxy",
+                &["forwarder", "upstream"],
+                registry,
+            )
+            .map_err(prometheus_to_io_error)?,
+            user_metrics,
             _registry: registry,
         }))
     }
@@ -101,6 +227,108 @@ impl Metrics {
             .inc_by(n as u64);
     }
 
+    /// Replace the remaining data quota allowances of the users
+    pub fn set_quota_remaining(&self, remaining: Vec<(String, u64)>) {
+        self.quota_remaining.reset();
+        for (username, x) in remaining {
+            self.quota_remaining
+                .with_label_values(&[&username])
+                .set(x.try_into().unwrap_or(i64::MAX));
+        }
+    }
+
+    /// Replace the numbers of the blocked client IP addresses and usernames
+    pub fn set_auth_blocked(&self, blocked: &[BanInfo]) {
+        for kind in ["ip", "username"] {
+            for (state, banned) in [("backoff", false), ("banned", true)] {
+                let n = blocked
+                    .iter()
+                    .filter(|x| x.kind() == kind && x.banned == banned)
+                    .count();
+                self.auth_blocked
+                    .with_label_values(&[kind, state])
+                    .set(n as i64);
+            }
+        }
+    }
+
+    /// Count an intercepted DNS query, per user too if the per-user metrics are enabled
+    pub fn add_dns_query(&self, username: Option<&str>, result: DnsQueryResult) {
+        self.dns_queries.with_label_values(&[result.as_str()]).inc();
+        if let (Some(metrics), Some(username)) = (&self.user_metrics, username) {
+            metrics
+                .dns_queries
+                .with_label_values(&[metrics.label(username), result.as_str()])
+                .inc();
+        }
+    }
+
+    /// Returns [`None`] if the per-user metrics are disabled
+    pub fn user_traffic_counter(&self, username: &str) -> Option<UserTrafficCounter> {
+        let metrics = self.user_metrics.as_ref()?;
+        let label = metrics.label(username);
+        Some(UserTrafficCounter {
+            inbound: metrics.inbound_traffic.with_label_values(&[label]),
+            outbound: metrics.outbound_traffic.with_label_values(&[label]),
+        })
+    }
+
+    /// Returns [`None`] if the per-user metrics are disabled
+    pub fn user_sessions_counter(&self, username: &str) -> Option<UserGaugeGuard> {
+        let metrics = self.user_metrics.as_ref()?;
+        Some(UserGaugeGuard::new(
+            metrics
+                .sessions
+                .with_label_values(&[metrics.label(username)]),
+        ))
+    }
+
+    /// Returns [`None`] if the per-user metrics are disabled
+    pub fn user_tcp_connections_counter(&self, username: &str) -> Option<UserGaugeGuard> {
+        let metrics = self.user_metrics.as_ref()?;
+        Some(UserGaugeGuard::new(
+            metrics
+                .tcp_connections
+                .with_label_values(&[metrics.label(username)]),
+        ))
+    }
+
+    /// Returns [`None`] if the per-user metrics are disabled
+    pub fn user_udp_connections_counter(&self, username: &str) -> Option<UserGaugeGuard> {
+        let metrics = self.user_metrics.as_ref()?;
+        Some(UserGaugeGuard::new(
+            metrics
+                .udp_connections
+                .with_label_values(&[metrics.label(username)]),
+        ))
+    }
+
+    /// `forwarder` is the name of the forwarder in the settings, or `default`
+    pub fn socks5_upstream_metrics(
+        &self,
+        forwarder: &str,
+        upstream: &SocketAddr,
+    ) -> Socks5UpstreamMetrics {
+        let upstream = upstream.to_string();
+        let labels = [forwarder, upstream.as_str()];
+        Socks5UpstreamMetrics {
+            up: self.socks5_upstream_up.with_label_values(&labels),
+            connections: self.socks5_upstream_connections.with_label_values(&labels),
+            failures: self.socks5_upstream_failures.with_label_values(&labels),
+        }
+    }
+
+    /// Stop exporting the metrics of an upstream removed from the settings
+    pub fn remove_socks5_upstream_metrics(&self, forwarder: &str, upstream: &SocketAddr) {
+        let upstream = upstream.to_string();
+        let labels = [forwarder, upstream.as_str()];
+        let _ = self.socks5_upstream_up.remove_label_values(&labels);
+        let _ = self
+            .socks5_upstream_connections
+            .remove_label_values(&labels);
+        let _ = self.socks5_upstream_failures.remove_label_values(&labels);
+    }
+
     fn collect(&self) -> (String, Bytes) {
         let encoder = prometheus::TextEncoder::new();
 
@@ -113,6 +341,123 @@ impl Metrics {
     }
 }
 
+impl UserMetrics {
+    fn new(max_users: usize, registry: &prometheus::Registry) -> io::Result<Self> {
+        Ok(Self {
+            max_users,
+            known_users: Default::default(),
+            sessions: prometheus::register_int_gauge_vec_with_registry!(
+                "user_client_sessions",
+                "Number of active client sessions per user",
+                &["username"],
+                registry,
+            )
+            .map_err(prometheus_to_io_error)?,
+            inbound_traffic: prometheus::register_int_counter_vec_with_registry!(
+                "user_inbound_traffic_bytes",
+                "Total number of bytes uploaded per user",
+                &["username"],
+                registry,
+            )
+            .map_err(prometheus_to_io_error)?,
+            outbound_traffic: prometheus::register_int_counter_vec_with_registry!(
+                "user_outbound_traffic_bytes",
+                "Total number of bytes downloaded per user",
+                &["username"],
+                registry,
+            )
+            .map_err(prometheus_to_io_error)?,
+            tcp_connections: prometheus::register_int_gauge_vec_with_registry!(
+                "user_tcp_connections",
+                "Number of active TCP connections per user",
+                &["username"],
+                registry,
+            )
+            .map_err(prometheus_to_io_error)?,
+            udp_connections: prometheus::register_int_gauge_vec_with_registry!(
+                "user_udp_connections",
+                "Number of active UDP multiplexers per user",
+                &["username"],
+                registry,
+            )
+            .map_err(prometheus_to_io_error)?,
+            dns_queries: prometheus::register_int_counter_vec_with_registry!(
+                "user_dns_queries_total",
+                "Total number of intercepted DNS queries per user",
+                &["username", "result"],
+                registry,
+            )
+            .map_err(prometheus_to_io_error)?,
+        })
+    }
+
+    /// Get the label for the user.
+    /// The first `max_users` distinct users get their own labels, the rest share
+    /// the [`OTHER_USERS_LABEL`] one.
+    fn label<'a>(&self, username: &'a str) -> &'a str {
+        let mut known_users = self.known_users.lock().unwrap();
+        if known_users.contains(username) {
+            username
+        } else if known_users.len() < self.max_users {
+            known_users.insert(username.to_string());
+            username
+        } else {
+            OTHER_USERS_LABEL
+        }
+    }
+}
+
+impl DnsQueryResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DnsQueryResult::Allowed => "allowed",
+            DnsQueryResult::Blocked => "blocked",
+            DnsQueryResult::Failed => "failed",
+        }
+    }
+}
+
+impl UserTrafficCounter {
+    pub fn add_inbound_bytes(&self, n: usize) {
+        self.inbound.inc_by(n as u64);
+    }
+
+    pub fn add_outbound_bytes(&self, n: usize) {
+        self.outbound.inc_by(n as u64);
+    }
+}
+
+impl Socks5UpstreamMetrics {
+    pub fn set_up(&self, up: bool) {
+        self.up.set(up as i64);
+    }
+
+    pub fn inc_connections(&self) {
+        self.connections.inc();
+    }
+
+    pub fn dec_connections(&self) {
+        self.connections.dec();
+    }
+
+    pub fn add_failure(&self) {
+        self.failures.inc();
+    }
+}
+
+impl UserGaugeGuard {
+    fn new(gauge: prometheus::IntGauge) -> Self {
+        gauge.inc();
+        Self { gauge }
+    }
+}
+
+impl Drop for UserGaugeGuard {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
 impl ClientSessionsCounter {
     fn new(metrics: Arc<Metrics>, protocol: Protocol) -> Self {
         metrics
@@ -159,6 +504,16 @@ impl Drop for OutboundUdpSocketCounter {
     }
 }
 
+/// Count the rejected authentication attempt
+pub(crate) fn add_auth_failure(reason: AuthError) {
+    AUTH_FAILURES.with_label_values(&[reason.as_str()]).inc();
+}
+
+/// Count the ban of a client IP address (`ip`) or a username (`username`)
+pub(crate) fn add_auth_ban(kind: &str) {
+    AUTH_BANS.with_label_values(&[kind]).inc();
+}
+
 pub(crate) async fn listen(
     context: Arc<core::Context>,
     log_chain: log_utils::IdChain<u64>,
@@ -183,13 +538,15 @@ async fn listen_inner(
     context: Arc<core::Context>,
     log_chain: log_utils::IdChain<u64>,
 ) -> io::Result<()> {
-    let settings = context.settings.metrics.as_ref();
-    if settings.is_none() {
-        return Ok(());
-    }
+    // The metrics listener is not affected by settings reloading
+    let settings = context.settings();
+    let address = match settings.metrics.as_ref() {
+        None => return Ok(()),
+        Some(x) => x.address,
+    };
 
     let next_id = AtomicU64::default();
-    let listener = TcpListener::bind(settings.unwrap().address).await?;
+    let listener = TcpListener::bind(address).await?;
 
     loop {
         let (stream, peer) = listener.accept().await?;
@@ -199,17 +556,19 @@ async fn listen_inner(
         ));
         log_id!(trace, log_id, "New connection from {}", peer);
         let context = context.clone();
-        tokio::spawn(async move { handle_request(context, stream, log_id).await });
+        let settings = settings.clone();
+        tokio::spawn(async move { handle_request(context, settings, stream, log_id).await });
     }
 }
 
 async fn handle_request(
     context: Arc<core::Context>,
+    settings: Arc<Settings>,
     io: TcpStream,
     log_id: log_utils::IdChain<u64>,
 ) {
-    let mut codec = Http1Codec::new(context.settings.clone(), io, log_id.clone());
-    let timeout = context.settings.metrics.as_ref().unwrap().request_timeout;
+    let timeout = settings.metrics.as_ref().unwrap().request_timeout;
+    let mut codec = Http1Codec::new(settings, io, log_id.clone());
     let stream = match tokio::time::timeout(timeout, codec.listen()).await {
         Ok(Ok(Some(x))) => {
             log_id!(trace, log_id, "Got request: {:?}", x.request().request());
@@ -250,7 +609,13 @@ async fn handle_request(
         let path = stream.request().request().uri.path();
         let result = match path {
             HEALTH_CHECK_PATH => handle_health_check(stream),
-            METRICS_PATH => handle_metrics_collect(&context.metrics, stream).await,
+            METRICS_PATH => {
+                context
+                    .metrics
+                    .set_quota_remaining(context.quotas.remaining(&context.settings()));
+                context.metrics.set_auth_blocked(&context.bans.list());
+                handle_metrics_collect(&context.metrics, stream).await
+            }
             x => {
                 log_id!(debug, log_id, "Unexpected path: {}", x);
                 let respond = stream.split().1;
@@ -317,3 +682,70 @@ fn prometheus_to_io_error(e: prometheus::Error) -> io::Error {
         e => io::Error::new(ErrorKind::Other, e.to_string()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_user_metrics_disabled_by_default() {
+        let metrics = Metrics::new(Some(&MetricsSettings::default())).unwrap();
+        assert!(metrics.user_traffic_counter("a").is_none());
+        assert!(metrics.user_sessions_counter("a").is_none());
+    }
+
+    #[test]
+    fn excess_users_folded_into_other() {
+        let settings = MetricsSettings::builder()
+            .per_user(true)
+            .max_users(2)
+            .build()
+            .unwrap();
+        let metrics = Metrics::new(Some(&settings)).unwrap();
+
+        for user in ["a", "b", "c", "a", "d"] {
+            metrics
+                .user_traffic_counter(user)
+                .unwrap()
+                .add_inbound_bytes(1);
+        }
+        let _guard = metrics.user_sessions_counter("c").unwrap();
+
+        let user_metrics = metrics.user_metrics.as_ref().unwrap();
+        let inbound = |x| user_metrics.inbound_traffic.with_label_values(&[x]).get();
+        assert_eq!(inbound("a"), 2);
+        assert_eq!(inbound("b"), 1);
+        assert_eq!(inbound(OTHER_USERS_LABEL), 2);
+        assert_eq!(
+            user_metrics
+                .sessions
+                .with_label_values(&[OTHER_USERS_LABEL])
+                .get(),
+            1
+        );
+    }
+
+    #[test]
+    fn dns_queries_per_user() {
+        let settings = MetricsSettings::builder().per_user(true).build().unwrap();
+        let metrics = Metrics::new(Some(&settings)).unwrap();
+
+        metrics.add_dns_query(Some("a"), DnsQueryResult::Blocked);
+        metrics.add_dns_query(Some("a"), DnsQueryResult::Allowed);
+        metrics.add_dns_query(None, DnsQueryResult::Blocked);
+
+        let global = |x: DnsQueryResult| metrics.dns_queries.with_label_values(&[x.as_str()]).get();
+        assert_eq!(global(DnsQueryResult::Blocked), 2);
+        assert_eq!(global(DnsQueryResult::Allowed), 1);
+        assert_eq!(global(DnsQueryResult::Failed), 0);
+
+        let user_metrics = metrics.user_metrics.as_ref().unwrap();
+        assert_eq!(
+            user_metrics
+                .dns_queries
+                .with_label_values(&["a", "blocked"])
+                .get(),
+            1
+        );
+    }
+}
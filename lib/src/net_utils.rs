@@ -8,8 +8,10 @@ extern "C" {
 }
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use rustls::{Certificate, ClientConfig, RootCertStore};
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::sync::Arc;
 
 pub(crate) const MIN_LINK_MTU: usize = 1280;
 pub(crate) const MIN_IPV4_HEADER_SIZE: usize = 20;
@@ -78,6 +80,29 @@ where
     }
 }
 
+/// Make the TLS configuration for the outgoing connections of the endpoint itself,
+/// verifying the peers against the system CAs
+pub(crate) fn make_client_tls_config() -> io::Result<Arc<ClientConfig>> {
+    let mut root_store = RootCertStore::empty();
+    let native_certs = rustls_native_certs::load_native_certs().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("failed to load system CAs: {}", e),
+        )
+    })?;
+    for cert in native_certs {
+        // Skip the certificates unsupported by the TLS library
+        let _ = root_store.add(&Certificate(cert.0));
+    }
+
+    Ok(Arc::new(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_store)
+            .with_no_client_auth(),
+    ))
+}
+
 pub(crate) fn make_udp_socket(is_v4: bool) -> io::Result<UdpSocket> {
     if is_v4 {
         UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
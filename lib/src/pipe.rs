@@ -1,3 +1,4 @@
+use crate::rate_limit::RateLimiter;
 use crate::{log_id, log_utils};
 use async_trait::async_trait;
 use bytes::Bytes;
@@ -6,6 +7,7 @@ use futures::{future, FutureExt};
 use std::fmt::{Display, Formatter};
 use std::io;
 use std::io::ErrorKind;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::Instant;
 
@@ -89,6 +91,7 @@ pub(crate) struct SimplexPipe<F> {
     source: Box<dyn Source>,
     sink: Box<dyn Sink>,
     update_metrics: F,
+    rate_limiter: Option<Arc<RateLimiter>>,
     pending_chunk: Option<Data>,
     direction: SimplexDirection,
     last_activity: Instant,
@@ -133,6 +136,7 @@ impl<F: Fn(SimplexDirection, usize) + Send> SimplexPipe<F> {
             source,
             sink,
             update_metrics,
+            rate_limiter: None,
             pending_chunk: Default::default(),
             direction,
             last_activity: Instant::now(),
@@ -184,6 +188,9 @@ impl<F: Fn(SimplexDirection, usize) + Send> SimplexPipe<F> {
                         .map_err(|e| io_to_pipe_error(id, e))?;
                     let sent = data_len - unsent_data.len();
                     (self.update_metrics)(self.direction, sent);
+                    if let Some(x) = &self.rate_limiter {
+                        x.throttle(self.direction, sent).await;
+                    }
                     self.source
                         .consume(sent)
                         .map_err(|e| io_to_pipe_error(id, e))?;
@@ -229,6 +236,13 @@ impl<F: Fn(SimplexDirection, usize) + Send + Clone> DuplexPipe<F> {
         }
     }
 
+    /// Limit the transfer rate in both directions
+    pub fn with_rate_limiter(mut self, rate_limiter: Option<Arc<RateLimiter>>) -> Self {
+        self.left_pipe.rate_limiter.clone_from(&rate_limiter);
+        self.right_pipe.rate_limiter = rate_limiter;
+        self
+    }
+
     pub async fn exchange(&mut self, timeout: Duration) -> io::Result<()> {
         let id = self.left_pipe.source.id();
         loop {
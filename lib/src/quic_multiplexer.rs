@@ -1,9 +1,10 @@
+use crate::bans::Bans;
 use crate::http_codec::{RequestHeaders, ResponseHeaders};
 use crate::settings::Settings;
 use crate::tls_demultiplexer::TlsDemux;
 use crate::utils::Either;
 use crate::{log_id, log_utils, net_utils, tls_demultiplexer, utils};
-use boring::ssl::{NameType, SelectCertError, SslContextBuilder, SslMethod, SslRef};
+use boring::ssl::{NameType, SelectCertError, SslContextBuilder, SslMethod, SslRef, SslVerifyMode};
 use bytes::{Buf, Bytes, BytesMut};
 use http::header::InvalidHeaderName;
 use lazy_static::lazy_static;
@@ -39,6 +40,7 @@ pub(crate) struct QuicMultiplexer {
     deadlines: HashMap<quiche::ConnectionId<'static>, Instant>,
     closest_deadline: Option<Instant>,
     tls_demux: Arc<std::sync::RwLock<TlsDemux>>,
+    bans: Arc<Bans>,
     token_prefix: [u8; TOKEN_PREFIX_SIZE],
     id: log_utils::IdChain<u64>,
     next_socket_id: Arc<AtomicU64>,
@@ -126,6 +128,7 @@ impl QuicMultiplexer {
         core_settings: Arc<Settings>,
         socket: UdpSocket,
         tls_demux: Arc<std::sync::RwLock<TlsDemux>>,
+        bans: Arc<Bans>,
         next_socket_id: Arc<AtomicU64>,
     ) -> io::Result<Self> {
         let queue_cap = core_settings
@@ -145,6 +148,7 @@ impl QuicMultiplexer {
             deadlines: Default::default(),
             closest_deadline: None,
             tls_demux,
+            bans,
             token_prefix: ring::rand::generate(&ring::rand::SystemRandom::new())
                 .unwrap()
                 .expose(),
@@ -402,6 +406,16 @@ impl QuicMultiplexer {
             ));
         }
 
+        if self.bans.is_ip_blocked(peer.ip()) {
+            log_id!(
+                trace,
+                self.id,
+                "Dropping packet of blocked client: {}",
+                peer
+            );
+            return Ok(UnknownPacketStatus::Skip);
+        }
+
         if !quiche::version_is_supported(header.version) {
             log_id!(trace, self.id, "Doing version negotiation: {:?}", header);
             let mut out = [0; net_utils::MAX_UDP_PAYLOAD_SIZE];
@@ -526,6 +540,20 @@ impl QuicMultiplexer {
             );
         }
 
+        if let Some(client_auth) = conn.tls_connection_meta.client_auth.clone() {
+            let chain = {
+                let mut quic = quic_conn.lock().unwrap();
+                let ssl: &mut SslRef = quic.as_mut();
+                peer_certificate_chain(ssl)
+            };
+            match client_auth.verify(&chain) {
+                Ok(x) => conn.tls_connection_meta.client_cert_username = x,
+                Err(e) => {
+                    return Err((io::Error::new(ErrorKind::PermissionDenied, e), quic_conn));
+                }
+            }
+        }
+
         let h3_conn = {
             let mut quic = quic_conn.lock().unwrap();
             let h3_config = h3::Config::new().unwrap();
@@ -905,6 +933,16 @@ impl QuicSocket {
         self.flush_pending_data()
     }
 
+    /// Close the connection with the application error code
+    pub fn shutdown_with_error(&self, code: u64) -> io::Result<()> {
+        self.quic_conn
+            .lock()
+            .unwrap()
+            .close(true, code, b"")
+            .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?;
+        self.flush_pending_data()
+    }
+
     pub async fn listen(&self) -> io::Result<QuicSocketEvent> {
         loop {
             let event = loop {
@@ -1155,6 +1193,23 @@ fn quic_recv(
     }
 }
 
+/// Get the certificate chain of a client, the end-entity certificate goes first
+fn peer_certificate_chain(ssl: &SslRef) -> Vec<rustls::Certificate> {
+    let end_entity = match ssl.peer_certificate().and_then(|x| x.to_der().ok()) {
+        Some(x) => x,
+        None => return vec![],
+    };
+
+    let mut chain = vec![rustls::Certificate(end_entity)];
+    // On the server side the chain does not include the end-entity certificate
+    for cert in ssl.peer_cert_chain().into_iter().flatten() {
+        if let Ok(x) = cert.to_der() {
+            chain.push(rustls::Certificate(x));
+        }
+    }
+    chain
+}
+
 fn make_quic_config_with_domain_contexts(
     core_settings: &Settings,
     tls_demux: Arc<std::sync::RwLock<TlsDemux>>,
@@ -1196,6 +1251,17 @@ fn make_quic_config_with_domain_contexts(
         ssl.set_private_key(&meta.boring.key)
             .map_err(|_| SelectCertError::ERROR)?;
 
+        if let Some(client_auth) = &meta.client_auth {
+            let mode = if client_auth.is_required() {
+                SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT
+            } else {
+                SslVerifyMode::PEER
+            };
+            // The chain is verified against the CA bundle of the host
+            // once the handshake is complete
+            ssl.set_verify_callback(mode, |_, _| true);
+        }
+
         Ok(())
     });
 
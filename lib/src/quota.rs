@@ -0,0 +1,329 @@
+use crate::settings::{QuotaPeriod, Settings};
+use crate::{core, log_id, log_utils};
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The registry of the per-user data usage counters
+#[derive(Default)]
+pub(crate) struct Quotas {
+    /// Key is the username
+    users: Mutex<HashMap<String, Arc<QuotaUsage>>>,
+}
+
+/// The data usage of a user within the current accounting period
+pub(crate) struct QuotaUsage {
+    used_bytes: AtomicU64,
+    /// The start of the current accounting period (seconds since the Unix epoch)
+    period_start: AtomicI64,
+    /// The end of the current accounting period (seconds since the Unix epoch),
+    /// the counter is reset on reaching it
+    period_end: AtomicI64,
+    /// The accounting period of the user as of the latest request
+    period: Mutex<QuotaPeriod>,
+}
+
+/// The persisted state of a usage counter
+#[derive(Serialize, Deserialize)]
+struct UsageRecord {
+    period_start: i64,
+    used_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct QuotaState {
+    /// Key is the username
+    users: HashMap<String, UsageRecord>,
+}
+
+impl Quotas {
+    /// Load the counters saved by [`Self::save()`].
+    /// A missing file is treated as an empty state.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Default::default()),
+            Err(e) => return Err(e),
+        };
+
+        let state: QuotaState = serde_json::from_str(&content)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        Ok(Self {
+            users: Mutex::new(
+                state
+                    .users
+                    .into_iter()
+                    .map(|(username, x)| {
+                        (
+                            username,
+                            Arc::new(QuotaUsage::new(x.used_bytes, x.period_start)),
+                        )
+                    })
+                    .collect(),
+            ),
+        })
+    }
+
+    /// Write the counters to the file.
+    /// The file is replaced atomically, so a crash in the middle does not corrupt it.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let state = QuotaState {
+            users: self
+                .users
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(username, x)| {
+                    (
+                        username.clone(),
+                        UsageRecord {
+                            period_start: x.period_start.load(Ordering::Relaxed),
+                            used_bytes: x.used_bytes.load(Ordering::Relaxed),
+                        },
+                    )
+                })
+                .collect(),
+        };
+        let content = serde_json::to_string(&state)
+            .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?;
+
+        let path = path.as_ref();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        std::fs::write(&tmp_path, content)?;
+        std::fs::rename(&tmp_path, path)
+    }
+
+    /// Get the usage counter of the user.
+    /// The counter is reset if the accounting period is over.
+    pub fn usage(&self, username: &str, period: QuotaPeriod) -> Arc<QuotaUsage> {
+        let usage = self
+            .users
+            .lock()
+            .unwrap()
+            .entry(username.to_string())
+            .or_insert_with(|| Arc::new(QuotaUsage::new(0, 0)))
+            .clone();
+
+        let previous = std::mem::replace(&mut *usage.period.lock().unwrap(), period);
+        if previous != period {
+            // The period has been changed by the settings reload
+            usage.period_end.store(0, Ordering::Release);
+        }
+        usage.roll_over(Utc::now());
+        usage
+    }
+
+    /// Get the remaining allowances of the users having a quota
+    pub fn remaining(&self, settings: &Settings) -> Vec<(String, u64)> {
+        settings
+            .clients
+            .iter()
+            .filter_map(|x| {
+                let limit = x.quota_bytes?;
+                let usage = self.usage(&x.username, x.quota_period);
+                Some((x.username.clone(), limit.saturating_sub(usage.used_bytes())))
+            })
+            .collect()
+    }
+}
+
+impl QuotaUsage {
+    /// The period is unknown until the first [`Quotas::usage()`] call,
+    /// which is made before any data is counted
+    fn new(used_bytes: u64, period_start: i64) -> Self {
+        Self {
+            used_bytes: AtomicU64::new(used_bytes),
+            period_start: AtomicI64::new(period_start),
+            period_end: AtomicI64::new(0),
+            period: Mutex::new(QuotaPeriod::Never),
+        }
+    }
+
+    /// Add the transferred bytes to the counter.
+    /// The counter is reset first if the accounting period is over, so that the tunnels
+    /// outliving the period are accounted in the next one.
+    pub fn add(&self, n: usize) {
+        self.roll_over(Utc::now());
+        self.used_bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.used_bytes.load(Ordering::Acquire)
+    }
+
+    /// Reset the counter if `now` is out of the current accounting period
+    fn roll_over(&self, now: DateTime<Utc>) {
+        if now.timestamp() < self.period_end.load(Ordering::Acquire) {
+            return;
+        }
+
+        let (start, end) = period_bounds(*self.period.lock().unwrap(), now);
+        if self.period_start.swap(start, Ordering::AcqRel) != start {
+            self.used_bytes.store(0, Ordering::Release);
+        }
+        self.period_end.store(end, Ordering::Release);
+    }
+}
+
+/// Save the counters periodically and on shutdown, if the persistence is configured
+pub(crate) async fn run_saver(context: Arc<core::Context>, log_id: log_utils::IdChain<u64>) {
+    let (mut shutdown_notification, _shutdown_completion) = {
+        let shutdown = context.shutdown.lock().unwrap();
+        (shutdown.notification_handler(), shutdown.completion_guard())
+    };
+
+    // The saver is not affected by settings reloading
+    let settings = context.settings();
+    let quota_settings = match settings.quota.as_ref() {
+        None => return,
+        Some(x) => x,
+    };
+
+    let save = || {
+        if let Err(e) = context.quotas.save(&quota_settings.state_file) {
+            log_id!(
+                error,
+                log_id,
+                "Failed to save quota state to {}: {}",
+                quota_settings.state_file,
+                e
+            );
+        }
+    };
+
+    let periodic = async {
+        let mut interval = tokio::time::interval(quota_settings.save_interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            save();
+        }
+    };
+
+    tokio::select! {
+        x = shutdown_notification.wait() => {
+            if let Err(e) = x {
+                log_id!(debug, log_id, "Shutdown notification failure: {}", e);
+            }
+        }
+        _ = periodic => (),
+    }
+
+    save();
+}
+
+/// Get the start and the end of the accounting period containing `now`
+/// (seconds since the Unix epoch).
+/// The periods are calendar-based in UTC, a week starts on Monday.
+fn period_bounds(period: QuotaPeriod, now: DateTime<Utc>) -> (i64, i64) {
+    let today = now.date_naive();
+    let (start, end) = match period {
+        QuotaPeriod::Never => return (0, i64::MAX),
+        QuotaPeriod::Day => (today, today.checked_add_days(Days::new(1))),
+        QuotaPeriod::Week => {
+            let start = today
+                .checked_sub_days(Days::new(today.weekday().num_days_from_monday() as u64))
+                .unwrap_or(today);
+            (start, start.checked_add_days(Days::new(7)))
+        }
+        QuotaPeriod::Month => {
+            let start = NaiveDate::from_ymd_opt(today.year(), today.month(), 1).unwrap();
+            (start, start.checked_add_months(Months::new(1)))
+        }
+    };
+
+    let timestamp = |x: NaiveDate| x.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp();
+    (timestamp(start), end.map_or(i64::MAX, timestamp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn period_boundaries() {
+        // Wednesday
+        let now = Utc.with_ymd_and_hms(2024, 5, 15, 13, 45, 0).unwrap();
+        let ts = |y, m, d| Utc.with_ymd_and_hms(y, m, d, 0, 0, 0).unwrap().timestamp();
+
+        assert_eq!(period_bounds(QuotaPeriod::Never, now), (0, i64::MAX));
+        assert_eq!(
+            period_bounds(QuotaPeriod::Day, now),
+            (ts(2024, 5, 15), ts(2024, 5, 16))
+        );
+        assert_eq!(
+            period_bounds(QuotaPeriod::Week, now),
+            (ts(2024, 5, 13), ts(2024, 5, 20))
+        );
+        assert_eq!(
+            period_bounds(QuotaPeriod::Month, now),
+            (ts(2024, 5, 1), ts(2024, 6, 1))
+        );
+
+        let new_year_eve = Utc.with_ymd_and_hms(2024, 12, 31, 23, 59, 59).unwrap();
+        assert_eq!(
+            period_bounds(QuotaPeriod::Month, new_year_eve),
+            (ts(2024, 12, 1), ts(2025, 1, 1))
+        );
+    }
+
+    #[test]
+    fn usage_reset_on_new_period() {
+        let quotas = Quotas::default();
+        let usage = quotas.usage("a", QuotaPeriod::Month);
+        usage.add(100);
+        assert_eq!(quotas.usage("a", QuotaPeriod::Month).used_bytes(), 100);
+
+        usage.period_start.store(0, Ordering::Relaxed);
+        usage.period_end.store(0, Ordering::Relaxed);
+        assert_eq!(quotas.usage("a", QuotaPeriod::Month).used_bytes(), 0);
+    }
+
+    #[test]
+    fn usage_reset_while_counting() {
+        let quotas = Quotas::default();
+        let usage = quotas.usage("a", QuotaPeriod::Day);
+        usage.add(100);
+
+        // A long-lived tunnel keeps counting into the next period
+        usage.period_start.store(0, Ordering::Relaxed);
+        usage.period_end.store(0, Ordering::Relaxed);
+        usage.add(10);
+        assert_eq!(usage.used_bytes(), 10);
+        assert_eq!(quotas.usage("a", QuotaPeriod::Day).used_bytes(), 10);
+    }
+
+    #[test]
+    fn usage_reset_on_period_change() {
+        let quotas = Quotas::default();
+        quotas.usage("a", QuotaPeriod::Never).add(100);
+        assert_eq!(quotas.usage("a", QuotaPeriod::Never).used_bytes(), 100);
+        assert_eq!(quotas.usage("a", QuotaPeriod::Day).used_bytes(), 0);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("quota.json");
+        assert!(Quotas::load(&path)
+            .unwrap()
+            .users
+            .lock()
+            .unwrap()
+            .is_empty());
+
+        let quotas = Quotas::default();
+        quotas.usage("a", QuotaPeriod::Never).add(100);
+        quotas.save(&path).unwrap();
+
+        let quotas = Quotas::load(&path).unwrap();
+        assert_eq!(quotas.usage("a", QuotaPeriod::Never).used_bytes(), 100);
+    }
+}
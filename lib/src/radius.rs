@@ -0,0 +1,763 @@
+//! A minimal RADIUS client: PAP authentication
+//! ([RFC 2865](https://datatracker.ietf.org/doc/html/rfc2865)) and accounting
+//! ([RFC 2866](https://datatracker.ietf.org/doc/html/rfc2866))
+
+use crate::authentication::radius_auth::RadiusAuthConfig;
+use crate::sessions::Session;
+use crate::settings::RadiusSettings;
+use hmac::Mac;
+use md5::{Digest, Md5};
+use ring::rand::SecureRandom;
+use std::io;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+const ACCESS_REQUEST: u8 = 1;
+const ACCESS_ACCEPT: u8 = 2;
+const ACCESS_REJECT: u8 = 3;
+const ACCOUNTING_REQUEST: u8 = 4;
+const ACCOUNTING_RESPONSE: u8 = 5;
+
+const USER_NAME: u8 = 1;
+const USER_PASSWORD: u8 = 2;
+const CALLING_STATION_ID: u8 = 31;
+const NAS_IDENTIFIER: u8 = 32;
+const ACCT_STATUS_TYPE: u8 = 40;
+const ACCT_INPUT_OCTETS: u8 = 42;
+const ACCT_OUTPUT_OCTETS: u8 = 43;
+const ACCT_SESSION_ID: u8 = 44;
+const ACCT_SESSION_TIME: u8 = 46;
+const ACCT_TERMINATE_CAUSE: u8 = 49;
+const ACCT_INPUT_GIGAWORDS: u8 = 52;
+const ACCT_OUTPUT_GIGAWORDS: u8 = 53;
+const MESSAGE_AUTHENTICATOR: u8 = 80;
+
+const HEADER_LENGTH: usize = 20;
+const MAX_PACKET_LENGTH: usize = 4096;
+const MAX_PASSWORD_LENGTH: usize = 128;
+/// The length of an attribute value is encoded in a byte along with the type and the length
+const MAX_ATTRIBUTE_LENGTH: usize = 253;
+/// `NAS-Request` termination cause
+const TERMINATE_CAUSE_NAS_REQUEST: u32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AcctStatusType {
+    Start = 1,
+    Stop = 2,
+    InterimUpdate = 3,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Packet {
+    pub code: u8,
+    pub identifier: u8,
+    pub authenticator: [u8; 16],
+    pub attributes: Vec<(u8, Vec<u8>)>,
+}
+
+impl Packet {
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        if self
+            .attributes
+            .iter()
+            .any(|(_, x)| x.len() > MAX_ATTRIBUTE_LENGTH)
+        {
+            return Err(invalid_input("Attribute is too long"));
+        }
+        let length = HEADER_LENGTH
+            + self
+                .attributes
+                .iter()
+                .map(|(_, x)| 2 + x.len())
+                .sum::<usize>();
+        if length > MAX_PACKET_LENGTH {
+            return Err(invalid_input("Packet is too long"));
+        }
+
+        let mut buffer = Vec::with_capacity(length);
+        buffer.push(self.code);
+        buffer.push(self.identifier);
+        buffer.extend_from_slice(&(length as u16).to_be_bytes());
+        buffer.extend_from_slice(&self.authenticator);
+        for (t, value) in &self.attributes {
+            buffer.push(*t);
+            buffer.push((2 + value.len()) as u8);
+            buffer.extend_from_slice(value);
+        }
+        Ok(buffer)
+    }
+
+    pub fn decode(data: &[u8]) -> io::Result<Self> {
+        if data.len() < HEADER_LENGTH {
+            return Err(invalid_data("Packet is too short"));
+        }
+        let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+        if !(HEADER_LENGTH..=data.len()).contains(&length) {
+            return Err(invalid_data("Invalid packet length"));
+        }
+
+        let mut attributes = Vec::new();
+        let mut rest = &data[HEADER_LENGTH..length];
+        while !rest.is_empty() {
+            let attribute_length = *rest
+                .get(1)
+                .ok_or_else(|| invalid_data("Truncated attribute"))?;
+            if attribute_length < 2 || attribute_length as usize > rest.len() {
+                return Err(invalid_data("Invalid attribute length"));
+            }
+            attributes.push((rest[0], rest[2..attribute_length as usize].to_vec()));
+            rest = &rest[attribute_length as usize..];
+        }
+
+        Ok(Self {
+            code: data[0],
+            identifier: data[1],
+            authenticator: data[4..HEADER_LENGTH].try_into().unwrap(),
+            attributes,
+        })
+    }
+
+    pub fn attribute(&self, t: u8) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|(x, _)| *x == t)
+            .map(|(_, x)| x.as_slice())
+    }
+}
+
+/// Sends the requests to a RADIUS server and waits for the verified responses
+pub(crate) struct Client {
+    server: SocketAddr,
+    secret: Vec<u8>,
+    nas_identifier: String,
+    timeout: Duration,
+    retries: usize,
+    next_identifier: AtomicU8,
+}
+
+impl Client {
+    /// The shared secret is read from the environment variable `config.secret_env`
+    pub fn new(config: &RadiusAuthConfig) -> io::Result<Self> {
+        let server = config.server.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::NotFound,
+                format!("Couldn't resolve {}", config.server),
+            )
+        })?;
+        let secret = std::env::var(&config.secret_env).map_err(|e| {
+            io::Error::new(
+                ErrorKind::NotFound,
+                format!("Couldn't read {}: {}", config.secret_env, e),
+            )
+        })?;
+
+        Ok(Self {
+            server,
+            secret: secret.into_bytes(),
+            nas_identifier: config.nas_identifier.clone(),
+            timeout: Duration::from_secs(config.timeout_secs),
+            retries: config.retries,
+            next_identifier: AtomicU8::new(random_bytes::<1>()[0]),
+        })
+    }
+
+    /// Check the credentials with an Access-Request.
+    /// Returns `true` if the server accepts them.
+    pub async fn authenticate(&self, username: &str, password: &str) -> io::Result<bool> {
+        // Such credentials can't be sent, so they can't be valid
+        if username.len() > MAX_ATTRIBUTE_LENGTH || password.len() > MAX_PASSWORD_LENGTH {
+            return Ok(false);
+        }
+
+        let authenticator = random_bytes::<16>();
+        let mut request = Packet {
+            code: ACCESS_REQUEST,
+            identifier: self.next_identifier.fetch_add(1, Ordering::Relaxed),
+            authenticator,
+            attributes: vec![
+                (USER_NAME, username.as_bytes().to_vec()),
+                (
+                    USER_PASSWORD,
+                    hide_password(password.as_bytes(), &self.secret, &authenticator),
+                ),
+                (NAS_IDENTIFIER, self.nas_identifier.as_bytes().to_vec()),
+                (MESSAGE_AUTHENTICATOR, vec![0; 16]),
+            ],
+        };
+        let signature = message_authenticator(&self.secret, &request.encode()?);
+        request.attributes.last_mut().unwrap().1 = signature.to_vec();
+
+        match self.exchange(&request).await?.code {
+            ACCESS_ACCEPT => Ok(true),
+            ACCESS_REJECT => Ok(false),
+            x => Err(invalid_data(&format!("Unexpected response code: {}", x))),
+        }
+    }
+
+    /// Send an Accounting-Request with the attributes
+    pub async fn account(&self, attributes: Vec<(u8, Vec<u8>)>) -> io::Result<()> {
+        let mut request = Packet {
+            code: ACCOUNTING_REQUEST,
+            identifier: self.next_identifier.fetch_add(1, Ordering::Relaxed),
+            authenticator: [0; 16],
+            attributes,
+        };
+        request
+            .attributes
+            .push((NAS_IDENTIFIER, self.nas_identifier.as_bytes().to_vec()));
+        request.authenticator = md5_of(&[&request.encode()?, &self.secret]);
+
+        match self.exchange(&request).await?.code {
+            ACCOUNTING_RESPONSE => Ok(()),
+            x => Err(invalid_data(&format!("Unexpected response code: {}", x))),
+        }
+    }
+
+    async fn exchange(&self, request: &Packet) -> io::Result<Packet> {
+        let bind_address = match self.server {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let socket = UdpSocket::bind((bind_address, 0)).await?;
+        socket.connect(self.server).await?;
+
+        let encoded = request.encode()?;
+        for _ in 0..=self.retries {
+            socket.send(&encoded).await?;
+            if let Ok(response) =
+                tokio::time::timeout(self.timeout, self.receive(&socket, request)).await
+            {
+                return response;
+            }
+        }
+
+        Err(io::Error::new(
+            ErrorKind::TimedOut,
+            "RADIUS server did not respond",
+        ))
+    }
+
+    /// Wait for a response to the request, the forged ones are skipped
+    async fn receive(&self, socket: &UdpSocket, request: &Packet) -> io::Result<Packet> {
+        let mut buffer = vec![0; MAX_PACKET_LENGTH];
+        loop {
+            let n = socket.recv(&mut buffer).await?;
+            let response = match Packet::decode(&buffer[..n]) {
+                Ok(x) if x.identifier == request.identifier => x,
+                _ => continue,
+            };
+            if self.is_authentic(&buffer[..n], &response, &request.authenticator) {
+                return Ok(response);
+            }
+            log::debug!("Dropping RADIUS response with invalid authenticator");
+        }
+    }
+
+    fn is_authentic(&self, data: &[u8], response: &Packet, request_authenticator: &[u8]) -> bool {
+        let mut data = data[..u16::from_be_bytes([data[2], data[3]]) as usize].to_vec();
+        data[4..HEADER_LENGTH].copy_from_slice(request_authenticator);
+        if md5_of(&[&data, &self.secret]) != response.authenticator {
+            return false;
+        }
+
+        match response.attribute(MESSAGE_AUTHENTICATOR) {
+            None => true,
+            Some(x) => {
+                let mut unsigned = response.attributes.clone();
+                for (t, value) in unsigned.iter_mut() {
+                    if *t == MESSAGE_AUTHENTICATOR {
+                        value.fill(0);
+                    }
+                }
+                let unsigned = Packet {
+                    authenticator: request_authenticator.try_into().unwrap(),
+                    attributes: unsigned,
+                    ..*response
+                };
+                unsigned
+                    .encode()
+                    .is_ok_and(|data| message_authenticator(&self.secret, &data) == x)
+            }
+        }
+    }
+}
+
+/// Reports the sessions to a RADIUS accounting server
+pub(crate) struct Accounting {
+    client: Client,
+    interim_interval: Duration,
+    /// Distinguishes the session IDs of the endpoint runs
+    session_id_prefix: String,
+}
+
+/// Sends the Stop record of the session on drop
+pub(crate) struct AccountingGuard {
+    _stop: oneshot::Sender<()>,
+}
+
+impl Accounting {
+    /// Returns [`None`] if the accounting server is not configured
+    pub fn new(settings: &RadiusSettings) -> io::Result<Option<Arc<Self>>> {
+        let server = match &settings.accounting_server {
+            None => return Ok(None),
+            Some(x) => x,
+        };
+
+        Ok(Some(Arc::new(Self {
+            client: Client::new(&RadiusAuthConfig {
+                server: server.clone(),
+                ..settings.to_auth_config()
+            })?,
+            interim_interval: Duration::from_secs(settings.interim_interval_secs),
+            session_id_prefix: format!(
+                "{:x}",
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |x| x.as_secs())
+            ),
+        })))
+    }
+
+    /// Account the session: the Start record is sent once the session is authenticated,
+    /// the Interim-Update ones periodically, and the Stop one after the guard is dropped
+    pub fn start(self: Arc<Self>, session: Arc<Session>) -> AccountingGuard {
+        let (stop_tx, mut stop_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let username = tokio::select! {
+                x = session.username() => x,
+                _ = &mut stop_rx => return,
+            };
+
+            self.send(&session, &username, AcctStatusType::Start).await;
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(self.interim_interval) => {
+                        self.send(&session, &username, AcctStatusType::InterimUpdate).await
+                    }
+                    _ = &mut stop_rx => break,
+                }
+            }
+            self.send(&session, &username, AcctStatusType::Stop).await;
+        });
+
+        AccountingGuard { _stop: stop_tx }
+    }
+
+    async fn send(&self, session: &Session, username: &str, status: AcctStatusType) {
+        let info = session.info();
+        let mut attributes = vec![
+            (ACCT_STATUS_TYPE, (status as u32).to_be_bytes().to_vec()),
+            (
+                ACCT_SESSION_ID,
+                format!("{}-{}", self.session_id_prefix, info.id).into_bytes(),
+            ),
+            (USER_NAME, username.as_bytes().to_vec()),
+            (
+                CALLING_STATION_ID,
+                info.client_address.to_string().into_bytes(),
+            ),
+        ];
+        if status != AcctStatusType::Start {
+            let session_time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |x| x.as_secs())
+                .saturating_sub(info.started_at);
+            attributes.extend([
+                (
+                    ACCT_SESSION_TIME,
+                    (session_time as u32).to_be_bytes().to_vec(),
+                ),
+                (
+                    ACCT_INPUT_OCTETS,
+                    (info.inbound_bytes as u32).to_be_bytes().to_vec(),
+                ),
+                (
+                    ACCT_INPUT_GIGAWORDS,
+                    ((info.inbound_bytes >> 32) as u32).to_be_bytes().to_vec(),
+                ),
+                (
+                    ACCT_OUTPUT_OCTETS,
+                    (info.outbound_bytes as u32).to_be_bytes().to_vec(),
+                ),
+                (
+                    ACCT_OUTPUT_GIGAWORDS,
+                    ((info.outbound_bytes >> 32) as u32).to_be_bytes().to_vec(),
+                ),
+            ]);
+        }
+        if status == AcctStatusType::Stop {
+            attributes.push((
+                ACCT_TERMINATE_CAUSE,
+                TERMINATE_CAUSE_NAS_REQUEST.to_be_bytes().to_vec(),
+            ));
+        }
+
+        if let Err(e) = self.client.account(attributes).await {
+            log::warn!(
+                "Failed to send RADIUS accounting {:?} of session {}: {}",
+                status,
+                info.id,
+                e
+            );
+        }
+    }
+}
+
+/// Hide the password as described in
+/// [RFC 2865 section 5.2](https://datatracker.ietf.org/doc/html/rfc2865#section-5.2)
+fn hide_password(password: &[u8], secret: &[u8], authenticator: &[u8; 16]) -> Vec<u8> {
+    let mut hidden = password.to_vec();
+    hidden.resize(password.len().div_ceil(16).max(1) * 16, 0);
+    let mut previous = authenticator.to_vec();
+    for chunk in hidden.chunks_mut(16) {
+        let key = md5_of(&[secret, &previous]);
+        chunk.iter_mut().zip(key).for_each(|(x, k)| *x ^= k);
+        previous = chunk.to_vec();
+    }
+    hidden
+}
+
+fn message_authenticator(secret: &[u8], packet: &[u8]) -> [u8; 16] {
+    let mut mac = hmac::Hmac::<Md5>::new_from_slice(secret).expect("HMAC accepts any key");
+    mac.update(packet);
+    mac.finalize().into_bytes().into()
+}
+
+fn md5_of(parts: &[&[u8]]) -> [u8; 16] {
+    let mut hasher = Md5::new();
+    parts.iter().for_each(|x| hasher.update(x));
+    hasher.finalize().into()
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut x = [0; N];
+    ring::rand::SystemRandom::new()
+        .fill(&mut x)
+        .expect("Couldn't generate random bytes");
+    x
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, message.to_string())
+}
+
+/// A RADIUS stand-in server: passes the [`USERS`] and acknowledges the accounting records
+#[cfg(test)]
+pub(crate) mod test_server {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+
+    pub const SECRET: &str = "testing123";
+    pub const USERS: [(&str, &str); 2] = [
+        ("alice", "secret"),
+        ("bob", "a password longer than one block"),
+    ];
+
+    pub struct TestServer {
+        pub address: SocketAddr,
+        secret_env: String,
+        /// The received valid requests
+        pub requests: Arc<Mutex<Vec<Packet>>>,
+        /// Number of the next requests to leave unanswered
+        pub drop_requests: Arc<AtomicUsize>,
+        /// Number of the next requests to answer with a forged response first
+        pub forge_responses: Arc<AtomicUsize>,
+    }
+
+    impl TestServer {
+        pub fn config(&self) -> RadiusAuthConfig {
+            RadiusAuthConfig {
+                server: self.address.to_string(),
+                secret_env: self.secret_env.clone(),
+                nas_identifier: "test".into(),
+                timeout_secs: 1,
+                retries: 1,
+            }
+        }
+    }
+
+    /// `secret_env` must be unique per test as the tests run in parallel
+    pub async fn run(secret_env: &str) -> TestServer {
+        std::env::set_var(secret_env, SECRET);
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = TestServer {
+            address: socket.local_addr().unwrap(),
+            secret_env: secret_env.to_string(),
+            requests: Default::default(),
+            drop_requests: Default::default(),
+            forge_responses: Default::default(),
+        };
+
+        let requests = server.requests.clone();
+        let drop_requests = server.drop_requests.clone();
+        let forge_responses = server.forge_responses.clone();
+        tokio::spawn(async move {
+            let mut buffer = vec![0; MAX_PACKET_LENGTH];
+            loop {
+                let (n, peer) = socket.recv_from(&mut buffer).await.unwrap();
+                let request = Packet::decode(&buffer[..n]).unwrap();
+                let code = match request.code {
+                    ACCESS_REQUEST => {
+                        let signature = request.attribute(MESSAGE_AUTHENTICATOR).unwrap();
+                        let mut unsigned = buffer[..n].to_vec();
+                        let offset = unsigned.len() - 16;
+                        unsigned[offset..].fill(0);
+                        assert_eq!(
+                            message_authenticator(SECRET.as_bytes(), &unsigned),
+                            signature
+                        );
+
+                        let password = reveal_password(
+                            request.attribute(USER_PASSWORD).unwrap(),
+                            &request.authenticator,
+                        );
+                        let username = request.attribute(USER_NAME).unwrap();
+                        if USERS
+                            .iter()
+                            .any(|(u, p)| u.as_bytes() == username && p.as_bytes() == password)
+                        {
+                            ACCESS_ACCEPT
+                        } else {
+                            ACCESS_REJECT
+                        }
+                    }
+                    ACCOUNTING_REQUEST => {
+                        let mut unsigned = buffer[..n].to_vec();
+                        unsigned[4..HEADER_LENGTH].fill(0);
+                        assert_eq!(
+                            md5_of(&[&unsigned, SECRET.as_bytes()]),
+                            request.authenticator
+                        );
+                        ACCOUNTING_RESPONSE
+                    }
+                    x => panic!("Unexpected request code: {}", x),
+                };
+
+                if drop_requests
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| x.checked_sub(1))
+                    .is_ok()
+                {
+                    continue;
+                }
+
+                let mut response = Packet {
+                    code,
+                    identifier: request.identifier,
+                    authenticator: request.authenticator,
+                    attributes: vec![],
+                }
+                .encode()
+                .unwrap();
+                let authenticator = md5_of(&[&response, SECRET.as_bytes()]);
+                response[4..HEADER_LENGTH].copy_from_slice(&authenticator);
+
+                if forge_responses
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| x.checked_sub(1))
+                    .is_ok()
+                {
+                    let mut forged = response.clone();
+                    forged[0] = ACCESS_ACCEPT;
+                    socket.send_to(&forged, peer).await.unwrap();
+                }
+                requests.lock().unwrap().push(request);
+                socket.send_to(&response, peer).await.unwrap();
+            }
+        });
+
+        server
+    }
+
+    fn reveal_password(hidden: &[u8], authenticator: &[u8; 16]) -> Vec<u8> {
+        let mut password = Vec::with_capacity(hidden.len());
+        let mut previous = &authenticator[..];
+        for chunk in hidden.chunks(16) {
+            let key = md5_of(&[SECRET.as_bytes(), previous]);
+            password.extend(chunk.iter().zip(key).map(|(x, k)| x ^ k));
+            previous = chunk;
+        }
+        while password.last() == Some(&0) {
+            password.pop();
+        }
+        password
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_server::TestServer;
+    use super::*;
+    use crate::sessions::Sessions;
+    use crate::tls_demultiplexer::Protocol;
+
+    fn u32_attribute(packet: &Packet, t: u8) -> u32 {
+        u32::from_be_bytes(packet.attribute(t).unwrap().try_into().unwrap())
+    }
+
+    /// The example of [RFC 2865 section 7.1](https://datatracker.ietf.org/doc/html/rfc2865#section-7.1)
+    #[test]
+    fn rfc_example() {
+        let request = hex::decode(
+            "010000380f403f9473978057bd83d5cb98f4227a01066e656d6f02120dbe708d93d413ce3196e43f782a0aee0406c0a80110050600000003",
+        )
+        .unwrap();
+        let response = hex::decode(
+            "0200002686fe220e7624ba2a1005f6bf9b55e0b20606000000010f06000000000e06c0a80103",
+        )
+        .unwrap();
+        let secret = b"xyzzy5461";
+
+        let request_packet = Packet::decode(&request).unwrap();
+        assert_eq!(request_packet.encode().unwrap(), request);
+        assert_eq!(request_packet.attribute(USER_NAME), Some(&b"nemo"[..]));
+        assert_eq!(
+            hide_password(b"arctangent", secret, &request_packet.authenticator),
+            request_packet.attribute(USER_PASSWORD).unwrap()
+        );
+
+        let client = Client {
+            server: "127.0.0.1:1812".parse().unwrap(),
+            secret: secret.to_vec(),
+            nas_identifier: "test".into(),
+            timeout: Duration::from_secs(1),
+            retries: 0,
+            next_identifier: Default::default(),
+        };
+        let response_packet = Packet::decode(&response).unwrap();
+        assert_eq!(response_packet.code, ACCESS_ACCEPT);
+        assert!(client.is_authentic(&response, &response_packet, &request_packet.authenticator));
+        let mut forged = response.clone();
+        forged[HEADER_LENGTH + 2] ^= 1;
+        let forged_packet = Packet::decode(&forged).unwrap();
+        assert!(!client.is_authentic(&forged, &forged_packet, &request_packet.authenticator));
+
+        assert!(Packet::decode(&request[..HEADER_LENGTH - 1]).is_err());
+        assert!(Packet::decode(&request[..request.len() - 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn retransmissions() {
+        let server = test_server::run("RADIUS_RETRANSMISSIONS").await;
+        let client = Client::new(&server.config()).unwrap();
+
+        server.drop_requests.store(1, Ordering::Relaxed);
+        assert!(client.authenticate("alice", "secret").await.unwrap());
+
+        server.drop_requests.store(2, Ordering::Relaxed);
+        let e = client.authenticate("alice", "secret").await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn long_attributes() {
+        let packet = |value: Vec<u8>| Packet {
+            code: ACCESS_REQUEST,
+            identifier: 0,
+            authenticator: [0; 16],
+            attributes: vec![(USER_NAME, value)],
+        };
+        let encoded = packet(vec![b'a'; MAX_ATTRIBUTE_LENGTH]).encode().unwrap();
+        assert_eq!(encoded[HEADER_LENGTH + 1], 255);
+        let e = packet(vec![b'a'; MAX_ATTRIBUTE_LENGTH + 1])
+            .encode()
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+
+        // Rejected without asking the server
+        let server = test_server::run("RADIUS_LONG_ATTRIBUTES").await;
+        let client = Client::new(&server.config()).unwrap();
+        let username = "a".repeat(MAX_ATTRIBUTE_LENGTH + 1);
+        assert!(!client.authenticate(&username, "secret").await.unwrap());
+        assert!(server.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn forged_responses() {
+        let server = test_server::run("RADIUS_FORGED_RESPONSES").await;
+        let client = Client::new(&server.config()).unwrap();
+
+        server.forge_responses.store(1, Ordering::Relaxed);
+        assert!(!client.authenticate("alice", "wrong").await.unwrap());
+        assert!(client.authenticate("alice", "secret").await.unwrap());
+        // The password spans several blocks
+        let (username, password) = test_server::USERS[1];
+        assert!(client.authenticate(username, password).await.unwrap());
+        assert!(!client.authenticate(username, "secret").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn session_accounting() {
+        let server: TestServer = test_server::run("RADIUS_SESSION_ACCOUNTING").await;
+        let accounting = Arc::new(Accounting {
+            client: Client::new(&server.config()).unwrap(),
+            interim_interval: Duration::from_millis(300),
+            session_id_prefix: "test".into(),
+        });
+        let sessions = Arc::new(Sessions::default());
+        let session = sessions
+            .clone()
+            .register(
+                42,
+                "192.0.2.1".parse().unwrap(),
+                Protocol::Http2,
+                "example.org".into(),
+                None,
+            )
+            .unwrap();
+
+        // Not authenticated sessions are not accounted
+        drop(accounting.clone().start(session.session()));
+        let guard = accounting.clone().start(session.session());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(server.requests.lock().unwrap().is_empty());
+
+        assert!(sessions.set_username(&session, "alice", None));
+        session.add_inbound_bytes(100);
+        session.add_outbound_bytes((5 << 32) + 200);
+        tokio::time::sleep(Duration::from_millis(450)).await;
+        drop(guard);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let requests = std::mem::take(&mut *server.requests.lock().unwrap());
+        let statuses: Vec<_> = requests
+            .iter()
+            .map(|x| u32_attribute(x, ACCT_STATUS_TYPE))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                AcctStatusType::Start as u32,
+                AcctStatusType::InterimUpdate as u32,
+                AcctStatusType::Stop as u32,
+            ]
+        );
+        for request in &requests {
+            assert_eq!(request.attribute(USER_NAME), Some(&b"alice"[..]));
+            assert_eq!(request.attribute(ACCT_SESSION_ID), Some(&b"test-42"[..]));
+            assert_eq!(
+                request.attribute(CALLING_STATION_ID),
+                Some(&b"192.0.2.1"[..])
+            );
+            assert_eq!(request.attribute(NAS_IDENTIFIER), Some(&b"test"[..]));
+        }
+        assert_eq!(requests[0].attribute(ACCT_INPUT_OCTETS), None);
+        let stop = &requests[2];
+        assert_eq!(u32_attribute(stop, ACCT_INPUT_OCTETS), 100);
+        assert_eq!(u32_attribute(stop, ACCT_INPUT_GIGAWORDS), 0);
+        assert_eq!(u32_attribute(stop, ACCT_OUTPUT_OCTETS), 200);
+        assert_eq!(u32_attribute(stop, ACCT_OUTPUT_GIGAWORDS), 5);
+        assert_eq!(
+            u32_attribute(stop, ACCT_TERMINATE_CAUSE),
+            TERMINATE_CAUSE_NAS_REQUEST
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use crate::pipe::SimplexDirection;
+use crate::settings::RateLimitSettings;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The registry of the per-user rate limiters.
+/// A limiter lives while at least one connection of the user holds it.
+#[derive(Default)]
+pub(crate) struct RateLimiters {
+    /// Key is the username
+    limiters: Mutex<HashMap<String, Weak<RateLimiter>>>,
+}
+
+/// Limits the upload and download rates of a single user
+pub(crate) struct RateLimiter {
+    upload: TokenBucket,
+    download: TokenBucket,
+}
+
+/// A token bucket which is allowed to go into debt: the transferred amount is
+/// taken at once and the caller waits until the debt is paid off.
+/// The bucket capacity equals 1 second worth of the rate.
+struct TokenBucket {
+    state: Mutex<TokenBucketState>,
+}
+
+struct TokenBucketState {
+    /// Bytes per second, 0 means unlimited
+    rate: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiters {
+    /// Get the limiter of the user.
+    /// The limits of an existing limiter are updated to the passed ones.
+    /// Returns [`None`] if the user is not limited.
+    pub fn get(&self, username: &str, limits: &RateLimitSettings) -> Option<Arc<RateLimiter>> {
+        let mut limiters = self.limiters.lock().unwrap();
+        if let Some(x) = limiters.get(username).and_then(Weak::upgrade) {
+            x.set_limits(limits);
+            return Some(x);
+        }
+
+        if limits.upload_bytes_per_sec.is_none() && limits.download_bytes_per_sec.is_none() {
+            return None;
+        }
+
+        limiters.retain(|_, x| x.strong_count() > 0);
+        let limiter = Arc::new(RateLimiter {
+            upload: TokenBucket::new(limits.upload_bytes_per_sec.unwrap_or_default()),
+            download: TokenBucket::new(limits.download_bytes_per_sec.unwrap_or_default()),
+        });
+        limiters.insert(username.to_string(), Arc::downgrade(&limiter));
+        Some(limiter)
+    }
+}
+
+impl RateLimiter {
+    /// Account the transferred bytes and wait if the user exceeded the limit
+    pub async fn throttle(&self, direction: SimplexDirection, n: usize) {
+        let bucket = match direction {
+            SimplexDirection::Outgoing => &self.upload,
+            SimplexDirection::Incoming => &self.download,
+        };
+
+        if let Some(delay) = bucket.take(n) {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    fn set_limits(&self, limits: &RateLimitSettings) {
+        self.upload
+            .set_rate(limits.upload_bytes_per_sec.unwrap_or_default());
+        self.download
+            .set_rate(limits.download_bytes_per_sec.unwrap_or_default());
+    }
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            state: Mutex::new(TokenBucketState {
+                rate,
+                tokens: rate as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    fn set_rate(&self, rate: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.rate != rate {
+            state.refill();
+            state.rate = rate;
+            state.tokens = state.tokens.min(rate as f64);
+        }
+    }
+
+    /// Take `n` tokens.
+    /// Returns the time to wait until the bucket gets out of debt, if it is in debt.
+    fn take(&self, n: usize) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        if state.rate == 0 {
+            return None;
+        }
+
+        state.refill();
+        state.tokens -= n as f64;
+        (state.tokens < 0.0).then(|| Duration::from_secs_f64(-state.tokens / state.rate as f64))
+    }
+}
+
+impl TokenBucketState {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(upload: Option<u64>, download: Option<u64>) -> RateLimitSettings {
+        RateLimitSettings {
+            upload_bytes_per_sec: upload,
+            download_bytes_per_sec: download,
+        }
+    }
+
+    #[test]
+    fn unlimited_user() {
+        let limiters = RateLimiters::default();
+        assert!(limiters.get("a", &limits(None, None)).is_none());
+    }
+
+    #[test]
+    fn limiter_shared_between_connections() {
+        let limiters = RateLimiters::default();
+        let a1 = limiters.get("a", &limits(Some(100), None)).unwrap();
+        let a2 = limiters.get("a", &limits(Some(100), None)).unwrap();
+        let b = limiters.get("b", &limits(Some(100), None)).unwrap();
+        assert!(Arc::ptr_eq(&a1, &a2));
+        assert!(!Arc::ptr_eq(&a1, &b));
+
+        drop((a1, a2));
+        let a3 = limiters.get("a", &limits(Some(100), None)).unwrap();
+        assert_eq!(Arc::strong_count(&a3), 1);
+    }
+
+    #[test]
+    fn bucket_debt() {
+        let bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.take(1000), None);
+        let delay = bucket.take(500).unwrap();
+        assert!(delay > Duration::from_millis(400) && delay <= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn limits_update() {
+        let limiters = RateLimiters::default();
+        let limiter = limiters.get("a", &limits(Some(1000), None)).unwrap();
+        assert_eq!(limiter.download.take(1_000_000), None);
+
+        let _ = limiters.get("a", &limits(None, Some(1000))).unwrap();
+        assert_eq!(limiter.upload.take(1_000_000), None);
+        assert!(limiter.download.take(1500).is_some());
+    }
+}
@@ -53,7 +53,7 @@ async fn listen_inner(
     log_id: &log_utils::IdChain<u64>,
 ) {
     let manager = Arc::new(SessionManager::default());
-    let timeout = context.settings.connection_establishment_timeout;
+    let timeout = context.settings().connection_establishment_timeout;
     loop {
         match tokio::time::timeout(timeout, codec.listen()).await {
             Ok(Ok(Some(x))) => {
@@ -111,7 +111,8 @@ async fn handle_stream(
     log_id!(trace, log_id, "Received request: {:?}", request.request());
 
     let forwarder = Box::new(TcpForwarder::new(context.clone()));
-    let settings = context.settings.reverse_proxy.as_ref().unwrap();
+    let core_settings = context.settings();
+    let settings = core_settings.reverse_proxy.as_ref().unwrap();
     let (mut server_source, mut server_sink) = forwarder
         .connect(
             log_id.clone(),
@@ -196,6 +197,6 @@ async fn handle_stream(
         |_, _| (),
     );
 
-    pipe.exchange(context.settings.tcp_connections_timeout)
+    pipe.exchange(context.settings().tcp_connections_timeout)
         .await
 }
@@ -0,0 +1,385 @@
+use crate::forwarder::{Forwarder, IcmpMultiplexer, UdpMultiplexer};
+use crate::net_utils::TcpDestination;
+use crate::settings::Settings;
+use crate::{core, datagram_pipe, downstream, forwarder, log_id, log_utils, pipe, rules, tunnel};
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// The number of datagrams read by the forwarder multiplexers waiting to be
+/// passed to a client
+const READ_QUEUE_CAPACITY: usize = 16;
+
+/// Routes each connection through one of the named forwarders chosen by
+/// the routing rules, or through the default one if no rule matches.
+/// The datagram multiplexer authentication and ICMP go through the default forwarder.
+pub(crate) struct RoutingForwarder {
+    context: Arc<core::Context>,
+    forwarders: Arc<Forwarders>,
+}
+
+struct Forwarders {
+    default: Mutex<Box<dyn Forwarder>>,
+    named: HashMap<String, Mutex<Box<dyn Forwarder>>>,
+}
+
+struct TcpConnector {
+    context: Arc<core::Context>,
+    forwarders: Arc<Forwarders>,
+}
+
+/// The name of a forwarder and the result of reading its multiplexer
+type ReadResult = (Option<String>, io::Result<forwarder::UdpDatagramReadStatus>);
+
+/// The sink of a forwarder multiplexer, written only by the [`DatagramSink`]
+type SharedSink =
+    Arc<tokio::sync::Mutex<Box<dyn datagram_pipe::Sink<Input = downstream::UdpDatagram>>>>;
+
+/// Creates a multiplexer of a forwarder on the first UDP "connection" routed through it
+struct UdpRouter {
+    context: Arc<core::Context>,
+    forwarders: Arc<Forwarders>,
+    meta: forwarder::UdpMultiplexerMeta,
+    id: log_utils::IdChain<u64>,
+    multiplexers: Mutex<HashMap<Option<String>, Multiplexer>>,
+    /// The forwarder each UDP "connection" is routed through
+    routes: Mutex<HashMap<forwarder::UdpDatagramMeta, Option<String>>>,
+    read_tx: mpsc::Sender<ReadResult>,
+}
+
+struct Multiplexer {
+    shared: Arc<dyn forwarder::UdpDatagramPipeShared>,
+    sink: SharedSink,
+    reader: JoinHandle<()>,
+}
+
+struct DatagramSource {
+    router: Arc<UdpRouter>,
+    read_rx: mpsc::Receiver<ReadResult>,
+    /// The UDP "connections" of the failed multiplexers
+    pending_closures: VecDeque<(forwarder::UdpDatagramMeta, io::Error)>,
+    id: log_utils::IdChain<u64>,
+}
+
+struct DatagramSink {
+    router: Arc<UdpRouter>,
+}
+
+impl RoutingForwarder {
+    pub fn new(
+        context: Arc<core::Context>,
+        default: Box<dyn Forwarder>,
+        named: HashMap<String, Box<dyn Forwarder>>,
+    ) -> Self {
+        Self {
+            context,
+            forwarders: Arc::new(Forwarders {
+                default: Mutex::new(default),
+                named: named
+                    .into_iter()
+                    .map(|(name, x)| (name, Mutex::new(x)))
+                    .collect(),
+            }),
+        }
+    }
+}
+
+impl Forwarder for RoutingForwarder {
+    fn tcp_connector(&self) -> Box<dyn forwarder::TcpConnector> {
+        Box::new(TcpConnector {
+            context: self.context.clone(),
+            forwarders: self.forwarders.clone(),
+        })
+    }
+
+    fn datagram_mux_authenticator(&self) -> Box<dyn forwarder::DatagramMultiplexerAuthenticator> {
+        self.forwarders
+            .default
+            .lock()
+            .unwrap()
+            .datagram_mux_authenticator()
+    }
+
+    fn make_udp_datagram_multiplexer(
+        &self,
+        id: log_utils::IdChain<u64>,
+        meta: forwarder::UdpMultiplexerMeta,
+    ) -> io::Result<UdpMultiplexer> {
+        let (read_tx, read_rx) = mpsc::channel(READ_QUEUE_CAPACITY);
+        let router = Arc::new(UdpRouter {
+            context: self.context.clone(),
+            forwarders: self.forwarders.clone(),
+            meta,
+            id: id.clone(),
+            multiplexers: Default::default(),
+            routes: Default::default(),
+            read_tx,
+        });
+
+        Ok((
+            router.clone(),
+            Box::new(DatagramSource {
+                router: router.clone(),
+                read_rx,
+                pending_closures: Default::default(),
+                id,
+            }),
+            Box::new(DatagramSink { router }),
+        ))
+    }
+
+    fn make_icmp_datagram_multiplexer(
+        &self,
+        id: log_utils::IdChain<u64>,
+    ) -> io::Result<Option<IcmpMultiplexer>> {
+        self.forwarders
+            .default
+            .lock()
+            .unwrap()
+            .make_icmp_datagram_multiplexer(id)
+    }
+}
+
+impl Forwarders {
+    /// Choose the forwarder of a connection by the routing rules.
+    /// [`None`] stands for the default forwarder.
+    fn route(
+        &self,
+        settings: &Settings,
+        route: &rules::RouteInfo,
+        id: &log_utils::IdChain<u64>,
+    ) -> Option<String> {
+        let name = settings.rules_engine.as_ref()?.evaluate_route(route)?;
+        if !self.named.contains_key(name) {
+            // The rules may have been reloaded after the tunnel was established
+            log_id!(
+                debug,
+                id,
+                "Unknown forwarder {}, routing through the default one",
+                name
+            );
+            return None;
+        }
+
+        log_id!(trace, id, "Routing through forwarder {}", name);
+        Some(name.to_string())
+    }
+
+    fn get(&self, name: &Option<String>) -> &Mutex<Box<dyn Forwarder>> {
+        name.as_ref()
+            .and_then(|x| self.named.get(x))
+            .unwrap_or(&self.default)
+    }
+}
+
+#[async_trait]
+impl forwarder::TcpConnector for TcpConnector {
+    async fn connect(
+        self: Box<Self>,
+        id: log_utils::IdChain<u64>,
+        meta: forwarder::TcpConnectionMeta,
+    ) -> Result<(Box<dyn pipe::Source>, Box<dyn pipe::Sink>), tunnel::ConnectionError> {
+        let (destination, port) = match &meta.destination {
+            TcpDestination::Address(x) => (rules::Destination::Address(x.ip()), x.port()),
+            TcpDestination::HostName((host, port)) => (rules::Destination::HostName(host), *port),
+        };
+        let username = meta.auth.as_ref().and_then(|x| x.username());
+        let name = self.forwarders.route(
+            &self.context.settings(),
+            &rules::RouteInfo {
+                username: username.as_deref(),
+                sni: &meta.tls_domain,
+                destination,
+                port,
+                protocol: rules::EgressProtocol::Tcp,
+            },
+            &id,
+        );
+
+        let connector = self.forwarders.get(&name).lock().unwrap().tcp_connector();
+        connector.connect(id, meta).await
+    }
+}
+
+impl UdpRouter {
+    /// Get the multiplexer of the forwarder, creating it if needed
+    fn multiplexer(
+        &self,
+        name: &Option<String>,
+    ) -> io::Result<Arc<dyn forwarder::UdpDatagramPipeShared>> {
+        let mut multiplexers = self.multiplexers.lock().unwrap();
+        if let Some(x) = multiplexers.get(name) {
+            return Ok(x.shared.clone());
+        }
+
+        let (shared, mut source, sink) = self
+            .forwarders
+            .get(name)
+            .lock()
+            .unwrap()
+            .make_udp_datagram_multiplexer(self.id.clone(), self.meta.clone())?;
+        let reader = tokio::spawn({
+            let name = name.clone();
+            let read_tx = self.read_tx.clone();
+            async move {
+                loop {
+                    let x = source.read().await;
+                    let failed = x.is_err();
+                    if read_tx.send((name.clone(), x)).await.is_err() || failed {
+                        break;
+                    }
+                }
+            }
+        });
+
+        multiplexers.insert(
+            name.clone(),
+            Multiplexer {
+                shared: shared.clone(),
+                sink: Arc::new(tokio::sync::Mutex::new(sink)),
+                reader,
+            },
+        );
+        Ok(shared)
+    }
+
+    /// Forget the failed multiplexer and return the UDP "connections" routed through it
+    fn remove_multiplexer(&self, name: &Option<String>) -> Vec<forwarder::UdpDatagramMeta> {
+        self.multiplexers.lock().unwrap().remove(name);
+
+        let mut closed = vec![];
+        self.routes.lock().unwrap().retain(|meta, x| {
+            if x == name {
+                closed.push(*meta);
+            }
+            x != name
+        });
+        closed
+    }
+
+    fn sink(&self, meta: &forwarder::UdpDatagramMeta) -> Option<SharedSink> {
+        let name = self.routes.lock().unwrap().get(meta)?.clone();
+        self.multiplexers
+            .lock()
+            .unwrap()
+            .get(&name)
+            .map(|x| x.sink.clone())
+    }
+}
+
+#[async_trait]
+impl forwarder::UdpDatagramPipeShared for UdpRouter {
+    async fn on_new_udp_connection(&self, meta: &downstream::UdpDatagramMeta) -> io::Result<()> {
+        let username = self.meta.auth.as_ref().and_then(|x| x.username());
+        let name = self.forwarders.route(
+            &self.context.settings(),
+            &rules::RouteInfo {
+                username: username.as_deref(),
+                sni: &self.meta.tls_domain,
+                destination: rules::Destination::Address(meta.destination.ip()),
+                port: meta.destination.port(),
+                protocol: rules::EgressProtocol::Udp,
+            },
+            &self.id,
+        );
+
+        let shared = self.multiplexer(&name)?;
+        self.routes
+            .lock()
+            .unwrap()
+            .insert(forwarder::UdpDatagramMeta::from(meta), name);
+        shared.on_new_udp_connection(meta).await
+    }
+
+    fn on_connection_closed(&self, meta: &forwarder::UdpDatagramMeta) {
+        // The pipe may pass the metadata of either direction
+        let name = {
+            let mut routes = self.routes.lock().unwrap();
+            match routes
+                .remove(&meta.reversed())
+                .or_else(|| routes.remove(meta))
+            {
+                Some(x) => x,
+                None => return,
+            }
+        };
+
+        let shared = self
+            .multiplexers
+            .lock()
+            .unwrap()
+            .get(&name)
+            .map(|x| x.shared.clone());
+        if let Some(x) = shared {
+            x.on_connection_closed(meta);
+        }
+    }
+}
+
+impl Drop for Multiplexer {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[async_trait]
+impl datagram_pipe::Source for DatagramSource {
+    type Output = forwarder::UdpDatagramReadStatus;
+
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.id.clone()
+    }
+
+    async fn read(&mut self) -> io::Result<Self::Output> {
+        loop {
+            if let Some((meta, e)) = self.pending_closures.pop_front() {
+                return Ok(forwarder::UdpDatagramReadStatus::UdpClose(meta, e));
+            }
+
+            match self.read_rx.recv().await {
+                Some((_, Ok(x))) => return Ok(x),
+                Some((name, Err(e))) => {
+                    log_id!(
+                        debug,
+                        self.id,
+                        "UDP multiplexer of forwarder {} failed: {}",
+                        name.as_deref().unwrap_or("default"),
+                        e
+                    );
+                    self.pending_closures.extend(
+                        self.router
+                            .remove_multiplexer(&name)
+                            .into_iter()
+                            .map(|meta| (meta, io::Error::new(e.kind(), e.to_string()))),
+                    );
+                }
+                None => {
+                    return Err(io::Error::new(
+                        ErrorKind::Other,
+                        "UDP multiplexers are closed",
+                    ))
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl datagram_pipe::Sink for DatagramSink {
+    type Input = downstream::UdpDatagram;
+
+    async fn write(&mut self, datagram: Self::Input) -> io::Result<datagram_pipe::SendStatus> {
+        match self
+            .router
+            .sink(&forwarder::UdpDatagramMeta::from(&datagram.meta))
+        {
+            Some(x) => x.lock().await.write(datagram).await,
+            // The multiplexer the "connection" was routed through has failed
+            None => Ok(datagram_pipe::SendStatus::Dropped),
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
+use std::ops::RangeInclusive;
 
 /// Action to take when a rule matches
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -24,19 +25,163 @@ pub struct Rule {
     #[serde(default)]
     pub client_random_prefix: Option<String>,
 
+    /// TLS server name pattern, see [`EgressRule::domain`] for the format
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sni: Option<String>,
+
+    /// Authenticated username to match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    /// Group of the authenticated user to match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+
+    /// Substring to search for in the request User-Agent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+
     /// Action to take when this rule matches
     pub action: RuleAction,
 }
 
+/// Parameters of a client connection the rules are matched against
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionInfo<'a> {
+    pub client_ip: IpAddr,
+    pub client_random: Option<&'a [u8]>,
+    /// The TLS server name the client connected to
+    pub sni: Option<&'a str>,
+}
+
+/// Parameters of an authenticated client request the rules are matched against
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestInfo<'a> {
+    pub username: Option<&'a str>,
+    /// The groups of the authenticated user
+    pub groups: &'a [String],
+    pub user_agent: Option<&'a str>,
+}
+
+/// Transport protocol of an outgoing connection
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EgressProtocol {
+    Tcp,
+    Udp,
+}
+
+/// Destination of an outgoing connection
+#[derive(Debug, Clone, Copy)]
+pub enum Destination<'a> {
+    /// Requested by IP address
+    Address(IpAddr),
+    /// Requested by host name
+    HostName(&'a str),
+    /// Requested by host name and resolved to the address
+    Resolved { host: &'a str, address: IpAddr },
+}
+
+/// Individual outgoing connection filter rule
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EgressRule {
+    /// Destination host name pattern:
+    /// - "example.com" matches the host name exactly
+    /// - ".example.com" matches the domain and all its subdomains
+    /// - "*.example.com" matches the host name against the wildcard, `*` stands for
+    ///   any sequence of characters
+    ///
+    /// Matches only the destinations requested by host name.
+    #[serde(default)]
+    pub domain: Option<String>,
+
+    /// CIDR range to match against destination IP.
+    /// Matches the destinations requested by IP address, and the addresses the host names
+    /// resolve to when the endpoint connects to them by itself.
+    #[serde(default)]
+    pub cidr: Option<String>,
+
+    /// Destination ports: a comma-separated list of ports and ranges (e.g., "25,6881-6889")
+    #[serde(default)]
+    pub ports: Option<String>,
+
+    /// Transport protocol
+    #[serde(default)]
+    pub protocol: Option<EgressProtocol>,
+
+    /// Authenticated username to match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    /// Group of the authenticated user to match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+
+    /// Action to take when this rule matches
+    pub action: RuleAction,
+}
+
+/// Individual routing rule choosing the forwarder of an outgoing connection.
+/// All the specified conditions must match.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteRule {
+    /// Authenticated username to match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    /// TLS server name pattern, see [`EgressRule::domain`] for the format
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sni: Option<String>,
+
+    /// Destination host name pattern, see [`EgressRule::domain`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+
+    /// CIDR range to match against destination IP, see [`EgressRule::cidr`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cidr: Option<String>,
+
+    /// Destination ports, see [`EgressRule::ports`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ports: Option<String>,
+
+    /// Transport protocol
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<EgressProtocol>,
+
+    /// Name of the forwarder to route the matching connections through
+    pub forwarder: String,
+}
+
+/// Parameters of an outgoing connection the routing rules are matched against
+#[derive(Debug, Clone, Copy)]
+pub struct RouteInfo<'a> {
+    pub username: Option<&'a str>,
+    /// The TLS server name the client connected to
+    pub sni: &'a str,
+    pub destination: Destination<'a>,
+    pub port: u16,
+    pub protocol: EgressProtocol,
+}
+
 /// Rules configuration
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct RulesConfig {
     /// List of filter rules
     #[serde(default)]
     pub rule: Vec<Rule>,
+
+    /// List of outgoing connection filter rules
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub egress: Vec<EgressRule>,
+
+    /// List of routing rules
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub route: Vec<RouteRule>,
 }
 
 /// Rule evaluation engine
+#[derive(Clone)]
 pub struct RulesEngine {
     rules: RulesConfig,
 }
@@ -49,7 +194,61 @@ pub enum RuleEvaluation {
 }
 
 impl Rule {
-    /// Check if this rule matches the given connection parameters
+    /// Check if the rule has conditions which can only be checked on a client request:
+    /// `username`, `group` or `user_agent`
+    pub fn has_request_conditions(&self) -> bool {
+        self.username.is_some() || self.group.is_some() || self.user_agent.is_some()
+    }
+
+    /// Check if this rule matches the given connection and request parameters.
+    /// A rule with request conditions never matches without the request parameters.
+    pub fn matches_all(&self, connection: &ConnectionInfo, request: Option<&RequestInfo>) -> bool {
+        if !self.matches_connection(connection) {
+            return false;
+        }
+
+        let request = match request {
+            Some(x) => x,
+            None => return !self.has_request_conditions(),
+        };
+
+        if let Some(username) = &self.username {
+            if request.username != Some(username.as_str()) {
+                return false;
+            }
+        }
+
+        if let Some(group) = &self.group {
+            if !request.groups.contains(group) {
+                return false;
+            }
+        }
+
+        if let Some(user_agent) = &self.user_agent {
+            if !request
+                .user_agent
+                .is_some_and(|x| x.contains(user_agent.as_str()))
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Check if the connection level conditions of this rule match
+    fn matches_connection(&self, connection: &ConnectionInfo) -> bool {
+        if let Some(pattern) = &self.sni {
+            if !connection.sni.is_some_and(|x| domain_matches(pattern, x)) {
+                return false;
+            }
+        }
+
+        self.matches(&connection.client_ip, connection.client_random)
+    }
+
+    /// Check if the `cidr` and `client_random_prefix` conditions of this rule match
+    /// the given connection parameters
     pub fn matches(&self, client_ip: &IpAddr, client_random: Option<&[u8]>) -> bool {
         let mut matches = true;
 
@@ -115,6 +314,175 @@ impl Rule {
     }
 }
 
+impl EgressRule {
+    /// Check if the `username` and `group` conditions of this rule match the client
+    /// opening the connection
+    pub fn matches_user(&self, user: &RequestInfo) -> bool {
+        if let Some(username) = &self.username {
+            if user.username != Some(username.as_str()) {
+                return false;
+            }
+        }
+
+        if let Some(group) = &self.group {
+            if !user.groups.contains(group) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Check if the destination conditions of this rule match the given outgoing
+    /// connection parameters
+    pub fn matches(&self, destination: Destination, port: u16, protocol: EgressProtocol) -> bool {
+        destination_matches(
+            DestinationConditions {
+                domain: self.domain.as_deref(),
+                cidr: self.cidr.as_deref(),
+                ports: self.ports.as_deref(),
+                protocol: self.protocol,
+            },
+            destination,
+            port,
+            protocol,
+        )
+    }
+}
+
+impl RouteRule {
+    /// Check if this rule matches the given outgoing connection parameters
+    pub fn matches(&self, route: &RouteInfo) -> bool {
+        if let Some(username) = &self.username {
+            if route.username != Some(username.as_str()) {
+                return false;
+            }
+        }
+
+        if let Some(pattern) = &self.sni {
+            if !domain_matches(pattern, route.sni) {
+                return false;
+            }
+        }
+
+        destination_matches(
+            DestinationConditions {
+                domain: self.domain.as_deref(),
+                cidr: self.cidr.as_deref(),
+                ports: self.ports.as_deref(),
+                protocol: self.protocol,
+            },
+            route.destination,
+            route.port,
+            route.protocol,
+        )
+    }
+}
+
+/// Outgoing connection conditions shared by the egress and the routing rules
+struct DestinationConditions<'a> {
+    domain: Option<&'a str>,
+    cidr: Option<&'a str>,
+    ports: Option<&'a str>,
+    protocol: Option<EgressProtocol>,
+}
+
+fn destination_matches(
+    conditions: DestinationConditions,
+    destination: Destination,
+    port: u16,
+    protocol: EgressProtocol,
+) -> bool {
+    if conditions.protocol.is_some_and(|x| x != protocol) {
+        return false;
+    }
+
+    if let Some(ports) = conditions.ports {
+        // Invalid list, rule doesn't match
+        match parse_port_ranges(ports) {
+            Some(x) if x.iter().any(|r| r.contains(&port)) => (),
+            _ => return false,
+        }
+    }
+
+    if let Some(cidr_str) = conditions.cidr {
+        match (cidr_str.parse::<IpNet>(), destination) {
+            (Ok(cidr), Destination::Address(ip) | Destination::Resolved { address: ip, .. })
+                if cidr.contains(&ip) => {}
+            // Invalid CIDR, non-matching address or unresolved host name destination
+            _ => return false,
+        }
+    }
+
+    if let Some(pattern) = conditions.domain {
+        match destination {
+            Destination::HostName(host) | Destination::Resolved { host, .. }
+                if domain_matches(pattern, host) => {}
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+/// Parse a comma-separated list of ports and port ranges (e.g., "25,6881-6889")
+pub(crate) fn parse_port_ranges(ports: &str) -> Option<Vec<RangeInclusive<u16>>> {
+    ports
+        .split(',')
+        .map(str::trim)
+        .map(|x| match x.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+                (start <= end).then_some(start..=end)
+            }
+            None => x.parse().ok().map(|p| p..=p),
+        })
+        .collect()
+}
+
+fn domain_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+
+    if pattern.contains('*') {
+        wildcard_matches(pattern.as_bytes(), host.as_bytes())
+    } else if let Some(suffix) = pattern.strip_prefix('.') {
+        host == suffix || host.ends_with(&pattern)
+    } else {
+        host == pattern
+    }
+}
+
+/// Match the string against the pattern where `*` stands for any sequence of characters.
+/// On a mismatch only the last `*` is extended, which keeps it O(n * m).
+fn wildcard_matches(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // The position after the last `*` and the string position it is matched up to
+    let mut backtrack = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                backtrack = Some((p, i));
+            }
+            Some(c) if *c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_i)) => {
+                    p = star_p;
+                    i = star_i + 1;
+                    backtrack = Some((star_p, i));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
 impl RulesEngine {
     /// Create a new rules engine from rules config
     pub fn from_config(rules: RulesConfig) -> Self {
@@ -124,14 +492,22 @@ impl RulesEngine {
     /// Create a default rules engine that allows all connections
     pub fn default_allow() -> Self {
         Self {
-            rules: RulesConfig { rule: vec![] },
+            rules: Default::default(),
         }
     }
 
     /// Evaluate connection against all rules
-    /// Returns the action from the first matching rule, or Allow if no rules match
-    pub fn evaluate(&self, client_ip: &IpAddr, client_random: Option<&[u8]>) -> RuleEvaluation {
-        if client_random.is_none()
+    /// Returns the action from the first matching rule, or Allow if no rules match.
+    ///
+    /// Without the request parameters, the evaluation stops at the first rule with
+    /// request conditions which may match the connection, and the connection is allowed:
+    /// the final decision is made on its requests.
+    pub fn evaluate(
+        &self,
+        connection: &ConnectionInfo,
+        request: Option<&RequestInfo>,
+    ) -> RuleEvaluation {
+        if connection.client_random.is_none()
             && self
                 .rules
                 .rule
@@ -142,7 +518,14 @@ impl RulesEngine {
         }
 
         for rule in &self.rules.rule {
-            if rule.matches(client_ip, client_random) {
+            if request.is_none()
+                && rule.has_request_conditions()
+                && rule.matches_connection(connection)
+            {
+                return RuleEvaluation::Allow;
+            }
+
+            if rule.matches_all(connection, request) {
                 return match rule.action {
                     RuleAction::Allow => RuleEvaluation::Allow,
                     RuleAction::Deny => RuleEvaluation::Deny,
@@ -154,6 +537,36 @@ impl RulesEngine {
         RuleEvaluation::Allow
     }
 
+    /// Evaluate outgoing connection of the `user` against the egress rules
+    /// Returns the action from the first matching rule, or Allow if no rules match
+    pub fn evaluate_egress(
+        &self,
+        user: &RequestInfo,
+        destination: Destination,
+        port: u16,
+        protocol: EgressProtocol,
+    ) -> RuleEvaluation {
+        self.rules
+            .egress
+            .iter()
+            .find(|r| r.matches_user(user) && r.matches(destination, port, protocol))
+            .map_or(RuleEvaluation::Allow, |r| match r.action {
+                RuleAction::Allow => RuleEvaluation::Allow,
+                RuleAction::Deny => RuleEvaluation::Deny,
+            })
+    }
+
+    /// Find the forwarder for an outgoing connection.
+    /// Returns the forwarder name from the first matching routing rule, or `None`
+    /// if no rules match, meaning the default forwarder.
+    pub fn evaluate_route(&self, route: &RouteInfo) -> Option<&str> {
+        self.rules
+            .route
+            .iter()
+            .find(|r| r.matches(route))
+            .map(|r| r.forwarder.as_str())
+    }
+
     /// Get a reference to the rules configuration
     pub fn config(&self) -> &RulesConfig {
         &self.rules
@@ -165,11 +578,23 @@ mod tests {
     use super::*;
     use std::str::FromStr;
 
+    fn connection<'a>(client_ip: IpAddr) -> ConnectionInfo<'a> {
+        ConnectionInfo {
+            client_ip,
+            client_random: None,
+            sni: None,
+        }
+    }
+
     #[test]
     fn test_cidr_rule_matching() {
         let rule = Rule {
             cidr: Some("192.168.1.0/24".to_string()),
             client_random_prefix: None,
+            sni: None,
+            username: None,
+            group: None,
+            user_agent: None,
             action: RuleAction::Allow,
         };
 
@@ -185,6 +610,10 @@ mod tests {
         let rule = Rule {
             cidr: None,
             client_random_prefix: Some("aabbcc".to_string()),
+            sni: None,
+            username: None,
+            group: None,
+            user_agent: None,
             action: RuleAction::Deny,
         };
 
@@ -203,6 +632,10 @@ mod tests {
         let rule = Rule {
             cidr: Some("10.0.0.0/8".to_string()),
             client_random_prefix: Some("ff".to_string()),
+            sni: None,
+            username: None,
+            group: None,
+            user_agent: None,
             action: RuleAction::Allow,
         };
 
@@ -221,20 +654,34 @@ mod tests {
     #[test]
     fn test_rules_engine_evaluation() {
         let rules = RulesConfig {
+            egress: vec![],
+            route: vec![],
             rule: vec![
                 Rule {
                     cidr: Some("192.168.1.0/24".to_string()),
                     client_random_prefix: None,
+                    sni: None,
+                    username: None,
+                    group: None,
+                    user_agent: None,
                     action: RuleAction::Deny,
                 },
                 Rule {
                     cidr: Some("10.0.0.0/8".to_string()),
                     client_random_prefix: None,
+                    sni: None,
+                    username: None,
+                    group: None,
+                    user_agent: None,
                     action: RuleAction::Allow,
                 },
                 Rule {
                     cidr: None,
                     client_random_prefix: None,
+                    sni: None,
+                    username: None,
+                    group: None,
+                    user_agent: None,
                     action: RuleAction::Deny, // Catch-all deny
                 },
             ],
@@ -246,9 +693,18 @@ mod tests {
         let ip_allow = IpAddr::from_str("10.1.2.3").unwrap();
         let ip_default = IpAddr::from_str("172.16.1.1").unwrap();
 
-        assert_eq!(engine.evaluate(&ip_deny, None), RuleEvaluation::Deny);
-        assert_eq!(engine.evaluate(&ip_allow, None), RuleEvaluation::Allow);
-        assert_eq!(engine.evaluate(&ip_default, None), RuleEvaluation::Deny); // Default deny
+        assert_eq!(
+            engine.evaluate(&connection(ip_deny), None),
+            RuleEvaluation::Deny
+        );
+        assert_eq!(
+            engine.evaluate(&connection(ip_allow), None),
+            RuleEvaluation::Allow
+        );
+        assert_eq!(
+            engine.evaluate(&connection(ip_default), None),
+            RuleEvaluation::Deny
+        ); // Default deny
     }
 
     #[test]
@@ -257,14 +713,20 @@ mod tests {
             rule: vec![Rule {
                 cidr: None,
                 client_random_prefix: Some("aabbcc".to_string()),
+                sni: None,
+                username: None,
+                group: None,
+                user_agent: None,
                 action: RuleAction::Allow,
             }],
+            egress: vec![],
+            route: vec![],
         };
 
         let engine = RulesEngine::from_config(rules);
         let ip = IpAddr::from_str("127.0.0.1").unwrap();
 
-        assert_eq!(engine.evaluate(&ip, None), RuleEvaluation::Deny);
+        assert_eq!(engine.evaluate(&connection(ip), None), RuleEvaluation::Deny);
     }
 
     #[test]
@@ -274,6 +736,10 @@ mod tests {
         let rule = Rule {
             cidr: None,
             client_random_prefix: Some("a0b0/f0f0".to_string()), // prefix=a0b0, mask=f0f0
+            sni: None,
+            username: None,
+            group: None,
+            user_agent: None,
             action: RuleAction::Allow,
         };
 
@@ -300,6 +766,10 @@ mod tests {
         let rule = Rule {
             cidr: None,
             client_random_prefix: Some("12345678/ffff0000".to_string()),
+            sni: None,
+            username: None,
+            group: None,
+            user_agent: None,
             action: RuleAction::Allow,
         };
 
@@ -320,6 +790,10 @@ mod tests {
         let rule = Rule {
             cidr: None,
             client_random_prefix: Some("aabbcc/".to_string()), // Invalid: empty mask
+            sni: None,
+            username: None,
+            group: None,
+            user_agent: None,
             action: RuleAction::Allow,
         };
 
@@ -329,4 +803,419 @@ mod tests {
         // Should not match due to invalid format
         assert!(!rule.matches(&ip, Some(&client_random)));
     }
+
+    #[test]
+    fn test_request_conditions() {
+        let rule = |username: Option<&str>, group: Option<&str>, user_agent: Option<&str>| Rule {
+            cidr: None,
+            client_random_prefix: None,
+            sni: None,
+            username: username.map(str::to_string),
+            group: group.map(str::to_string),
+            user_agent: user_agent.map(str::to_string),
+            action: RuleAction::Deny,
+        };
+        let engine = RulesEngine::from_config(RulesConfig {
+            rule: vec![
+                Rule {
+                    sni: Some("vpn1.example.com".to_string()),
+                    action: RuleAction::Allow,
+                    ..rule(None, Some("free"), None)
+                },
+                rule(None, Some("free"), None),
+                rule(None, None, Some("BadApp")),
+                rule(Some("mallory"), None, None),
+            ],
+            egress: vec![],
+            route: vec![],
+        });
+
+        let ip = IpAddr::from_str("10.1.2.3").unwrap();
+        let vpn1 = ConnectionInfo {
+            sni: Some("vpn1.example.com"),
+            ..connection(ip)
+        };
+        let vpn2 = ConnectionInfo {
+            sni: Some("vpn2.example.com"),
+            ..connection(ip)
+        };
+        let groups = ["free".to_string()];
+        let free_user = RequestInfo {
+            username: Some("alice"),
+            groups: &groups,
+            user_agent: None,
+        };
+
+        // The decision is deferred until a request
+        assert_eq!(engine.evaluate(&vpn2, None), RuleEvaluation::Allow);
+
+        assert_eq!(
+            engine.evaluate(&vpn1, Some(&free_user)),
+            RuleEvaluation::Allow
+        );
+        assert_eq!(
+            engine.evaluate(&vpn2, Some(&free_user)),
+            RuleEvaluation::Deny
+        );
+        assert_eq!(
+            engine.evaluate(
+                &vpn2,
+                Some(&RequestInfo {
+                    username: Some("bob"),
+                    user_agent: Some("Mozilla/5.0 BadApp/1.0"),
+                    ..Default::default()
+                })
+            ),
+            RuleEvaluation::Deny
+        );
+        assert_eq!(
+            engine.evaluate(
+                &vpn2,
+                Some(&RequestInfo {
+                    username: Some("mallory"),
+                    ..Default::default()
+                })
+            ),
+            RuleEvaluation::Deny
+        );
+        assert_eq!(
+            engine.evaluate(
+                &vpn2,
+                Some(&RequestInfo {
+                    username: Some("bob"),
+                    ..Default::default()
+                })
+            ),
+            RuleEvaluation::Allow
+        );
+    }
+
+    #[test]
+    fn test_wildcard_matching() {
+        for (pattern, s, expected) in [
+            ("*", "", true),
+            ("*", "abc", true),
+            ("a*c", "abc", true),
+            ("a*c", "abcd", false),
+            ("a*b*c", "aXbYbZc", true),
+            ("*.example.*", "www.example.org", true),
+            ("*.example.*", "example.org", false),
+            ("a**", "a", true),
+            ("", "a", false),
+        ] {
+            assert_eq!(
+                wildcard_matches(pattern.as_bytes(), s.as_bytes()),
+                expected,
+                "{} {}",
+                pattern,
+                s
+            );
+        }
+
+        // Exponential with the recursive backtracking
+        let pattern = "*a".repeat(64) + "b";
+        let s = "a".repeat(1024);
+        assert!(!wildcard_matches(pattern.as_bytes(), s.as_bytes()));
+    }
+
+    fn egress_rule(domain: Option<&str>, cidr: Option<&str>, ports: Option<&str>) -> EgressRule {
+        EgressRule {
+            domain: domain.map(str::to_string),
+            cidr: cidr.map(str::to_string),
+            ports: ports.map(str::to_string),
+            protocol: None,
+            username: None,
+            group: None,
+            action: RuleAction::Deny,
+        }
+    }
+
+    #[test]
+    fn test_egress_domain_matching() {
+        let host = |x| Destination::HostName(x);
+        let tcp = EgressProtocol::Tcp;
+
+        let exact = egress_rule(Some("example.com"), None, None);
+        assert!(exact.matches(host("example.com"), 443, tcp));
+        assert!(exact.matches(host("EXAMPLE.com."), 443, tcp));
+        assert!(!exact.matches(host("www.example.com"), 443, tcp));
+
+        let suffix = egress_rule(Some(".example.com"), None, None);
+        assert!(suffix.matches(host("example.com"), 443, tcp));
+        assert!(suffix.matches(host("a.b.example.com"), 443, tcp));
+        assert!(!suffix.matches(host("badexample.com"), 443, tcp));
+
+        let wildcard = egress_rule(Some("tracker*.example.*"), None, None);
+        assert!(wildcard.matches(host("tracker1.example.org"), 443, tcp));
+        assert!(!wildcard.matches(host("www.example.org"), 443, tcp));
+
+        let ip = IpAddr::from_str("93.184.216.34").unwrap();
+        assert!(!exact.matches(Destination::Address(ip), 443, tcp));
+    }
+
+    #[test]
+    fn test_egress_address_port_protocol_matching() {
+        let rule = EgressRule {
+            protocol: Some(EgressProtocol::Tcp),
+            ..egress_rule(None, Some("10.0.0.0/8"), Some("25, 6881-6889"))
+        };
+
+        let inside = Destination::Address(IpAddr::from_str("10.1.2.3").unwrap());
+        let outside = Destination::Address(IpAddr::from_str("192.168.1.1").unwrap());
+
+        assert!(rule.matches(inside, 25, EgressProtocol::Tcp));
+        assert!(rule.matches(inside, 6885, EgressProtocol::Tcp));
+        assert!(!rule.matches(inside, 6890, EgressProtocol::Tcp));
+        assert!(!rule.matches(inside, 25, EgressProtocol::Udp));
+        assert!(!rule.matches(outside, 25, EgressProtocol::Tcp));
+        assert!(!rule.matches(
+            Destination::HostName("example.com"),
+            25,
+            EgressProtocol::Tcp
+        ));
+
+        let invalid_ports = egress_rule(None, None, Some("25-x"));
+        assert!(!invalid_ports.matches(inside, 25, EgressProtocol::Tcp));
+    }
+
+    #[test]
+    fn test_egress_evaluation() {
+        let engine = RulesEngine::from_config(RulesConfig {
+            rule: vec![],
+            egress: vec![
+                EgressRule {
+                    action: RuleAction::Allow,
+                    ..egress_rule(Some("mail.example.com"), None, None)
+                },
+                egress_rule(None, None, Some("25")),
+            ],
+            route: vec![],
+        });
+
+        let host = |x| Destination::HostName(x);
+        assert_eq!(
+            engine.evaluate_egress(
+                &RequestInfo::default(),
+                host("mail.example.com"),
+                25,
+                EgressProtocol::Tcp
+            ),
+            RuleEvaluation::Allow
+        );
+        assert_eq!(
+            engine.evaluate_egress(
+                &RequestInfo::default(),
+                host("example.com"),
+                25,
+                EgressProtocol::Tcp
+            ),
+            RuleEvaluation::Deny
+        );
+        assert_eq!(
+            engine.evaluate_egress(
+                &RequestInfo::default(),
+                host("example.com"),
+                443,
+                EgressProtocol::Tcp
+            ),
+            RuleEvaluation::Allow
+        );
+    }
+
+    #[test]
+    fn test_egress_user_evaluation() {
+        let engine = RulesEngine::from_config(RulesConfig {
+            rule: vec![],
+            egress: vec![
+                EgressRule {
+                    username: Some("admin".to_string()),
+                    action: RuleAction::Allow,
+                    ..egress_rule(None, Some("10.0.0.0/8"), None)
+                },
+                EgressRule {
+                    group: Some("staff".to_string()),
+                    action: RuleAction::Allow,
+                    ..egress_rule(None, Some("10.0.0.0/8"), Some("443"))
+                },
+                egress_rule(None, Some("10.0.0.0/8"), None),
+            ],
+            route: vec![],
+        });
+
+        let staff = vec!["staff".to_string()];
+        let user = |username, groups| RequestInfo {
+            username,
+            groups,
+            user_agent: None,
+        };
+        let internal = Destination::Address(IpAddr::from_str("10.1.2.3").unwrap());
+        let tcp = EgressProtocol::Tcp;
+
+        assert_eq!(
+            engine.evaluate_egress(&user(Some("admin"), &[]), internal, 22, tcp),
+            RuleEvaluation::Allow
+        );
+        assert_eq!(
+            engine.evaluate_egress(&user(Some("alice"), &staff), internal, 443, tcp),
+            RuleEvaluation::Allow
+        );
+        assert_eq!(
+            engine.evaluate_egress(&user(Some("alice"), &staff), internal, 22, tcp),
+            RuleEvaluation::Deny
+        );
+        assert_eq!(
+            engine.evaluate_egress(&user(Some("bob"), &[]), internal, 443, tcp),
+            RuleEvaluation::Deny
+        );
+        assert_eq!(
+            engine.evaluate_egress(&user(None, &[]), internal, 22, tcp),
+            RuleEvaluation::Deny
+        );
+    }
+
+    #[test]
+    fn test_egress_resolved_destination() {
+        let engine = RulesEngine::from_config(RulesConfig {
+            rule: vec![],
+            egress: vec![
+                EgressRule {
+                    action: RuleAction::Allow,
+                    ..egress_rule(Some("intranet.example.com"), None, None)
+                },
+                egress_rule(None, Some("10.0.0.0/8"), None),
+            ],
+            route: vec![],
+        });
+
+        let resolved = |host, address| Destination::Resolved {
+            host,
+            address: IpAddr::from_str(address).unwrap(),
+        };
+        assert_eq!(
+            engine.evaluate_egress(
+                &RequestInfo::default(),
+                Destination::HostName("internal.example.com"),
+                443,
+                EgressProtocol::Tcp
+            ),
+            RuleEvaluation::Allow
+        );
+        assert_eq!(
+            engine.evaluate_egress(
+                &RequestInfo::default(),
+                resolved("internal.example.com", "10.1.2.3"),
+                443,
+                EgressProtocol::Tcp
+            ),
+            RuleEvaluation::Deny
+        );
+        assert_eq!(
+            engine.evaluate_egress(
+                &RequestInfo::default(),
+                resolved("intranet.example.com", "10.1.2.3"),
+                443,
+                EgressProtocol::Tcp
+            ),
+            RuleEvaluation::Allow
+        );
+        assert_eq!(
+            engine.evaluate_egress(
+                &RequestInfo::default(),
+                resolved("internal.example.com", "192.0.2.1"),
+                443,
+                EgressProtocol::Tcp
+            ),
+            RuleEvaluation::Allow
+        );
+    }
+
+    fn route_rule(forwarder: &str) -> RouteRule {
+        RouteRule {
+            username: None,
+            sni: None,
+            domain: None,
+            cidr: None,
+            ports: None,
+            protocol: None,
+            forwarder: forwarder.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_route_evaluation() {
+        let engine = RulesEngine::from_config(RulesConfig {
+            rule: vec![],
+            egress: vec![],
+            route: vec![
+                RouteRule {
+                    username: Some("alice".to_string()),
+                    ..route_rule("upstream2")
+                },
+                RouteRule {
+                    domain: Some(".video.example".to_string()),
+                    ..route_rule("direct")
+                },
+                RouteRule {
+                    sni: Some("udp.vpn.example.com".to_string()),
+                    protocol: Some(EgressProtocol::Udp),
+                    ..route_rule("direct")
+                },
+                RouteRule {
+                    cidr: Some("10.0.0.0/8".to_string()),
+                    ports: Some("22".to_string()),
+                    ..route_rule("office")
+                },
+            ],
+        });
+
+        let route = |username, sni, destination, port, protocol| RouteInfo {
+            username,
+            sni,
+            destination,
+            port,
+            protocol,
+        };
+        let host = |x| Destination::HostName(x);
+        let office = Destination::Address(IpAddr::from_str("10.1.2.3").unwrap());
+        let tcp = EgressProtocol::Tcp;
+        let udp = EgressProtocol::Udp;
+        let sni = "vpn.example.com";
+
+        assert_eq!(
+            engine.evaluate_route(&route(
+                Some("alice"),
+                sni,
+                host("cdn.video.example"),
+                443,
+                tcp
+            )),
+            Some("upstream2")
+        );
+        assert_eq!(
+            engine.evaluate_route(&route(
+                Some("bob"),
+                sni,
+                host("cdn.video.example"),
+                443,
+                tcp
+            )),
+            Some("direct")
+        );
+        assert_eq!(
+            engine.evaluate_route(&route(None, "udp.vpn.example.com", host("a.b"), 53, udp)),
+            Some("direct")
+        );
+        assert_eq!(
+            engine.evaluate_route(&route(None, "udp.vpn.example.com", host("a.b"), 53, tcp)),
+            None
+        );
+        assert_eq!(
+            engine.evaluate_route(&route(None, sni, office, 22, tcp)),
+            Some("office")
+        );
+        assert_eq!(
+            engine.evaluate_route(&route(None, sni, office, 80, tcp)),
+            None
+        );
+    }
 }
@@ -0,0 +1,305 @@
+use crate::tls_demultiplexer::Protocol;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+/// The registry of the active client sessions (tunnels)
+#[derive(Default)]
+pub(crate) struct Sessions {
+    /// Key is the tunnel ID
+    sessions: Mutex<HashMap<u64, Arc<Session>>>,
+}
+
+pub(crate) struct Session {
+    id: u64,
+    client_address: IpAddr,
+    protocol: Protocol,
+    sni: String,
+    started_at: SystemTime,
+    /// The name of the last authenticated user
+    username: Mutex<Option<String>>,
+    inbound_bytes: AtomicU64,
+    outbound_bytes: AtomicU64,
+    /// Fires once the session is requested to be closed
+    kick: Notify,
+    /// Fires once the session is attributed to a user
+    authenticated: Notify,
+}
+
+/// Removes the session from the registry on drop
+pub(crate) struct SessionGuard {
+    sessions: Arc<Sessions>,
+    session: Arc<Session>,
+}
+
+/// The snapshot of a session state
+#[derive(Serialize)]
+pub(crate) struct SessionInfo {
+    pub id: u64,
+    pub client_address: IpAddr,
+    pub username: Option<String>,
+    pub sni: String,
+    pub protocol: &'static str,
+    /// Seconds since the Unix epoch
+    pub started_at: u64,
+    /// Number of bytes uploaded by the client
+    pub inbound_bytes: u64,
+    /// Number of bytes downloaded by the client
+    pub outbound_bytes: u64,
+}
+
+impl Sessions {
+    /// Register a new session.
+    /// Returns [`None`] if the client address already has `max_per_address` sessions.
+    pub fn register(
+        self: Arc<Self>,
+        id: u64,
+        client_address: IpAddr,
+        protocol: Protocol,
+        sni: String,
+        max_per_address: Option<usize>,
+    ) -> Option<SessionGuard> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(max) = max_per_address {
+            let n = sessions
+                .values()
+                .filter(|x| x.client_address == client_address)
+                .count();
+            if n >= max {
+                return None;
+            }
+        }
+
+        let session = Arc::new(Session {
+            id,
+            client_address,
+            protocol,
+            sni,
+            started_at: SystemTime::now(),
+            username: Default::default(),
+            inbound_bytes: Default::default(),
+            outbound_bytes: Default::default(),
+            kick: Notify::new(),
+            authenticated: Notify::new(),
+        });
+        sessions.insert(id, session.clone());
+        drop(sessions);
+
+        Some(SessionGuard {
+            sessions: self,
+            session,
+        })
+    }
+
+    /// Attribute the session to the user.
+    /// Returns `false` if the user already has `max_per_user` other sessions.
+    pub fn set_username(
+        &self,
+        session: &Session,
+        username: &str,
+        max_per_user: Option<usize>,
+    ) -> bool {
+        let sessions = self.sessions.lock().unwrap();
+        if session.username.lock().unwrap().as_deref() == Some(username) {
+            return true;
+        }
+
+        if let Some(max) = max_per_user {
+            let n = sessions
+                .values()
+                .filter(|x| x.username.lock().unwrap().as_deref() == Some(username))
+                .count();
+            if n >= max {
+                return false;
+            }
+        }
+
+        *session.username.lock().unwrap() = Some(username.to_string());
+        session.authenticated.notify_waiters();
+        true
+    }
+
+    /// Get the snapshot of the active sessions sorted by ID
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut list: Vec<_> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .map(|x| x.info())
+            .collect();
+        list.sort_unstable_by_key(|x| x.id);
+        list
+    }
+
+    /// Request the session to be closed.
+    /// Returns `false` if there is no such session.
+    pub fn kick(&self, id: u64) -> bool {
+        match self.sessions.lock().unwrap().get(&id) {
+            None => false,
+            Some(x) => {
+                x.kick.notify_one();
+                true
+            }
+        }
+    }
+}
+
+impl Session {
+    pub fn client_address(&self) -> IpAddr {
+        self.client_address
+    }
+
+    pub fn add_inbound_bytes(&self, n: usize) {
+        self.inbound_bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_outbound_bytes(&self, n: usize) {
+        self.outbound_bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Wait for the session to be kicked
+    pub async fn kicked(&self) {
+        self.kick.notified().await
+    }
+
+    /// Wait for the session to be attributed to a user and get the name
+    pub async fn username(&self) -> String {
+        loop {
+            let notified = self.authenticated.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if let Some(x) = self.username.lock().unwrap().clone() {
+                return x;
+            }
+            notified.await;
+        }
+    }
+
+    pub fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id,
+            client_address: self.client_address,
+            username: self.username.lock().unwrap().clone(),
+            sni: self.sni.clone(),
+            protocol: self.protocol.as_str(),
+            started_at: self
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |x| x.as_secs()),
+            inbound_bytes: self.inbound_bytes.load(Ordering::Relaxed),
+            outbound_bytes: self.outbound_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl SessionGuard {
+    pub fn session(&self) -> Arc<Session> {
+        self.session.clone()
+    }
+}
+
+impl Deref for SessionGuard {
+    type Target = Session;
+
+    fn deref(&self) -> &Self::Target {
+        &self.session
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.sessions
+            .sessions
+            .lock()
+            .unwrap()
+            .remove(&self.session.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn guard_unregisters_session() {
+        let sessions = Arc::new(Sessions::default());
+        let guard = sessions
+            .clone()
+            .register(
+                1,
+                Ipv4Addr::LOCALHOST.into(),
+                Protocol::Http2,
+                "example.org".into(),
+                None,
+            )
+            .unwrap();
+        assert!(sessions.set_username(&guard, "a", None));
+        guard.add_inbound_bytes(10);
+        guard.add_outbound_bytes(20);
+
+        let list = sessions.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].username.as_deref(), Some("a"));
+        assert_eq!(list[0].inbound_bytes, 10);
+        assert_eq!(list[0].outbound_bytes, 20);
+
+        drop(guard);
+        assert!(sessions.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn kick_session() {
+        let sessions = Arc::new(Sessions::default());
+        let guard = sessions
+            .clone()
+            .register(
+                1,
+                Ipv4Addr::LOCALHOST.into(),
+                Protocol::Http1,
+                "example.org".into(),
+                None,
+            )
+            .unwrap();
+
+        assert!(!sessions.kick(2));
+        assert!(sessions.kick(1));
+        tokio::time::timeout(std::time::Duration::from_secs(1), guard.kicked())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn session_limits() {
+        let sessions = Arc::new(Sessions::default());
+        let register = |id, ip: Ipv4Addr| {
+            sessions.clone().register(
+                id,
+                ip.into(),
+                Protocol::Http2,
+                "example.org".into(),
+                Some(2),
+            )
+        };
+
+        let s1 = register(1, Ipv4Addr::LOCALHOST).unwrap();
+        let s2 = register(2, Ipv4Addr::LOCALHOST).unwrap();
+        assert!(register(3, Ipv4Addr::LOCALHOST).is_none());
+        let s3 = register(3, Ipv4Addr::new(127, 0, 0, 2)).unwrap();
+
+        assert!(sessions.set_username(&s1, "a", Some(1)));
+        assert!(sessions.set_username(&s1, "a", Some(1)));
+        assert!(!sessions.set_username(&s2, "a", Some(1)));
+        assert!(sessions.set_username(&s2, "b", Some(1)));
+        assert!(sessions.set_username(&s3, "a", None));
+
+        drop(s1);
+        assert!(register(1, Ipv4Addr::LOCALHOST).is_some());
+    }
+}
@@ -1,18 +1,23 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Formatter};
 use std::io;
 use std::io::ErrorKind;
-use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
 use std::path::Path;
 use std::time::Duration;
 
-use crate::{authentication, rules, utils};
+use crate::{authentication, dns, rules, utils};
+use authentication::http_auth::HttpAuthConfig;
 use authentication::jwt::{JwtAlgorithm, JwtAuthConfig};
+use authentication::password;
+use authentication::radius_auth::RadiusAuthConfig;
+use authentication::registry_based;
 use authentication::registry_based::Client;
+use base64::Engine;
 #[cfg(feature = "rt_doc")]
 use macros::{Getter, RuntimeDoc};
 use serde::{Deserialize, Serialize};
-use toml_edit::{Document, Item};
+use toml_edit::{Document, Item, Table};
 
 pub type Socks5BuilderResult<T> = Result<T, Socks5Error>;
 
@@ -34,6 +39,34 @@ pub enum ValidationError {
     /// No credentials configured while listening on a public address
     NoCredentialsOnPublicAddress,
     MissingJwtAuthConfig,
+    /// Invalid [`AuthSettings.jwt`]
+    Jwt(String),
+    /// Invalid [`Settings.rate_limit`] or client rate limits
+    RateLimit(String),
+    /// Invalid [`Settings.quota`]
+    Quota(String),
+    /// Invalid [`Settings.limits`]
+    Limits(String),
+    /// Invalid [`Settings.brute_force`]
+    BruteForce(String),
+    /// Invalid [`Settings.dns`]
+    Dns(String),
+    /// Invalid client entry of the credentials file
+    Credentials(String),
+    /// Invalid or missing [`AuthSettings.http`]
+    HttpAuth(String),
+    /// Invalid or missing [`AuthSettings.radius`]
+    Radius(String),
+    /// Invalid [`DirectForwarderSettings`]
+    Direct(String),
+    /// Invalid [`Socks5ForwarderSettings`]
+    Socks5(String),
+    /// Invalid [`HttpConnectForwarderSettings`]
+    HttpConnect(String),
+    /// Invalid [`CascadeForwarderSettings`]
+    Cascade(String),
+    /// Invalid [`Settings.forwarders`] or routing rules
+    Routing(String),
 }
 
 impl Debug for ValidationError {
@@ -55,6 +88,20 @@ impl Debug for ValidationError {
                 f,
                 "JWT auth mode is enabled, but [auth.jwt] configuration is missing or invalid"
             ),
+            Self::Jwt(x) => write!(f, "Invalid JWT settings: {}", x),
+            Self::RateLimit(x) => write!(f, "Invalid rate limit settings: {}", x),
+            Self::Quota(x) => write!(f, "Invalid quota settings: {}", x),
+            Self::Limits(x) => write!(f, "Invalid limits settings: {}", x),
+            Self::BruteForce(x) => write!(f, "Invalid brute-force protection settings: {}", x),
+            Self::Dns(x) => write!(f, "Invalid DNS settings: {}", x),
+            Self::Credentials(x) => write!(f, "Invalid credentials: {}", x),
+            Self::HttpAuth(x) => write!(f, "Invalid HTTP authentication settings: {}", x),
+            Self::Radius(x) => write!(f, "Invalid RADIUS settings: {}", x),
+            Self::Direct(x) => write!(f, "Invalid direct forwarder settings: {}", x),
+            Self::Socks5(x) => write!(f, "Invalid SOCKS5 forwarder settings: {}", x),
+            Self::HttpConnect(x) => write!(f, "Invalid HTTP CONNECT forwarder settings: {}", x),
+            Self::Cascade(x) => write!(f, "Invalid cascade forwarder settings: {}", x),
+            Self::Routing(x) => write!(f, "Invalid routing settings: {}", x),
         }
     }
 }
@@ -72,7 +119,7 @@ impl Debug for Socks5Error {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
 pub struct Settings {
     /// The address to listen on
@@ -128,6 +175,14 @@ pub struct Settings {
     /// The set of connection forwarder settings
     #[serde(default)]
     pub(crate) forward_protocol: ForwardProtocolSettings,
+    /// The named forwarders the routing rules of the rules file choose from.
+    /// The connections not matched by any routing rule go through
+    /// [forward_protocol](Settings.forward_protocol).
+    #[serde(default)]
+    pub(crate) forwarders: HashMap<String, ForwardProtocolSettings>,
+    /// The upstream DNS resolver settings.
+    /// If not set, the host names of the peers are resolved with the system resolver.
+    pub(crate) dns: Option<DnsSettings>,
     /// The set of enabled client listener codecs
     pub(crate) listen_protocols: ListenProtocolSettings,
     // TODO (ayakushin): fix docs
@@ -148,6 +203,19 @@ pub struct Settings {
     /// [[client]]
     /// username = "a"
     /// password = "b"
+    /// # Optional, override the limits from [`Settings::rate_limit`]
+    /// upload_bytes_per_sec = 1000000
+    /// download_bytes_per_sec = 5000000
+    /// # Optional, the groups the client belongs to, can be referenced in the rules
+    /// groups = ["free"]
+    /// # Optional, the data quota and its reset period (day | week | month | never)
+    /// quota_bytes = 10000000000
+    /// quota_period = "month"
+    /// # Optional, the account validity window, a date is treated as midnight UTC
+    /// not_before = 2025-01-01
+    /// expires_at = 2025-02-01T00:00:00Z
+    /// # Optional, suspend the account without deleting it
+    /// disabled = true
     ///
     /// [[client]]
     /// ...
@@ -159,6 +227,18 @@ pub struct Settings {
     pub(crate) clients: Vec<Client>,
     #[serde(default)]
     pub(crate) auth: AuthSettings,
+    /// The default per-user bandwidth limits.
+    /// Can be overridden for a specific client in the credentials file.
+    #[serde(default)]
+    pub(crate) rate_limit: RateLimitSettings,
+    /// The data quota accounting settings
+    pub(crate) quota: Option<QuotaSettings>,
+    /// The limits on the number of concurrent sessions and connections
+    #[serde(default)]
+    pub(crate) limits: LimitSettings,
+    /// The protection against the credentials guessing.
+    /// If not set, the failed authentication attempts are not limited.
+    pub(crate) brute_force: Option<BruteForceSettings>,
     /// The reverse proxy settings.
     /// With this one set up the endpoint does TLS termination on such connections and
     /// translates HTTP/x traffic into HTTP/1.1 protocol towards the server and back
@@ -175,6 +255,8 @@ pub struct Settings {
     pub(crate) icmp: Option<IcmpSettings>,
     /// The metrics gathering request handler settings
     pub(crate) metrics: Option<MetricsSettings>,
+    /// The administration API request handler settings
+    pub(crate) admin: Option<AdminSettings>,
     /// Path to the rules file for connection filtering.
     /// If not specified or file doesn't exist, all connections are allowed by default.
     #[serde(default)]
@@ -187,6 +269,13 @@ pub struct Settings {
     #[serde(default = "Settings::default_speedtest_enable")]
     pub(crate) speedtest_enable: bool,
 
+    /// The credentials file the clients were loaded from, see [`Settings::set_source_files`]
+    #[serde(skip)]
+    pub(crate) credentials_path: Option<String>,
+    /// The rules file the rules were loaded from, see [`Settings::set_source_files`]
+    #[serde(skip)]
+    pub(crate) rules_path: Option<String>,
+
     /// Whether an instance was built through a [`SettingsBuilder`].
     /// This flag is a workaround for absence of the ability to validate
     /// the deserialized structure.
@@ -202,6 +291,220 @@ pub struct AuthSettings {
     pub(crate) mode: AuthMode,
     #[serde(default)]
     pub(crate) jwt: Option<JwtSettings>,
+    /// The external HTTP authentication service.
+    /// Required in the `http` mode, and is tried after the JWT and the credentials
+    /// in the `mixed` mode if set.
+    #[serde(default)]
+    pub(crate) http: Option<HttpAuthSettings>,
+    /// The RADIUS server.
+    /// Required in the `radius` mode, and is tried after the other providers
+    /// in the `mixed` mode if set.
+    /// Also configures the accounting of the sessions.
+    #[serde(default)]
+    pub(crate) radius: Option<RadiusSettings>,
+    /// Reject the credentials file entries with the plain text passwords,
+    /// only the argon2, scrypt and bcrypt hashes are accepted
+    #[serde(default)]
+    pub(crate) reject_plaintext_passwords: bool,
+    /// Timeout of a client authentication, the client is rejected on expiration
+    #[serde(default = "AuthSettings::default_timeout")]
+    #[serde(rename = "timeout_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) timeout: Duration,
+    /// Interval between the re-authentications of the established sessions.
+    /// A session is closed once a client it has authenticated is rejected.
+    /// The sessions are also re-authenticated on the settings reload.
+    /// Zero disables the periodic re-authentication.
+    #[serde(default = "AuthSettings::default_reauthentication_interval")]
+    #[serde(rename = "reauthentication_interval_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) reauthentication_interval: Duration,
+}
+
+impl AuthSettings {
+    pub fn default_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    pub fn default_reauthentication_interval() -> Duration {
+        Duration::from_secs(300)
+    }
+}
+
+/// The per-user bandwidth limits.
+/// A limit is shared by all the TCP connections and UDP multiplexers of a user,
+/// including the ones on concurrent client sessions.
+/// The users are identified by the authenticated username, the connections without one
+/// are not limited.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct RateLimitSettings {
+    /// The maximum upload rate of a user in bytes per second. Unlimited if not set.
+    #[serde(default)]
+    pub(crate) upload_bytes_per_sec: Option<u64>,
+    /// The maximum download rate of a user in bytes per second. Unlimited if not set.
+    #[serde(default)]
+    pub(crate) download_bytes_per_sec: Option<u64>,
+}
+
+/// The limits on the number of concurrent client sessions and connections.
+/// The requests exceeding a limit are rejected, nothing is limited if a limit is not set.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct LimitSettings {
+    /// The maximum number of concurrent sessions authenticated with the same username
+    #[serde(default)]
+    pub(crate) max_sessions_per_user: Option<usize>,
+    /// The maximum number of concurrent sessions from the same client IP address
+    #[serde(default)]
+    pub(crate) max_sessions_per_ip: Option<usize>,
+    /// The maximum number of concurrent TCP connections within a session
+    #[serde(default)]
+    pub(crate) max_tcp_connections_per_session: Option<usize>,
+    /// The maximum number of concurrent UDP flows within a UDP multiplexer
+    #[serde(default)]
+    pub(crate) max_udp_flows_per_multiplexer: Option<usize>,
+}
+
+/// The protection against the credentials guessing.
+/// The failed authentication attempts are counted per client IP address and per username.
+/// Starting from `backoff_after_failures` failures, each next failure blocks the client
+/// for a period doubling from `initial_backoff`, and `ban_after_failures` failures
+/// result in a ban for `ban_duration`.
+/// A blocked IP address is dropped before the TLS handshake, the authentication attempts
+/// of a blocked username are rejected without checking the credentials.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct BruteForceSettings {
+    /// The number of failures after which the client is blocked for the backoff period
+    #[serde(default = "BruteForceSettings::default_backoff_after_failures")]
+    pub(crate) backoff_after_failures: u32,
+    /// The first backoff period, doubled on each next failure
+    #[serde(default = "BruteForceSettings::default_initial_backoff")]
+    #[serde(rename = "initial_backoff_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) initial_backoff: Duration,
+    /// The number of failures after which the client is banned
+    #[serde(default = "BruteForceSettings::default_ban_after_failures")]
+    pub(crate) ban_after_failures: u32,
+    /// Duration of a ban
+    #[serde(default = "BruteForceSettings::default_ban_duration")]
+    #[serde(rename = "ban_duration_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) ban_duration: Duration,
+    /// The failures are forgotten if the client has not failed for this period
+    #[serde(default = "BruteForceSettings::default_failure_window")]
+    #[serde(rename = "failure_window_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) failure_window: Duration,
+}
+
+/// The data quota accounting settings.
+/// The quotas themselves are configured per client in the credentials file.
+/// Without these settings the usage counters are not persisted across restarts.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct QuotaSettings {
+    /// The file the usage counters are persisted to
+    pub(crate) state_file: String,
+    /// Interval between the counters savings
+    #[serde(default = "QuotaSettings::default_save_interval")]
+    #[serde(rename = "save_interval_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) save_interval: Duration,
+}
+
+/// The upstream DNS resolver settings.
+/// The settings are not affected by settings reloading.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct DnsSettings {
+    /// The upstream servers, tried in order until one of them answers.
+    /// The supported forms are:
+    /// * plain DNS over UDP with the fallback to TCP: `8.8.8.8`, `udp://8.8.8.8:53`
+    /// * plain DNS over TCP: `tcp://8.8.8.8`
+    /// * DNS-over-TLS: `tls://dns.google`, `tls://8.8.8.8:853`
+    /// * DNS-over-HTTPS: `https://dns.google/dns-query`
+    ///
+    /// The host names of the encrypted upstreams are resolved with the system resolver.
+    pub(crate) upstreams: Vec<String>,
+    /// Timeout of a query to an upstream
+    #[serde(default = "DnsSettings::default_query_timeout")]
+    #[serde(rename = "query_timeout_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) query_timeout: Duration,
+    /// The maximum number of the cached answers, 0 disables the caching.
+    /// The answers are cached for their time to live.
+    #[serde(default = "DnsSettings::default_cache_size")]
+    pub(crate) cache_size: usize,
+    /// The delay before the connection attempt to the next address of a peer
+    /// if the previous attempt has not completed yet (happy eyeballs)
+    #[serde(default = "DnsSettings::default_happy_eyeballs_delay")]
+    #[serde(rename = "happy_eyeballs_delay_ms")]
+    #[serde(
+        deserialize_with = "deserialize_duration_ms",
+        serialize_with = "serialize_duration_ms"
+    )]
+    pub(crate) happy_eyeballs_delay: Duration,
+    /// Whether the plain DNS queries tunneled to UDP port 53 are answered by the endpoint
+    /// through the upstreams instead of being forwarded to their destinations
+    #[serde(default)]
+    pub(crate) intercept: bool,
+    /// The files with the names blocked for the intercepted queries.
+    /// A file may be in the hosts file format (`0.0.0.0 example.com`, blocks the exact name),
+    /// or contain the adblock-style rules (`||example.com^`, `@@||example.com^`)
+    /// and the plain names (`example.com`), which block the subdomains as well.
+    #[serde(default)]
+    pub(crate) blocklists: Vec<String>,
+    /// How the queries for the blocked names are answered
+    #[serde(default)]
+    pub(crate) blocking_mode: DnsBlockingMode,
+}
+
+/// The answer to a DNS query for a blocked name
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsBlockingMode {
+    /// The name does not exist
+    #[default]
+    Nxdomain,
+    /// The name resolves to `0.0.0.0` or `::`
+    Unspecified,
+}
+
+/// The period after which the data quota usage of a client is reset.
+/// The periods are calendar-based in UTC.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaPeriod {
+    Day,
+    /// Starts on Monday
+    Week,
+    #[default]
+    Month,
+    /// The usage is never reset
+    Never,
 }
 
 #[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
@@ -213,12 +516,17 @@ pub enum AuthMode {
     Jwt,
     #[serde(rename = "mixed")]
     Mixed,
+    #[serde(rename = "http")]
+    Http,
+    #[serde(rename = "radius")]
+    Radius,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 #[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
 pub struct JwtSettings {
-    pub(crate) algorithm: JwtAlgorithm,
+    #[serde(default)]
+    pub(crate) algorithm: Option<JwtAlgorithm>,
     #[serde(default)]
     pub(crate) issuer: Option<String>,
     #[serde(default)]
@@ -231,6 +539,23 @@ pub struct JwtSettings {
     pub(crate) public_key_path: Option<String>,
     #[serde(default)]
     pub(crate) hmac_secret_env: Option<String>,
+    /// Path to a JSON Web Key Set file, the keys are selected by the `kid` header of a token
+    #[serde(default)]
+    pub(crate) jwks_path: Option<String>,
+    /// URL of a JSON Web Key Set, the keys are selected by the `kid` header of a token
+    #[serde(default)]
+    pub(crate) jwks_url: Option<String>,
+    /// Interval of reloading the JSON Web Key Set
+    #[serde(default = "JwtSettings::default_jwks_refresh_interval_secs")]
+    pub(crate) jwks_refresh_interval_secs: u64,
+    /// Maximum age of a token counted from its `iat` claim.
+    /// If set, the tokens without the `iat` claim are rejected.
+    #[serde(default)]
+    pub(crate) max_token_age_secs: Option<u64>,
+    /// Path to a file with the IDs (`jti` claims) of the revoked tokens, one per line.
+    /// The file is reloaded on change.
+    #[serde(default)]
+    pub(crate) jti_denylist_path: Option<String>,
 }
 
 impl JwtSettings {
@@ -242,15 +567,224 @@ impl JwtSettings {
         "sub".to_string()
     }
 
+    fn default_jwks_refresh_interval_secs() -> u64 {
+        600
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        let key_sources = [
+            self.public_key_path.is_some(),
+            self.hmac_secret_env.is_some(),
+            self.jwks_path.is_some(),
+            self.jwks_url.is_some(),
+        ];
+        if key_sources.iter().filter(|x| **x).count() != 1 {
+            return Err(ValidationError::Jwt(
+                "Exactly one of public_key_path, hmac_secret_env, jwks_path and jwks_url must be set"
+                    .into(),
+            ));
+        }
+
+        match self.algorithm {
+            None if self.public_key_path.is_some() || self.hmac_secret_env.is_some() => {
+                return Err(ValidationError::Jwt(
+                    "Algorithm must be set unless the keys come from JWKS".into(),
+                ))
+            }
+            Some(JwtAlgorithm::HS256) if self.public_key_path.is_some() => {
+                return Err(ValidationError::Jwt(
+                    "HS256 requires hmac_secret_env".into(),
+                ))
+            }
+            Some(x) if x != JwtAlgorithm::HS256 && self.hmac_secret_env.is_some() => {
+                return Err(ValidationError::Jwt(format!(
+                    "hmac_secret_env is not applicable to {:?}",
+                    x
+                )))
+            }
+            _ => (),
+        }
+
+        if let Some(url) = &self.jwks_url {
+            match url.parse::<http::Uri>() {
+                Ok(x) if matches!(x.scheme_str(), Some("http" | "https")) && x.host().is_some() => {
+                }
+                _ => return Err(ValidationError::Jwt(format!("Invalid JWKS URL: {}", url))),
+            }
+        }
+
+        if self.jwks_refresh_interval_secs == 0 {
+            return Err(ValidationError::Jwt(
+                "JWKS refresh interval must be positive".into(),
+            ));
+        }
+
+        if self.max_token_age_secs == Some(0) {
+            return Err(ValidationError::Jwt(
+                "Maximum token age must be positive".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
     pub fn to_auth_config(&self) -> JwtAuthConfig {
         JwtAuthConfig {
-            algorithm: self.algorithm.clone(),
+            algorithm: self.algorithm,
             issuer: self.issuer.clone(),
             audience: self.audience.clone(),
             leeway_seconds: self.leeway_seconds,
             username_claim: self.username_claim.clone(),
             public_key_path: self.public_key_path.clone(),
             hmac_secret_env: self.hmac_secret_env.clone(),
+            jwks_path: self.jwks_path.clone(),
+            jwks_url: self.jwks_url.clone(),
+            jwks_refresh_interval_secs: self.jwks_refresh_interval_secs,
+            max_token_age_secs: self.max_token_age_secs,
+            jti_denylist_path: self.jti_denylist_path.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct HttpAuthSettings {
+    /// `http` or `https` URL the credentials are POSTed to as
+    /// `{"username": "...", "password": "..."}`.
+    /// A `2xx` response passes the client, `401` and `403` reject it.
+    pub(crate) url: String,
+    /// Timeout of a request to the service
+    #[serde(default = "HttpAuthSettings::default_request_timeout_secs")]
+    pub(crate) request_timeout_secs: u64,
+    /// How long a passed check is cached, 0 disables the caching
+    #[serde(default = "HttpAuthSettings::default_positive_cache_ttl_secs")]
+    pub(crate) positive_cache_ttl_secs: u64,
+    /// How long a rejected check is cached, 0 disables the caching
+    #[serde(default = "HttpAuthSettings::default_negative_cache_ttl_secs")]
+    pub(crate) negative_cache_ttl_secs: u64,
+}
+
+impl HttpAuthSettings {
+    fn default_request_timeout_secs() -> u64 {
+        5
+    }
+
+    fn default_positive_cache_ttl_secs() -> u64 {
+        300
+    }
+
+    fn default_negative_cache_ttl_secs() -> u64 {
+        30
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        match self.url.parse::<http::Uri>() {
+            Ok(x) if matches!(x.scheme_str(), Some("http" | "https")) && x.host().is_some() => (),
+            _ => {
+                return Err(ValidationError::HttpAuth(format!(
+                    "Invalid URL: {}",
+                    self.url
+                )))
+            }
+        }
+
+        if self.request_timeout_secs == 0 {
+            return Err(ValidationError::HttpAuth(
+                "Request timeout must be positive".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn to_auth_config(&self) -> HttpAuthConfig {
+        HttpAuthConfig {
+            url: self.url.clone(),
+            request_timeout_secs: self.request_timeout_secs,
+            positive_cache_ttl_secs: self.positive_cache_ttl_secs,
+            negative_cache_ttl_secs: self.negative_cache_ttl_secs,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct RadiusSettings {
+    /// `host:port` of the RADIUS authentication server
+    pub(crate) server: String,
+    /// The name of the environment variable containing the shared secret
+    pub(crate) secret_env: String,
+    /// The `NAS-Identifier` attribute of the requests
+    #[serde(default = "RadiusSettings::default_nas_identifier")]
+    pub(crate) nas_identifier: String,
+    /// Timeout of a single request attempt
+    #[serde(default = "RadiusSettings::default_timeout_secs")]
+    pub(crate) timeout_secs: u64,
+    /// Number of the request retransmissions on timeout
+    #[serde(default = "RadiusSettings::default_retries")]
+    pub(crate) retries: usize,
+    /// `host:port` of the RADIUS accounting server.
+    /// If set, the Start, Interim-Update and Stop records are sent for every session.
+    /// Not affected by settings reloading.
+    #[serde(default)]
+    pub(crate) accounting_server: Option<String>,
+    /// Interval between the Interim-Update records of a session
+    #[serde(default = "RadiusSettings::default_interim_interval_secs")]
+    pub(crate) interim_interval_secs: u64,
+}
+
+impl RadiusSettings {
+    fn default_nas_identifier() -> String {
+        "trusttunnel".to_string()
+    }
+
+    fn default_timeout_secs() -> u64 {
+        3
+    }
+
+    fn default_retries() -> usize {
+        2
+    }
+
+    fn default_interim_interval_secs() -> u64 {
+        300
+    }
+
+    fn validate(&self) -> Result<(), ValidationError> {
+        for server in std::iter::once(&self.server).chain(&self.accounting_server) {
+            match server.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => (),
+                _ => {
+                    return Err(ValidationError::Radius(format!(
+                        "Invalid server address: {}",
+                        server
+                    )))
+                }
+            }
+        }
+
+        if self.secret_env.is_empty() {
+            return Err(ValidationError::Radius("Secret is not set".into()));
+        }
+        if self.timeout_secs == 0 {
+            return Err(ValidationError::Radius("Timeout must be positive".into()));
+        }
+        if self.interim_interval_secs == 0 {
+            return Err(ValidationError::Radius(
+                "Interim update interval must be positive".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn to_auth_config(&self) -> RadiusAuthConfig {
+        RadiusAuthConfig {
+            server: self.server.clone(),
+            secret_env: self.secret_env.clone(),
+            nas_identifier: self.nas_identifier.clone(),
+            timeout_secs: self.timeout_secs,
+            retries: self.retries,
         }
     }
 }
@@ -260,6 +794,11 @@ impl Default for AuthSettings {
         Self {
             mode: AuthMode::Credentials,
             jwt: None,
+            http: None,
+            radius: None,
+            reject_plaintext_passwords: false,
+            timeout: AuthSettings::default_timeout(),
+            reauthentication_interval: AuthSettings::default_reauthentication_interval(),
         }
     }
 }
@@ -287,6 +826,43 @@ pub struct TlsHostInfo {
     /// When a client sends one of these SNIs, the connection will use this host's certificate.
     #[serde(default)]
     pub allowed_sni: Vec<String>,
+    /// Mutual TLS: request the clients connecting to this host to present a certificate.
+    /// A client with a valid certificate is authenticated by the username mapped from it.
+    #[serde(default)]
+    pub client_certificate: Option<ClientCertificateSettings>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "rt_doc", derive(RuntimeDoc))]
+pub struct ClientCertificateSettings {
+    /// Path to a file containing the CA certificates the client certificates
+    /// are verified against
+    #[serde(deserialize_with = "deserialize_file_path")]
+    pub ca_path: String,
+    /// Reject the clients without a certificate.
+    /// Otherwise such clients are authenticated in the regular way.
+    #[serde(default)]
+    pub required: bool,
+    /// The certificate field the username is taken from
+    #[serde(default)]
+    pub username_field: CertificateUsernameField,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum CertificateUsernameField {
+    /// The common name of the subject
+    #[default]
+    #[serde(rename = "common_name")]
+    CommonName,
+    /// The first e-mail address of the subject alternative names
+    #[serde(rename = "san_email")]
+    SanEmail,
+    /// The first DNS name of the subject alternative names
+    #[serde(rename = "san_dns")]
+    SanDns,
+    /// The first URI of the subject alternative names
+    #[serde(rename = "san_uri")]
+    SanUri,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -325,7 +901,7 @@ pub struct TlsHostsSettings {
     built: bool,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[cfg_attr(feature = "rt_doc", derive(RuntimeDoc))]
 pub struct ReverseProxySettings {
     /// The origin server address
@@ -342,7 +918,7 @@ pub struct ReverseProxySettings {
 }
 
 /// The set of connection forwarder settings
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case")]
 #[cfg_attr(feature = "rt_doc", derive(RuntimeDoc))]
 pub enum ForwardProtocolSettings {
@@ -350,25 +926,196 @@ pub enum ForwardProtocolSettings {
     Direct(DirectForwarderSettings),
     /// A SOCKS5 forwarder routes a connection though a SOCKS5 proxy
     Socks5(Socks5ForwarderSettings),
+    /// An HTTP CONNECT forwarder routes a TCP connection through an HTTP proxy.
+    /// UDP and ICMP are routed directly.
+    HttpConnect(HttpConnectForwarderSettings),
+    /// A cascade forwarder routes TCP connections and UDP through another endpoint
+    /// over an HTTP/2 session. ICMP is routed directly.
+    Cascade(CascadeForwarderSettings),
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct DirectForwarderSettings {}
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct DirectForwarderSettings {
+    /// The pool of the source addresses the outbound TCP connections and UDP sockets
+    /// are bound to.
+    /// A client sticks to an address of the pool of the destination address family.
+    /// If the pool has no address of the family, the system chooses the source address.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) source_addresses: Vec<IpAddr>,
+    /// The IPv6 prefix a random source address is generated from for each client,
+    /// e.g. `2001:db8:1:2::/64`.
+    /// Takes precedence over the IPv6 addresses of [`DirectForwarderSettings.source_addresses`].
+    /// The prefix must be routed to the host as local,
+    /// e.g. `ip -6 route add local 2001:db8:1:2::/64 dev lo`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) ipv6_source_prefix: Option<String>,
+    /// What the source address of a client sticks to
+    #[serde(default)]
+    pub(crate) source_affinity: SourceAffinity,
+}
 
-#[derive(Serialize, Deserialize)]
+/// What the source address of a client sticks to
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceAffinity {
+    /// The authenticated username, or the IP address of an unauthenticated client
+    #[default]
+    User,
+    /// The client session, i.e. each session of a user may get a different address
+    Session,
+}
+
+pub struct DirectForwarderSettingsBuilder {
+    settings: DirectForwarderSettings,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 #[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
 pub struct Socks5ForwarderSettings {
-    /// The address of a proxy
+    /// The address of a proxy.
+    /// Not set if the proxies are listed in `upstreams`.
+    #[serde(default = "Socks5ForwarderSettings::default_address")]
     pub(crate) address: SocketAddr,
+    /// The addresses of the pool of proxies the connections are balanced across
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) upstreams: Vec<SocketAddr>,
+    /// The way an upstream proxy is chosen for a connection
+    #[serde(default)]
+    pub(crate) balancing: Socks5Balancing,
+    /// The active health checks of the upstream proxies.
+    /// If not set, the upstreams are never ejected from the pool.
+    #[serde(default)]
+    pub(crate) health_check: Option<Socks5HealthCheckSettings>,
     /// Whether the extended authentication is enabled
     #[serde(default)]
     pub(crate) extended_auth: bool,
 }
 
+/// The way an upstream proxy of a pool is chosen for a connection
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Socks5Balancing {
+    /// The upstreams are used in turn
+    #[default]
+    RoundRobin,
+    /// The upstream with the fewest active connections is used
+    LeastConnections,
+    /// The connections of a user always use the same upstream while it is healthy.
+    /// The clients without a username are distinguished by their IP address.
+    ConsistentHash,
+}
+
+/// The active health checks of the upstream proxies.
+/// A probe connects to an upstream and performs the SOCKS5 greeting.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct Socks5HealthCheckSettings {
+    /// Interval between the probes of an upstream
+    #[serde(default = "Socks5HealthCheckSettings::default_interval")]
+    #[serde(rename = "interval_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) interval: Duration,
+    /// Timeout of a probe, as well as of a connection attempt to an upstream
+    #[serde(default = "Socks5HealthCheckSettings::default_timeout")]
+    #[serde(rename = "timeout_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) timeout: Duration,
+    /// The number of consecutive failed probes or connection attempts
+    /// after which an upstream is ejected from the pool
+    #[serde(default = "Socks5HealthCheckSettings::default_unhealthy_threshold")]
+    pub(crate) unhealthy_threshold: u32,
+    /// The number of consecutive successful probes after which an ejected upstream
+    /// is restored
+    #[serde(default = "Socks5HealthCheckSettings::default_healthy_threshold")]
+    pub(crate) healthy_threshold: u32,
+}
+
 pub struct Socks5ForwarderSettingsBuilder {
     settings: Socks5ForwarderSettings,
 }
 
+pub struct Socks5HealthCheckSettingsBuilder {
+    settings: Socks5HealthCheckSettings,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct HttpConnectForwarderSettings {
+    /// The address of a proxy
+    pub(crate) address: SocketAddr,
+    /// The HTTP version of the CONNECT requests
+    #[serde(default)]
+    pub(crate) protocol: HttpConnectProtocol,
+    /// Whether the connection to the proxy is secured with TLS.
+    /// The proxy certificate is verified against the system CAs.
+    #[serde(default)]
+    pub(crate) tls: bool,
+    /// The name the proxy certificate is verified for.
+    /// If not set, the certificate is verified for the proxy IP address.
+    #[serde(default)]
+    pub(crate) tls_server_name: Option<String>,
+    /// The username of the basic authentication on the proxy
+    #[serde(default)]
+    pub(crate) username: Option<String>,
+    /// The name of the environment variable containing the password
+    /// of the basic authentication on the proxy
+    #[serde(default)]
+    pub(crate) password_env: Option<String>,
+    /// Whether the identity of the VPN client is passed to the proxy
+    /// in the `X-Tunnel-*` request headers. See README for details.
+    #[serde(default)]
+    pub(crate) forward_identity: bool,
+    /// Whether UDP and ICMP traffic, which the CONNECT method can't carry,
+    /// is routed directly from the endpoint. If disabled, it is rejected.
+    #[serde(default)]
+    pub(crate) direct_datagrams: bool,
+}
+
+/// The HTTP version of the CONNECT requests to the proxy
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpConnectProtocol {
+    /// Each connection is tunneled through a separate HTTP/1.1 connection
+    #[default]
+    Http1,
+    /// The connections of a client session are multiplexed over a single HTTP/2 connection.
+    /// Without TLS the proxy must accept HTTP/2 with prior knowledge.
+    Http2,
+}
+
+pub struct HttpConnectForwarderSettingsBuilder {
+    settings: HttpConnectForwarderSettings,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct CascadeForwarderSettings {
+    /// The address of the next endpoint
+    pub(crate) address: SocketAddr,
+    /// The name sent in SNI to the next endpoint, selects its `hostname`
+    pub(crate) server_name: String,
+    /// The username of the credentials on the next endpoint
+    pub(crate) username: String,
+    /// The name of the environment variable containing the password
+    /// of the credentials on the next endpoint
+    pub(crate) password_env: String,
+    /// The base64-encoded SHA-256 digests of the public keys the certificate
+    /// of the next endpoint is accepted with. If set, the system CAs are not consulted.
+    #[serde(default)]
+    pub(crate) certificate_pins: Vec<String>,
+}
+
+pub struct CascadeForwarderSettingsBuilder {
+    settings: CascadeForwarderSettings,
+}
+
 /// The set of enabled client listener codecs
 #[derive(Clone, Default, Serialize, Deserialize)]
 #[cfg_attr(feature = "rt_doc", derive(RuntimeDoc))]
@@ -386,7 +1133,7 @@ pub struct ListenProtocolSettings {
 
 /// The ICMP forwarding settings.
 /// Setting up this feature requires superuser rights on some systems.
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
 pub struct IcmpSettings {
     /// The name of a network interface to bind the outbound ICMP socket to
@@ -409,7 +1156,7 @@ pub struct IcmpSettings {
 }
 
 /// The metrics gathering request handler settings
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
 pub struct MetricsSettings {
     /// The address to listen on for settings export requests
@@ -423,6 +1170,39 @@ pub struct MetricsSettings {
         serialize_with = "serialize_duration_secs"
     )]
     pub(crate) request_timeout: Duration,
+    /// Whether to also report the client sessions, traffic and connections
+    /// labeled by the authenticated username
+    #[serde(default)]
+    pub(crate) per_user: bool,
+    /// The maximum number of distinct usernames reported in the per-user metrics.
+    /// The users beyond the limit are reported under the `other` label.
+    #[serde(default = "MetricsSettings::default_max_users")]
+    pub(crate) max_users: usize,
+}
+
+/// The administration API request handler settings.
+/// The API allows listing and kicking the active client sessions, managing the client
+/// credentials and replacing the connection filtering rules at runtime.
+/// The changes made through the API are not written to the settings files.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rt_doc", derive(Getter, RuntimeDoc))]
+pub struct AdminSettings {
+    /// The address to listen on for administration requests
+    #[serde(default = "AdminSettings::default_listen_address")]
+    pub(crate) address: SocketAddr,
+    /// Timeout of an administration request
+    #[serde(default = "AdminSettings::default_request_timeout")]
+    #[serde(rename = "request_timeout_secs")]
+    #[serde(
+        deserialize_with = "deserialize_duration_secs",
+        serialize_with = "serialize_duration_secs"
+    )]
+    pub(crate) request_timeout: Duration,
+    /// The name of the environment variable containing the token.
+    /// Requests must carry it in the `Authorization: Bearer <token>` header.
+    /// The listener fails to start if the variable is not set or empty.
+    #[serde(default = "AdminSettings::default_token_env")]
+    pub(crate) token_env: String,
 }
 
 /// The set of HTTP/1.1 listener codec settings
@@ -543,17 +1323,57 @@ pub struct MetricsSettingsBuilder {
     settings: MetricsSettings,
 }
 
-impl Settings {
-    pub fn builder() -> SettingsBuilder {
-        SettingsBuilder::new()
-    }
+pub struct AdminSettingsBuilder {
+    settings: AdminSettings,
+}
 
-    pub(crate) fn is_built(&self) -> bool {
-        self.built
-    }
+pub struct RateLimitSettingsBuilder {
+    settings: RateLimitSettings,
+}
 
-    pub(crate) fn validate(&self) -> Result<(), ValidationError> {
-        if self.listen_address.ip().is_unspecified() && self.listen_address.port() == 0 {
+pub struct QuotaSettingsBuilder {
+    settings: QuotaSettings,
+}
+
+pub struct DnsSettingsBuilder {
+    settings: DnsSettings,
+}
+
+pub struct LimitSettingsBuilder {
+    settings: LimitSettings,
+}
+
+pub struct BruteForceSettingsBuilder {
+    settings: BruteForceSettings,
+}
+
+impl Settings {
+    pub fn builder() -> SettingsBuilder {
+        SettingsBuilder::new()
+    }
+
+    pub(crate) fn is_built(&self) -> bool {
+        self.built
+    }
+
+    /// Remember the credentials and rules files the settings `document` refers to.
+    /// The administration API saves its changes of the credentials and the rules to them,
+    /// so that the changes survive the settings reload.
+    pub fn set_source_files(&mut self, document: &str) {
+        let document = document.parse::<Document>().ok();
+        let path = |key| {
+            document
+                .as_ref()
+                .and_then(|x| x.get(key))
+                .and_then(Item::as_str)
+                .map(String::from)
+        };
+        self.credentials_path = path("credentials_file");
+        self.rules_path = path("rules_file");
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ValidationError> {
+        if self.listen_address.ip().is_unspecified() && self.listen_address.port() == 0 {
             return Err(ValidationError::ListenAddressNotSet);
         }
 
@@ -572,6 +1392,31 @@ impl Settings {
         if matches!(self.auth.mode, AuthMode::Jwt | AuthMode::Mixed) && self.auth.jwt.is_none() {
             return Err(ValidationError::MissingJwtAuthConfig);
         }
+        self.auth
+            .jwt
+            .as_ref()
+            .map(JwtSettings::validate)
+            .transpose()?;
+        if self.auth.mode == AuthMode::Http && self.auth.http.is_none() {
+            return Err(ValidationError::HttpAuth(
+                "HTTP auth mode is enabled, but [auth.http] is missing".into(),
+            ));
+        }
+        self.auth
+            .http
+            .as_ref()
+            .map(HttpAuthSettings::validate)
+            .transpose()?;
+        if self.auth.mode == AuthMode::Radius && self.auth.radius.is_none() {
+            return Err(ValidationError::Radius(
+                "RADIUS auth mode is enabled, but [auth.radius] is missing".into(),
+            ));
+        }
+        self.auth
+            .radius
+            .as_ref()
+            .map(RadiusSettings::validate)
+            .transpose()?;
 
         // Do not start the endpoint without credentials on a public address
         if matches!(self.auth.mode, AuthMode::Credentials | AuthMode::Mixed)
@@ -581,9 +1426,96 @@ impl Settings {
             return Err(ValidationError::NoCredentialsOnPublicAddress);
         }
 
+        self.rate_limit.validate()?;
+        self.quota
+            .as_ref()
+            .map(QuotaSettings::validate)
+            .transpose()?;
+        self.limits.validate()?;
+        self.brute_force
+            .as_ref()
+            .map(BruteForceSettings::validate)
+            .transpose()?;
+        self.dns.as_ref().map(DnsSettings::validate).transpose()?;
+        self.forward_protocol.validate()?;
+        for x in self.forwarders.values() {
+            x.validate()?;
+        }
+        if let Some(x) = self.rules_engine.as_ref().and_then(|x| {
+            x.config()
+                .route
+                .iter()
+                .find(|r| !self.forwarders.contains_key(&r.forwarder))
+        }) {
+            return Err(ValidationError::Routing(format!(
+                "Routing rule refers to unknown forwarder: {}",
+                x.forwarder
+            )));
+        }
+        for x in &self.clients {
+            if x.upload_bytes_per_sec == Some(0) || x.download_bytes_per_sec == Some(0) {
+                return Err(ValidationError::RateLimit(format!(
+                    "Client {}: limits must be positive",
+                    x.username
+                )));
+            }
+            if self.auth.reject_plaintext_passwords
+                && password::Format::of(&x.password) == password::Format::Plain
+            {
+                return Err(ValidationError::Credentials(format!(
+                    "Client {}: plain text password is not allowed",
+                    x.username
+                )));
+            }
+            password::validate(&x.password).map_err(|e| {
+                ValidationError::Credentials(format!(
+                    "Client {}: invalid password hash: {}",
+                    x.username, e
+                ))
+            })?;
+            if let (Some(not_before), Some(expires_at)) = (x.not_before, x.expires_at) {
+                if not_before >= expires_at {
+                    return Err(ValidationError::Credentials(format!(
+                        "Client {}: not_before must precede expires_at",
+                        x.username
+                    )));
+                }
+            }
+        }
+
         Ok(())
     }
 
+    /// Get the groups of the user from the credentials file
+    pub(crate) fn user_groups(&self, username: &str) -> &[String] {
+        self.clients
+            .iter()
+            .find(|x| x.username == username)
+            .map_or(&[], |x| x.groups.as_slice())
+    }
+
+    /// Get the data quota of the user and its reset period
+    pub(crate) fn user_quota(&self, username: &str) -> Option<(u64, QuotaPeriod)> {
+        self.clients
+            .iter()
+            .find(|x| x.username == username)
+            .and_then(|x| Some((x.quota_bytes?, x.quota_period)))
+    }
+
+    /// Get the bandwidth limits of the user: the ones from the client entry with
+    /// the fallback to [`Self::rate_limit`]
+    pub(crate) fn user_rate_limit(&self, username: &str) -> RateLimitSettings {
+        let client = self.clients.iter().find(|x| x.username == username);
+        RateLimitSettings {
+            upload_bytes_per_sec: client
+                .and_then(|x| x.upload_bytes_per_sec)
+                .or(self.rate_limit.upload_bytes_per_sec),
+            download_bytes_per_sec: client
+                .and_then(|x| x.download_bytes_per_sec)
+                .or(self.rate_limit.download_bytes_per_sec),
+        }
+    }
+
     pub fn default_listen_address() -> SocketAddr {
         SocketAddr::from((Ipv4Addr::UNSPECIFIED, 443))
     }
@@ -634,8 +1566,14 @@ impl Default for Settings {
             tcp_connections_timeout: Settings::default_tcp_connections_timeout(),
             udp_connections_timeout: Settings::default_udp_connections_timeout(),
             forward_protocol: Default::default(),
+            forwarders: Default::default(),
+            dns: None,
             clients: Default::default(),
             auth: Default::default(),
+            rate_limit: Default::default(),
+            quota: None,
+            limits: Default::default(),
+            brute_force: None,
             listen_protocols: ListenProtocolSettings {
                 http1: Some(Http1Settings::builder().build()),
                 http2: Some(Http2Settings::builder().build()),
@@ -644,8 +1582,11 @@ impl Default for Settings {
             reverse_proxy: None,
             icmp: None,
             metrics: Default::default(),
+            admin: None,
             rules_engine: Some(rules::RulesEngine::default_allow()),
             speedtest_enable: false,
+            credentials_path: None,
+            rules_path: None,
             built: false,
         }
     }
@@ -683,6 +1624,21 @@ impl TlsHostsSettings {
                 format!("Invalid key: path='{}', error='{}'", h.private_key_path, e)
             })?;
 
+            if let Some(x) = &h.client_certificate {
+                match utils::load_certs(&x.ca_path) {
+                    Ok(certs) if !certs.is_empty() => (),
+                    Ok(_) => {
+                        return Err(format!("No client CA certificates: path='{}'", x.ca_path))
+                    }
+                    Err(e) => {
+                        return Err(format!(
+                            "Invalid client CA certificates: path='{}', error='{}'",
+                            x.ca_path, e
+                        ))
+                    }
+                }
+            }
+
             if !unique_hosts.insert(&h.hostname) {
                 return Err(format!("Hostname must be unique: {}", h.hostname));
             }
@@ -709,10 +1665,227 @@ impl TlsHostsSettings {
     }
 }
 
+impl ListenProtocolSettings {
+    /// Check whether both instances enable the same set of listen protocols
+    pub(crate) fn has_same_protocols(&self, other: &Self) -> bool {
+        self.http1.is_some() == other.http1.is_some()
+            && self.http2.is_some() == other.http2.is_some()
+            && self.quic.is_some() == other.quic.is_some()
+    }
+}
+
+impl ForwardProtocolSettings {
+    pub(crate) fn validate(&self) -> Result<(), ValidationError> {
+        match self {
+            Self::HttpConnect(x) => x.validate(),
+            Self::Cascade(x) => x.validate(),
+            Self::Socks5(x) => x.validate(),
+            Self::Direct(x) => x.validate(),
+        }
+    }
+}
+
+impl DirectForwarderSettings {
+    pub fn builder() -> DirectForwarderSettingsBuilder {
+        DirectForwarderSettingsBuilder {
+            settings: Default::default(),
+        }
+    }
+
+    /// The parsed [`DirectForwarderSettings.ipv6_source_prefix`]
+    pub(crate) fn ipv6_prefix(&self) -> Option<ipnet::Ipv6Net> {
+        self.ipv6_source_prefix.as_ref()?.parse().ok()
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ValidationError> {
+        if let Some(x) = self
+            .source_addresses
+            .iter()
+            .find(|x| x.is_unspecified() || x.is_multicast())
+        {
+            return Err(ValidationError::Direct(format!(
+                "Invalid source address: {}",
+                x
+            )));
+        }
+        if let Some(x) = &self.ipv6_source_prefix {
+            if x.parse::<ipnet::Ipv6Net>().is_err() {
+                return Err(ValidationError::Direct(format!(
+                    "Invalid IPv6 source prefix: {}",
+                    x
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DirectForwarderSettingsBuilder {
+    /// Add an address to the pool of the source addresses
+    pub fn source_address(mut self, v: IpAddr) -> Self {
+        self.settings.source_addresses.push(v);
+        self
+    }
+
+    /// Set the IPv6 prefix the per-client source addresses are generated from
+    pub fn ipv6_source_prefix(mut self, v: String) -> Self {
+        self.settings.ipv6_source_prefix = Some(v);
+        self
+    }
+
+    /// Set what the source address of a client sticks to
+    pub fn source_affinity(mut self, v: SourceAffinity) -> Self {
+        self.settings.source_affinity = v;
+        self
+    }
+
+    /// Finalize [`DirectForwarderSettings`]
+    pub fn build(self) -> Result<DirectForwarderSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
 impl Socks5ForwarderSettings {
     pub fn builder() -> Socks5ForwarderSettingsBuilder {
         Socks5ForwarderSettingsBuilder::new()
     }
+
+    pub fn default_address() -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+    }
+
+    /// The addresses of the upstream proxies
+    pub(crate) fn upstream_addresses(&self) -> Vec<SocketAddr> {
+        if self.upstreams.is_empty() {
+            vec![self.address]
+        } else {
+            self.upstreams.clone()
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ValidationError> {
+        let has_address = !self.address.ip().is_unspecified() || self.address.port() != 0;
+        if has_address != self.upstreams.is_empty() {
+            return Err(ValidationError::Socks5(
+                "Exactly one of address and upstreams must be set".into(),
+            ));
+        }
+        if let Some(x) = self.upstreams.iter().find(|x| x.ip().is_unspecified()) {
+            return Err(ValidationError::Socks5(format!(
+                "Invalid upstream address: {}",
+                x
+            )));
+        }
+        self.health_check
+            .as_ref()
+            .map(Socks5HealthCheckSettings::validate)
+            .transpose()?;
+
+        Ok(())
+    }
+}
+
+impl Socks5HealthCheckSettings {
+    pub fn builder() -> Socks5HealthCheckSettingsBuilder {
+        Socks5HealthCheckSettingsBuilder::new()
+    }
+
+    pub fn default_interval() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    pub fn default_timeout() -> Duration {
+        Duration::from_secs(3)
+    }
+
+    pub fn default_unhealthy_threshold() -> u32 {
+        3
+    }
+
+    pub fn default_healthy_threshold() -> u32 {
+        2
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ValidationError> {
+        if self.interval.is_zero() || self.timeout.is_zero() {
+            return Err(ValidationError::Socks5(
+                "Health check interval and timeout must be positive".into(),
+            ));
+        }
+        if self.unhealthy_threshold == 0 || self.healthy_threshold == 0 {
+            return Err(ValidationError::Socks5(
+                "Health check thresholds must be positive".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl HttpConnectForwarderSettings {
+    pub fn builder() -> HttpConnectForwarderSettingsBuilder {
+        HttpConnectForwarderSettingsBuilder::new()
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ValidationError> {
+        if self.address.ip().is_unspecified() || self.address.port() == 0 {
+            return Err(ValidationError::HttpConnect(
+                "Proxy address is not set".into(),
+            ));
+        }
+        if let Some(name) = &self.tls_server_name {
+            if !self.tls {
+                return Err(ValidationError::HttpConnect(
+                    "tls_server_name requires tls".into(),
+                ));
+            }
+            rustls::ServerName::try_from(name.as_str()).map_err(|_| {
+                ValidationError::HttpConnect(format!("Invalid TLS server name: {}", name))
+            })?;
+        }
+        if self.password_env.is_some() && self.username.is_none() {
+            return Err(ValidationError::HttpConnect(
+                "password_env requires username".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl CascadeForwarderSettings {
+    pub fn builder() -> CascadeForwarderSettingsBuilder {
+        CascadeForwarderSettingsBuilder::new()
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ValidationError> {
+        if self.address.ip().is_unspecified() || self.address.port() == 0 {
+            return Err(ValidationError::Cascade(
+                "Endpoint address is not set".into(),
+            ));
+        }
+        rustls::ServerName::try_from(self.server_name.as_str()).map_err(|_| {
+            ValidationError::Cascade(format!("Invalid server name: {}", self.server_name))
+        })?;
+        if self.username.is_empty() || self.password_env.is_empty() {
+            return Err(ValidationError::Cascade(
+                "username and password_env must be set".into(),
+            ));
+        }
+        for pin in &self.certificate_pins {
+            let digest = base64::engine::general_purpose::STANDARD
+                .decode(pin)
+                .map_err(|_| ValidationError::Cascade(format!("Invalid pin: {}", pin)))?;
+            if digest.len() != ring::digest::SHA256_OUTPUT_LEN {
+                return Err(ValidationError::Cascade(format!(
+                    "Pin is not a SHA-256 digest: {}",
+                    pin
+                )));
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Http1Settings {
@@ -867,6 +2040,10 @@ impl MetricsSettings {
     pub fn default_request_timeout() -> Duration {
         Duration::from_secs(3)
     }
+
+    pub fn default_max_users() -> usize {
+        100
+    }
 }
 
 impl Default for MetricsSettings {
@@ -874,109 +2051,303 @@ impl Default for MetricsSettings {
         Self {
             address: MetricsSettings::default_listen_address(),
             request_timeout: MetricsSettings::default_request_timeout(),
+            per_user: false,
+            max_users: MetricsSettings::default_max_users(),
         }
     }
 }
 
-impl SettingsBuilder {
-    fn new() -> Self {
-        Self {
-            settings: Settings {
-                listen_address: Settings::default_listen_address(),
-                ipv6_available: Settings::default_ipv6_available(),
-                allow_private_network_connections:
-                    Settings::default_allow_private_network_connections(),
-                tls_handshake_timeout: Settings::default_tls_handshake_timeout(),
-                client_listener_timeout: Settings::default_client_listener_timeout(),
-                connection_establishment_timeout:
-                    Settings::default_connection_establishment_timeout(),
-                tcp_connections_timeout: Settings::default_tcp_connections_timeout(),
-                udp_connections_timeout: Settings::default_udp_connections_timeout(),
-                forward_protocol: Default::default(),
-                listen_protocols: Default::default(),
-                clients: Default::default(),
-                auth: Default::default(),
-                reverse_proxy: None,
-                icmp: None,
-                metrics: Default::default(),
-                rules_engine: Some(rules::RulesEngine::default_allow()),
-                speedtest_enable: Settings::default_speedtest_enable(),
-                built: true,
-            },
-        }
+impl AdminSettings {
+    pub fn builder() -> AdminSettingsBuilder {
+        AdminSettingsBuilder::new()
     }
 
-    /// Finalize [`Settings`]
-    pub fn build(self) -> Result<Settings, ValidationError> {
-        self.settings.validate()?;
+    pub fn default_listen_address() -> SocketAddr {
+        (Ipv4Addr::LOCALHOST, 1988).into()
+    }
 
-        Ok(self.settings)
+    pub fn default_request_timeout() -> Duration {
+        Duration::from_secs(3)
     }
 
-    /// Set the address to listen on
-    pub fn listen_address<A: ToSocketAddrs>(mut self, addr: A) -> io::Result<Self> {
-        self.settings.listen_address = addr
-            .to_socket_addrs()?
-            .next()
-            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Address is parsed to empty list"))?;
-        Ok(self)
+    pub fn default_token_env() -> String {
+        "TRUSTTUNNEL_ADMIN_TOKEN".into()
     }
+}
 
-    /// Set the reverse proxy settings.
-    /// With this one set up the endpoint does TLS termination on such connections and
-    /// translates HTTP/x traffic into HTTP/1.1 protocol towards the server and back
-    /// into original HTTP/x towards the client. Like this:
-    ///
-    /// ```(client) TLS(HTTP/x) <--(endpoint)--> (server) HTTP/1.1```
-    ///
-    /// The translated HTTP/1.1 requests have the custom header `X-Original-Protocol`
-    /// appended. For now, its value can be either `HTTP1`, or `HTTP3`.
-    /// TLS hosts for the reverse proxy channel are configured through [`TlsHostsSettings`].
-    pub fn reverse_proxy(mut self, settings: ReverseProxySettings) -> Self {
-        self.settings.reverse_proxy = Some(settings);
-        self
+impl Default for AdminSettings {
+    fn default() -> Self {
+        Self {
+            address: AdminSettings::default_listen_address(),
+            request_timeout: AdminSettings::default_request_timeout(),
+            token_env: AdminSettings::default_token_env(),
+        }
     }
+}
 
-    /// Set IPv6 availability
-    pub fn ipv6_available(mut self, v: bool) -> Self {
-        self.settings.ipv6_available = v;
-        self
+impl QuotaSettings {
+    pub fn builder() -> QuotaSettingsBuilder {
+        QuotaSettingsBuilder::new()
     }
 
-    /// Allow/disallow connections to private network of the endpoint
-    pub fn allow_private_network_connections(mut self, v: bool) -> Self {
-        self.settings.allow_private_network_connections = v;
-        self
+    pub fn default_save_interval() -> Duration {
+        Duration::from_secs(60)
     }
 
-    /// Set timeout of TLS handshake
-    pub fn tls_handshake_timeout(mut self, v: Duration) -> Self {
-        self.settings.tls_handshake_timeout = v;
-        self
+    pub(crate) fn validate(&self) -> Result<(), ValidationError> {
+        if self.state_file.is_empty() {
+            return Err(ValidationError::Quota("State file is not set".to_string()));
+        }
+        if self.save_interval.is_zero() {
+            return Err(ValidationError::Quota(
+                "Save interval must be positive".to_string(),
+            ));
+        }
+        Ok(())
     }
+}
 
-    /// Set timeout of client listener
-    pub fn client_listener_timeout(mut self, v: Duration) -> Self {
-        self.settings.client_listener_timeout = v;
-        self
+impl DnsSettings {
+    pub fn builder() -> DnsSettingsBuilder {
+        DnsSettingsBuilder::new()
     }
 
-    /// Set timeout of outgoing connection establishment.
-    /// For example, it is related to client's connection requests.
-    pub fn connection_establishment_timeout(mut self, v: Duration) -> Self {
-        self.settings.connection_establishment_timeout = v;
-        self
+    pub fn default_query_timeout() -> Duration {
+        Duration::from_secs(5)
     }
 
-    /// Set timeout of tunneled TCP connections
-    pub fn tcp_connections_timeout(mut self, v: Duration) -> Self {
-        self.settings.tcp_connections_timeout = v;
-        self
+    pub fn default_cache_size() -> usize {
+        4096
     }
 
-    /// Set timeout of tunneled UDP "connections"
-    pub fn udp_connections_timeout(mut self, v: Duration) -> Self {
-        self.settings.udp_connections_timeout = v;
+    pub fn default_happy_eyeballs_delay() -> Duration {
+        Duration::from_millis(250) // RFC 8305 recommendation
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ValidationError> {
+        if self.upstreams.is_empty() {
+            return Err(ValidationError::Dns("Upstreams are not set".to_string()));
+        }
+        for x in &self.upstreams {
+            dns::upstream::UpstreamAddress::parse(x).map_err(ValidationError::Dns)?;
+        }
+        if self.query_timeout.is_zero() {
+            return Err(ValidationError::Dns(
+                "Query timeout must be positive".to_string(),
+            ));
+        }
+        if !self.blocklists.is_empty() && !self.intercept {
+            return Err(ValidationError::Dns(
+                "Blocklists require the interception to be enabled".to_string(),
+            ));
+        }
+        for x in &self.blocklists {
+            validate_file_path(x)
+                .map_err(|e| ValidationError::Dns(format!("Invalid blocklist {}: {}", x, e)))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for QuotaPeriod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(Self::Day),
+            "week" => Ok(Self::Week),
+            "month" => Ok(Self::Month),
+            "never" => Ok(Self::Never),
+            x => Err(format!("unexpected quota period: {}", x)),
+        }
+    }
+}
+
+impl RateLimitSettings {
+    pub fn builder() -> RateLimitSettingsBuilder {
+        RateLimitSettingsBuilder::new()
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ValidationError> {
+        if self.upload_bytes_per_sec == Some(0) || self.download_bytes_per_sec == Some(0) {
+            return Err(ValidationError::RateLimit(
+                "Limits must be positive".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl LimitSettings {
+    pub fn builder() -> LimitSettingsBuilder {
+        LimitSettingsBuilder::new()
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ValidationError> {
+        if [
+            self.max_sessions_per_user,
+            self.max_sessions_per_ip,
+            self.max_tcp_connections_per_session,
+            self.max_udp_flows_per_multiplexer,
+        ]
+        .contains(&Some(0))
+        {
+            return Err(ValidationError::Limits(
+                "Limits must be positive".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl BruteForceSettings {
+    pub fn builder() -> BruteForceSettingsBuilder {
+        BruteForceSettingsBuilder::new()
+    }
+
+    pub fn default_backoff_after_failures() -> u32 {
+        3
+    }
+
+    pub fn default_initial_backoff() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    pub fn default_ban_after_failures() -> u32 {
+        10
+    }
+
+    pub fn default_ban_duration() -> Duration {
+        Duration::from_secs(15 * 60)
+    }
+
+    pub fn default_failure_window() -> Duration {
+        Duration::from_secs(15 * 60)
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ValidationError> {
+        if self.ban_after_failures == 0 {
+            return Err(ValidationError::BruteForce(
+                "Number of failures resulting in a ban must be positive".to_string(),
+            ));
+        }
+        if self.ban_duration.is_zero() || self.failure_window.is_zero() {
+            return Err(ValidationError::BruteForce(
+                "Ban duration and failure window must be positive".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl SettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: Settings {
+                listen_address: Settings::default_listen_address(),
+                ipv6_available: Settings::default_ipv6_available(),
+                allow_private_network_connections:
+                    Settings::default_allow_private_network_connections(),
+                tls_handshake_timeout: Settings::default_tls_handshake_timeout(),
+                client_listener_timeout: Settings::default_client_listener_timeout(),
+                connection_establishment_timeout:
+                    Settings::default_connection_establishment_timeout(),
+                tcp_connections_timeout: Settings::default_tcp_connections_timeout(),
+                udp_connections_timeout: Settings::default_udp_connections_timeout(),
+                forward_protocol: Default::default(),
+                forwarders: Default::default(),
+                dns: None,
+                listen_protocols: Default::default(),
+                clients: Default::default(),
+                auth: Default::default(),
+                rate_limit: Default::default(),
+                quota: None,
+                limits: Default::default(),
+                brute_force: None,
+                reverse_proxy: None,
+                icmp: None,
+                metrics: Default::default(),
+                admin: None,
+                rules_engine: Some(rules::RulesEngine::default_allow()),
+                speedtest_enable: Settings::default_speedtest_enable(),
+                credentials_path: None,
+                rules_path: None,
+                built: true,
+            },
+        }
+    }
+
+    /// Finalize [`Settings`]
+    pub fn build(self) -> Result<Settings, ValidationError> {
+        self.settings.validate()?;
+
+        Ok(self.settings)
+    }
+
+    /// Set the address to listen on
+    pub fn listen_address<A: ToSocketAddrs>(mut self, addr: A) -> io::Result<Self> {
+        self.settings.listen_address = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Address is parsed to empty list"))?;
+        Ok(self)
+    }
+
+    /// Set the reverse proxy settings.
+    /// With this one set up the endpoint does TLS termination on such connections and
+    /// translates HTTP/x traffic into HTTP/1.1 protocol towards the server and back
+    /// into original HTTP/x towards the client. Like this:
+    ///
+    /// ```(client) TLS(HTTP/x) <--(endpoint)--> (server) HTTP/1.1```
+    ///
+    /// The translated HTTP/1.1 requests have the custom header `X-Original-Protocol`
+    /// appended. For now, its value can be either `HTTP1`, or `HTTP3`.
+    /// TLS hosts for the reverse proxy channel are configured through [`TlsHostsSettings`].
+    pub fn reverse_proxy(mut self, settings: ReverseProxySettings) -> Self {
+        self.settings.reverse_proxy = Some(settings);
+        self
+    }
+
+    /// Set IPv6 availability
+    pub fn ipv6_available(mut self, v: bool) -> Self {
+        self.settings.ipv6_available = v;
+        self
+    }
+
+    /// Allow/disallow connections to private network of the endpoint
+    pub fn allow_private_network_connections(mut self, v: bool) -> Self {
+        self.settings.allow_private_network_connections = v;
+        self
+    }
+
+    /// Set timeout of TLS handshake
+    pub fn tls_handshake_timeout(mut self, v: Duration) -> Self {
+        self.settings.tls_handshake_timeout = v;
+        self
+    }
+
+    /// Set timeout of client listener
+    pub fn client_listener_timeout(mut self, v: Duration) -> Self {
+        self.settings.client_listener_timeout = v;
+        self
+    }
+
+    /// Set timeout of outgoing connection establishment.
+    /// For example, it is related to client's connection requests.
+    pub fn connection_establishment_timeout(mut self, v: Duration) -> Self {
+        self.settings.connection_establishment_timeout = v;
+        self
+    }
+
+    /// Set timeout of tunneled TCP connections
+    pub fn tcp_connections_timeout(mut self, v: Duration) -> Self {
+        self.settings.tcp_connections_timeout = v;
+        self
+    }
+
+    /// Set timeout of tunneled UDP "connections"
+    pub fn udp_connections_timeout(mut self, v: Duration) -> Self {
+        self.settings.udp_connections_timeout = v;
         self
     }
 
@@ -986,6 +2357,12 @@ impl SettingsBuilder {
         self
     }
 
+    /// Add a named forwarder the routing rules may choose
+    pub fn forwarder(mut self, name: String, settings: ForwardProtocolSettings) -> Self {
+        self.settings.forwarders.insert(name, settings);
+        self
+    }
+
     /// Set the listener codec settings
     pub fn listen_protocols(mut self, settings: ListenProtocolSettings) -> Self {
         self.settings.listen_protocols = settings;
@@ -998,6 +2375,36 @@ impl SettingsBuilder {
         self
     }
 
+    /// Set the default per-user bandwidth limits
+    pub fn rate_limit(mut self, x: RateLimitSettings) -> Self {
+        self.settings.rate_limit = x;
+        self
+    }
+
+    /// Set the data quota accounting settings
+    pub fn quota(mut self, x: QuotaSettings) -> Self {
+        self.settings.quota = Some(x);
+        self
+    }
+
+    /// Set the upstream DNS resolver settings
+    pub fn dns(mut self, x: DnsSettings) -> Self {
+        self.settings.dns = Some(x);
+        self
+    }
+
+    /// Set the limits on the number of concurrent sessions and connections
+    pub fn limits(mut self, x: LimitSettings) -> Self {
+        self.settings.limits = x;
+        self
+    }
+
+    /// Set the protection against the credentials guessing
+    pub fn brute_force(mut self, x: BruteForceSettings) -> Self {
+        self.settings.brute_force = Some(x);
+        self
+    }
+
     /// Set the ICMP forwarder settings
     pub fn icmp(mut self, x: IcmpSettings) -> Self {
         self.settings.icmp = Some(x);
@@ -1010,6 +2417,12 @@ impl SettingsBuilder {
         self
     }
 
+    /// Set the administration API listener settings
+    pub fn admin(mut self, x: AdminSettings) -> Self {
+        self.settings.admin = Some(x);
+        self
+    }
+
     /// Set the rules engine for connection filtering
     pub fn rules_engine(mut self, x: rules::RulesEngine) -> Self {
         self.settings.rules_engine = Some(x);
@@ -1083,7 +2496,10 @@ impl Socks5ForwarderSettingsBuilder {
     fn new() -> Self {
         Self {
             settings: Socks5ForwarderSettings {
-                address: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                address: Socks5ForwarderSettings::default_address(),
+                upstreams: vec![],
+                balancing: Default::default(),
+                health_check: None,
                 extended_auth: false,
             },
         }
@@ -1091,13 +2507,36 @@ impl Socks5ForwarderSettingsBuilder {
 
     /// Finalize [`Socks5ForwarderSettings`]
     pub fn build(self) -> Socks5BuilderResult<Socks5ForwarderSettings> {
-        if self.settings.address.ip().is_unspecified() {
+        if self.settings.address.ip().is_unspecified() && self.settings.upstreams.is_empty() {
             return Err(Socks5Error::AddressNotSet);
         }
 
         Ok(self.settings)
     }
 
+    /// Add an upstream proxy to the pool, instead of the single
+    /// [server address](Self::server_address)
+    pub fn upstream<A: ToSocketAddrs>(mut self, v: A) -> io::Result<Self> {
+        self.settings.upstreams.push(
+            v.to_socket_addrs()?.next().ok_or_else(|| {
+                io::Error::new(ErrorKind::Other, "Address is parsed to empty list")
+            })?,
+        );
+        Ok(self)
+    }
+
+    /// Set the way an upstream proxy is chosen for a connection
+    pub fn balancing(mut self, v: Socks5Balancing) -> Self {
+        self.settings.balancing = v;
+        self
+    }
+
+    /// Enable the active health checks of the upstream proxies
+    pub fn health_check(mut self, v: Socks5HealthCheckSettings) -> Self {
+        self.settings.health_check = Some(v);
+        self
+    }
+
     /// Set the SOCKS proxy address
     pub fn server_address<A: ToSocketAddrs>(mut self, v: A) -> io::Result<Self> {
         self.settings.address = v
@@ -1115,6 +2554,170 @@ impl Socks5ForwarderSettingsBuilder {
     }
 }
 
+impl Socks5HealthCheckSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: Socks5HealthCheckSettings {
+                interval: Socks5HealthCheckSettings::default_interval(),
+                timeout: Socks5HealthCheckSettings::default_timeout(),
+                unhealthy_threshold: Socks5HealthCheckSettings::default_unhealthy_threshold(),
+                healthy_threshold: Socks5HealthCheckSettings::default_healthy_threshold(),
+            },
+        }
+    }
+
+    /// Set the interval between the probes of an upstream
+    pub fn interval(mut self, v: Duration) -> Self {
+        self.settings.interval = v;
+        self
+    }
+
+    /// Set the timeout of a probe and of a connection attempt to an upstream
+    pub fn timeout(mut self, v: Duration) -> Self {
+        self.settings.timeout = v;
+        self
+    }
+
+    /// Set the number of consecutive failures after which an upstream is ejected
+    pub fn unhealthy_threshold(mut self, v: u32) -> Self {
+        self.settings.unhealthy_threshold = v;
+        self
+    }
+
+    /// Set the number of consecutive successful probes after which an upstream is restored
+    pub fn healthy_threshold(mut self, v: u32) -> Self {
+        self.settings.healthy_threshold = v;
+        self
+    }
+
+    /// Finalize [`Socks5HealthCheckSettings`]
+    pub fn build(self) -> Result<Socks5HealthCheckSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl HttpConnectForwarderSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: HttpConnectForwarderSettings {
+                address: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                protocol: Default::default(),
+                tls: false,
+                tls_server_name: None,
+                username: None,
+                password_env: None,
+                forward_identity: false,
+                direct_datagrams: false,
+            },
+        }
+    }
+
+    /// Finalize [`HttpConnectForwarderSettings`]
+    pub fn build(self) -> Result<HttpConnectForwarderSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+
+    /// Set the HTTP proxy address
+    pub fn server_address<A: ToSocketAddrs>(mut self, v: A) -> io::Result<Self> {
+        self.settings.address = v
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Address is parsed to empty list"))?;
+        Ok(self)
+    }
+
+    /// Set the HTTP version of the CONNECT requests
+    pub fn protocol(mut self, v: HttpConnectProtocol) -> Self {
+        self.settings.protocol = v;
+        self
+    }
+
+    /// Enable/disable TLS on the connections to the proxy
+    pub fn tls(mut self, v: bool) -> Self {
+        self.settings.tls = v;
+        self
+    }
+
+    /// Set the name the proxy certificate is verified for
+    pub fn tls_server_name(mut self, v: String) -> Self {
+        self.settings.tls_server_name = Some(v);
+        self
+    }
+
+    /// Set the basic authentication username and the name of the environment
+    /// variable containing the password
+    pub fn basic_auth(mut self, username: String, password_env: Option<String>) -> Self {
+        self.settings.username = Some(username);
+        self.settings.password_env = password_env;
+        self
+    }
+
+    /// Enable/disable passing the identity of the VPN client to the proxy.
+    /// See README for details.
+    pub fn forward_identity(mut self, v: bool) -> Self {
+        self.settings.forward_identity = v;
+        self
+    }
+
+    /// Enable/disable routing UDP and ICMP traffic directly from the endpoint
+    pub fn direct_datagrams(mut self, v: bool) -> Self {
+        self.settings.direct_datagrams = v;
+        self
+    }
+}
+
+impl CascadeForwarderSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: CascadeForwarderSettings {
+                address: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                server_name: Default::default(),
+                username: Default::default(),
+                password_env: Default::default(),
+                certificate_pins: Default::default(),
+            },
+        }
+    }
+
+    /// Finalize [`CascadeForwarderSettings`]
+    pub fn build(self) -> Result<CascadeForwarderSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+
+    /// Set the next endpoint address
+    pub fn server_address<A: ToSocketAddrs>(mut self, v: A) -> io::Result<Self> {
+        self.settings.address = v
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Address is parsed to empty list"))?;
+        Ok(self)
+    }
+
+    /// Set the name sent in SNI to the next endpoint
+    pub fn server_name(mut self, v: String) -> Self {
+        self.settings.server_name = v;
+        self
+    }
+
+    /// Set the username and the name of the environment variable containing
+    /// the password of the credentials on the next endpoint
+    pub fn credentials(mut self, username: String, password_env: String) -> Self {
+        self.settings.username = username;
+        self.settings.password_env = password_env;
+        self
+    }
+
+    /// Add a base64-encoded SHA-256 digest of a public key the certificate
+    /// of the next endpoint is accepted with
+    pub fn certificate_pin(mut self, v: String) -> Self {
+        self.settings.certificate_pins.push(v);
+        self
+    }
+}
+
 impl Http1SettingsBuilder {
     fn new() -> Self {
         Self {
@@ -1387,15 +2990,268 @@ impl MetricsSettingsBuilder {
         self
     }
 
+    /// Enable or disable the per-user metrics
+    pub fn per_user(mut self, v: bool) -> Self {
+        self.settings.per_user = v;
+        self
+    }
+
+    /// Set the maximum number of distinct usernames reported in the per-user metrics
+    pub fn max_users(mut self, v: usize) -> Self {
+        self.settings.max_users = v;
+        self
+    }
+
     /// Finalize [`MetricsSettings`]
     pub fn build(self) -> Result<MetricsSettings, ValidationError> {
         Ok(self.settings)
     }
 }
 
+impl AdminSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: Default::default(),
+        }
+    }
+
+    /// Set the address to listen on for administration requests
+    pub fn listen_address<A: ToSocketAddrs>(mut self, addr: A) -> io::Result<Self> {
+        self.settings.address = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Address is parsed to empty list"))?;
+        Ok(self)
+    }
+
+    /// Set the administration request timeout
+    pub fn request_timeout(mut self, v: Duration) -> Self {
+        self.settings.request_timeout = v;
+        self
+    }
+
+    /// Set the name of the environment variable containing the token
+    pub fn token_env<S: Into<String>>(mut self, v: S) -> Self {
+        self.settings.token_env = v.into();
+        self
+    }
+
+    /// Finalize [`AdminSettings`]
+    pub fn build(self) -> Result<AdminSettings, ValidationError> {
+        Ok(self.settings)
+    }
+}
+
+impl QuotaSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: QuotaSettings {
+                state_file: Default::default(),
+                save_interval: QuotaSettings::default_save_interval(),
+            },
+        }
+    }
+
+    /// Set the file the usage counters are persisted to
+    pub fn state_file<S: Into<String>>(mut self, v: S) -> Self {
+        self.settings.state_file = v.into();
+        self
+    }
+
+    /// Set the interval between the counters savings
+    pub fn save_interval(mut self, v: Duration) -> Self {
+        self.settings.save_interval = v;
+        self
+    }
+
+    /// Finalize [`QuotaSettings`]
+    pub fn build(self) -> Result<QuotaSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl RateLimitSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: Default::default(),
+        }
+    }
+
+    /// Set the maximum upload rate of a user in bytes per second
+    pub fn upload_bytes_per_sec(mut self, v: u64) -> Self {
+        self.settings.upload_bytes_per_sec = Some(v);
+        self
+    }
+
+    /// Set the maximum download rate of a user in bytes per second
+    pub fn download_bytes_per_sec(mut self, v: u64) -> Self {
+        self.settings.download_bytes_per_sec = Some(v);
+        self
+    }
+
+    /// Finalize [`RateLimitSettings`]
+    pub fn build(self) -> Result<RateLimitSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl DnsSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: DnsSettings {
+                upstreams: Default::default(),
+                query_timeout: DnsSettings::default_query_timeout(),
+                cache_size: DnsSettings::default_cache_size(),
+                happy_eyeballs_delay: DnsSettings::default_happy_eyeballs_delay(),
+                intercept: false,
+                blocklists: Default::default(),
+                blocking_mode: Default::default(),
+            },
+        }
+    }
+
+    /// Set the upstream servers
+    pub fn upstreams(mut self, v: Vec<String>) -> Self {
+        self.settings.upstreams = v;
+        self
+    }
+
+    /// Set the timeout of a query to an upstream
+    pub fn query_timeout(mut self, v: Duration) -> Self {
+        self.settings.query_timeout = v;
+        self
+    }
+
+    /// Set the maximum number of the cached answers
+    pub fn cache_size(mut self, v: usize) -> Self {
+        self.settings.cache_size = v;
+        self
+    }
+
+    /// Set the delay before the connection attempt to the next address of a peer
+    pub fn happy_eyeballs_delay(mut self, v: Duration) -> Self {
+        self.settings.happy_eyeballs_delay = v;
+        self
+    }
+
+    /// Set whether the tunneled plain DNS queries are answered by the endpoint
+    pub fn intercept(mut self, v: bool) -> Self {
+        self.settings.intercept = v;
+        self
+    }
+
+    /// Set the files with the blocked names
+    pub fn blocklists(mut self, v: Vec<String>) -> Self {
+        self.settings.blocklists = v;
+        self
+    }
+
+    /// Set how the queries for the blocked names are answered
+    pub fn blocking_mode(mut self, v: DnsBlockingMode) -> Self {
+        self.settings.blocking_mode = v;
+        self
+    }
+
+    /// Finalize [`DnsSettings`]
+    pub fn build(self) -> Result<DnsSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl LimitSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: Default::default(),
+        }
+    }
+
+    /// Set the maximum number of concurrent sessions of a user
+    pub fn max_sessions_per_user(mut self, v: usize) -> Self {
+        self.settings.max_sessions_per_user = Some(v);
+        self
+    }
+
+    /// Set the maximum number of concurrent sessions from a client IP address
+    pub fn max_sessions_per_ip(mut self, v: usize) -> Self {
+        self.settings.max_sessions_per_ip = Some(v);
+        self
+    }
+
+    /// Set the maximum number of concurrent TCP connections within a session
+    pub fn max_tcp_connections_per_session(mut self, v: usize) -> Self {
+        self.settings.max_tcp_connections_per_session = Some(v);
+        self
+    }
+
+    /// Set the maximum number of concurrent UDP flows within a UDP multiplexer
+    pub fn max_udp_flows_per_multiplexer(mut self, v: usize) -> Self {
+        self.settings.max_udp_flows_per_multiplexer = Some(v);
+        self
+    }
+
+    /// Finalize [`LimitSettings`]
+    pub fn build(self) -> Result<LimitSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
+impl BruteForceSettingsBuilder {
+    fn new() -> Self {
+        Self {
+            settings: BruteForceSettings {
+                backoff_after_failures: BruteForceSettings::default_backoff_after_failures(),
+                initial_backoff: BruteForceSettings::default_initial_backoff(),
+                ban_after_failures: BruteForceSettings::default_ban_after_failures(),
+                ban_duration: BruteForceSettings::default_ban_duration(),
+                failure_window: BruteForceSettings::default_failure_window(),
+            },
+        }
+    }
+
+    /// Set the number of failures after which a client is blocked for the backoff period
+    pub fn backoff_after_failures(mut self, v: u32) -> Self {
+        self.settings.backoff_after_failures = v;
+        self
+    }
+
+    /// Set the first backoff period
+    pub fn initial_backoff(mut self, v: Duration) -> Self {
+        self.settings.initial_backoff = v;
+        self
+    }
+
+    /// Set the number of failures after which a client is banned
+    pub fn ban_after_failures(mut self, v: u32) -> Self {
+        self.settings.ban_after_failures = v;
+        self
+    }
+
+    /// Set the duration of a ban
+    pub fn ban_duration(mut self, v: Duration) -> Self {
+        self.settings.ban_duration = v;
+        self
+    }
+
+    /// Set the period after which the failures are forgotten
+    pub fn failure_window(mut self, v: Duration) -> Self {
+        self.settings.failure_window = v;
+        self
+    }
+
+    /// Finalize [`BruteForceSettings`]
+    pub fn build(self) -> Result<BruteForceSettings, ValidationError> {
+        self.settings.validate()?;
+        Ok(self.settings)
+    }
+}
+
 impl Default for ForwardProtocolSettings {
     fn default() -> Self {
-        ForwardProtocolSettings::Direct(DirectForwarderSettings {})
+        ForwardProtocolSettings::Direct(Default::default())
     }
 }
 
@@ -1407,6 +3263,34 @@ fn validate_file_path(path: &str) -> io::Result<()> {
 }
 
 fn deserialize_duration_secs<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    deserialize_unsigned(deserializer).map(Duration::from_secs)
+}
+
+fn serialize_duration_secs<S>(x: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::ser::Serializer,
+{
+    serializer.serialize_u64(x.as_secs())
+}
+
+fn deserialize_duration_ms<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    deserialize_unsigned(deserializer).map(Duration::from_millis)
+}
+
+fn serialize_duration_ms<S>(x: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::ser::Serializer,
+{
+    serializer.serialize_u64(x.as_millis() as u64)
+}
+
+fn deserialize_unsigned<'de, D>(deserializer: D) -> Result<u64, D::Error>
 where
     D: serde::de::Deserializer<'de>,
 {
@@ -1428,17 +3312,9 @@ where
                 .then_some(v as u64)
                 .ok_or_else(|| E::invalid_type(serde::de::Unexpected::Signed(v), &Visitor {}))
         }
-    }
-
-    let x = deserializer.deserialize_u64(Visitor)?;
-    Ok(Duration::from_secs(x))
-}
-
-fn serialize_duration_secs<S>(x: &Duration, serializer: S) -> Result<S::Ok, S::Error>
-where
-    S: serde::ser::Serializer,
-{
-    serializer.serialize_u64(x.as_secs())
+    }
+
+    deserializer.deserialize_u64(Visitor)
 }
 
 fn deserialize_file_path<'de, D>(deserializer: D) -> Result<String, D::Error>
@@ -1470,7 +3346,7 @@ where
     deserializer.deserialize_str(Visitor)
 }
 
-fn deserialize_clients<'de, D>(deserializer: D) -> Result<Vec<Client>, D::Error>
+pub(crate) fn deserialize_clients<'de, D>(deserializer: D) -> Result<Vec<Client>, D::Error>
 where
     D: serde::de::Deserializer<'de>,
 {
@@ -1505,6 +3381,20 @@ where
         .map(|(idx, x)| {
             let username = demangle_toml_string(x["username"].to_string());
             let password = demangle_toml_string(x["password"].to_string());
+            let positive_integer = |key: &str| match x.get(key) {
+                None => Ok(None),
+                Some(v) => v
+                    .as_integer()
+                    .filter(|v| *v > 0)
+                    .map(|v| Some(v as u64))
+                    .ok_or_else(|| {
+                        serde::de::Error::custom(format!(
+                            "Client #{}: {} must be a positive integer",
+                            idx + 1,
+                            key
+                        ))
+                    }),
+            };
 
             if username.is_empty() {
                 return Err(serde::de::Error::custom(format!(
@@ -1519,7 +3409,79 @@ where
                 )));
             }
 
-            Ok(Client { username, password })
+            let quota_bytes = positive_integer("quota_bytes")?;
+            let quota_period = match x.get("quota_period") {
+                None => Default::default(),
+                Some(v) => v
+                    .as_str()
+                    .ok_or_else(|| "not a string".to_string())
+                    .and_then(str::parse)
+                    .map_err(|e| {
+                        serde::de::Error::custom(format!(
+                            "Client #{}: invalid quota_period: {}",
+                            idx + 1,
+                            e
+                        ))
+                    })?,
+            };
+
+            let timestamp = |key: &str| match x.get(key) {
+                None => Ok(None),
+                Some(v) => v
+                    .as_datetime()
+                    .map(ToString::to_string)
+                    .or_else(|| v.as_str().map(str::to_string))
+                    .ok_or_else(|| "not a datetime".to_string())
+                    .and_then(|v| registry_based::parse_timestamp(&v))
+                    .map(Some)
+                    .map_err(|e| {
+                        serde::de::Error::custom(format!(
+                            "Client #{}: invalid {}: {}",
+                            idx + 1,
+                            key,
+                            e
+                        ))
+                    }),
+            };
+            let disabled = match x.get("disabled") {
+                None => false,
+                Some(v) => v.as_bool().ok_or_else(|| {
+                    serde::de::Error::custom(format!(
+                        "Client #{}: disabled must be a boolean",
+                        idx + 1
+                    ))
+                })?,
+            };
+
+            let groups = match x.get("groups") {
+                None => vec![],
+                Some(v) => v
+                    .as_array()
+                    .and_then(|a| {
+                        a.iter()
+                            .map(|g| g.as_str().map(str::to_string))
+                            .collect::<Option<Vec<_>>>()
+                    })
+                    .ok_or_else(|| {
+                        serde::de::Error::custom(format!(
+                            "Client #{}: groups must be an array of strings",
+                            idx + 1
+                        ))
+                    })?,
+            };
+
+            Ok(Client {
+                username,
+                password,
+                groups,
+                upload_bytes_per_sec: positive_integer("upload_bytes_per_sec")?,
+                download_bytes_per_sec: positive_integer("download_bytes_per_sec")?,
+                quota_bytes,
+                quota_period,
+                not_before: timestamp("not_before")?,
+                expires_at: timestamp("expires_at")?,
+                disabled,
+            })
         })
         .collect::<Result<Vec<_>, _>>()?;
 
@@ -1537,7 +3499,9 @@ where
     Ok(res)
 }
 
-fn deserialize_rules<'de, D>(deserializer: D) -> Result<Option<rules::RulesEngine>, D::Error>
+pub(crate) fn deserialize_rules<'de, D>(
+    deserializer: D,
+) -> Result<Option<rules::RulesEngine>, D::Error>
 where
     D: serde::de::Deserializer<'de>,
 {
@@ -1569,47 +3533,145 @@ where
         }
     };
 
-    let rules_config = match rules_doc.get("rule").and_then(Item::as_array_of_tables) {
-        Some(rules_array) => {
-            let rules: Vec<rules::Rule> = rules_array
-                .iter()
-                .filter_map(|rule_table| {
-                    let cidr = rule_table
-                        .get("cidr")
+    let rule = match rules_doc.get("rule").and_then(Item::as_array_of_tables) {
+        Some(rules_array) => rules_array
+            .iter()
+            .filter_map(|rule_table| {
+                let cidr = rule_table
+                    .get("cidr")
+                    .and_then(Item::as_str)
+                    .map(|s| s.to_string());
+
+                let client_random_prefix = rule_table
+                    .get("client_random_prefix")
+                    .and_then(Item::as_str)
+                    .map(|s| s.to_string());
+
+                let string_field = |key| {
+                    rule_table
+                        .get(key)
                         .and_then(Item::as_str)
-                        .map(|s| s.to_string());
+                        .map(|s| s.to_string())
+                };
+
+                let action = parse_rule_action(rule_table)?;
+
+                Some(rules::Rule {
+                    cidr,
+                    client_random_prefix,
+                    sni: string_field("sni"),
+                    username: string_field("username"),
+                    group: string_field("group"),
+                    user_agent: string_field("user_agent"),
+                    action,
+                })
+            })
+            .collect(),
+        // No rules array found
+        None => vec![],
+    };
 
-                    let client_random_prefix = rule_table
-                        .get("client_random_prefix")
+    let egress = match rules_doc.get("egress").and_then(Item::as_array_of_tables) {
+        Some(rules_array) => rules_array
+            .iter()
+            .filter_map(|rule_table| {
+                let domain = rule_table
+                    .get("domain")
+                    .and_then(Item::as_str)
+                    .map(|s| s.to_string());
+
+                let cidr = rule_table
+                    .get("cidr")
+                    .and_then(Item::as_str)
+                    .map(|s| s.to_string());
+
+                let ports = parse_rule_ports(rule_table);
+                let protocol = parse_rule_protocol(rule_table)?;
+                let action = parse_rule_action(rule_table)?;
+
+                let string_field = |key| {
+                    rule_table
+                        .get(key)
                         .and_then(Item::as_str)
-                        .map(|s| s.to_string());
+                        .map(|s| s.to_string())
+                };
+
+                Some(rules::EgressRule {
+                    domain,
+                    cidr,
+                    ports,
+                    protocol,
+                    username: string_field("username"),
+                    group: string_field("group"),
+                    action,
+                })
+            })
+            .collect(),
+        // No egress rules array found
+        None => vec![],
+    };
 
-                    let action = rule_table
-                        .get("action")
+    let route = match rules_doc.get("route").and_then(Item::as_array_of_tables) {
+        Some(rules_array) => rules_array
+            .iter()
+            .filter_map(|rule_table| {
+                let string_field = |key| {
+                    rule_table
+                        .get(key)
                         .and_then(Item::as_str)
-                        .and_then(|s| match s {
-                            "allow" => Some(rules::RuleAction::Allow),
-                            "deny" => Some(rules::RuleAction::Deny),
-                            _ => None,
-                        })?;
-
-                    Some(rules::Rule {
-                        cidr,
-                        client_random_prefix,
-                        action,
-                    })
+                        .map(|s| s.to_string())
+                };
+
+                Some(rules::RouteRule {
+                    username: string_field("username"),
+                    sni: string_field("sni"),
+                    domain: string_field("domain"),
+                    cidr: string_field("cidr"),
+                    ports: parse_rule_ports(rule_table),
+                    protocol: parse_rule_protocol(rule_table)?,
+                    forwarder: string_field("forwarder")?,
                 })
-                .collect();
-
-            rules::RulesConfig { rule: rules }
-        }
-        None => {
-            // No rules array found, create empty config
-            rules::RulesConfig { rule: vec![] }
-        }
+            })
+            .collect(),
+        // No routing rules array found
+        None => vec![],
     };
 
-    Ok(Some(rules::RulesEngine::from_config(rules_config)))
+    Ok(Some(rules::RulesEngine::from_config(rules::RulesConfig {
+        rule,
+        egress,
+        route,
+    })))
+}
+
+/// Either a single port number or a list of ports and ranges
+fn parse_rule_ports(rule_table: &Table) -> Option<String> {
+    rule_table.get("ports").and_then(|x| {
+        x.as_str()
+            .map(|s| s.to_string())
+            .or_else(|| x.as_integer().map(|i| i.to_string()))
+    })
+}
+
+/// Returns `None` in case the protocol is invalid
+fn parse_rule_protocol(rule_table: &Table) -> Option<Option<rules::EgressProtocol>> {
+    match rule_table.get("protocol").map(Item::as_str) {
+        None => Some(None),
+        Some(Some("tcp")) => Some(Some(rules::EgressProtocol::Tcp)),
+        Some(Some("udp")) => Some(Some(rules::EgressProtocol::Udp)),
+        Some(_) => None,
+    }
+}
+
+fn parse_rule_action(rule_table: &Table) -> Option<rules::RuleAction> {
+    rule_table
+        .get("action")
+        .and_then(Item::as_str)
+        .and_then(|s| match s {
+            "allow" => Some(rules::RuleAction::Allow),
+            "deny" => Some(rules::RuleAction::Deny),
+            _ => None,
+        })
 }
 
 fn demangle_toml_string(x: String) -> String {
@@ -1618,8 +3680,10 @@ fn demangle_toml_string(x: String) -> String {
 
 #[cfg(test)]
 mod tests {
+    use base64::Engine;
     use serde::de::value::{Error as ValueError, StringDeserializer};
     use std::fs;
+    use std::time::Duration;
 
     #[test]
     fn rejects_duplicate_usernames_in_credentials_file() {
@@ -1650,4 +3714,651 @@ password = "second"
             "unexpected error: {err}"
         );
     }
+
+    #[test]
+    fn client_rate_limits_override_default() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let credentials_path = temp_dir.path().join("credentials.toml");
+        fs::write(
+            &credentials_path,
+            r#"
+[[client]]
+username = "alice"
+password = "first"
+upload_bytes_per_sec = 1000
+
+[[client]]
+username = "bob"
+password = "second"
+"#,
+        )
+        .unwrap();
+
+        let path = credentials_path.to_str().unwrap().to_string();
+        let deserializer = StringDeserializer::<ValueError>::new(path);
+        let clients = super::deserialize_clients(deserializer).unwrap();
+
+        let settings = super::Settings {
+            clients,
+            rate_limit: super::RateLimitSettings::builder()
+                .upload_bytes_per_sec(10)
+                .download_bytes_per_sec(20)
+                .build()
+                .unwrap(),
+            ..Default::default()
+        };
+
+        let alice = settings.user_rate_limit("alice");
+        assert_eq!(alice.upload_bytes_per_sec, Some(1000));
+        assert_eq!(alice.download_bytes_per_sec, Some(20));
+        let bob = settings.user_rate_limit("bob");
+        assert_eq!(bob.upload_bytes_per_sec, Some(10));
+        assert_eq!(bob.download_bytes_per_sec, Some(20));
+    }
+
+    #[test]
+    fn rejects_non_positive_client_rate_limit() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let credentials_path = temp_dir.path().join("credentials.toml");
+        fs::write(
+            &credentials_path,
+            r#"
+[[client]]
+username = "alice"
+password = "first"
+download_bytes_per_sec = 0
+"#,
+        )
+        .unwrap();
+
+        let path = credentials_path.to_str().unwrap().to_string();
+        let deserializer = StringDeserializer::<ValueError>::new(path);
+        let err = match super::deserialize_clients(deserializer) {
+            Ok(_) => panic!("zero limit must fail"),
+            Err(err) => err.to_string(),
+        };
+        assert!(
+            err.contains("download_bytes_per_sec must be a positive integer"),
+            "unexpected error: {err}"
+        );
+    }
+
+    #[test]
+    fn jwt_key_sources() {
+        let settings =
+            |algorithm, public_key_path: Option<&str>, jwks_url: Option<&str>| super::JwtSettings {
+                algorithm,
+                issuer: None,
+                audience: None,
+                leeway_seconds: 0,
+                username_claim: "sub".into(),
+                public_key_path: public_key_path.map(Into::into),
+                hmac_secret_env: None,
+                jwks_path: None,
+                jwks_url: jwks_url.map(Into::into),
+                jwks_refresh_interval_secs: 600,
+                max_token_age_secs: None,
+                jti_denylist_path: None,
+            };
+        use super::JwtAlgorithm::*;
+
+        assert!(settings(Some(ES256), Some("key.pem"), None)
+            .validate()
+            .is_ok());
+        assert!(settings(None, None, Some("https://idp/jwks.json"))
+            .validate()
+            .is_ok());
+        assert!(settings(Some(EdDSA), None, Some("http://127.0.0.1/jwks"))
+            .validate()
+            .is_ok());
+
+        assert!(settings(None, Some("key.pem"), None).validate().is_err());
+        assert!(settings(Some(HS256), Some("key.pem"), None)
+            .validate()
+            .is_err());
+        assert!(
+            settings(None, Some("key.pem"), Some("https://idp/jwks.json"))
+                .validate()
+                .is_err()
+        );
+        assert!(settings(None, None, None).validate().is_err());
+        assert!(settings(None, None, Some("ftp://idp/jwks.json"))
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn client_quotas() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let credentials_path = temp_dir.path().join("credentials.toml");
+        fs::write(
+            &credentials_path,
+            r#"
+[[client]]
+username = "alice"
+password = "first"
+quota_bytes = 1000
+quota_period = "week"
+
+[[client]]
+username = "bob"
+password = "second"
+quota_bytes = 2000
+
+[[client]]
+username = "carol"
+password = "third"
+"#,
+        )
+        .unwrap();
+
+        let path = credentials_path.to_str().unwrap().to_string();
+        let deserializer = StringDeserializer::<ValueError>::new(path);
+        let settings = super::Settings {
+            clients: super::deserialize_clients(deserializer).unwrap(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            settings.user_quota("alice"),
+            Some((1000, super::QuotaPeriod::Week))
+        );
+        assert_eq!(
+            settings.user_quota("bob"),
+            Some((2000, super::QuotaPeriod::Month))
+        );
+        assert_eq!(settings.user_quota("carol"), None);
+    }
+
+    #[test]
+    fn client_validity_windows() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let credentials_path = temp_dir.path().join("credentials.toml");
+        let parse = |content: &str| {
+            fs::write(&credentials_path, content).unwrap();
+            let path = credentials_path.to_str().unwrap().to_string();
+            super::deserialize_clients(StringDeserializer::<ValueError>::new(path))
+        };
+
+        let clients = parse(
+            r#"
+[[client]]
+username = "alice"
+password = "first"
+not_before = 2025-01-01
+expires_at = 2025-02-01T12:00:00+02:00
+
+[[client]]
+username = "bob"
+password = "second"
+expires_at = "2025-03-01 00:00:00"
+disabled = true
+"#,
+        )
+        .unwrap();
+        let timestamp = |x| super::registry_based::parse_timestamp(x).unwrap();
+        assert_eq!(
+            clients[0].not_before,
+            Some(timestamp("2025-01-01T00:00:00Z"))
+        );
+        assert_eq!(
+            clients[0].expires_at,
+            Some(timestamp("2025-02-01T10:00:00Z"))
+        );
+        assert!(!clients[0].disabled);
+        assert_eq!(clients[1].not_before, None);
+        assert_eq!(
+            clients[1].expires_at,
+            Some(timestamp("2025-03-01T00:00:00Z"))
+        );
+        assert!(clients[1].disabled);
+
+        assert!(parse("[[client]]\nusername = \"a\"\npassword = \"b\"\nexpires_at = 1\n").is_err());
+        assert!(
+            parse("[[client]]\nusername = \"a\"\npassword = \"b\"\ndisabled = \"yes\"\n").is_err()
+        );
+
+        let settings = super::Settings {
+            listen_address: "127.0.0.1:443".parse().unwrap(),
+            clients: vec![super::Client {
+                username: "alice".into(),
+                password: "first".into(),
+                not_before: Some(timestamp("2025-02-01T00:00:00Z")),
+                expires_at: Some(timestamp("2025-01-01T00:00:00Z")),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn hashed_passwords() {
+        let settings = |passwords: &[&str], reject_plaintext_passwords| super::Settings {
+            listen_address: "127.0.0.1:443".parse().unwrap(),
+            clients: passwords
+                .iter()
+                .map(|x| super::Client {
+                    username: "alice".into(),
+                    password: x.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            auth: super::AuthSettings {
+                reject_plaintext_passwords,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let hash = "$2b$04$sAzWoVsPKm6D38dTcBBYOOyQyLdGPoCKW8f4LGNzRhXfAfrLfaLZa";
+
+        assert!(settings(&["plain", hash], false).validate().is_ok());
+        assert!(settings(&[hash], true).validate().is_ok());
+        assert!(settings(&["plain", hash], true).validate().is_err());
+        assert!(settings(&["$2b$04$short"], false).validate().is_err());
+        assert!(settings(&["$argon2id$v=19$garbage"], false)
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn radius_settings() {
+        let settings = |radius: Option<super::RadiusSettings>| super::Settings {
+            listen_address: "127.0.0.1:443".parse().unwrap(),
+            auth: super::AuthSettings {
+                mode: super::AuthMode::Radius,
+                radius,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let radius = super::RadiusSettings {
+            server: "radius.example.org:1812".into(),
+            secret_env: "RADIUS_SECRET".into(),
+            nas_identifier: super::RadiusSettings::default_nas_identifier(),
+            timeout_secs: super::RadiusSettings::default_timeout_secs(),
+            retries: super::RadiusSettings::default_retries(),
+            accounting_server: Some("[::1]:1813".into()),
+            interim_interval_secs: super::RadiusSettings::default_interim_interval_secs(),
+        };
+
+        assert!(settings(Some(radius.clone())).validate().is_ok());
+        assert!(settings(None).validate().is_err());
+        for invalid in [
+            super::RadiusSettings {
+                server: "radius.example.org".into(),
+                ..radius.clone()
+            },
+            super::RadiusSettings {
+                accounting_server: Some(":1813".into()),
+                ..radius.clone()
+            },
+            super::RadiusSettings {
+                timeout_secs: 0,
+                ..radius.clone()
+            },
+            super::RadiusSettings {
+                interim_interval_secs: 0,
+                ..radius.clone()
+            },
+        ] {
+            assert!(settings(Some(invalid)).validate().is_err());
+        }
+    }
+
+    #[test]
+    fn brute_force_settings() {
+        let brute_force = super::BruteForceSettings::builder().build().unwrap();
+        let settings = super::Settings {
+            listen_address: "127.0.0.1:443".parse().unwrap(),
+            brute_force: Some(brute_force.clone()),
+            ..Default::default()
+        };
+        assert!(settings.validate().is_ok());
+
+        for invalid in [
+            super::BruteForceSettings {
+                ban_after_failures: 0,
+                ..brute_force.clone()
+            },
+            super::BruteForceSettings {
+                ban_duration: Duration::ZERO,
+                ..brute_force.clone()
+            },
+            super::BruteForceSettings {
+                failure_window: Duration::ZERO,
+                ..brute_force.clone()
+            },
+        ] {
+            assert!(invalid.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn direct_forwarder_settings() {
+        let direct = super::DirectForwarderSettings::builder()
+            .source_address("203.0.113.1".parse().unwrap())
+            .ipv6_source_prefix("2001:db8:1:2::/64".into())
+            .source_affinity(super::SourceAffinity::Session)
+            .build()
+            .unwrap();
+        assert_eq!(
+            direct.ipv6_prefix(),
+            Some("2001:db8:1:2::/64".parse().unwrap())
+        );
+
+        for invalid in [
+            super::DirectForwarderSettings {
+                source_addresses: vec!["0.0.0.0".parse().unwrap()],
+                ..direct.clone()
+            },
+            super::DirectForwarderSettings {
+                source_addresses: vec!["ff02::1".parse().unwrap()],
+                ..direct.clone()
+            },
+            super::DirectForwarderSettings {
+                ipv6_source_prefix: Some("203.0.113.0/24".into()),
+                ..direct.clone()
+            },
+        ] {
+            assert!(invalid.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn socks5_forwarder_settings() {
+        let pool = super::Socks5ForwarderSettings::builder()
+            .upstream("127.0.0.1:1080")
+            .unwrap()
+            .upstream("127.0.0.1:1081")
+            .unwrap()
+            .balancing(super::Socks5Balancing::ConsistentHash)
+            .health_check(super::Socks5HealthCheckSettings::builder().build().unwrap())
+            .build()
+            .unwrap();
+        assert!(pool.validate().is_ok());
+        assert_eq!(pool.upstream_addresses().len(), 2);
+
+        let single = super::Socks5ForwarderSettings::builder()
+            .server_address("127.0.0.1:1080")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(single.validate().is_ok());
+        assert_eq!(single.upstream_addresses(), vec![single.address]);
+
+        for invalid in [
+            super::Socks5ForwarderSettings {
+                address: single.address,
+                ..pool.clone()
+            },
+            super::Socks5ForwarderSettings {
+                upstreams: vec!["0.0.0.0:1080".parse().unwrap()],
+                ..pool.clone()
+            },
+            super::Socks5ForwarderSettings {
+                upstreams: vec![],
+                ..pool.clone()
+            },
+        ] {
+            assert!(invalid.validate().is_err());
+        }
+
+        assert!(super::Socks5HealthCheckSettings::builder()
+            .unhealthy_threshold(0)
+            .build()
+            .is_err());
+        assert!(super::Socks5HealthCheckSettings::builder()
+            .interval(Duration::ZERO)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn http_connect_forwarder_settings() {
+        let proxy = super::HttpConnectForwarderSettings::builder()
+            .server_address("127.0.0.1:3128")
+            .unwrap()
+            .protocol(super::HttpConnectProtocol::Http2)
+            .tls(true)
+            .tls_server_name("proxy.example.com".into())
+            .basic_auth("endpoint".into(), Some("PROXY_PASSWORD".into()))
+            .build()
+            .unwrap();
+
+        for invalid in [
+            super::HttpConnectForwarderSettings {
+                address: "0.0.0.0:3128".parse().unwrap(),
+                ..proxy.clone()
+            },
+            super::HttpConnectForwarderSettings {
+                tls: false,
+                ..proxy.clone()
+            },
+            super::HttpConnectForwarderSettings {
+                tls_server_name: Some("not a name".into()),
+                ..proxy.clone()
+            },
+            super::HttpConnectForwarderSettings {
+                username: None,
+                ..proxy.clone()
+            },
+        ] {
+            assert!(invalid.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn cascade_forwarder_settings() {
+        let pin = base64::engine::general_purpose::STANDARD.encode([0; 32]);
+        let cascade = super::CascadeForwarderSettings::builder()
+            .server_address("127.0.0.1:443")
+            .unwrap()
+            .server_name("exit.example.com".into())
+            .credentials("entry".into(), "EXIT_PASSWORD".into())
+            .certificate_pin(pin)
+            .build()
+            .unwrap();
+
+        for invalid in [
+            super::CascadeForwarderSettings {
+                address: "0.0.0.0:443".parse().unwrap(),
+                ..cascade.clone()
+            },
+            super::CascadeForwarderSettings {
+                server_name: "not a name".into(),
+                ..cascade.clone()
+            },
+            super::CascadeForwarderSettings {
+                password_env: String::new(),
+                ..cascade.clone()
+            },
+            super::CascadeForwarderSettings {
+                certificate_pins: vec!["not base64".into()],
+                ..cascade.clone()
+            },
+            super::CascadeForwarderSettings {
+                certificate_pins: vec![base64::engine::general_purpose::STANDARD.encode([0; 20])],
+                ..cascade.clone()
+            },
+        ] {
+            assert!(invalid.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn rules_file_with_egress_rules() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let rules_path = temp_dir.path().join("rules.toml");
+        fs::write(
+            &rules_path,
+            r#"
+[[rule]]
+cidr = "10.0.0.0/8"
+action = "deny"
+
+[[egress]]
+ports = 25
+protocol = "tcp"
+action = "deny"
+
+[[egress]]
+domain = ".example.com"
+ports = "80,443"
+group = "staff"
+action = "allow"
+
+[[egress]]
+protocol = "icmp"
+action = "deny"
+"#,
+        )
+        .unwrap();
+
+        let path = rules_path.to_str().unwrap().to_string();
+        let deserializer = StringDeserializer::<ValueError>::new(path);
+        let engine = super::deserialize_rules(deserializer).unwrap().unwrap();
+        let config = engine.config();
+
+        assert_eq!(config.rule.len(), 1);
+        // The rule with the unknown protocol is skipped
+        assert_eq!(config.egress.len(), 2);
+        assert_eq!(config.egress[0].ports.as_deref(), Some("25"));
+        assert_eq!(
+            config.egress[0].protocol,
+            Some(super::rules::EgressProtocol::Tcp)
+        );
+        assert_eq!(config.egress[1].domain.as_deref(), Some(".example.com"));
+        assert_eq!(config.egress[1].group.as_deref(), Some("staff"));
+        assert_eq!(config.egress[1].action, super::rules::RuleAction::Allow);
+    }
+
+    #[test]
+    fn rules_file_with_routing_rules() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let rules_path = temp_dir.path().join("rules.toml");
+        fs::write(
+            &rules_path,
+            r#"
+[[route]]
+domain = ".video.example"
+forwarder = "direct"
+
+[[route]]
+username = "alice"
+cidr = "10.0.0.0/8"
+ports = 22
+protocol = "tcp"
+forwarder = "office"
+
+[[route]]
+domain = ".example.com"
+
+[[route]]
+protocol = "icmp"
+forwarder = "direct"
+"#,
+        )
+        .unwrap();
+
+        let path = rules_path.to_str().unwrap().to_string();
+        let deserializer = StringDeserializer::<ValueError>::new(path);
+        let engine = super::deserialize_rules(deserializer).unwrap().unwrap();
+        let config = engine.config();
+
+        // The rules without forwarder and with the unknown protocol are skipped
+        assert_eq!(config.route.len(), 2);
+        assert_eq!(config.route[0].domain.as_deref(), Some(".video.example"));
+        assert_eq!(config.route[0].forwarder, "direct");
+        assert_eq!(config.route[1].username.as_deref(), Some("alice"));
+        assert_eq!(config.route[1].ports.as_deref(), Some("22"));
+        assert_eq!(
+            config.route[1].protocol,
+            Some(super::rules::EgressProtocol::Tcp)
+        );
+
+        let mut settings = super::Settings {
+            listen_address: "127.0.0.1:443".parse().unwrap(),
+            rules_engine: Some(engine),
+            ..Default::default()
+        };
+        settings.forwarders.insert(
+            "direct".into(),
+            super::ForwardProtocolSettings::Direct(Default::default()),
+        );
+        // The `office` forwarder is missing
+        assert!(settings.validate().is_err());
+
+        settings.forwarders.insert(
+            "office".into(),
+            super::ForwardProtocolSettings::Socks5(
+                super::Socks5ForwarderSettings::builder()
+                    .server_address("10.0.0.1:1080")
+                    .unwrap()
+                    .build()
+                    .unwrap(),
+            ),
+        );
+        assert!(settings.validate().is_ok());
+
+        settings.forwarders.insert(
+            "invalid".into(),
+            super::ForwardProtocolSettings::Cascade(super::CascadeForwarderSettings {
+                username: String::new(),
+                ..super::CascadeForwarderSettings::builder()
+                    .server_address("127.0.0.1:443")
+                    .unwrap()
+                    .server_name("exit.example.com".into())
+                    .credentials("entry".into(), "EXIT_PASSWORD".into())
+                    .build()
+                    .unwrap()
+            }),
+        );
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn client_groups() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let credentials_path = temp_dir.path().join("credentials.toml");
+        fs::write(
+            &credentials_path,
+            r#"
+[[client]]
+username = "alice"
+password = "first"
+groups = ["free", "eu"]
+
+[[client]]
+username = "bob"
+password = "second"
+"#,
+        )
+        .unwrap();
+
+        let path = credentials_path.to_str().unwrap().to_string();
+        let deserializer = StringDeserializer::<ValueError>::new(path);
+        let settings = super::Settings {
+            clients: super::deserialize_clients(deserializer).unwrap(),
+            ..Default::default()
+        };
+
+        assert_eq!(settings.user_groups("alice"), ["free", "eu"]);
+        assert!(settings.user_groups("bob").is_empty());
+        assert!(settings.user_groups("carol").is_empty());
+
+        fs::write(
+            &credentials_path,
+            r#"
+[[client]]
+username = "alice"
+password = "first"
+groups = "free"
+"#,
+        )
+        .unwrap();
+        let path = credentials_path.to_str().unwrap().to_string();
+        let deserializer = StringDeserializer::<ValueError>::new(path);
+        assert!(super::deserialize_clients(deserializer).is_err());
+    }
 }
@@ -111,6 +111,11 @@ pub(crate) enum ExtendedAuthenticationValue<'this> {
     /// domain name.
     /// Has no value (the length is zero).
     SniAuth,
+    /// The username mapped from the TLS client certificate the VPN client authenticated with.
+    /// The value is a UTF-8 string.
+    /// **MUST NOT** come together with the [`ExtendedAuthenticationValue::BasicProxyAuth`]
+    /// or [`ExtendedAuthenticationValue::SniAuth`] in the same message.
+    ClientCertificateAuth(Cow<'this, str>),
 }
 
 impl ExtendedAuthenticationValue<'_> {
@@ -121,6 +126,7 @@ impl ExtendedAuthenticationValue<'_> {
             Self::UserAgent(_) => 0x03,
             Self::BasicProxyAuth(_) => 0x04,
             Self::SniAuth => 0x05,
+            Self::ClientCertificateAuth(_) => 0x06,
         }
     }
 
@@ -135,6 +141,9 @@ impl ExtendedAuthenticationValue<'_> {
                 ExtendedAuthenticationValue::BasicProxyAuth(Cow::Owned(x.into_owned()))
             }
             Self::SniAuth => ExtendedAuthenticationValue::SniAuth,
+            Self::ClientCertificateAuth(x) => {
+                ExtendedAuthenticationValue::ClientCertificateAuth(Cow::Owned(x.into_owned()))
+            }
         }
     }
 }
@@ -222,6 +231,7 @@ impl Address<'_> {
 }
 
 /// https://datatracker.ietf.org/doc/html/rfc1928#section-4
+#[derive(Clone)]
 pub(crate) enum Request<'this> {
     /// CONNECT X'01'
     Connect(Address<'this>, u16),
@@ -714,6 +724,16 @@ where
         ExtendedAuthenticationValue::SniAuth => {
             put_u16(buf, 0_u16);
         }
+        ExtendedAuthenticationValue::ClientCertificateAuth(x) => {
+            if x.len() > u16::MAX as usize {
+                return Err(Error::Protocol(
+                    "Too long client certificate username".to_string(),
+                ));
+            }
+
+            put_u16(buf, x.len() as u16);
+            buf.extend_from_slice(x.as_bytes());
+        }
     }
 
     Ok(())
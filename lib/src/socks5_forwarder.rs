@@ -1,9 +1,10 @@
 use crate::forwarder::{Forwarder, IcmpMultiplexer, UdpMultiplexer};
-use crate::settings::{ForwardProtocolSettings, Settings, Socks5ForwarderSettings};
+use crate::settings::Socks5ForwarderSettings;
+use crate::socks5_pool::{UpstreamGuard, UpstreamPool};
 use crate::tcp_forwarder::TcpForwarder;
 use crate::{
     authentication, core, datagram_pipe, downstream, forwarder, log_id, log_utils, net_utils, pipe,
-    socks5_client, tunnel,
+    socks5_client, socks5_pool, tunnel,
 };
 use async_trait::async_trait;
 use base64::Engine;
@@ -17,19 +18,31 @@ use std::ops::Deref;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 
 pub(crate) struct Socks5Forwarder {
     context: Arc<core::Context>,
+    /// The snapshot of the settings taken on the forwarder creation
+    settings: Arc<Socks5ForwarderSettings>,
+    pool: Arc<UpstreamPool>,
 }
 
 struct TcpConnector {
     context: Arc<core::Context>,
+    settings: Arc<Socks5ForwarderSettings>,
+    pool: Arc<UpstreamPool>,
+}
+
+/// Keeps the upstream the connection goes through counted as active
+struct TcpSource {
+    source: Box<dyn pipe::Source>,
+    _upstream: UpstreamGuard,
 }
 
 struct DatagramSource {
     shared: Arc<DatagramTransceiverShared>,
     new_socket_rx: mpsc::Receiver<()>,
+    ejections: watch::Receiver<()>,
     pending_read: Option<SocketAddr>,
     pending_closures: LinkedList<(forwarder::UdpDatagramMeta, io::Error)>,
 }
@@ -40,6 +53,9 @@ struct DatagramSink {
 
 struct DatagramTransceiverShared {
     context: Arc<core::Context>,
+    pool: Arc<UpstreamPool>,
+    /// Identifies the client for the consistent hashing
+    balancing_key: String,
     /// Key is the source address received in packet from client
     associations: Mutex<HashMap<SocketAddr, UdpAssociation>>,
     new_socket_tx: mpsc::Sender<()>,
@@ -52,6 +68,7 @@ type UdpAssociationSocket = socks5_client::UdpAssociation<TcpStream>;
 struct UdpAssociation {
     socket: Arc<UdpAssociationSocket>,
     peers: HashSet<SocketAddr>,
+    upstream: UpstreamGuard,
     _metrics_guard: crate::metrics::OutboundUdpSocketCounter,
 }
 
@@ -61,15 +78,73 @@ struct SocketError {
 }
 
 struct DatagramMuxAuthenticator {
-    context: Arc<core::Context>,
+    settings: Arc<Socks5ForwarderSettings>,
+    pool: Arc<UpstreamPool>,
 }
 
 impl Socks5Forwarder {
-    pub fn new(context: Arc<core::Context>) -> Self {
-        Self { context }
+    /// `name` is the name of the forwarder in the settings, [`None`] stands for the default one
+    pub fn new(
+        context: Arc<core::Context>,
+        name: Option<String>,
+        settings: Arc<Socks5ForwarderSettings>,
+    ) -> Self {
+        let pool = context.socks5_pools.get(&context.metrics, &name, &settings);
+        Self {
+            context,
+            settings,
+            pool,
+        }
     }
 }
 
+/// Send the request through the upstreams of the pool in turn until one of them
+/// is reachable.
+/// Returns the result of the request along with the upstream it was sent through.
+async fn connect_upstream(
+    pool: &Arc<UpstreamPool>,
+    balancing_key: &str,
+    auth: Option<socks5_client::Authentication<'_>>,
+    request: socks5_client::Request<'_>,
+    id: &log_utils::IdChain<u64>,
+) -> Result<(socks5_client::ConnectResult<TcpStream>, UpstreamGuard), socks5_client::Error> {
+    let mut tried = Vec::with_capacity(pool.len());
+    let mut last_error = None;
+    while let Some(upstream) = pool.select(balancing_key, &tried) {
+        tried.push(upstream.index());
+        let stream = match upstream.connect(id).await {
+            Ok(x) => x,
+            Err(e) => {
+                last_error = Some(e);
+                continue;
+            }
+        };
+
+        match socks5_client::connect(stream, auth.clone(), request.clone()).await {
+            Ok(x) => {
+                upstream.report_success();
+                return Ok((x, upstream));
+            }
+            Err(socks5_client::Error::Io(e)) => {
+                log_id!(
+                    debug,
+                    id,
+                    "SOCKS upstream {} failed: {}",
+                    upstream.address(),
+                    e
+                );
+                upstream.report_failure();
+                last_error = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(socks5_client::Error::Io(last_error.unwrap_or_else(|| {
+        io::Error::new(ErrorKind::Other, "No SOCKS upstreams")
+    })))
+}
+
 #[async_trait]
 impl forwarder::UdpDatagramPipeShared for DatagramTransceiverShared {
     async fn on_new_udp_connection(&self, meta: &downstream::UdpDatagramMeta) -> io::Result<()> {
@@ -79,16 +154,20 @@ impl forwarder::UdpDatagramPipeShared for DatagramTransceiverShared {
             return Ok(());
         }
 
-        let socket = match socks5_client::connect(
-            TcpStream::connect(socks_settings(&self.context.settings).address).await?,
+        let (socket, upstream) = match connect_upstream(
+            &self.pool,
+            &self.balancing_key,
             self.auth.clone(),
             socks5_client::Request::UdpAssociate,
+            &self.id,
         )
         .await
         {
-            Ok(socks5_client::ConnectResult::TcpConnection(_)) => unreachable!(),
-            Ok(socks5_client::ConnectResult::UdpAssociation(x)) => Arc::new(x),
-            Ok(socks5_client::ConnectResult::Failure(x)) => {
+            Ok((socks5_client::ConnectResult::TcpConnection(_), _)) => unreachable!(),
+            Ok((socks5_client::ConnectResult::UdpAssociation(x), upstream)) => {
+                (Arc::new(x), upstream)
+            }
+            Ok((socks5_client::ConnectResult::Failure(x), _)) => {
                 return Err(io::Error::new(
                     ErrorKind::Other,
                     format!("SOCKS server replied with error code: {:?}", x),
@@ -116,6 +195,7 @@ impl forwarder::UdpDatagramPipeShared for DatagramTransceiverShared {
             UdpAssociation {
                 socket,
                 peers: HashSet::from([meta.destination]),
+                upstream,
                 _metrics_guard: metrics_guard,
             },
         );
@@ -147,12 +227,15 @@ impl Forwarder for Socks5Forwarder {
     fn tcp_connector(&self) -> Box<dyn forwarder::TcpConnector> {
         Box::new(TcpConnector {
             context: self.context.clone(),
+            settings: self.settings.clone(),
+            pool: self.pool.clone(),
         })
     }
 
     fn datagram_mux_authenticator(&self) -> Box<dyn forwarder::DatagramMultiplexerAuthenticator> {
         Box::new(DatagramMuxAuthenticator {
-            context: self.context.clone(),
+            settings: self.settings.clone(),
+            pool: self.pool.clone(),
         })
     }
 
@@ -162,14 +245,17 @@ impl Forwarder for Socks5Forwarder {
         meta: forwarder::UdpMultiplexerMeta,
     ) -> io::Result<UdpMultiplexer> {
         let (tx, rx) = mpsc::channel(1);
+        let username = meta.auth.as_ref().and_then(|x| x.username());
         let shared = Arc::new(DatagramTransceiverShared {
             context: self.context.clone(),
+            pool: self.pool.clone(),
+            balancing_key: socks5_pool::balancing_key(username.as_deref(), &meta.client_address),
             associations: Default::default(),
             new_socket_tx: tx,
             auth: meta
                 .auth
                 .map(|x| {
-                    if socks_settings(&self.context.settings).extended_auth {
+                    if self.settings.extended_auth {
                         make_extended_auth(
                             x,
                             &meta.tls_domain,
@@ -191,6 +277,7 @@ impl Forwarder for Socks5Forwarder {
             Box::new(DatagramSource {
                 shared: shared.clone(),
                 new_socket_rx: rx,
+                ejections: self.pool.subscribe(),
                 pending_read: None,
                 pending_closures: Default::default(),
             }),
@@ -226,17 +313,15 @@ impl forwarder::TcpConnector for TcpConnector {
             }
         };
 
-        let stream = match TcpStream::connect(socks_settings(&self.context.settings).address).await
-        {
-            Ok(s) => s,
-            Err(e) => return Err(tunnel::ConnectionError::Io(e)),
-        };
+        let username = meta.auth.as_ref().and_then(|x| x.username());
+        let balancing_key = socks5_pool::balancing_key(username.as_deref(), &meta.client_address);
 
-        match socks5_client::connect(
-            stream,
+        match connect_upstream(
+            &self.pool,
+            &balancing_key,
             meta.auth
                 .map(|x| {
-                    if socks_settings(&self.context.settings).extended_auth {
+                    if self.settings.extended_auth {
                         make_extended_auth(
                             x,
                             &meta.tls_domain,
@@ -250,31 +335,46 @@ impl forwarder::TcpConnector for TcpConnector {
                 .transpose()
                 .map_err(tunnel::ConnectionError::Other)?,
             socks5_client::Request::Connect(destination, port),
+            &id,
         )
         .await
         {
-            Ok(socks5_client::ConnectResult::TcpConnection(stream)) => {
+            Ok((socks5_client::ConnectResult::TcpConnection(stream), upstream)) => {
                 let metrics_guard = self.context.metrics.clone().outbound_tcp_socket_counter();
-                Ok(TcpForwarder::pipe_from_stream(stream, id, metrics_guard))
+                let (source, sink) = TcpForwarder::pipe_from_stream(stream, id, metrics_guard);
+                Ok((
+                    Box::new(TcpSource {
+                        source,
+                        _upstream: upstream,
+                    }),
+                    sink,
+                ))
             }
-            Ok(socks5_client::ConnectResult::UdpAssociation(_)) => unreachable!(),
-            Ok(socks5_client::ConnectResult::Failure(
-                socks5_client::ReplyCode::HostUnreachable,
+            Ok((socks5_client::ConnectResult::UdpAssociation(_), _)) => unreachable!(),
+            Ok((
+                socks5_client::ConnectResult::Failure(socks5_client::ReplyCode::HostUnreachable),
+                _,
             )) => Err(tunnel::ConnectionError::HostUnreachable),
-            Ok(socks5_client::ConnectResult::Failure(
-                socks5_client::ReplyCode::NetworkUnreachable,
+            Ok((
+                socks5_client::ConnectResult::Failure(socks5_client::ReplyCode::NetworkUnreachable),
+                _,
             )) => Err(tunnel::ConnectionError::HostUnreachable),
-            Ok(socks5_client::ConnectResult::Failure(
-                socks5_client::ReplyCode::ConnectionRefused,
+            Ok((
+                socks5_client::ConnectResult::Failure(socks5_client::ReplyCode::ConnectionRefused),
+                _,
             )) => Err(tunnel::ConnectionError::Io(
                 ErrorKind::ConnectionRefused.into(),
             )),
-            Ok(socks5_client::ConnectResult::Failure(socks5_client::ReplyCode::TtlExpired)) => {
-                Err(tunnel::ConnectionError::Timeout)
+            Ok((
+                socks5_client::ConnectResult::Failure(socks5_client::ReplyCode::TtlExpired),
+                _,
+            )) => Err(tunnel::ConnectionError::Timeout),
+            Ok((socks5_client::ConnectResult::Failure(x), _)) => {
+                Err(tunnel::ConnectionError::Other(format!(
+                    "SOCKS server replied with error code: {:?}",
+                    x
+                )))
             }
-            Ok(socks5_client::ConnectResult::Failure(x)) => Err(tunnel::ConnectionError::Other(
-                format!("SOCKS server replied with error code: {:?}", x),
-            )),
             Err(socks5_client::Error::Io(x)) => Err(tunnel::ConnectionError::Io(x)),
             Err(socks5_client::Error::Protocol(x)) => Err(tunnel::ConnectionError::Other(format!(
                 "SOCKS protocol error: {}",
@@ -287,6 +387,21 @@ impl forwarder::TcpConnector for TcpConnector {
     }
 }
 
+#[async_trait]
+impl pipe::Source for TcpSource {
+    fn id(&self) -> log_utils::IdChain<u64> {
+        self.source.id()
+    }
+
+    async fn read(&mut self) -> io::Result<pipe::Data> {
+        self.source.read().await
+    }
+
+    fn consume(&mut self, size: usize) -> io::Result<()> {
+        self.source.consume(size)
+    }
+}
+
 #[async_trait]
 impl forwarder::DatagramMultiplexerAuthenticator for DatagramMuxAuthenticator {
     async fn check_auth(
@@ -296,12 +411,13 @@ impl forwarder::DatagramMultiplexerAuthenticator for DatagramMuxAuthenticator {
         auth: authentication::Source<'_>,
         user_agent: Option<&'_ str>,
     ) -> Result<(), tunnel::ConnectionError> {
-        match socks5_client::connect(
-            TcpStream::connect(socks_settings(&self.context.settings).address)
-                .await
-                .map_err(tunnel::ConnectionError::Io)?,
+        let username = auth.username();
+        let balancing_key = socks5_pool::balancing_key(username.as_deref(), &client_address);
+        match connect_upstream(
+            &self.pool,
+            &balancing_key,
             Some(
-                if socks_settings(&self.context.settings).extended_auth {
+                if self.settings.extended_auth {
                     make_extended_auth(auth, tls_domain, &client_address, user_agent)
                 } else {
                     make_auth(auth)
@@ -309,14 +425,18 @@ impl forwarder::DatagramMultiplexerAuthenticator for DatagramMuxAuthenticator {
                 .map_err(|x| tunnel::ConnectionError::Io(io::Error::new(ErrorKind::Other, x)))?,
             ),
             socks5_client::Request::UdpAssociate,
+            &log_utils::IdChain::empty(),
         )
         .await
         {
-            Ok(socks5_client::ConnectResult::TcpConnection(_)) => unreachable!(),
-            Ok(socks5_client::ConnectResult::UdpAssociation(_)) => Ok(()),
-            Ok(socks5_client::ConnectResult::Failure(x)) => Err(tunnel::ConnectionError::Other(
-                format!("SOCKS server replied with error code: {:?}", x),
-            )),
+            Ok((socks5_client::ConnectResult::TcpConnection(_), _)) => unreachable!(),
+            Ok((socks5_client::ConnectResult::UdpAssociation(_), _)) => Ok(()),
+            Ok((socks5_client::ConnectResult::Failure(x), _)) => {
+                Err(tunnel::ConnectionError::Other(format!(
+                    "SOCKS server replied with error code: {:?}",
+                    x
+                )))
+            }
             Err(socks5_client::Error::Io(x)) => Err(tunnel::ConnectionError::Io(x)),
             Err(socks5_client::Error::Protocol(x)) => Err(tunnel::ConnectionError::Other(format!(
                 "SOCKS protocol error: {}",
@@ -394,32 +514,75 @@ impl DatagramSource {
             futures
         };
 
-        let wait_reads = futures::future::select_all(futures);
-        tokio::pin!(wait_reads);
+        let mut ejected = false;
+        let result = {
+            let wait_reads = futures::future::select_all(futures);
+            tokio::pin!(wait_reads);
 
-        let wait_new_socket = self.new_socket_rx.recv();
-        tokio::pin!(wait_new_socket);
+            let wait_new_socket = self.new_socket_rx.recv();
+            tokio::pin!(wait_new_socket);
 
-        tokio::select! {
-            reads = wait_reads => match reads.0 {
-                Ok(ready) => {
-                    debug_assert!(self.pending_read.is_none(), "{:?}", self.pending_read);
-                    self.pending_read = Some(ready);
-                    Ok(None)
-                }
-                Err(e) => {
-                    log_id!(debug, self.shared.id, "Error waiting for UDP read: source={} error={}",
-                        e.source, e.io);
-                    Ok(Some(e))
-                }
-            },
-            r = wait_new_socket => match r {
-                Some(_) => Ok(None),
-                None => {
-                    log_id!(debug, self.shared.id, "Wake sender dropped");
-                    Err(io::Error::from(ErrorKind::UnexpectedEof))
+            tokio::select! {
+                reads = wait_reads => match reads.0 {
+                    Ok(ready) => {
+                        debug_assert!(self.pending_read.is_none(), "{:?}", self.pending_read);
+                        self.pending_read = Some(ready);
+                        Ok(None)
+                    }
+                    Err(e) => {
+                        log_id!(debug, self.shared.id, "Error waiting for UDP read: source={} error={}",
+                            e.source, e.io);
+                        Ok(Some(e))
+                    }
+                },
+                r = wait_new_socket => match r {
+                    Some(_) => Ok(None),
+                    None => {
+                        log_id!(debug, self.shared.id, "Wake sender dropped");
+                        Err(io::Error::from(ErrorKind::UnexpectedEof))
+                    }
+                },
+                r = self.ejections.changed() => match r {
+                    Ok(_) => {
+                        ejected = true;
+                        Ok(None)
+                    }
+                    Err(_) => {
+                        log_id!(debug, self.shared.id, "Upstream pool dropped");
+                        Err(io::Error::from(ErrorKind::UnexpectedEof))
+                    }
                 }
             }
+        };
+        if ejected {
+            self.close_ejected();
+        }
+        result
+    }
+
+    /// Close the associations through the ejected upstreams, so that the subsequent
+    /// datagrams re-associate through the healthy ones
+    fn close_ejected(&mut self) {
+        let ejected = self
+            .shared
+            .associations
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, x)| x.upstream.is_ejected())
+            .map(|(source, _)| *source)
+            .collect::<Vec<_>>();
+        for source in ejected {
+            log_id!(
+                debug,
+                self.shared.id,
+                "Closing UDP association through ejected upstream: source={}",
+                source
+            );
+            self.on_socket_error(
+                &source,
+                io::Error::new(ErrorKind::Other, "SOCKS upstream is down"),
+            );
         }
     }
 }
@@ -522,6 +685,9 @@ fn make_auth(auth: authentication::Source) -> Result<socks5_client::Authenticati
                 ),
             )
         }
+        authentication::Source::ClientCertificate(x) => {
+            socks5_client::Authentication::UsernamePassword(x, Cow::Borrowed(""))
+        }
     })
 }
 
@@ -549,18 +715,14 @@ fn make_extended_auth<'a>(
         authentication::Source::ProxyBasic(x) => values.push(
             socks5_client::ExtendedAuthenticationValue::BasicProxyAuth(x),
         ),
+        authentication::Source::ClientCertificate(x) => {
+            values.push(socks5_client::ExtendedAuthenticationValue::ClientCertificateAuth(x))
+        }
     }
 
     Ok(socks5_client::Authentication::Extended(values))
 }
 
-const fn socks_settings(settings: &Settings) -> &Socks5ForwarderSettings {
-    match &settings.forward_protocol {
-        ForwardProtocolSettings::Socks5(x) => x,
-        ForwardProtocolSettings::Direct(_) => unreachable!(),
-    }
-}
-
 fn socks_to_io_error(err: socks5_client::Error) -> io::Error {
     match err {
         socks5_client::Error::Io(e) => e,
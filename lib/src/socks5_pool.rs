@@ -0,0 +1,454 @@
+use crate::metrics::{Metrics, Socks5UpstreamMetrics};
+use crate::settings::{Socks5Balancing, Socks5ForwarderSettings, Socks5HealthCheckSettings};
+use crate::{log_id, log_utils};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+
+/// The version byte of the SOCKS5 protocol
+const SOCKS_VERSION: u8 = 5;
+/// The greeting offering the no-authentication and username/password methods
+const PROBE_GREETING: [u8; 4] = [SOCKS_VERSION, 2, 0x00, 0x02];
+
+/// The pools of the upstream proxies shared by the SOCKS5 forwarders.
+/// Keyed by the forwarder name, [`None`] stands for the default forwarder.
+#[derive(Default)]
+pub(crate) struct UpstreamPools {
+    pools: Mutex<HashMap<Option<String>, Arc<UpstreamPool>>>,
+}
+
+/// The upstream proxies of a SOCKS5 forwarder
+pub(crate) struct UpstreamPool {
+    /// The settings the pool was created with
+    settings: Socks5ForwarderSettings,
+    upstreams: Vec<Upstream>,
+    /// The round-robin counter
+    next: AtomicUsize,
+    /// Notified each time an upstream is ejected
+    ejections: watch::Sender<()>,
+}
+
+struct Upstream {
+    address: SocketAddr,
+    health: Mutex<Health>,
+    /// The number of active connections
+    connections: AtomicUsize,
+    metrics: Socks5UpstreamMetrics,
+}
+
+#[derive(Default)]
+struct Health {
+    ejected: bool,
+    /// The number of consecutive failures
+    failures: u32,
+    /// The number of consecutive successful probes of an ejected upstream
+    successes: u32,
+}
+
+/// An upstream chosen for a connection.
+/// Counts the connection as active until dropped.
+pub(crate) struct UpstreamGuard {
+    pool: Arc<UpstreamPool>,
+    index: usize,
+}
+
+impl UpstreamPools {
+    /// Get the pool of the forwarder, creating it if there is none or the settings
+    /// have changed since it was created
+    pub fn get(
+        &self,
+        metrics: &Arc<Metrics>,
+        name: &Option<String>,
+        settings: &Socks5ForwarderSettings,
+    ) -> Arc<UpstreamPool> {
+        let mut pools = self.pools.lock().unwrap();
+        if let Some(x) = pools.get(name).filter(|x| x.settings == *settings) {
+            return x.clone();
+        }
+
+        let label = name.as_deref().unwrap_or("default");
+        let pool = UpstreamPool::new(metrics, label, settings.clone());
+        if let Some(old) = pools.insert(name.clone(), pool.clone()) {
+            for x in old
+                .upstreams
+                .iter()
+                .filter(|x| !pool.upstreams.iter().any(|y| y.address == x.address))
+            {
+                metrics.remove_socks5_upstream_metrics(label, &x.address);
+            }
+        }
+        pool
+    }
+}
+
+impl UpstreamPool {
+    fn new(metrics: &Metrics, label: &str, settings: Socks5ForwarderSettings) -> Arc<Self> {
+        let pool = Arc::new(Self {
+            upstreams: settings
+                .upstream_addresses()
+                .into_iter()
+                .map(|address| {
+                    let metrics = metrics.socks5_upstream_metrics(label, &address);
+                    metrics.set_up(true);
+                    Upstream {
+                        address,
+                        health: Default::default(),
+                        connections: Default::default(),
+                        metrics,
+                    }
+                })
+                .collect(),
+            settings,
+            next: Default::default(),
+            ejections: watch::channel(()).0,
+        });
+
+        if let Some(x) = pool.settings.health_check.clone() {
+            tokio::spawn(run_health_checks(Arc::downgrade(&pool), x));
+        }
+        pool
+    }
+
+    pub fn len(&self) -> usize {
+        self.upstreams.len()
+    }
+
+    /// The timeout of a connection attempt to an upstream
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.settings.health_check.as_ref().map(|x| x.timeout)
+    }
+
+    /// Subscribe to the upstream ejections
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.ejections.subscribe()
+    }
+
+    /// Choose an upstream for a connection skipping the already `tried` ones.
+    /// The ejected upstreams are chosen only if all the others have been tried.
+    /// `key` identifies the client for the consistent hashing.
+    pub fn select(self: &Arc<Self>, key: &str, tried: &[usize]) -> Option<UpstreamGuard> {
+        let untried = (0..self.upstreams.len())
+            .filter(|i| !tried.contains(i))
+            .collect::<Vec<_>>();
+        let healthy = untried
+            .iter()
+            .copied()
+            .filter(|i| !self.upstreams[*i].is_ejected())
+            .collect::<Vec<_>>();
+        let candidates = if healthy.is_empty() { untried } else { healthy };
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let index = match self.settings.balancing {
+            Socks5Balancing::RoundRobin => {
+                candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+            }
+            Socks5Balancing::LeastConnections => *candidates
+                .iter()
+                .min_by_key(|i| self.upstreams[**i].connections.load(Ordering::Relaxed))
+                .unwrap(),
+            // Rendezvous hashing keeps the clients of the remaining upstreams in place
+            // when one of them is ejected
+            Socks5Balancing::ConsistentHash => *candidates
+                .iter()
+                .max_by_key(|i| {
+                    let mut hasher = DefaultHasher::new();
+                    (key, self.upstreams[**i].address).hash(&mut hasher);
+                    hasher.finish()
+                })
+                .unwrap(),
+        };
+
+        Some(UpstreamGuard::new(self.clone(), index))
+    }
+
+    /// Count the failed probe or connection attempt
+    fn on_failure(&self, index: usize) {
+        let upstream = &self.upstreams[index];
+        upstream.metrics.add_failure();
+        let threshold = match self.settings.health_check.as_ref() {
+            Some(x) => x.unhealthy_threshold,
+            // The upstreams are never ejected without the health checks
+            None => return,
+        };
+
+        let mut health = upstream.health.lock().unwrap();
+        health.failures += 1;
+        health.successes = 0;
+        if !health.ejected && health.failures >= threshold {
+            health.ejected = true;
+            upstream.metrics.set_up(false);
+            log::warn!(
+                "SOCKS upstream {} is ejected from the pool",
+                upstream.address
+            );
+            self.ejections.send_replace(());
+        }
+    }
+
+    fn on_probe_success(&self, index: usize, threshold: u32) {
+        let upstream = &self.upstreams[index];
+        let mut health = upstream.health.lock().unwrap();
+        health.failures = 0;
+        if health.ejected {
+            health.successes += 1;
+            if health.successes >= threshold {
+                *health = Health::default();
+                upstream.metrics.set_up(true);
+                log::info!(
+                    "SOCKS upstream {} is restored to the pool",
+                    upstream.address
+                );
+            }
+        }
+    }
+}
+
+impl Upstream {
+    fn is_ejected(&self) -> bool {
+        self.health.lock().unwrap().ejected
+    }
+}
+
+impl UpstreamGuard {
+    fn new(pool: Arc<UpstreamPool>, index: usize) -> Self {
+        let upstream = &pool.upstreams[index];
+        upstream.connections.fetch_add(1, Ordering::Relaxed);
+        upstream.metrics.inc_connections();
+        Self { pool, index }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.pool.upstreams[self.index].address
+    }
+
+    pub fn is_ejected(&self) -> bool {
+        self.pool.upstreams[self.index].is_ejected()
+    }
+
+    /// Connect to the upstream, counting a failure towards its ejection
+    pub async fn connect(&self, id: &log_utils::IdChain<u64>) -> io::Result<TcpStream> {
+        let result = match self.pool.connect_timeout() {
+            None => TcpStream::connect(self.address()).await,
+            Some(x) => tokio::time::timeout(x, TcpStream::connect(self.address()))
+                .await
+                .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into())),
+        };
+        if let Err(e) = &result {
+            log_id!(
+                debug,
+                id,
+                "Failed to connect to SOCKS upstream {}: {}",
+                self.address(),
+                e
+            );
+            self.report_failure();
+        }
+        result
+    }
+
+    /// Count the failure towards the ejection of the upstream
+    pub fn report_failure(&self) {
+        self.pool.on_failure(self.index);
+    }
+
+    /// Reset the consecutive failures of the upstream
+    pub fn report_success(&self) {
+        self.pool.upstreams[self.index]
+            .health
+            .lock()
+            .unwrap()
+            .failures = 0;
+    }
+}
+
+impl Drop for UpstreamGuard {
+    fn drop(&mut self) {
+        let upstream = &self.pool.upstreams[self.index];
+        upstream.connections.fetch_sub(1, Ordering::Relaxed);
+        upstream.metrics.dec_connections();
+    }
+}
+
+/// Probe the upstreams of the pool until it is dropped
+async fn run_health_checks(pool: Weak<UpstreamPool>, settings: Socks5HealthCheckSettings) {
+    loop {
+        tokio::time::sleep(settings.interval).await;
+        let pool = match pool.upgrade() {
+            Some(x) => x,
+            None => break,
+        };
+
+        let results = futures::future::join_all(
+            pool.upstreams
+                .iter()
+                .map(|x| tokio::time::timeout(settings.timeout, probe(x.address))),
+        )
+        .await;
+        for (i, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(Ok(())) => pool.on_probe_success(i, settings.healthy_threshold),
+                Ok(Err(e)) => {
+                    log::debug!(
+                        "SOCKS upstream {} probe failed: {}",
+                        pool.upstreams[i].address,
+                        e
+                    );
+                    pool.on_failure(i);
+                }
+                Err(_) => {
+                    log::debug!(
+                        "SOCKS upstream {} probe timed out",
+                        pool.upstreams[i].address
+                    );
+                    pool.on_failure(i);
+                }
+            }
+        }
+    }
+}
+
+/// Check that the upstream answers the SOCKS5 greeting
+async fn probe(address: SocketAddr) -> io::Result<()> {
+    let mut stream = TcpStream::connect(address).await?;
+    stream.write_all(&PROBE_GREETING).await?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Unexpected SOCKS version: {}", reply[0]),
+        ));
+    }
+    Ok(())
+}
+
+/// The key the clients are distinguished by in the consistent hashing
+pub(crate) fn balancing_key(username: Option<&str>, client_address: &IpAddr) -> String {
+    match username {
+        Some(x) => x.to_string(),
+        None => client_address.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Socks5ForwarderSettings;
+
+    fn make_pool(balancing: Socks5Balancing, with_health_check: bool) -> Arc<UpstreamPool> {
+        let mut builder = Socks5ForwarderSettings::builder()
+            .upstream("127.0.0.1:1081")
+            .unwrap()
+            .upstream("127.0.0.1:1082")
+            .unwrap()
+            .upstream("127.0.0.1:1083")
+            .unwrap()
+            .balancing(balancing);
+        if with_health_check {
+            builder = builder.health_check(
+                Socks5HealthCheckSettings::builder()
+                    .unhealthy_threshold(2)
+                    .build()
+                    .unwrap(),
+            );
+        }
+        let metrics = Metrics::new(None).unwrap();
+        UpstreamPool::new(&metrics, "default", builder.build().unwrap())
+    }
+
+    #[tokio::test]
+    async fn round_robin() {
+        let pool = make_pool(Socks5Balancing::RoundRobin, false);
+        let chosen = (0..6)
+            .map(|_| pool.select("", &[]).unwrap().index())
+            .collect::<Vec<_>>();
+        assert_eq!(chosen, [0, 1, 2, 0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn least_connections() {
+        let pool = make_pool(Socks5Balancing::LeastConnections, false);
+        let first = pool.select("", &[]).unwrap();
+        let second = pool.select("", &[]).unwrap();
+        assert_ne!(first.index(), second.index());
+        let third = pool.select("", &[]).unwrap();
+        assert_ne!(third.index(), first.index());
+        assert_ne!(third.index(), second.index());
+
+        let released = second.index();
+        drop(second);
+        assert_eq!(pool.select("", &[]).unwrap().index(), released);
+    }
+
+    #[tokio::test]
+    async fn consistent_hash() {
+        let pool = make_pool(Socks5Balancing::ConsistentHash, true);
+        let alice = pool.select("alice", &[]).unwrap().index();
+        for _ in 0..4 {
+            assert_eq!(pool.select("alice", &[]).unwrap().index(), alice);
+        }
+
+        pool.on_failure(alice);
+        pool.on_failure(alice);
+        let moved = pool.select("alice", &[]).unwrap().index();
+        assert_ne!(moved, alice);
+        assert_eq!(pool.select("alice", &[]).unwrap().index(), moved);
+    }
+
+    #[tokio::test]
+    async fn ejection_and_restoration() {
+        let pool = make_pool(Socks5Balancing::RoundRobin, true);
+        let ejections = pool.subscribe();
+
+        pool.on_failure(0);
+        assert!(!pool.upstreams[0].is_ejected());
+        pool.on_failure(0);
+        assert!(pool.upstreams[0].is_ejected());
+        assert!(ejections.has_changed().unwrap());
+        assert!((0..4).all(|_| pool.select("", &[]).unwrap().index() != 0));
+
+        pool.on_probe_success(0, 2);
+        assert!(pool.upstreams[0].is_ejected());
+        pool.on_probe_success(0, 2);
+        assert!(!pool.upstreams[0].is_ejected());
+    }
+
+    #[tokio::test]
+    async fn failover_order() {
+        let pool = make_pool(Socks5Balancing::RoundRobin, true);
+        pool.on_failure(1);
+        pool.on_failure(1);
+
+        let mut tried = vec![];
+        while let Some(x) = pool.select("", &tried) {
+            tried.push(x.index());
+        }
+        // The ejected upstream is the last resort
+        assert_eq!(tried.len(), 3);
+        assert_eq!(tried[2], 1);
+    }
+
+    #[tokio::test]
+    async fn never_ejected_without_health_checks() {
+        let pool = make_pool(Socks5Balancing::RoundRobin, false);
+        for _ in 0..10 {
+            pool.on_failure(0);
+        }
+        assert!(!pool.upstreams[0].is_ejected());
+    }
+}
@@ -0,0 +1,241 @@
+use crate::settings::{DirectForwarderSettings, SourceAffinity};
+use ipnet::Ipv6Net;
+use siphasher::sip::SipHasher13;
+use std::hash::Hasher;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The keys of the hash mapping the clients to the source addresses.
+/// They are fixed, so that the clients keep their addresses across the restarts.
+const HASH_KEYS: (u64, u64) = (0x5f1c_9a3e_82d4_6b07, 0xc3a8_1e5d_70f2_94b6);
+
+/// Tells apart the sessions for [`SourceAffinity::Session`]
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(0);
+
+/// The source addresses the outbound sockets of a direct forwarder are bound to
+pub(crate) struct SourcePool {
+    addresses: Vec<IpAddr>,
+    ipv6_prefix: Option<Ipv6Net>,
+    affinity: SourceAffinity,
+    /// The session of the forwarder the pool was created for
+    session: u64,
+}
+
+/// Chooses the source addresses of the outbound sockets of a client
+#[derive(Clone)]
+pub(crate) struct SourceSelector {
+    pool: Arc<SourcePool>,
+    /// The hash of the key the client sticks to
+    key: u64,
+}
+
+impl SourcePool {
+    /// Create the pool for a client session.
+    /// Returns [`None`] if the settings have no source addresses.
+    pub fn new(settings: &DirectForwarderSettings) -> Option<Arc<Self>> {
+        let ipv6_prefix = settings.ipv6_prefix();
+        if settings.source_addresses.is_empty() && ipv6_prefix.is_none() {
+            return None;
+        }
+
+        Some(Arc::new(Self {
+            addresses: settings.source_addresses.clone(),
+            ipv6_prefix,
+            affinity: settings.source_affinity,
+            session: NEXT_SESSION.fetch_add(1, Ordering::Relaxed),
+        }))
+    }
+
+    /// Get the selector for a client identified by the `username`, or by
+    /// the `client_address` if it is not authenticated
+    pub fn selector(
+        self: &Arc<Self>,
+        username: Option<&str>,
+        client_address: &IpAddr,
+    ) -> SourceSelector {
+        let key = match (self.affinity, username) {
+            (SourceAffinity::Session, _) => hash(&[&self.session.to_be_bytes()]),
+            (SourceAffinity::User, Some(x)) => hash(&[x.as_bytes()]),
+            (SourceAffinity::User, None) => hash(&[&ip_octets(client_address)]),
+        };
+
+        SourceSelector {
+            pool: self.clone(),
+            key,
+        }
+    }
+}
+
+impl SourceSelector {
+    /// Choose the source address for a socket to the `peer`.
+    /// Returns [`None`] if the system should choose it.
+    pub fn select(&self, peer: &SocketAddr) -> Option<IpAddr> {
+        if let (SocketAddr::V6(_), Some(prefix)) = (peer, &self.pool.ipv6_prefix) {
+            return Some(IpAddr::V6(address_in_prefix(prefix, self.key)));
+        }
+
+        // Rendezvous hashing: a change of the pool moves only the clients
+        // of the added or removed addresses
+        self.pool
+            .addresses
+            .iter()
+            .filter(|x| x.is_ipv4() == peer.is_ipv4())
+            .max_by_key(|x| hash(&[&self.key.to_be_bytes(), &ip_octets(x)]))
+            .copied()
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> u64 {
+    let mut hasher = SipHasher13::new_with_keys(HASH_KEYS.0, HASH_KEYS.1);
+    for x in parts {
+        hasher.write(x);
+    }
+    hasher.finish()
+}
+
+fn ip_octets(ip: &IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(x) => x.octets().to_vec(),
+        IpAddr::V6(x) => x.octets().to_vec(),
+    }
+}
+
+/// Fill the host bits of the prefix with the bits of the key
+fn address_in_prefix(prefix: &Ipv6Net, key: u64) -> Ipv6Addr {
+    let key = u128::from(key) | (u128::from(key.rotate_left(32)) << 64);
+    let host_mask = u128::from(prefix.hostmask());
+    Ipv6Addr::from(u128::from(prefix.network()) | (key & host_mask))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::DirectForwarderSettings;
+    use std::net::Ipv4Addr;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+    const V4_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 443);
+    const V6_PEER: SocketAddr = SocketAddr::new(
+        IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0xffff, 0, 0, 0, 0, 1)),
+        443,
+    );
+
+    fn make_settings(affinity: SourceAffinity) -> DirectForwarderSettings {
+        DirectForwarderSettings::builder()
+            .source_address("203.0.113.1".parse().unwrap())
+            .source_address("203.0.113.2".parse().unwrap())
+            .source_address("203.0.113.3".parse().unwrap())
+            .ipv6_source_prefix("2001:db8:1:2::/64".into())
+            .source_affinity(affinity)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn no_pool() {
+        assert!(SourcePool::new(&DirectForwarderSettings::default()).is_none());
+    }
+
+    #[test]
+    fn sticky_per_user() {
+        let settings = make_settings(SourceAffinity::User);
+        let pool = SourcePool::new(&settings).unwrap();
+        let another_session = SourcePool::new(&settings).unwrap();
+
+        let source = pool
+            .selector(Some("alice"), &CLIENT)
+            .select(&V4_PEER)
+            .unwrap();
+        // The hash keys are fixed, so the mapping is the same across the restarts
+        assert_eq!(source, "203.0.113.1".parse::<IpAddr>().unwrap());
+        assert_eq!(
+            another_session
+                .selector(Some("alice"), &CLIENT)
+                .select(&V4_PEER),
+            Some(source)
+        );
+
+        let sources = (0..32)
+            .map(|i| {
+                pool.selector(Some(&format!("user{}", i)), &CLIENT)
+                    .select(&V4_PEER)
+                    .unwrap()
+            })
+            .collect::<std::collections::HashSet<_>>();
+        assert!(sources.len() > 1, "{:?}", sources);
+    }
+
+    #[test]
+    fn stable_across_pool_changes() {
+        let settings = make_settings(SourceAffinity::User);
+        let pool = SourcePool::new(&settings).unwrap();
+        let removed = settings.source_addresses[2];
+        let mut shrunk_settings = settings.clone();
+        shrunk_settings.source_addresses.truncate(2);
+        let shrunk = SourcePool::new(&shrunk_settings).unwrap();
+
+        for i in 0..32 {
+            let username = format!("user{}", i);
+            let before = pool
+                .selector(Some(&username), &CLIENT)
+                .select(&V4_PEER)
+                .unwrap();
+            let after = shrunk
+                .selector(Some(&username), &CLIENT)
+                .select(&V4_PEER)
+                .unwrap();
+            if before != removed {
+                assert_eq!(before, after, "{}", username);
+            }
+        }
+    }
+
+    #[test]
+    fn sticky_per_session() {
+        let settings = make_settings(SourceAffinity::Session);
+        let pool = SourcePool::new(&settings).unwrap();
+        let first = pool.selector(Some("alice"), &CLIENT).select(&V6_PEER);
+        assert_eq!(pool.selector(Some("bob"), &CLIENT).select(&V6_PEER), first);
+
+        let sources = (0..32)
+            .map(|_| {
+                SourcePool::new(&settings)
+                    .unwrap()
+                    .selector(Some("alice"), &CLIENT)
+                    .select(&V6_PEER)
+                    .unwrap()
+            })
+            .collect::<std::collections::HashSet<_>>();
+        assert!(sources.len() > 1, "{:?}", sources);
+    }
+
+    #[test]
+    fn ipv6_from_prefix() {
+        let settings = make_settings(SourceAffinity::User);
+        let prefix = settings.ipv6_prefix().unwrap();
+        let pool = SourcePool::new(&settings).unwrap();
+
+        let alice = match pool.selector(Some("alice"), &CLIENT).select(&V6_PEER) {
+            Some(IpAddr::V6(x)) => x,
+            x => panic!("Unexpected source: {:?}", x),
+        };
+        assert!(prefix.contains(&alice));
+        let bob = pool.selector(Some("bob"), &CLIENT).select(&V6_PEER);
+        assert_ne!(bob, Some(IpAddr::V6(alice)));
+    }
+
+    #[test]
+    fn family_without_addresses() {
+        let settings = DirectForwarderSettings::builder()
+            .source_address("203.0.113.1".parse().unwrap())
+            .build()
+            .unwrap();
+        let selector = SourcePool::new(&settings).unwrap().selector(None, &CLIENT);
+        assert_eq!(
+            selector.select(&V4_PEER),
+            Some("203.0.113.1".parse().unwrap())
+        );
+        assert_eq!(selector.select(&V6_PEER), None);
+    }
+}
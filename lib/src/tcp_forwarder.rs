@@ -1,19 +1,26 @@
 use crate::forwarder::TcpConnector;
 use crate::metrics::OutboundTcpSocketCounter;
 use crate::net_utils::TcpDestination;
-use crate::{core, forwarder, log_id, log_utils, net_utils, pipe, tunnel};
+use crate::settings::DnsSettings;
+use crate::source_address::{SourcePool, SourceSelector};
+use crate::{core, forwarder, log_id, log_utils, net_utils, pipe, rules, tunnel};
 use async_trait::async_trait;
 use bytes::{Buf, Bytes};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
 use std::io;
 use std::io::ErrorKind;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::TcpStream;
+use tokio::net::{TcpSocket, TcpStream};
 
 pub(crate) struct TcpForwarder {
     context: Arc<core::Context>,
+    /// The source addresses of the outbound connections, if configured
+    sources: Option<Arc<SourcePool>>,
 }
 
 struct StreamRx {
@@ -34,7 +41,14 @@ struct StreamTx {
 
 impl TcpForwarder {
     pub fn new(context: Arc<core::Context>) -> Self {
-        Self { context }
+        Self {
+            context,
+            sources: None,
+        }
+    }
+
+    pub fn with_sources(context: Arc<core::Context>, sources: Option<Arc<SourcePool>>) -> Self {
+        Self { context, sources }
     }
 
     pub(crate) fn pipe_from_stream(
@@ -65,11 +79,12 @@ impl TcpConnector for TcpForwarder {
         id: log_utils::IdChain<u64>,
         meta: forwarder::TcpConnectionMeta,
     ) -> Result<(Box<dyn pipe::Source>, Box<dyn pipe::Sink>), tunnel::ConnectionError> {
-        let peer = match meta.destination {
+        let settings = self.context.settings();
+        let username = meta.auth.as_ref().and_then(|x| x.username());
+        let peers = match meta.destination {
             TcpDestination::Address(peer) => {
                 let peer_ip = peer.ip();
-                if !self.context.settings.allow_private_network_connections
-                    && !net_utils::is_global_ip(&peer_ip)
+                if !settings.allow_private_network_connections && !net_utils::is_global_ip(&peer_ip)
                 {
                     if peer_ip.is_loopback() {
                         return Err(tunnel::ConnectionError::DnsLoopback);
@@ -77,33 +92,52 @@ impl TcpConnector for TcpForwarder {
                     return Err(tunnel::ConnectionError::DnsNonroutable);
                 }
 
-                peer
+                vec![peer]
             }
             TcpDestination::HostName(peer) => {
                 log_id!(trace, id, "Resolving peer: {:?}", peer);
 
-                let resolved = tokio::net::lookup_host(format!("{}:{}", peer.0, peer.1))
+                let resolved = self
+                    .context
+                    .resolver
+                    .resolve(&peer.0, &id)
                     .await
                     .map_err(io_to_connection_error)?;
 
                 enum SelectionStatus {
                     Loopback,
                     NonRoutable,
-                    Suitable(SocketAddr),
+                    Suitable,
                 }
 
                 let mut status = None;
-                for a in resolved {
-                    let ip = a.ip();
-                    if ip.is_ipv6() && !self.context.settings.ipv6_available {
+                let mut egress_denied = false;
+                let mut suitable = Vec::with_capacity(resolved.len());
+                for ip in resolved {
+                    if ip.is_ipv6() && !settings.ipv6_available {
+                        continue;
+                    }
+
+                    // Host names may resolve into the ranges the egress rules deny
+                    let destination = rules::Destination::Resolved {
+                        host: &peer.0,
+                        address: ip,
+                    };
+                    if !tunnel::is_egress_allowed(
+                        &settings,
+                        username.as_deref(),
+                        destination,
+                        peer.1,
+                        rules::EgressProtocol::Tcp,
+                    ) {
+                        egress_denied = true;
                         continue;
                     }
 
-                    if net_utils::is_global_ip(&ip)
-                        || self.context.settings.allow_private_network_connections
-                    {
-                        status = Some(SelectionStatus::Suitable(a));
-                        break;
+                    if net_utils::is_global_ip(&ip) || settings.allow_private_network_connections {
+                        status = Some(SelectionStatus::Suitable);
+                        suitable.push(SocketAddr::new(ip, peer.1));
+                        continue;
                     }
 
                     if status.is_none() && ip.is_loopback() {
@@ -111,10 +145,13 @@ impl TcpConnector for TcpForwarder {
                         continue;
                     }
 
-                    status = Some(SelectionStatus::NonRoutable);
+                    if !matches!(status, Some(SelectionStatus::Suitable)) {
+                        status = Some(SelectionStatus::NonRoutable);
+                    }
                 }
 
                 match status {
+                    None if egress_denied => return Err(tunnel::ConnectionError::EgressDenied),
                     None => {
                         return Err(io_to_connection_error(io::Error::new(
                             ErrorKind::Other,
@@ -127,37 +164,107 @@ impl TcpConnector for TcpForwarder {
                     Some(SelectionStatus::NonRoutable) => {
                         return Err(tunnel::ConnectionError::DnsNonroutable)
                     }
-                    Some(SelectionStatus::Suitable(x)) => {
-                        log_id!(trace, id, "Selected address: {}", x);
-                        x
-                    }
+                    Some(SelectionStatus::Suitable) => suitable,
                 }
             }
         };
 
-        log_id!(trace, id, "Connecting to peer: {}", peer);
+        log_id!(trace, id, "Connecting to peer: {:?}", peers);
+        let happy_eyeballs_delay = settings
+            .dns
+            .as_ref()
+            .map_or_else(DnsSettings::default_happy_eyeballs_delay, |x| {
+                x.happy_eyeballs_delay
+            });
+        let sources = self
+            .sources
+            .as_ref()
+            .map(|x| x.selector(username.as_deref(), &meta.client_address));
         let metrics_guard = self.context.metrics.clone().outbound_tcp_socket_counter();
-        TcpStream::connect(peer)
+        connect_happy_eyeballs(peers, happy_eyeballs_delay, sources.as_ref())
             .await
-            .and_then(|s| {
-                s.set_nodelay(true)?;
-                Ok(s)
-            })
-            .map(|s| {
-                if let Ok(local_addr) = s.local_addr() {
-                    log_id!(
-                        trace,
-                        id,
-                        "Connection established, local port: {}",
-                        local_addr.port()
-                    );
-                }
-                TcpForwarder::pipe_from_stream(s, id, metrics_guard)
-            })
+            .and_then(|s| on_connected(s, id, metrics_guard))
             .map_err(io_to_connection_error)
     }
 }
 
+fn on_connected(
+    stream: TcpStream,
+    id: log_utils::IdChain<u64>,
+    metrics_guard: OutboundTcpSocketCounter,
+) -> io::Result<(Box<dyn pipe::Source>, Box<dyn pipe::Sink>)> {
+    stream.set_nodelay(true)?;
+    if let Ok(local_addr) = stream.local_addr() {
+        log_id!(
+            trace,
+            id,
+            "Connection established, local port: {}",
+            local_addr.port()
+        );
+    }
+    Ok(TcpForwarder::pipe_from_stream(stream, id, metrics_guard))
+}
+
+/// Connect to the first responding address. The next attempt is started if the previous
+/// one fails or does not complete within the delay, the addresses are tried alternating
+/// the families ([RFC 8305](https://datatracker.ietf.org/doc/html/rfc8305#section-5)).
+async fn connect_happy_eyeballs(
+    addresses: Vec<SocketAddr>,
+    delay: Duration,
+    sources: Option<&SourceSelector>,
+) -> io::Result<TcpStream> {
+    let mut remaining = interleave_families(addresses).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        if let Some(x) = remaining.next() {
+            attempts.push(connect(x, sources.and_then(|s| s.select(&x))));
+        }
+        if attempts.is_empty() {
+            return Err(last_error
+                .unwrap_or_else(|| io::Error::new(ErrorKind::Other, "No addresses to connect")));
+        }
+
+        tokio::select! {
+            Some(x) = attempts.next() => match x {
+                Ok(x) => return Ok(x),
+                Err(e) => last_error = Some(e),
+            },
+            _ = tokio::time::sleep(delay), if remaining.len() > 0 => (),
+            else => (),
+        }
+    }
+}
+
+/// Connect to the peer from the `source` address, or from the one chosen by the system
+async fn connect(peer: SocketAddr, source: Option<IpAddr>) -> io::Result<TcpStream> {
+    let source = match source {
+        Some(x) => x,
+        None => return TcpStream::connect(peer).await,
+    };
+
+    let socket = if peer.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.bind(SocketAddr::new(source, 0))?;
+    socket.connect(peer).await
+}
+
+/// Order the addresses alternating the families starting from IPv6
+fn interleave_families(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addresses.into_iter().partition(SocketAddr::is_ipv6);
+    let mut result = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break result,
+            (x, y) => result.extend(x.into_iter().chain(y)),
+        }
+    }
+}
+
 #[async_trait]
 impl pipe::Source for StreamRx {
     fn id(&self) -> log_utils::IdChain<u64> {
@@ -254,14 +361,13 @@ fn io_to_connection_error(error: io::Error) -> tunnel::ConnectionError {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use crate::settings::Settings;
+    use std::net::Ipv4Addr;
 
     fn make_test_context_disallow_private_network() -> Arc<core::Context> {
-        let mut ctx = core::Context::default();
-        Arc::get_mut(&mut ctx.settings)
-            .unwrap()
-            .allow_private_network_connections = false;
-        Arc::new(ctx)
+        let mut settings = Settings::default();
+        settings.allow_private_network_connections = false;
+        Arc::new(core::Context::with_settings(settings))
     }
 
     #[tokio::test]
@@ -308,4 +414,114 @@ mod tests {
 
         assert!(matches!(err, tunnel::ConnectionError::DnsNonroutable));
     }
+
+    #[tokio::test]
+    async fn test_connect_denies_host_name_resolved_into_denied_range() {
+        let deny = |cidr: &str| rules::EgressRule {
+            domain: None,
+            cidr: Some(cidr.to_string()),
+            ports: None,
+            protocol: None,
+            username: None,
+            group: None,
+            action: rules::RuleAction::Deny,
+        };
+        let rules = rules::RulesConfig {
+            egress: vec![deny("127.0.0.0/8"), deny("::1/128")],
+            ..Default::default()
+        };
+        let mut settings = Settings::default();
+        settings.allow_private_network_connections = true;
+        settings.rules_engine = Some(rules::RulesEngine::from_config(rules));
+        let context = Arc::new(core::Context::with_settings(settings));
+        let connector: Box<dyn TcpConnector> = Box::new(TcpForwarder::new(context));
+
+        let meta = forwarder::TcpConnectionMeta {
+            client_address: IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)),
+            destination: TcpDestination::HostName(("localhost".to_string(), 22)),
+            auth: None,
+            tls_domain: String::new(),
+            user_agent: None,
+        };
+
+        let err = match connector.connect(log_utils::IdChain::empty(), meta).await {
+            Ok(_) => panic!("Expected connection to be denied"),
+            Err(e) => e,
+        };
+
+        assert!(
+            matches!(err, tunnel::ConnectionError::EgressDenied),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_interleave_families() {
+        let v4 = |x| SocketAddr::from((Ipv4Addr::new(192, 0, 2, x), 80));
+        let v6 =
+            |x| SocketAddr::from((std::net::Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, x), 80));
+        assert_eq!(
+            interleave_families(vec![v4(1), v4(2), v4(3), v6(1)]),
+            vec![v6(1), v4(1), v4(2), v4(3)]
+        );
+        assert_eq!(
+            interleave_families(vec![v6(1), v6(2), v4(1), v4(2)]),
+            vec![v6(1), v4(1), v6(2), v4(2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs_falls_back_to_next_address() {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let refused = {
+            let x = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            x.local_addr().unwrap()
+        };
+
+        let stream = connect_happy_eyeballs(
+            vec![refused, listener.local_addr().unwrap()],
+            Duration::from_secs(10),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+
+        assert!(
+            connect_happy_eyeballs(vec![refused], Duration::from_secs(10), None)
+                .await
+                .is_err()
+        );
+    }
+
+    // Only Linux routes the whole 127.0.0.0/8 to the loopback interface
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_connect_from_source_address() {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let source = IpAddr::from(Ipv4Addr::new(127, 0, 0, 2));
+        let settings = crate::settings::DirectForwarderSettings::builder()
+            .source_address(source)
+            .build()
+            .unwrap();
+        let selector = SourcePool::new(&settings)
+            .unwrap()
+            .selector(None, &Ipv4Addr::LOCALHOST.into());
+
+        let stream = connect_happy_eyeballs(
+            vec![listener.local_addr().unwrap()],
+            Duration::from_secs(10),
+            Some(&selector),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), source);
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer.ip(), source);
+    }
 }
@@ -1,3 +1,5 @@
+use crate::authentication::client_certificate::ClientCertificateAuth;
+use crate::authentication::Source;
 use crate::net_utils::Channel;
 use crate::settings::Settings;
 use crate::{net_utils, settings, utils};
@@ -37,6 +39,8 @@ struct Host {
     allowed_sni: Vec<String>,
     /// Pre-parsed certificates and private key for boring SSL (performance optimization)
     boring: BoringIdentity,
+    /// Verifies the client certificates if mutual TLS is enabled for the host
+    client_auth: Option<Arc<ClientCertificateAuth>>,
 }
 
 #[derive(Clone)]
@@ -59,6 +63,11 @@ pub(crate) struct ConnectionMeta {
     pub sni_auth_creds: Option<String>,
     /// Pre-parsed certificates and private key for boring SSL (performance optimization)
     pub boring: BoringIdentity,
+    /// Verifies the client certificates if mutual TLS is enabled for the host
+    pub client_auth: Option<Arc<ClientCertificateAuth>>,
+    /// The username mapped from the verified client certificate if some.
+    /// Set once the handshake is complete.
+    pub client_cert_username: Option<String>,
 }
 
 impl Debug for ConnectionMeta {
@@ -76,9 +85,10 @@ impl Debug for ConnectionMeta {
                    sni: \"{}\", \
                    protocol: {:?}, \
                    channel: {:?}, \
-                   sni_auth_creds: {:?} \
+                   sni_auth_creds: {:?}, \
+                   client_cert_username: {:?} \
                }}",
-            sni_ref, self.protocol, self.channel, self.sni_auth_creds,
+            sni_ref, self.protocol, self.channel, self.sni_auth_creds, self.client_cert_username,
         )
     }
 }
@@ -92,6 +102,18 @@ pub(crate) struct TlsDemux {
     allowed_sni_to_main_host: HashMap<String, String>,
 }
 
+impl ConnectionMeta {
+    /// The authentication info of the whole connection if some.
+    /// A client certificate takes precedence over the SNI credentials.
+    pub fn authentication_source(&self) -> Option<Source<'static>> {
+        match (&self.client_cert_username, &self.sni_auth_creds) {
+            (Some(x), _) => Some(Source::ClientCertificate(x.clone().into())),
+            (None, Some(x)) => Some(Source::Sni(x.clone().into())),
+            (None, None) => None,
+        }
+    }
+}
+
 impl Protocol {
     pub fn as_alpn(&self) -> &'static str {
         match self {
@@ -177,6 +199,12 @@ impl TlsDemux {
                     key_path: x.private_key_path.clone(),
                     allowed_sni: x.allowed_sni.clone(),
                     boring,
+                    client_auth: x
+                        .client_certificate
+                        .as_ref()
+                        .map(ClientCertificateAuth::new)
+                        .transpose()?
+                        .map(Arc::new),
                 },
             ))
         };
@@ -239,6 +267,8 @@ impl TlsDemux {
             key_path: host.key_path.clone(),
             sni_auth_creds: None,
             boring: host.boring.clone(),
+            client_auth: None,
+            client_cert_username: None,
         }
     }
 
@@ -338,6 +368,8 @@ impl TlsDemux {
             key_path: host.key_path.clone(),
             sni_auth_creds: auth,
             boring: host.boring.clone(),
+            client_auth: host.client_auth.clone(),
+            client_cert_username: None,
         })
     }
 
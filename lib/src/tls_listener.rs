@@ -1,3 +1,4 @@
+use crate::authentication::client_certificate::ClientCertificateAuth;
 use crate::{log_utils, net_utils, tls_demultiplexer};
 use rustls::{Certificate, PrivateKey, ServerConfig};
 use std::io;
@@ -196,19 +197,21 @@ impl TlsAcceptor {
         protocol: tls_demultiplexer::Protocol,
         cert_chain: Vec<Certificate>,
         key: PrivateKey,
+        client_auth: Option<&ClientCertificateAuth>,
         _log_id: &log_utils::IdChain<u64>,
     ) -> io::Result<TlsStream<PrebufferedTcpStream>> {
         let tls_config = {
-            let mut cfg = ServerConfig::builder()
-                .with_safe_defaults()
-                .with_no_client_auth()
-                .with_single_cert(cert_chain, key)
-                .map_err(|e| {
-                    io::Error::new(
-                        ErrorKind::Other,
-                        format!("Failed to create TLS configuration: {}", e),
-                    )
-                })?;
+            let builder = ServerConfig::builder().with_safe_defaults();
+            let builder = match client_auth {
+                None => builder.with_no_client_auth(),
+                Some(x) => builder.with_client_cert_verifier(x.verifier()),
+            };
+            let mut cfg = builder.with_single_cert(cert_chain, key).map_err(|e| {
+                io::Error::new(
+                    ErrorKind::Other,
+                    format!("Failed to create TLS configuration: {}", e),
+                )
+            })?;
 
             cfg.alpn_protocols = vec![protocol.as_alpn().as_bytes().to_vec()];
             Arc::new(cfg)
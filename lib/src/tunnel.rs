@@ -4,13 +4,21 @@ use crate::downstream::{
     PendingTcpConnectRequest,
 };
 use crate::forwarder::Forwarder;
+use crate::metrics::UserGaugeGuard;
+use crate::net_utils::TcpDestination;
 use crate::pipe::DuplexPipe;
+use crate::rate_limit::RateLimiter;
+use crate::sessions::Session;
+use crate::settings::Settings;
 use crate::{
-    authentication, core, datagram_pipe, downstream, forwarder, log_id, log_utils, pipe, udp_pipe,
+    authentication, core, datagram_pipe, dns, downstream, forwarder, log_id, log_utils, pipe,
+    rules, udp_pipe,
 };
 use std::fmt::{Display, Formatter};
 use std::io;
 use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 #[derive(Clone)]
@@ -27,9 +35,21 @@ pub(crate) struct Tunnel {
     downstream: Box<dyn Downstream>,
     forwarder: Arc<Mutex<Box<dyn Forwarder>>>,
     authentication_policy: AuthenticationPolicy<'static>,
+    session: Arc<Session>,
+    /// The latest authentication info of each user authenticated within the session,
+    /// re-checked periodically and on the settings reload
+    authenticated: Arc<Mutex<Vec<authentication::Source<'static>>>>,
+    /// Counts the session in the per-user metrics of the first authenticated user
+    user_sessions_counter: Arc<Mutex<Option<UserGaugeGuard>>>,
+    /// The number of the active TCP connections
+    tcp_connections: Arc<AtomicUsize>,
+    client_random: Option<Arc<[u8]>>,
     id: log_utils::IdChain<u64>,
 }
 
+/// Holds a slot in the tunnel TCP connections limit, releases it on drop
+struct TcpConnectionGuard(Arc<AtomicUsize>);
+
 #[derive(Debug)]
 pub(crate) enum ConnectionError {
     Io(io::Error),
@@ -38,6 +58,10 @@ pub(crate) enum ConnectionError {
     HostUnreachable,
     DnsNonroutable,
     DnsLoopback,
+    QuotaExceeded,
+    LimitExceeded(String),
+    EgressDenied,
+    RuleDenied,
     Other(String),
 }
 
@@ -50,24 +74,94 @@ impl Display for ConnectionError {
             Self::HostUnreachable => write!(f, "Remote host is unreachable"),
             Self::DnsNonroutable => write!(f, "DNS: resolved address in non-routable network"),
             Self::DnsLoopback => write!(f, "DNS: resolved address in loopback"),
+            Self::QuotaExceeded => write!(f, "Data quota exceeded"),
+            Self::LimitExceeded(x) => write!(f, "Limit exceeded: {}", x),
+            Self::EgressDenied => write!(f, "Destination is denied by egress rules"),
+            Self::RuleDenied => write!(f, "Request is denied by filtering rules"),
             Self::Other(x) => write!(f, "{}", x),
         }
     }
 }
 
+/// Check the request against the filtering rules having conditions unknown
+/// at the connection time
+fn is_request_allowed(
+    settings: &Settings,
+    connection: &rules::ConnectionInfo,
+    username: Option<&str>,
+    user_agent: Option<&str>,
+) -> bool {
+    let request = rules::RequestInfo {
+        username,
+        groups: username.map_or(&[], |x| settings.user_groups(x)),
+        user_agent,
+    };
+    settings
+        .rules_engine
+        .as_ref()
+        .is_none_or(|x| x.evaluate(connection, Some(&request)) == rules::RuleEvaluation::Allow)
+}
+
+pub(crate) fn is_egress_allowed(
+    settings: &Settings,
+    username: Option<&str>,
+    destination: rules::Destination,
+    port: u16,
+    protocol: rules::EgressProtocol,
+) -> bool {
+    let user = rules::RequestInfo {
+        username,
+        groups: username.map_or(&[], |x| settings.user_groups(x)),
+        user_agent: None,
+    };
+    settings.rules_engine.as_ref().is_none_or(|x| {
+        x.evaluate_egress(&user, destination, port, protocol) == rules::RuleEvaluation::Allow
+    })
+}
+
+impl TcpConnectionGuard {
+    /// Returns [`None`] if the counter has already reached `max`
+    fn acquire(counter: &Arc<AtomicUsize>, max: Option<usize>) -> Option<Self> {
+        let max = max.unwrap_or(usize::MAX);
+        counter
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |x| {
+                (x < max).then_some(x + 1)
+            })
+            .ok()?;
+        Some(Self(counter.clone()))
+    }
+}
+
+impl Drop for TcpConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
 impl Tunnel {
     pub fn new(
         context: Arc<core::Context>,
         downstream: Box<dyn Downstream>,
         forwarder: Box<dyn Forwarder>,
         authentication_policy: AuthenticationPolicy<'static>,
+        session: Arc<Session>,
+        client_random: Option<Vec<u8>>,
         id: log_utils::IdChain<u64>,
     ) -> Self {
+        let authenticated = match &authentication_policy {
+            AuthenticationPolicy::Default => vec![],
+            AuthenticationPolicy::Authenticated(x) => vec![x.clone()],
+        };
         Self {
             context,
             downstream,
             forwarder: Arc::new(Mutex::new(forwarder)),
             authentication_policy,
+            session,
+            authenticated: Arc::new(Mutex::new(authenticated)),
+            user_sessions_counter: Default::default(),
+            tcp_connections: Default::default(),
+            client_random: client_random.map(Arc::from),
             id,
         }
     }
@@ -77,6 +171,12 @@ impl Tunnel {
             let shutdown = self.context.shutdown.lock().unwrap();
             (shutdown.notification_handler(), shutdown.completion_guard())
         };
+        let session = self.session.clone();
+        let reauthentication = Self::reauthenticate(
+            self.context.clone(),
+            self.authenticated.clone(),
+            self.id.clone(),
+        );
         tokio::select! {
             x = shutdown_notification.wait() => {
                 match x {
@@ -84,15 +184,82 @@ impl Tunnel {
                     Err(e) => Err(io::Error::new(ErrorKind::Other, format!("{}", e))),
                 }
             }
+            _ = session.kicked() => {
+                log_id!(debug, self.id, "Tunnel is kicked");
+                self.downstream.graceful_shutdown().await
+            }
+            _ = reauthentication => {
+                log_id!(debug, self.id, "Tunnel is closed on failed re-authentication");
+                self.downstream.shutdown_unauthenticated().await
+            }
             x = self.listen_inner() => x,
         }
     }
 
+    /// Re-authenticate the clients authenticated within the tunnel periodically
+    /// and on the settings reload.
+    /// Returns once any of them is rejected. A check which could not be completed
+    /// is retried on the next interval.
+    async fn reauthenticate(
+        context: Arc<core::Context>,
+        authenticated: Arc<Mutex<Vec<authentication::Source<'static>>>>,
+        log_id: log_utils::IdChain<u64>,
+    ) {
+        let mut settings_reload = context.subscribe_settings_reload();
+        loop {
+            let interval = context.settings().auth.reauthentication_interval;
+            tokio::select! {
+                _ = tokio::time::sleep(interval), if !interval.is_zero() => (),
+                x = settings_reload.changed() => if x.is_err() {
+                    return futures::future::pending().await;
+                },
+            }
+
+            let authenticator = match context.authenticator() {
+                Some(x) => x,
+                None => continue,
+            };
+            let sources = authenticated.lock().unwrap().clone();
+            for source in sources {
+                log_id!(trace, log_id, "Re-authenticating {:?}", source.username());
+                let status = authentication::authenticate_with_timeout(
+                    authenticator.as_ref(),
+                    &source,
+                    context.settings().auth.timeout,
+                    &log_id,
+                )
+                .await;
+                match status {
+                    Status::Pass => (),
+                    Status::Reject => {
+                        log_id!(
+                            debug,
+                            log_id,
+                            "Re-authentication rejected: username={:?}",
+                            source.username()
+                        );
+                        return;
+                    }
+                    // The clients are not to blame for the authentication service failure
+                    Status::Unavailable => {
+                        log_id!(
+                            debug,
+                            log_id,
+                            "Re-authentication is not completed, retrying later: username={:?}",
+                            source.username()
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
     async fn listen_inner(&mut self) -> io::Result<()> {
         loop {
             log_id!(trace, self.id, "Tunnel waiting for request");
             let request = match tokio::time::timeout(
-                self.context.settings.client_listener_timeout,
+                self.context.settings().client_listener_timeout,
                 self.downstream.listen(),
             )
             .await
@@ -120,14 +287,12 @@ impl Tunnel {
             let tls_domain = self.downstream.tls_domain().to_string();
             let authentication_policy = self.authentication_policy.clone();
             let log_id = self.id.clone();
-            let update_metrics = {
-                let metrics = context.metrics.clone();
-                let protocol = self.downstream.protocol();
-                move |direction, n| match direction {
-                    pipe::SimplexDirection::Incoming => metrics.add_inbound_bytes(protocol, n),
-                    pipe::SimplexDirection::Outgoing => metrics.add_outbound_bytes(protocol, n),
-                }
-            };
+            let session = self.session.clone();
+            let authenticated = self.authenticated.clone();
+            let user_sessions_counter = self.user_sessions_counter.clone();
+            let tcp_connections = self.tcp_connections.clone();
+            let client_random = self.client_random.clone();
+            let protocol = self.downstream.protocol();
 
             tokio::spawn(async move {
                 fn report_fatal_if_too_many_open_files(
@@ -149,12 +314,28 @@ impl Tunnel {
                 let forwarder_auth = match (
                     auth_info,
                     authentication_policy,
-                    context.authenticator.clone(),
+                    context.authenticator(),
                 ) {
                     (Ok(Some(source)), _, Some(authenticator)) => {
-                        match authenticator.authenticate(&source, &log_id) {
-                            Status::Pass => Some(source),
-                            Status::Reject => {
+                        match context
+                            .bans
+                            .authenticate(
+                                &context.settings(),
+                                authenticator.as_ref(),
+                                &source,
+                                session.client_address(),
+                                &log_id,
+                            )
+                            .await
+                        {
+                            Status::Pass => {
+                                let username = source.username();
+                                let mut authenticated = authenticated.lock().unwrap();
+                                authenticated.retain(|x| x.username() != username);
+                                authenticated.push(source.clone());
+                                Some(source)
+                            }
+                            Status::Reject | Status::Unavailable => {
                                 let err = ConnectionError::Authentication(
                                     "Authentication failed".to_string(),
                                 );
@@ -184,6 +365,85 @@ impl Tunnel {
                     }
                 };
 
+                let username = forwarder_auth
+                    .as_ref()
+                    .and_then(authentication::Source::username);
+                let quota_usage = match username.as_ref().and_then(|x| {
+                    let (limit, period) = context.settings().user_quota(x)?;
+                    Some((limit, context.quotas.usage(x, period)))
+                }) {
+                    Some((limit, usage)) if usage.used_bytes() >= limit => {
+                        let err = ConnectionError::QuotaExceeded;
+                        log_id!(debug, request_id, "{}", err);
+                        request.fail_request(err);
+                        return;
+                    }
+                    x => x.map(|(_, usage)| usage),
+                };
+                if let Some(x) = &username {
+                    let max_sessions = context.settings().limits.max_sessions_per_user;
+                    if !context.sessions.set_username(&session, x, max_sessions) {
+                        let err = ConnectionError::LimitExceeded(
+                            "Too many sessions of the user".to_string(),
+                        );
+                        log_id!(debug, request_id, "{}", err);
+                        request.fail_request(err);
+                        return;
+                    }
+                }
+                let user_traffic_counter = username.as_ref().and_then(|x| {
+                    let mut sessions_counter = user_sessions_counter.lock().unwrap();
+                    if sessions_counter.is_none() {
+                        *sessions_counter = context.metrics.user_sessions_counter(x);
+                    }
+                    context.metrics.user_traffic_counter(x)
+                });
+                let rate_limiter = username.as_ref().and_then(|x| {
+                    context
+                        .rate_limiters
+                        .get(x, &context.settings().user_rate_limit(x))
+                });
+                let update_metrics = {
+                    let metrics = context.metrics.clone();
+                    let session = session.clone();
+                    move |direction, n| match direction {
+                        pipe::SimplexDirection::Incoming => {
+                            metrics.add_inbound_bytes(protocol, n);
+                            session.add_inbound_bytes(n);
+                            if let Some(x) = &user_traffic_counter {
+                                x.add_inbound_bytes(n);
+                            }
+                            if let Some(x) = &quota_usage {
+                                x.add(n);
+                            }
+                        }
+                        pipe::SimplexDirection::Outgoing => {
+                            metrics.add_outbound_bytes(protocol, n);
+                            session.add_outbound_bytes(n);
+                            if let Some(x) = &user_traffic_counter {
+                                x.add_outbound_bytes(n);
+                            }
+                            if let Some(x) = &quota_usage {
+                                x.add(n);
+                            }
+                        }
+                    }
+                };
+
+                let is_allowed_by_rules = |user_agent: Option<String>| {
+                    let connection = rules::ConnectionInfo {
+                        client_ip: session.client_address(),
+                        client_random: client_random.as_deref(),
+                        sni: Some(&tls_domain),
+                    };
+                    is_request_allowed(
+                        &context.settings(),
+                        &connection,
+                        username.as_deref(),
+                        user_agent.as_deref(),
+                    )
+                };
+
                 log_id!(
                     trace,
                     request_id,
@@ -195,6 +455,26 @@ impl Tunnel {
                     }
                     Ok(Some(PendingDemultiplexedRequest::TcpConnect(request))) => {
                         log_id!(trace, request_id, "Handling TCP connect request");
+                        if !is_allowed_by_rules(request.user_agent()) {
+                            let err = ConnectionError::RuleDenied;
+                            log_id!(debug, request_id, "{}", err);
+                            request.fail_request(err);
+                            return;
+                        }
+                        let _tcp_connection_guard = match TcpConnectionGuard::acquire(
+                            &tcp_connections,
+                            context.settings().limits.max_tcp_connections_per_session,
+                        ) {
+                            Some(x) => x,
+                            None => {
+                                let err = ConnectionError::LimitExceeded(
+                                    "Too many TCP connections in the session".to_string(),
+                                );
+                                log_id!(debug, request_id, "{}", err);
+                                request.fail_request(err);
+                                return;
+                            }
+                        };
                         if let Err((request, message, e)) = Tunnel::on_tcp_connect_request(
                             context.clone(),
                             forwarder,
@@ -202,6 +482,7 @@ impl Tunnel {
                             forwarder_auth,
                             tls_domain,
                             update_metrics,
+                            rate_limiter,
                         )
                         .await
                         {
@@ -214,6 +495,12 @@ impl Tunnel {
                     }
                     Ok(Some(PendingDemultiplexedRequest::DatagramMultiplexer(request))) => {
                         log_id!(trace, request_id, "Handling datagram multiplexer request");
+                        if !is_allowed_by_rules(request.user_agent()) {
+                            let err = ConnectionError::RuleDenied;
+                            log_id!(debug, request_id, "{}", err);
+                            request.fail_request(err);
+                            return;
+                        }
                         if let Err((request, message, e)) = Tunnel::on_datagram_mux_request(
                             context.clone(),
                             forwarder,
@@ -221,6 +508,7 @@ impl Tunnel {
                             forwarder_auth,
                             tls_domain,
                             update_metrics,
+                            rate_limiter,
                         )
                         .await
                         {
@@ -246,6 +534,7 @@ impl Tunnel {
         forwarder_auth: Option<authentication::Source<'static>>,
         tls_domain: String,
         update_metrics: F,
+        rate_limiter: Option<Arc<RateLimiter>>,
     ) -> Result<
         (),
         (
@@ -270,6 +559,27 @@ impl Tunnel {
             }
         };
 
+        let (rule_destination, port) = match &destination {
+            TcpDestination::Address(x) => (rules::Destination::Address(x.ip()), x.port()),
+            TcpDestination::HostName((host, port)) => (rules::Destination::HostName(host), *port),
+        };
+        let username = forwarder_auth
+            .as_ref()
+            .and_then(authentication::Source::username);
+        if !is_egress_allowed(
+            &context.settings(),
+            username.as_deref(),
+            rule_destination,
+            port,
+            rules::EgressProtocol::Tcp,
+        ) {
+            return Err((
+                Some(request),
+                "Destination is denied",
+                ConnectionError::EgressDenied,
+            ));
+        }
+
         let meta = forwarder::TcpConnectionMeta {
             client_address: match request.client_address() {
                 Ok(x) => x,
@@ -290,7 +600,7 @@ impl Tunnel {
         log_id!(trace, request_id, "TCP connect: connecting to peer");
         let connector = forwarder.lock().unwrap().tcp_connector();
         let (fwd_rx, fwd_tx) = match tokio::time::timeout(
-            context.settings.connection_establishment_timeout,
+            context.settings().connection_establishment_timeout,
             connector.connect(request_id.clone(), meta.clone()),
         )
         .await
@@ -308,6 +618,11 @@ impl Tunnel {
         };
 
         log_id!(debug, request_id, "Successfully connected to {:?}", meta);
+        let _user_metrics_guard = meta
+            .auth
+            .as_ref()
+            .and_then(authentication::Source::username)
+            .and_then(|x| context.metrics.user_tcp_connections_counter(&x));
         log_id!(
             trace,
             request_id,
@@ -329,11 +644,12 @@ impl Tunnel {
             (pipe::SimplexDirection::Outgoing, dstr_rx, fwd_tx),
             (pipe::SimplexDirection::Incoming, fwd_rx, dstr_tx),
             update_metrics,
-        );
+        )
+        .with_rate_limiter(rate_limiter);
 
         log_id!(trace, request_id, "TCP connect: pipe exchange started");
         match pipe
-            .exchange(context.settings.tcp_connections_timeout)
+            .exchange(context.settings().tcp_connections_timeout)
             .await
         {
             Ok(_) => {
@@ -354,6 +670,7 @@ impl Tunnel {
         forwarder_auth: Option<authentication::Source<'static>>,
         tls_domain: String,
         update_metrics: F,
+        rate_limiter: Option<Arc<RateLimiter>>,
     ) -> Result<
         (),
         (
@@ -390,8 +707,15 @@ impl Tunnel {
             }
         }
 
+        let mut _user_metrics_guard = None;
         let mut pipe: Box<dyn datagram_pipe::DuplexPipe> = match request.promote_to_next_state() {
             Ok(downstream::DatagramPipeHalves::Udp(dstr_source, dstr_sink)) => {
+                let username = forwarder_auth
+                    .as_ref()
+                    .and_then(authentication::Source::username);
+                _user_metrics_guard = username
+                    .as_ref()
+                    .and_then(|x| context.metrics.user_udp_connections_counter(x));
                 let meta = forwarder::UdpMultiplexerMeta {
                     client_address,
                     auth: forwarder_auth,
@@ -413,12 +737,42 @@ impl Tunnel {
                     }
                 };
 
-                Box::new(udp_pipe::DuplexPipe::new(
+                let mut pipe = udp_pipe::DuplexPipe::new(
                     (dstr_source, dstr_sink),
                     (fwd_shared, fwd_source, fwd_sink),
                     update_metrics,
-                    context.settings.udp_connections_timeout,
-                ))
+                    context.settings().udp_connections_timeout,
+                );
+                let username: Option<Arc<str>> = username.map(Into::into);
+                if context.dns_filter.is_some() {
+                    let context = context.clone();
+                    let username = username.clone();
+                    pipe = pipe.with_dns_interceptor(move |query, log_id| {
+                        let context = context.clone();
+                        let username = username.clone();
+                        Box::pin(async move {
+                            dns::filter::answer(&context, &query, username.as_deref(), &log_id)
+                                .await
+                        })
+                    });
+                }
+
+                Box::new(
+                    pipe.with_rate_limiter(rate_limiter)
+                        .with_max_flows(context.settings().limits.max_udp_flows_per_multiplexer)
+                        .with_destination_filter({
+                            let context = context.clone();
+                            move |x: &SocketAddr| {
+                                is_egress_allowed(
+                                    &context.settings(),
+                                    username.as_deref(),
+                                    rules::Destination::Address(x.ip()),
+                                    x.port(),
+                                    rules::EgressProtocol::Udp,
+                                )
+                            }
+                        }),
+                )
             }
             Ok(downstream::DatagramPipeHalves::Icmp(dstr_source, dstr_sink)) => {
                 let (fwd_source, fwd_sink) = match forwarder
@@ -471,3 +825,51 @@ impl Tunnel {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::{Authenticator, Source};
+    use std::time::Duration;
+
+    struct Fixed(Status);
+
+    #[async_trait::async_trait]
+    impl Authenticator for Fixed {
+        async fn authenticate(&self, _: &Source<'_>, _: &log_utils::IdChain<u64>) -> Status {
+            self.0.clone()
+        }
+    }
+
+    fn set_authenticator(context: &core::Context, status: Status) {
+        context
+            .modify_settings(|_, authenticator| {
+                *authenticator = Some(Arc::new(Fixed(status)));
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reauthentication_unavailable() {
+        let context = Arc::new(core::Context::default());
+        let authenticated = Arc::new(Mutex::new(vec![Source::ClientCertificate("a".into())]));
+        let mut reauthentication = tokio::spawn(Tunnel::reauthenticate(
+            context.clone(),
+            authenticated,
+            log_utils::IdChain::empty(),
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The tunnel stays open while the check is not completed
+        set_authenticator(&context, Status::Unavailable);
+        let result = tokio::time::timeout(Duration::from_millis(300), &mut reauthentication).await;
+        assert!(result.is_err());
+
+        set_authenticator(&context, Status::Reject);
+        tokio::time::timeout(Duration::from_secs(1), reauthentication)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 use crate::forwarder::UdpMultiplexer;
 use crate::metrics::OutboundUdpSocketCounter;
+use crate::source_address::SourceSelector;
 use crate::{core, datagram_pipe, downstream, forwarder, log_id, log_utils, net_utils};
 use async_trait::async_trait;
 use bytes::Bytes;
@@ -7,7 +8,7 @@ use std::collections::hash_map::Entry;
 use std::collections::{HashMap, LinkedList};
 use std::io;
 use std::io::ErrorKind;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
@@ -25,6 +26,8 @@ type Connections = HashMap<forwarder::UdpDatagramMeta, Connection>;
 struct MultiplexerShared {
     connections: Mutex<Connections>,
     context: Arc<core::Context>,
+    /// Chooses the source addresses of the sockets, if configured
+    sources: Option<SourceSelector>,
 }
 
 struct MultiplexerSource {
@@ -52,10 +55,12 @@ enum PollStatus {
 pub(crate) fn make_multiplexer(
     context: Arc<core::Context>,
     id: log_utils::IdChain<u64>,
+    sources: Option<SourceSelector>,
 ) -> io::Result<UdpMultiplexer> {
     let shared = Arc::new(MultiplexerShared {
         connections: Mutex::new(Default::default()),
         context,
+        sources,
     });
     let (wake_tx, wake_rx) = sync::mpsc::channel(1);
 
@@ -180,7 +185,12 @@ impl forwarder::UdpDatagramPipeShared for MultiplexerShared {
             Entry::Vacant(e) => {
                 let metrics_guard = self.context.metrics.clone().outbound_udp_socket_counter();
                 e.insert(Connection {
-                    socket: Arc::new(make_udp_socket(&meta.destination)?),
+                    socket: Arc::new(make_udp_socket(
+                        &meta.destination,
+                        self.sources
+                            .as_ref()
+                            .and_then(|x| x.select(&meta.destination)),
+                    )?),
                     being_listened: false,
                     _metrics_guard: metrics_guard,
                 });
@@ -263,8 +273,12 @@ impl datagram_pipe::Sink for MultiplexerSink {
     }
 }
 
-fn make_udp_socket(peer: &SocketAddr) -> io::Result<UdpSocket> {
-    let socket = net_utils::make_udp_socket(peer.is_ipv4())?;
+/// Make a socket connected to the peer and bound to the `source` address, if any
+fn make_udp_socket(peer: &SocketAddr, source: Option<IpAddr>) -> io::Result<UdpSocket> {
+    let socket = match source {
+        Some(x) => std::net::UdpSocket::bind(SocketAddr::new(x, 0))?,
+        None => net_utils::make_udp_socket(peer.is_ipv4())?,
+    };
     socket.connect(peer)?;
     socket.set_nonblocking(true)?;
     UdpSocket::from_std(socket)
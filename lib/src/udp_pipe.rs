@@ -1,13 +1,26 @@
+use crate::rate_limit::RateLimiter;
 use crate::{datagram_pipe, downstream, forwarder, log_id, log_utils, net_utils, pipe};
 use async_trait::async_trait;
+use bytes::Bytes;
 use futures::future;
-use futures::future::Either;
+use futures::future::{BoxFuture, Either};
 use std::collections::HashMap;
 use std::io;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::sync::mpsc;
 use tokio::time::Instant;
 
+type DestinationFilter = Box<dyn Fn(&SocketAddr) -> bool + Send + Sync>;
+/// Answers an intercepted DNS query, [`None`] means the query is dropped
+type DnsInterceptor = Arc<
+    dyn Fn(Bytes, log_utils::IdChain<u64>) -> BoxFuture<'static, Option<Vec<u8>>> + Send + Sync,
+>;
+
+/// The maximum number of the answers to the intercepted queries waiting to be sent
+const MAX_PENDING_DNS_ANSWERS: usize = 64;
+
 pub(crate) struct DuplexPipe<F: Send + Sync> {
     left_pipe: LeftPipe<F>,
     right_pipe: RightPipe<F>,
@@ -19,6 +32,13 @@ struct LeftPipe<F: Send + Sync> {
     source: Box<dyn datagram_pipe::Source<Output = downstream::UdpDatagram>>,
     sink: Box<dyn datagram_pipe::Sink<Input = downstream::UdpDatagram>>,
     shared: Arc<UdpPipeShared<F>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// The maximum number of concurrent UDP flows
+    max_flows: Option<usize>,
+    /// Decides whether a new flow to the destination is allowed
+    destination_filter: Option<DestinationFilter>,
+    /// Answers the queries to the plain DNS port instead of forwarding them
+    dns_interceptor: Option<(DnsInterceptor, mpsc::Sender<forwarder::UdpDatagram>)>,
     direction: pipe::SimplexDirection,
     next_connection_id: std::ops::RangeFrom<u64>,
 }
@@ -28,6 +48,9 @@ struct RightPipe<F: Send + Sync> {
     source: Box<dyn datagram_pipe::Source<Output = forwarder::UdpDatagramReadStatus>>,
     sink: Box<dyn datagram_pipe::Sink<Input = forwarder::UdpDatagram>>,
     shared: Arc<UdpPipeShared<F>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// The answers to the intercepted DNS queries
+    dns_answers: Option<mpsc::Receiver<forwarder::UdpDatagram>>,
     direction: pipe::SimplexDirection,
 }
 
@@ -59,6 +82,33 @@ impl<F: Fn(pipe::SimplexDirection, usize) + Send + Sync> LeftPipe<F> {
             let datagram = self.source.read().await?;
             log_id!(trace, self.source.id(), "--> Datagram: {:?}", datagram);
 
+            if datagram.meta.destination.port() == net_utils::PLAIN_DNS_PORT_NUMBER {
+                if let Some((interceptor, answers)) = &self.dns_interceptor {
+                    let datagram_len = datagram.payload.len();
+                    let meta = forwarder::UdpDatagramMeta::from(&datagram.meta).reversed();
+                    let answer = interceptor(datagram.payload, self.source.id());
+                    let answers = answers.clone();
+                    let log_id = self.source.id();
+                    tokio::spawn(async move {
+                        if let Some(payload) = answer.await {
+                            let answer = forwarder::UdpDatagram {
+                                meta,
+                                payload: Bytes::from(payload),
+                            };
+                            if answers.try_send(answer).is_err() {
+                                log_id!(debug, log_id, "<-- Dropping DNS answer: queue is full");
+                            }
+                        }
+                    });
+
+                    (self.shared.update_metrics)(self.direction, datagram_len);
+                    if let Some(x) = &self.rate_limiter {
+                        x.throttle(self.direction, datagram_len).await;
+                    }
+                    continue;
+                }
+            }
+
             if let Err(e) = self.on_udp_packet(&datagram.meta).await {
                 log_id!(
                     debug,
@@ -74,6 +124,9 @@ impl<F: Fn(pipe::SimplexDirection, usize) + Send + Sync> LeftPipe<F> {
             match self.sink.write(datagram).await? {
                 datagram_pipe::SendStatus::Sent => {
                     (self.shared.update_metrics)(self.direction, datagram_len);
+                    if let Some(x) = &self.rate_limiter {
+                        x.throttle(self.direction, datagram_len).await;
+                    }
                 }
                 datagram_pipe::SendStatus::Dropped => {
                     log_id!(trace, self.source.id(), "--> Datagram dropped")
@@ -94,6 +147,24 @@ impl<F: Fn(pipe::SimplexDirection, usize) + Send + Sync> LeftPipe<F> {
             return Ok(());
         }
 
+        if let Some(filter) = &self.destination_filter {
+            if !filter(&meta.destination) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Destination is denied by egress rules",
+                ));
+            }
+        }
+
+        if let Some(max) = self.max_flows {
+            if self.shared.udp_connections.lock().unwrap().len() >= max {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Too many concurrent UDP flows",
+                ));
+            }
+        }
+
         let is_plain_dns = meta.destination.port() == net_utils::PLAIN_DNS_PORT_NUMBER;
         self.shared.udp_connections.lock().unwrap().insert(
             forwarder::UdpDatagramMeta::from(meta),
@@ -128,7 +199,18 @@ impl<F: Fn(pipe::SimplexDirection, usize) + Send + Sync> LeftPipe<F> {
 impl<F: Fn(pipe::SimplexDirection, usize) + Send + Sync> RightPipe<F> {
     async fn exchange(&mut self) -> io::Result<()> {
         loop {
-            let datagram = match self.source.read().await? {
+            let status = match &mut self.dns_answers {
+                None => self.source.read().await?,
+                Some(answers) => tokio::select! {
+                    x = self.source.read() => x?,
+                    Some(x) = answers.recv() => {
+                        log_id!(trace, self.source.id(), "<-- DNS answer: {:?}", x);
+                        self.write(x).await?;
+                        continue;
+                    }
+                },
+            };
+            let datagram = match status {
                 forwarder::UdpDatagramReadStatus::Read(x) => x,
                 forwarder::UdpDatagramReadStatus::UdpClose(meta, e) => {
                     if let Some(c) = self.shared.udp_connections.lock().unwrap().remove(&meta) {
@@ -146,15 +228,7 @@ impl<F: Fn(pipe::SimplexDirection, usize) + Send + Sync> RightPipe<F> {
             log_id!(trace, self.source.id(), "<-- Datagram: {:?}", datagram);
 
             let meta = datagram.meta;
-            let datagram_len = datagram.payload.len();
-            match self.sink.write(datagram).await? {
-                datagram_pipe::SendStatus::Sent => {
-                    (self.shared.update_metrics)(self.direction, datagram_len);
-                }
-                datagram_pipe::SendStatus::Dropped => {
-                    log_id!(trace, self.source.id(), "<-- Datagram dropped")
-                }
-            }
+            self.write(datagram).await?;
 
             let reversed = meta.reversed();
             let x = self.on_udp_packet(&reversed);
@@ -176,6 +250,22 @@ impl<F: Fn(pipe::SimplexDirection, usize) + Send + Sync> RightPipe<F> {
         }
     }
 
+    async fn write(&mut self, datagram: forwarder::UdpDatagram) -> io::Result<()> {
+        let datagram_len = datagram.payload.len();
+        match self.sink.write(datagram).await? {
+            datagram_pipe::SendStatus::Sent => {
+                (self.shared.update_metrics)(self.direction, datagram_len);
+                if let Some(x) = &self.rate_limiter {
+                    x.throttle(self.direction, datagram_len).await;
+                }
+            }
+            datagram_pipe::SendStatus::Dropped => {
+                log_id!(trace, self.source.id(), "<-- Datagram dropped")
+            }
+        }
+        Ok(())
+    }
+
     fn on_udp_packet(&mut self, meta: &forwarder::UdpDatagramMeta) -> UdpConnectionStatus {
         match self.shared.udp_connections.lock().unwrap().get_mut(meta) {
             None => UdpConnectionStatus::Continue,
@@ -233,6 +323,10 @@ impl<F: Fn(pipe::SimplexDirection, usize) + Send + Sync> DuplexPipe<F> {
                 source: source1,
                 sink: sink2,
                 shared: shared.clone(),
+                rate_limiter: None,
+                max_flows: None,
+                destination_filter: None,
+                dns_interceptor: None,
                 direction: pipe::SimplexDirection::Outgoing,
                 next_connection_id: 0..,
             },
@@ -240,12 +334,51 @@ impl<F: Fn(pipe::SimplexDirection, usize) + Send + Sync> DuplexPipe<F> {
                 source: source2,
                 sink: sink1,
                 shared,
+                rate_limiter: None,
+                dns_answers: None,
                 direction: pipe::SimplexDirection::Incoming,
             },
             timeout,
         }
     }
 
+    /// Limit the transfer rate in both directions
+    pub fn with_rate_limiter(mut self, rate_limiter: Option<Arc<RateLimiter>>) -> Self {
+        self.left_pipe.rate_limiter.clone_from(&rate_limiter);
+        self.right_pipe.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// Limit the number of concurrent UDP flows
+    pub fn with_max_flows(mut self, max_flows: Option<usize>) -> Self {
+        self.left_pipe.max_flows = max_flows;
+        self
+    }
+
+    /// Filter the destinations of the new UDP flows
+    pub fn with_destination_filter<G>(mut self, filter: G) -> Self
+    where
+        G: Fn(&SocketAddr) -> bool + Send + Sync + 'static,
+    {
+        self.left_pipe.destination_filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Answer the queries to the plain DNS port with the interceptor instead of
+    /// forwarding them to the destination
+    pub fn with_dns_interceptor<G>(mut self, interceptor: G) -> Self
+    where
+        G: Fn(Bytes, log_utils::IdChain<u64>) -> BoxFuture<'static, Option<Vec<u8>>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let (tx, rx) = mpsc::channel(MAX_PENDING_DNS_ANSWERS);
+        self.left_pipe.dns_interceptor = Some((Arc::new(interceptor), tx));
+        self.right_pipe.dns_answers = Some(rx);
+        self
+    }
+
     async fn exchange_once(&mut self) -> io::Result<()> {
         let left = self.left_pipe.exchange();
         futures::pin_mut!(left);
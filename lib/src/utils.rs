@@ -107,6 +107,12 @@ pub fn load_private_key(filename: &str) -> io::Result<PrivateKey> {
         .map(|key| PrivateKey(key.secret_der().to_vec()))
 }
 
+/// Compare the byte strings in time independent of their contents.
+/// The length of the strings is not considered a secret.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 pub trait IterJoin {
     type Output;
 
@@ -151,7 +157,7 @@ impl ToTomlComment for String {
 
 #[cfg(test)]
 mod tests {
-    use crate::utils::IterJoin;
+    use crate::utils::{constant_time_eq, IterJoin};
 
     #[test]
     fn iter_join() {
@@ -159,4 +165,12 @@ mod tests {
         assert_eq!("a", std::iter::once("a").join("x"));
         assert_eq!("", std::iter::empty::<&str>().join("x"));
     }
+
+    #[test]
+    fn constant_time_eq_compares_contents() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+        assert!(constant_time_eq(b"", b""));
+    }
 }
@@ -0,0 +1,359 @@
+use base64::engine::general_purpose::STANDARD as BASE64_ENGINE;
+use base64::Engine;
+use bytes::Bytes;
+use futures::future;
+use http::Request;
+use log::info;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use trusttunnel::authentication;
+use trusttunnel::settings::{
+    AdminSettings, Http1Settings, Http2Settings, ListenProtocolSettings, Settings, TlsHostInfo,
+    TlsHostsSettings,
+};
+
+#[allow(dead_code)]
+mod common;
+
+const TOKEN_ENV: &str = "TRUSTTUNNEL_TEST_ADMIN_TOKEN";
+const TOKEN: &str = "secret";
+
+#[tokio::test]
+async fn unauthorized_request() {
+    common::set_up_logger();
+    let endpoint_address = common::make_endpoint_address();
+    let admin_address = common::make_endpoint_address();
+
+    let client_task = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let (status, _) =
+            do_admin_request(&admin_address, http::Method::GET, "/sessions", None, None).await;
+        assert_eq!(status, http::StatusCode::UNAUTHORIZED);
+
+        let (status, _) = do_admin_request(
+            &admin_address,
+            http::Method::GET,
+            "/sessions",
+            Some("wrong"),
+            None,
+        )
+        .await;
+        assert_eq!(status, http::StatusCode::UNAUTHORIZED);
+    };
+
+    tokio::select! {
+        _ = run_endpoint(&endpoint_address, &admin_address) => unreachable!(),
+        _ = client_task => (),
+        _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
+    }
+}
+
+#[tokio::test]
+async fn manage_credentials() {
+    common::set_up_logger();
+    let endpoint_address = common::make_endpoint_address();
+    let admin_address = common::make_endpoint_address();
+
+    let client_task = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(
+            do_connect_request(&endpoint_address, &admin_address, "c:d").await,
+            http::StatusCode::PROXY_AUTHENTICATION_REQUIRED
+        );
+
+        let (status, _) = do_admin_request(
+            &admin_address,
+            http::Method::POST,
+            "/credentials",
+            Some(TOKEN),
+            Some(r#"{"username":"c","password":"d"}"#),
+        )
+        .await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_ne!(
+            do_connect_request(&endpoint_address, &admin_address, "c:d").await,
+            http::StatusCode::PROXY_AUTHENTICATION_REQUIRED
+        );
+
+        let (status, body) = do_admin_request(
+            &admin_address,
+            http::Method::GET,
+            "/credentials",
+            Some(TOKEN),
+            None,
+        )
+        .await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(body, r#"[{"username":"a"},{"username":"c"}]"#);
+
+        let (status, _) = do_admin_request(
+            &admin_address,
+            http::Method::DELETE,
+            "/credentials/a",
+            Some(TOKEN),
+            None,
+        )
+        .await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(
+            do_connect_request(&endpoint_address, &admin_address, "a:b").await,
+            http::StatusCode::PROXY_AUTHENTICATION_REQUIRED
+        );
+
+        let (status, _) = do_admin_request(
+            &admin_address,
+            http::Method::DELETE,
+            "/credentials/a",
+            Some(TOKEN),
+            None,
+        )
+        .await;
+        assert_eq!(status, http::StatusCode::NOT_FOUND);
+    };
+
+    tokio::select! {
+        _ = run_endpoint(&endpoint_address, &admin_address) => unreachable!(),
+        _ = client_task => (),
+        _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
+    }
+}
+
+#[tokio::test]
+async fn revoked_session_closed() {
+    common::set_up_logger();
+    let endpoint_address = common::make_endpoint_address();
+    let admin_address = common::make_endpoint_address();
+
+    let client_task = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let stream = common::establish_tls_connection(
+            common::MAIN_DOMAIN_NAME,
+            &endpoint_address,
+            Some(b"h2"),
+        )
+        .await;
+        let (mut client, connection) = h2::client::handshake(stream).await.unwrap();
+        let connection = tokio::spawn(connection);
+
+        let request = Request::builder()
+            .method(http::Method::CONNECT)
+            .uri(admin_address.to_string())
+            .header(
+                http::header::PROXY_AUTHORIZATION,
+                format!("Basic {}", BASE64_ENGINE.encode("a:b")),
+            )
+            .body(())
+            .unwrap();
+        let (response, _stream) = client.send_request(request, false).unwrap();
+        assert_eq!(response.await.unwrap().status(), http::StatusCode::OK);
+
+        // Keep a user, the authentication is off without any credentials
+        let (status, _) = do_admin_request(
+            &admin_address,
+            http::Method::POST,
+            "/credentials",
+            Some(TOKEN),
+            Some(r#"{"username":"c","password":"d"}"#),
+        )
+        .await;
+        assert_eq!(status, http::StatusCode::OK);
+        let (status, _) = do_admin_request(
+            &admin_address,
+            http::Method::DELETE,
+            "/credentials/a",
+            Some(TOKEN),
+            None,
+        )
+        .await;
+        assert_eq!(status, http::StatusCode::OK);
+
+        let err = connection.await.unwrap().unwrap_err();
+        assert_eq!(err.reason(), Some(h2::Reason::from(0x1F)));
+    };
+
+    tokio::select! {
+        _ = run_endpoint(&endpoint_address, &admin_address) => unreachable!(),
+        _ = client_task => (),
+        _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
+    }
+}
+
+#[tokio::test]
+async fn replace_rules() {
+    common::set_up_logger();
+    let endpoint_address = common::make_endpoint_address();
+    let admin_address = common::make_endpoint_address();
+
+    let client_task = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let (status, _) = do_admin_request(
+            &admin_address,
+            http::Method::PUT,
+            "/rules",
+            Some(TOKEN),
+            Some(r#"{"rule":[{"cidr":"not a cidr","action":"deny"}]}"#),
+        )
+        .await;
+        assert_eq!(status, http::StatusCode::BAD_REQUEST);
+
+        let (status, _) = do_admin_request(
+            &admin_address,
+            http::Method::PUT,
+            "/rules",
+            Some(TOKEN),
+            Some(r#"{"route":[{"domain":".example.com","forwarder":"unknown"}]}"#),
+        )
+        .await;
+        assert_eq!(status, http::StatusCode::BAD_REQUEST);
+
+        let rules =
+            r#"{"rule":[{"cidr":"127.0.0.0/8","client_random_prefix":null,"action":"deny"}]}"#;
+        let (status, _) = do_admin_request(
+            &admin_address,
+            http::Method::PUT,
+            "/rules",
+            Some(TOKEN),
+            Some(rules),
+        )
+        .await;
+        assert_eq!(status, http::StatusCode::OK);
+
+        let (status, body) = do_admin_request(
+            &admin_address,
+            http::Method::GET,
+            "/rules",
+            Some(TOKEN),
+            None,
+        )
+        .await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(body, rules);
+    };
+
+    tokio::select! {
+        _ = run_endpoint(&endpoint_address, &admin_address) => unreachable!(),
+        _ = client_task => (),
+        _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
+    }
+}
+
+async fn run_endpoint(listen_address: &SocketAddr, admin_address: &SocketAddr) {
+    std::env::set_var(TOKEN_ENV, TOKEN);
+
+    let settings = Settings::builder()
+        .listen_address(listen_address)
+        .unwrap()
+        .listen_protocols(ListenProtocolSettings {
+            http1: Some(Http1Settings::builder().build()),
+            http2: Some(Http2Settings::builder().build()),
+            ..Default::default()
+        })
+        .allow_private_network_connections(true)
+        .clients(vec![authentication::registry_based::Client {
+            username: "a".into(),
+            password: "b".into(),
+            ..Default::default()
+        }])
+        .admin(
+            AdminSettings::builder()
+                .listen_address(admin_address)
+                .unwrap()
+                .token_env(TOKEN_ENV)
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
+
+    let cert_key_file = common::make_cert_key_file();
+    let cert_key_path = cert_key_file.path.to_str().unwrap();
+    let hosts_settings = TlsHostsSettings::builder()
+        .main_hosts(vec![TlsHostInfo {
+            hostname: common::MAIN_DOMAIN_NAME.to_string(),
+            cert_chain_path: cert_key_path.to_string(),
+            private_key_path: cert_key_path.to_string(),
+            allowed_sni: vec![],
+            client_certificate: None,
+        }])
+        .build()
+        .unwrap();
+
+    common::run_endpoint_with_settings(settings, hosts_settings).await;
+}
+
+async fn do_admin_request(
+    admin_address: &SocketAddr,
+    method: http::Method,
+    path: &str,
+    token: Option<&str>,
+    body: Option<&'static str>,
+) -> (http::StatusCode, Bytes) {
+    let stream = TcpStream::connect(admin_address).await.unwrap();
+    let (mut request, conn_driver) = hyper::client::conn::Builder::new()
+        .handshake(stream)
+        .await
+        .unwrap();
+
+    let exchange = async move {
+        let mut rr = Request::builder()
+            .version(http::Version::HTTP_11)
+            .method(method)
+            .uri(path);
+        if let Some(x) = token {
+            rr = rr.header(http::header::AUTHORIZATION, format!("Bearer {}", x));
+        }
+
+        let rr = rr
+            .body(body.map_or_else(hyper::Body::empty, hyper::Body::from))
+            .unwrap();
+        let response = request.send_request(rr).await.unwrap();
+        info!("Admin response: {:?}", response);
+        let (parts, body) = response.into_parts();
+        (parts.status, hyper::body::to_bytes(body).await.unwrap())
+    };
+
+    futures::pin_mut!(conn_driver);
+    futures::pin_mut!(exchange);
+    match future::select(conn_driver, exchange).await {
+        future::Either::Left((_, exchange)) => exchange.await,
+        future::Either::Right((x, _)) => x,
+    }
+}
+
+async fn do_connect_request(
+    endpoint_address: &SocketAddr,
+    destination: &SocketAddr,
+    proxy_auth: &str,
+) -> http::StatusCode {
+    let stream =
+        common::establish_tls_connection(common::MAIN_DOMAIN_NAME, endpoint_address, None).await;
+
+    let (mut request, conn_driver) = hyper::client::conn::Builder::new()
+        .handshake(stream)
+        .await
+        .unwrap();
+
+    let exchange = async move {
+        let rr = Request::builder()
+            .version(http::Version::HTTP_11)
+            .method(http::Method::CONNECT)
+            .uri(destination.to_string())
+            .header(
+                http::header::PROXY_AUTHORIZATION,
+                format!("Basic {}", BASE64_ENGINE.encode(proxy_auth)),
+            )
+            .body(hyper::Body::empty())
+            .unwrap();
+        let response = request.send_request(rr).await.unwrap();
+        info!("CONNECT response: {:?}", response);
+        response.status()
+    };
+
+    futures::pin_mut!(conn_driver);
+    futures::pin_mut!(exchange);
+    match future::select(conn_driver, exchange).await {
+        future::Either::Left((_, exchange)) => exchange.await,
+        future::Either::Right((x, _)) => x,
+    }
+}
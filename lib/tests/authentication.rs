@@ -7,10 +7,10 @@ use std::future::Future;
 use std::net::{Ipv4Addr, SocketAddr};
 use std::time::Duration;
 use tokio::io::AsyncReadExt;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
 use trusttunnel::authentication;
 use trusttunnel::settings::{
-    ForwardProtocolSettings, Http1Settings, ListenProtocolSettings, Settings,
+    BruteForceSettings, ForwardProtocolSettings, Http1Settings, ListenProtocolSettings, Settings,
     Socks5ForwarderSettings, TlsHostInfo, TlsHostsSettings,
 };
 
@@ -29,7 +29,7 @@ async fn registry_proxy_auth_success() {
     };
 
     tokio::select! {
-        _ = run_endpoint(&endpoint_address, true, None) => unreachable!(),
+        _ = run_endpoint(&endpoint_address, true, None, None) => unreachable!(),
         _ = client_task => (),
         _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
     }
@@ -47,7 +47,41 @@ async fn registry_proxy_auth_failure() {
     };
 
     tokio::select! {
-        _ = run_endpoint(&endpoint_address, true, None) => unreachable!(),
+        _ = run_endpoint(&endpoint_address, true, None, None) => unreachable!(),
+        _ = client_task => (),
+        _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
+    }
+}
+
+#[tokio::test]
+async fn client_banned_after_failures() {
+    common::set_up_logger();
+    let endpoint_address = common::make_endpoint_address();
+    let brute_force = BruteForceSettings::builder()
+        .backoff_after_failures(10)
+        .ban_after_failures(2)
+        .build()
+        .unwrap();
+
+    let client_task = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        for _ in 0..2 {
+            let status = do_connect_request(&endpoint_address, Some("a:c".into())).await;
+            assert_eq!(status, http::StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+        }
+
+        // The connection is closed before the TLS handshake
+        let mut stream = TcpStream::connect(endpoint_address).await.unwrap();
+        let mut buf = [0; 1];
+        let n = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf))
+            .await
+            .expect("Connection of banned client must be closed")
+            .unwrap_or_default();
+        assert_eq!(n, 0);
+    };
+
+    tokio::select! {
+        _ = run_endpoint(&endpoint_address, true, None, Some(brute_force)) => unreachable!(),
         _ = client_task => (),
         _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
     }
@@ -66,7 +100,7 @@ async fn no_authenticator_socks_standard_auth() {
     };
 
     tokio::select! {
-        _ = run_endpoint(&endpoint_address, true, Some(socks_addr)) => unreachable!(),
+        _ = run_endpoint(&endpoint_address, true, Some(socks_addr), None) => unreachable!(),
         _ = client_task => unreachable!(),
         x = socks_task => assert!(x.contains(&0x02), "{:?}", x),
         _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
@@ -86,7 +120,7 @@ async fn no_authenticator_no_socks_auth() {
     };
 
     tokio::select! {
-        _ = run_endpoint(&endpoint_address, false, Some(socks_addr)) => unreachable!(),
+        _ = run_endpoint(&endpoint_address, false, Some(socks_addr), None) => unreachable!(),
         _ = client_task => unreachable!(),
         x = socks_task => assert!(!x.iter().any(|x| *x != 0x00), "Must not contain non-NoAuth methods: {:?}", x),
         _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
@@ -106,7 +140,7 @@ async fn authenticator_present_socks_standard_auth() {
     };
 
     tokio::select! {
-        _ = run_endpoint(&endpoint_address, true, Some(socks_addr)) => unreachable!(),
+        _ = run_endpoint(&endpoint_address, true, Some(socks_addr), None) => unreachable!(),
         _ = client_task => unreachable!(),
         x = socks_task => assert!(x.contains(&0x02), "{:?}", x),
         _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
@@ -117,6 +151,7 @@ async fn run_endpoint(
     listen_address: &SocketAddr,
     with_auth: bool,
     socks_proxy: Option<SocketAddr>,
+    brute_force: Option<BruteForceSettings>,
 ) {
     let mut builder = Settings::builder()
         .listen_address(listen_address)
@@ -132,6 +167,7 @@ async fn run_endpoint(
             authentication::registry_based::Client {
                 username: "a".into(),
                 password: "b".into(),
+                ..Default::default()
             },
         )));
     }
@@ -146,6 +182,10 @@ async fn run_endpoint(
         ));
     }
 
+    if let Some(x) = brute_force {
+        builder = builder.brute_force(x);
+    }
+
     let settings = builder.build().unwrap();
 
     let cert_key_file = common::make_cert_key_file();
@@ -156,6 +196,7 @@ async fn run_endpoint(
             cert_chain_path: cert_key_path.to_string(),
             private_key_path: cert_key_path.to_string(),
             allowed_sni: vec![],
+            client_certificate: None,
         }])
         .build()
         .unwrap();
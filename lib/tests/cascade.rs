@@ -0,0 +1,276 @@
+use base64::Engine;
+use futures::future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use trusttunnel::authentication;
+use trusttunnel::cert_verification;
+use trusttunnel::rules::{RouteRule, RulesConfig, RulesEngine};
+use trusttunnel::settings::{
+    CascadeForwarderSettings, ForwardProtocolSettings, Http2Settings, ListenProtocolSettings,
+    Settings, SettingsBuilder, TlsHostInfo, TlsHostsSettings,
+};
+
+#[allow(dead_code)]
+mod common;
+
+const PASSWORD_ENV: &str = "CASCADE_TEST_PASSWORD";
+const CONTENT: &[u8] = b"hello from the other side";
+
+#[tokio::test]
+async fn tcp_through_next_endpoint() {
+    common::set_up_logger();
+    let exit_address = common::make_endpoint_address();
+    let entry_address = common::make_endpoint_address();
+
+    let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let server_address = server.local_addr().unwrap();
+    let server_task = async move {
+        let (mut socket, _) = server.accept().await.unwrap();
+        socket.write_all(CONTENT).await.unwrap();
+        let mut buf = [0; CONTENT.len()];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, CONTENT);
+        future::pending::<()>().await;
+    };
+
+    let client_task = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let (status, mut io) = common::connect(&entry_address, server_address.to_string()).await;
+        assert_eq!(status, http::StatusCode::OK);
+        let mut io = io.take().unwrap();
+
+        let mut buf = [0; CONTENT.len()];
+        io.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, CONTENT);
+        io.write_all(CONTENT).await.unwrap();
+        io.flush().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    };
+
+    tokio::select! {
+        _ = run_exit_endpoint(&exit_address) => unreachable!(),
+        _ = run_entry_endpoint(&entry_address, &exit_address, None) => unreachable!(),
+        _ = server_task => unreachable!(),
+        _ = client_task => (),
+        _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
+    }
+}
+
+#[tokio::test]
+async fn udp_through_next_endpoint() {
+    common::set_up_logger();
+    let exit_address = common::make_endpoint_address();
+    let entry_address = common::make_endpoint_address();
+
+    let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let server_address = server.local_addr().unwrap();
+    let server_task = async move {
+        let mut buf = [0; 1024];
+        loop {
+            let (n, peer) = server.recv_from(&mut buf).await.unwrap();
+            server.send_to(&buf[..n], peer).await.unwrap();
+        }
+    };
+
+    let client_task = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let (status, mut io) = common::connect(&entry_address, "_udp2".to_string()).await;
+        assert_eq!(status, http::StatusCode::OK);
+        let mut io = io.take().unwrap();
+
+        io.write_all(&common::encode_udp_packet(&server_address, CONTENT))
+            .await
+            .unwrap();
+        io.flush().await.unwrap();
+
+        // The outgoing packet format: length, source, destination, payload
+        let mut length = [0; 4];
+        io.read_exact(&mut length).await.unwrap();
+        let mut packet = vec![0; u32::from_be_bytes(length) as usize];
+        io.read_exact(&mut packet).await.unwrap();
+        assert_eq!(&packet[12..16], Ipv4Addr::LOCALHOST.octets());
+        assert_eq!(&packet[2 * (16 + 2)..], CONTENT);
+    };
+
+    tokio::select! {
+        _ = run_exit_endpoint(&exit_address) => unreachable!(),
+        _ = run_entry_endpoint(&entry_address, &exit_address, None) => unreachable!(),
+        _ = server_task => unreachable!(),
+        _ = client_task => (),
+        _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
+    }
+}
+
+#[tokio::test]
+async fn rejects_unpinned_certificate() {
+    common::set_up_logger();
+    let exit_address = common::make_endpoint_address();
+    let entry_address = common::make_endpoint_address();
+
+    let client_task = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let (status, _) = common::connect(&entry_address, "127.0.0.1:1".to_string()).await;
+        assert_eq!(status, http::StatusCode::BAD_GATEWAY);
+    };
+
+    let pin = base64::engine::general_purpose::STANDARD.encode([0; 32]);
+    tokio::select! {
+        _ = run_exit_endpoint(&exit_address) => unreachable!(),
+        _ = run_entry_endpoint(&entry_address, &exit_address, Some(pin)) => unreachable!(),
+        _ = client_task => (),
+        _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
+    }
+}
+
+#[tokio::test]
+async fn stalled_next_endpoint_isolated() {
+    common::set_up_logger();
+    let exit_address = common::make_endpoint_address();
+    let entry_address = common::make_endpoint_address();
+
+    // Accepts the connections and never completes the TLS handshake
+    let stalled = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let stalled_address = stalled.local_addr().unwrap();
+    let stalled_task = async move {
+        let mut accepted = vec![];
+        loop {
+            accepted.push(stalled.accept().await.unwrap());
+        }
+    };
+    let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let server_address = server.local_addr().unwrap();
+
+    let settings = entry_settings(&entry_address, &exit_address, None)
+        .forwarder(
+            "stalled".into(),
+            ForwardProtocolSettings::Cascade(
+                CascadeForwarderSettings::builder()
+                    .server_address(stalled_address)
+                    .unwrap()
+                    .server_name(common::MAIN_DOMAIN_NAME.into())
+                    .credentials("entry".into(), PASSWORD_ENV.into())
+                    .build()
+                    .unwrap(),
+            ),
+        )
+        .rules_engine(RulesEngine::from_config(RulesConfig {
+            route: vec![RouteRule {
+                username: None,
+                sni: None,
+                domain: None,
+                cidr: Some("127.0.0.1/32".into()),
+                ports: Some("1".into()),
+                protocol: None,
+                forwarder: "stalled".into(),
+            }],
+            ..Default::default()
+        }))
+        .build()
+        .unwrap();
+
+    let client_task = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        tokio::select! {
+            _ = common::connect(&entry_address, "127.0.0.1:1".to_string()) => {
+                panic!("Expected the session establishment to stall")
+            }
+            (status, _) = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                common::connect(&entry_address, server_address.to_string()).await
+            } => assert_eq!(status, http::StatusCode::OK),
+        }
+    };
+
+    tokio::select! {
+        _ = run_exit_endpoint(&exit_address) => unreachable!(),
+        _ = run_endpoint(settings) => unreachable!(),
+        _ = stalled_task => unreachable!(),
+        _ = client_task => (),
+        _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
+    }
+}
+
+async fn run_exit_endpoint(listen_address: &SocketAddr) {
+    let settings = Settings::builder()
+        .listen_address(listen_address)
+        .unwrap()
+        .listen_protocols(ListenProtocolSettings {
+            http2: Some(Http2Settings::builder().build()),
+            ..Default::default()
+        })
+        .allow_private_network_connections(true)
+        .clients(vec![authentication::registry_based::Client {
+            username: "entry".into(),
+            password: "secret".into(),
+            ..Default::default()
+        }])
+        .build()
+        .unwrap();
+
+    run_endpoint(settings).await;
+}
+
+async fn run_entry_endpoint(
+    listen_address: &SocketAddr,
+    exit_address: &SocketAddr,
+    pin: Option<String>,
+) {
+    run_endpoint(
+        entry_settings(listen_address, exit_address, pin)
+            .build()
+            .unwrap(),
+    )
+    .await;
+}
+
+/// Make the settings of an endpoint forwarding through the next one by default
+fn entry_settings(
+    listen_address: &SocketAddr,
+    exit_address: &SocketAddr,
+    pin: Option<String>,
+) -> SettingsBuilder {
+    std::env::set_var(PASSWORD_ENV, "secret");
+    let cert_key_file = common::make_cert_key_file();
+    let pin = pin.unwrap_or_else(|| {
+        let certs = trusttunnel::utils::load_certs(cert_key_file.path.to_str().unwrap()).unwrap();
+        cert_verification::public_key_pin(&certs[0].0).unwrap()
+    });
+
+    Settings::builder()
+        .listen_address(listen_address)
+        .unwrap()
+        .listen_protocols(ListenProtocolSettings {
+            http2: Some(Http2Settings::builder().build()),
+            ..Default::default()
+        })
+        .allow_private_network_connections(true)
+        .forwarder_settings(ForwardProtocolSettings::Cascade(
+            CascadeForwarderSettings::builder()
+                .server_address(exit_address)
+                .unwrap()
+                .server_name(common::MAIN_DOMAIN_NAME.into())
+                .credentials("entry".into(), PASSWORD_ENV.into())
+                .certificate_pin(pin)
+                .build()
+                .unwrap(),
+        ))
+}
+
+async fn run_endpoint(settings: Settings) {
+    let cert_key_file = common::make_cert_key_file();
+    let cert_key_path = cert_key_file.path.to_str().unwrap();
+    let hosts_settings = TlsHostsSettings::builder()
+        .main_hosts(vec![TlsHostInfo {
+            hostname: common::MAIN_DOMAIN_NAME.to_string(),
+            cert_chain_path: cert_key_path.to_string(),
+            private_key_path: cert_key_path.to_string(),
+            allowed_sni: vec![],
+            client_certificate: None,
+        }])
+        .build()
+        .unwrap();
+
+    common::run_endpoint_with_settings(settings, hosts_settings).await;
+}
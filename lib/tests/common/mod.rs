@@ -1,4 +1,4 @@
-use bytes::{Buf, Bytes, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures::future;
 use http::{Request, Response};
 use hyper::body::HttpBody;
@@ -9,7 +9,7 @@ use ring::rand::{SecureRandom, SystemRandom};
 use rustls::client::ServerCertVerified;
 use rustls::{Certificate, ServerName};
 use std::io::{ErrorKind, Write};
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::ops::Deref;
 use std::path::PathBuf;
 use std::pin::Pin;
@@ -22,12 +22,12 @@ use tokio::net::{TcpStream, UdpSocket};
 use tokio_rustls::TlsConnector;
 use trusttunnel::authentication::{registry_based::RegistryBasedAuthenticator, Authenticator};
 use trusttunnel::core::Core;
-use trusttunnel::log_utils;
 use trusttunnel::settings::{
     Http1Settings, Http2Settings, ListenProtocolSettings, QuicSettings, Settings, TlsHostInfo,
     TlsHostsSettings,
 };
 use trusttunnel::shutdown::Shutdown;
+use trusttunnel::{log_utils, net_utils};
 
 pub const MAIN_DOMAIN_NAME: &str = "localhost";
 pub const ENDPOINT_IP: Ipv4Addr = Ipv4Addr::LOCALHOST;
@@ -102,6 +102,70 @@ pub fn make_stream_of_chunks(
     futures::stream::iter(iter::repeat(&CHUNK[..size]).take(total_size / size))
 }
 
+/// Send a CONNECT request to the endpoint and return the response status and the tunnel
+pub async fn connect(
+    endpoint_address: &SocketAddr,
+    authority: String,
+) -> (http::StatusCode, Option<hyper::upgrade::Upgraded>) {
+    let stream = establish_tls_connection(
+        MAIN_DOMAIN_NAME,
+        endpoint_address,
+        Some(net_utils::HTTP2_ALPN.as_bytes()),
+    )
+    .await;
+
+    let (mut request, conn) = hyper::client::conn::Builder::new()
+        .http2_only(true)
+        .handshake(stream)
+        .await
+        .unwrap();
+    tokio::spawn(conn);
+
+    let response = request
+        .send_request(
+            Request::builder()
+                .version(http::Version::HTTP_2)
+                .method(http::Method::CONNECT)
+                .uri(authority)
+                .body(hyper::Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let status = response.status();
+    if !status.is_success() {
+        return (status, None);
+    }
+
+    (status, Some(hyper::upgrade::on(response).await.unwrap()))
+}
+
+/// Encode a UDP packet in the format of the `_udp2` stream: length, source, destination,
+/// application name, payload
+pub fn encode_udp_packet(destination: &SocketAddr, payload: &[u8]) -> Vec<u8> {
+    const APP_NAME: &str = "test";
+    const SOURCE_IP: Ipv4Addr = Ipv4Addr::LOCALHOST;
+    const SOURCE_PORT: u16 = 1234;
+
+    let mut buffer = vec![];
+    buffer.put_u32((2 * (16 + 2) + 1 + APP_NAME.len() + payload.len()) as u32);
+    buffer.put_slice(&[0; 12]);
+    buffer.put_slice(&SOURCE_IP.octets());
+    buffer.put_u16(SOURCE_PORT);
+    buffer.put_slice(&[0; 12]);
+    buffer.put_slice(&match destination.ip() {
+        IpAddr::V4(ip) => ip.octets(),
+        _ => unreachable!(),
+    });
+    buffer.put_u16(destination.port());
+    buffer.put_u8(APP_NAME.len() as u8);
+    buffer.put_slice(APP_NAME.as_bytes());
+    buffer.put_slice(payload);
+
+    buffer
+}
+
 pub struct File {
     pub path: PathBuf,
 }
@@ -214,24 +278,28 @@ pub async fn run_endpoint(listen_address: &SocketAddr) {
             cert_chain_path: cert_key_path.to_string(),
             private_key_path: cert_key_path.to_string(),
             allowed_sni: vec![],
+            client_certificate: None,
         }])
         .ping_hosts(vec![TlsHostInfo {
             hostname: format!("ping.{}", MAIN_DOMAIN_NAME),
             cert_chain_path: cert_key_path.to_string(),
             private_key_path: cert_key_path.to_string(),
             allowed_sni: vec![],
+            client_certificate: None,
         }])
         .speedtest_hosts(vec![TlsHostInfo {
             hostname: format!("speed.{}", MAIN_DOMAIN_NAME),
             cert_chain_path: cert_key_path.to_string(),
             private_key_path: cert_key_path.to_string(),
             allowed_sni: vec![],
+            client_certificate: None,
         }])
         .reverse_proxy_hosts(vec![TlsHostInfo {
             hostname: format!("hello.{}", MAIN_DOMAIN_NAME),
             cert_chain_path: cert_key_path.to_string(),
             private_key_path: cert_key_path.to_string(),
             allowed_sni: vec![],
+            client_certificate: None,
         }])
         .build()
         .unwrap();
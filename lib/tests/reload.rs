@@ -0,0 +1,243 @@
+use base64::engine::general_purpose::STANDARD as BASE64_ENGINE;
+use base64::Engine;
+use bytes::Bytes;
+use http::Request;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use trusttunnel::authentication::registry_based::{Client, RegistryBasedAuthenticator};
+use trusttunnel::authentication::Authenticator;
+use trusttunnel::core::Core;
+use trusttunnel::rules::{EgressRule, RuleAction, RulesConfig, RulesEngine};
+use trusttunnel::settings::{
+    Http1Settings, Http2Settings, ListenProtocolSettings, ReverseProxySettings, Settings,
+    TlsHostInfo, TlsHostsSettings,
+};
+use trusttunnel::shutdown::Shutdown;
+
+#[allow(dead_code)]
+mod common;
+
+type Session = (
+    h2::client::SendRequest<Bytes>,
+    JoinHandle<Result<(), h2::Error>>,
+);
+
+#[tokio::test]
+async fn credentials_and_rules_applied() {
+    common::set_up_logger();
+    let endpoint_address = common::make_endpoint_address();
+    let (allowed, allowed_task) = run_destination().await;
+    let (denied, denied_task) = run_destination().await;
+    let cert_key_file = common::make_cert_key_file();
+    let core = make_core(
+        make_settings(&endpoint_address, "a", "b").build().unwrap(),
+        make_hosts_settings(cert_key_file.path.to_str().unwrap()),
+    );
+
+    let client_task = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let (mut session, connection) = open_session(&endpoint_address).await;
+        let (status, _tunnel) = connect(&mut session, &allowed, "a:b").await;
+        assert_eq!(status, http::StatusCode::OK);
+
+        let settings = make_settings(&endpoint_address, "c", "d")
+            .rules_engine(RulesEngine::from_config(RulesConfig {
+                egress: vec![EgressRule {
+                    domain: None,
+                    cidr: Some(format!("{}/32", denied.ip())),
+                    ports: Some(denied.port().to_string()),
+                    protocol: None,
+                    username: None,
+                    group: None,
+                    action: RuleAction::Deny,
+                }],
+                ..Default::default()
+            }))
+            .build()
+            .unwrap();
+        let authenticator = make_authenticator(&settings);
+        core.reload_settings(settings, authenticator).unwrap();
+
+        // The live session of the removed user is closed
+        let err = connection.await.unwrap().unwrap_err();
+        assert_eq!(err.reason(), Some(h2::Reason::from(0x1F)));
+
+        let (mut session, _connection) = open_session(&endpoint_address).await;
+        assert_eq!(
+            connect(&mut session, &allowed, "a:b").await.0,
+            http::StatusCode::PROXY_AUTHENTICATION_REQUIRED
+        );
+        assert_eq!(
+            connect(&mut session, &allowed, "c:d").await.0,
+            http::StatusCode::OK
+        );
+        assert_eq!(
+            connect(&mut session, &denied, "c:d").await.0,
+            http::StatusCode::FORBIDDEN
+        );
+    };
+
+    tokio::select! {
+        _ = core.listen() => unreachable!(),
+        _ = allowed_task => unreachable!(),
+        _ = denied_task => unreachable!(),
+        _ = client_task => (),
+        _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
+    }
+}
+
+#[tokio::test]
+async fn restart_only_changes_rejected() {
+    common::set_up_logger();
+    let endpoint_address = common::make_endpoint_address();
+    let (destination, destination_task) = run_destination().await;
+    let cert_key_file = common::make_cert_key_file();
+    let core = make_core(
+        make_settings(&endpoint_address, "a", "b").build().unwrap(),
+        make_hosts_settings(cert_key_file.path.to_str().unwrap()),
+    );
+
+    let client_task = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let (mut live_session, _live_connection) = open_session(&endpoint_address).await;
+        assert_eq!(
+            connect(&mut live_session, &destination, "a:b").await.0,
+            http::StatusCode::OK
+        );
+
+        let another_address = common::make_endpoint_address();
+        let changes = [
+            make_settings(&another_address, "c", "d"),
+            make_settings(&endpoint_address, "c", "d").listen_protocols(ListenProtocolSettings {
+                http2: Some(Http2Settings::builder().build()),
+                ..Default::default()
+            }),
+            make_settings(&endpoint_address, "c", "d").reverse_proxy(
+                ReverseProxySettings::builder()
+                    .server_address((Ipv4Addr::LOCALHOST, 1))
+                    .unwrap()
+                    .path_mask("/hello".to_string())
+                    .build()
+                    .unwrap(),
+            ),
+        ];
+        for settings in changes {
+            let settings = settings.build().unwrap();
+            let authenticator = make_authenticator(&settings);
+            assert!(core.reload_settings(settings, authenticator).is_err());
+        }
+
+        // The previous settings are kept
+        assert_eq!(
+            connect(&mut live_session, &destination, "a:b").await.0,
+            http::StatusCode::OK
+        );
+        let (mut session, _connection) = open_session(&endpoint_address).await;
+        assert_eq!(
+            connect(&mut session, &destination, "c:d").await.0,
+            http::StatusCode::PROXY_AUTHENTICATION_REQUIRED
+        );
+        assert_eq!(
+            connect(&mut session, &destination, "a:b").await.0,
+            http::StatusCode::OK
+        );
+    };
+
+    tokio::select! {
+        _ = core.listen() => unreachable!(),
+        _ = destination_task => unreachable!(),
+        _ = client_task => (),
+        _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
+    }
+}
+
+fn make_settings(
+    listen_address: &SocketAddr,
+    username: &str,
+    password: &str,
+) -> trusttunnel::settings::SettingsBuilder {
+    Settings::builder()
+        .listen_address(listen_address)
+        .unwrap()
+        .listen_protocols(ListenProtocolSettings {
+            http1: Some(Http1Settings::builder().build()),
+            http2: Some(Http2Settings::builder().build()),
+            ..Default::default()
+        })
+        .allow_private_network_connections(true)
+        .clients(vec![Client {
+            username: username.into(),
+            password: password.into(),
+            ..Default::default()
+        }])
+}
+
+fn make_hosts_settings(cert_key_path: &str) -> TlsHostsSettings {
+    TlsHostsSettings::builder()
+        .main_hosts(vec![TlsHostInfo {
+            hostname: common::MAIN_DOMAIN_NAME.to_string(),
+            cert_chain_path: cert_key_path.to_string(),
+            private_key_path: cert_key_path.to_string(),
+            allowed_sni: vec![],
+            client_certificate: None,
+        }])
+        .build()
+        .unwrap()
+}
+
+fn make_authenticator(settings: &Settings) -> Option<Arc<dyn Authenticator>> {
+    Some(Arc::new(RegistryBasedAuthenticator::new(
+        settings.get_clients(),
+    )))
+}
+
+fn make_core(settings: Settings, hosts_settings: TlsHostsSettings) -> Core {
+    let authenticator = make_authenticator(&settings);
+    Core::new(settings, authenticator, hosts_settings, Shutdown::new()).unwrap()
+}
+
+/// Run a TCP server accepting the connections and keeping them open
+async fn run_destination() -> (SocketAddr, impl std::future::Future<Output = ()>) {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let accepted = Mutex::new(vec![]);
+    let task = async move {
+        loop {
+            let (socket, _) = listener.accept().await.unwrap();
+            accepted.lock().unwrap().push(socket);
+        }
+    };
+    (address, task)
+}
+
+async fn open_session(endpoint_address: &SocketAddr) -> Session {
+    let stream =
+        common::establish_tls_connection(common::MAIN_DOMAIN_NAME, endpoint_address, Some(b"h2"))
+            .await;
+    let (client, connection) = h2::client::handshake(stream).await.unwrap();
+    (client, tokio::spawn(connection))
+}
+
+/// Send a CONNECT request within the session and return the response status
+/// and the tunnel stream
+async fn connect(
+    session: &mut h2::client::SendRequest<Bytes>,
+    destination: &SocketAddr,
+    proxy_auth: &str,
+) -> (http::StatusCode, h2::SendStream<Bytes>) {
+    let request = Request::builder()
+        .method(http::Method::CONNECT)
+        .uri(destination.to_string())
+        .header(
+            http::header::PROXY_AUTHORIZATION,
+            format!("Basic {}", BASE64_ENGINE.encode(proxy_auth)),
+        )
+        .body(())
+        .unwrap();
+    let mut ready = session.clone().ready().await.unwrap();
+    let (response, stream) = ready.send_request(request, false).unwrap();
+    (response.await.unwrap().status(), stream)
+}
@@ -161,12 +161,14 @@ async fn run_endpoint(endpoint_address: &SocketAddr, proxy_address: &SocketAddr)
             cert_chain_path: cert_key_path.to_string(),
             private_key_path: cert_key_path.to_string(),
             allowed_sni: vec![],
+            client_certificate: None,
         }])
         .reverse_proxy_hosts(vec![TlsHostInfo {
             hostname: format!("hello.{}", common::MAIN_DOMAIN_NAME),
             cert_chain_path: cert_key_path.to_string(),
             private_key_path: cert_key_path.to_string(),
             allowed_sni: vec![],
+            client_certificate: None,
         }])
         .build()
         .unwrap();
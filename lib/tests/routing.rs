@@ -0,0 +1,147 @@
+use futures::future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use trusttunnel::rules::{RouteRule, RulesConfig, RulesEngine};
+use trusttunnel::settings::{
+    DirectForwarderSettings, ForwardProtocolSettings, Http2Settings, ListenProtocolSettings,
+    Settings, Socks5ForwarderSettings, TlsHostInfo, TlsHostsSettings,
+};
+
+#[allow(dead_code)]
+mod common;
+
+const CONTENT: &[u8] = b"routed around the proxy";
+
+#[tokio::test]
+async fn tcp_routed_by_port() {
+    common::set_up_logger();
+    let endpoint_address = common::make_endpoint_address();
+
+    let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let server_address = server.local_addr().unwrap();
+    let server_task = async move {
+        let (mut socket, _) = server.accept().await.unwrap();
+        socket.write_all(CONTENT).await.unwrap();
+        future::pending::<()>().await;
+    };
+    // Reachable directly, but not matched by the routing rule
+    let unrouted = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let unrouted_address = unrouted.local_addr().unwrap();
+
+    let client_task = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let (status, io) = common::connect(&endpoint_address, server_address.to_string()).await;
+        assert_eq!(status, http::StatusCode::OK);
+        let mut buf = [0; CONTENT.len()];
+        io.unwrap().read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, CONTENT);
+
+        // Goes through the unreachable SOCKS5 proxy
+        let (status, _) = common::connect(&endpoint_address, unrouted_address.to_string()).await;
+        assert_ne!(status, http::StatusCode::OK);
+    };
+
+    tokio::select! {
+        _ = run_endpoint(&endpoint_address, &server_address) => unreachable!(),
+        _ = server_task => unreachable!(),
+        _ = client_task => (),
+        _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
+    }
+}
+
+#[tokio::test]
+async fn udp_routed_by_port() {
+    common::set_up_logger();
+    let endpoint_address = common::make_endpoint_address();
+
+    let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let server_address = server.local_addr().unwrap();
+    let server_task = async move {
+        let mut buf = [0; 1024];
+        loop {
+            let (n, peer) = server.recv_from(&mut buf).await.unwrap();
+            server.send_to(&buf[..n], peer).await.unwrap();
+        }
+    };
+
+    let client_task = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let (status, io) = common::connect(&endpoint_address, "_udp2".to_string()).await;
+        assert_eq!(status, http::StatusCode::OK);
+        let mut io = io.unwrap();
+
+        io.write_all(&common::encode_udp_packet(&server_address, CONTENT))
+            .await
+            .unwrap();
+        io.flush().await.unwrap();
+
+        // The outgoing packet format: length, source, destination, payload
+        let mut length = [0; 4];
+        io.read_exact(&mut length).await.unwrap();
+        let mut packet = vec![0; u32::from_be_bytes(length) as usize];
+        io.read_exact(&mut packet).await.unwrap();
+        assert_eq!(&packet[2 * (16 + 2)..], CONTENT);
+    };
+
+    tokio::select! {
+        _ = run_endpoint(&endpoint_address, &server_address) => unreachable!(),
+        _ = server_task => unreachable!(),
+        _ = client_task => (),
+        _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
+    }
+}
+
+/// Run an endpoint forwarding through an unreachable SOCKS5 proxy by default,
+/// and directly to the port of the server
+async fn run_endpoint(listen_address: &SocketAddr, server_address: &SocketAddr) {
+    let settings = Settings::builder()
+        .listen_address(listen_address)
+        .unwrap()
+        .listen_protocols(ListenProtocolSettings {
+            http2: Some(Http2Settings::builder().build()),
+            ..Default::default()
+        })
+        .allow_private_network_connections(true)
+        .forwarder_settings(ForwardProtocolSettings::Socks5(
+            Socks5ForwarderSettings::builder()
+                .server_address((Ipv4Addr::LOCALHOST, 1))
+                .unwrap()
+                .build()
+                .unwrap(),
+        ))
+        .forwarder(
+            "direct".into(),
+            ForwardProtocolSettings::Direct(DirectForwarderSettings::default()),
+        )
+        .rules_engine(RulesEngine::from_config(RulesConfig {
+            route: vec![RouteRule {
+                username: None,
+                sni: None,
+                domain: None,
+                cidr: Some("127.0.0.0/8".into()),
+                ports: Some(server_address.port().to_string()),
+                protocol: None,
+                forwarder: "direct".into(),
+            }],
+            ..Default::default()
+        }))
+        .build()
+        .unwrap();
+
+    let cert_key_file = common::make_cert_key_file();
+    let cert_key_path = cert_key_file.path.to_str().unwrap();
+    let hosts_settings = TlsHostsSettings::builder()
+        .main_hosts(vec![TlsHostInfo {
+            hostname: common::MAIN_DOMAIN_NAME.to_string(),
+            cert_chain_path: cert_key_path.to_string(),
+            private_key_path: cert_key_path.to_string(),
+            allowed_sni: vec![],
+            client_certificate: None,
+        }])
+        .build()
+        .unwrap();
+
+    common::run_endpoint_with_settings(settings, hosts_settings).await;
+}
@@ -0,0 +1,108 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use trusttunnel::settings::{
+    ForwardProtocolSettings, Http2Settings, ListenProtocolSettings, Settings, Socks5Balancing,
+    Socks5ForwarderSettings, TlsHostInfo, TlsHostsSettings,
+};
+
+#[allow(dead_code)]
+mod common;
+
+const CONTENT: &[u8] = b"through the live upstream";
+
+#[tokio::test]
+async fn tcp_fails_over_to_live_upstream() {
+    common::set_up_logger();
+    let endpoint_address = common::make_endpoint_address();
+
+    let proxy = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let proxy_address = proxy.local_addr().unwrap();
+    let proxy_task = async move {
+        loop {
+            let (socket, _) = proxy.accept().await.unwrap();
+            tokio::spawn(serve_socks_connect(socket));
+        }
+    };
+
+    let client_task = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        // The round-robin starts from the unreachable upstream
+        for _ in 0..2 {
+            let (status, io) = common::connect(&endpoint_address, "127.0.0.1:80".to_string()).await;
+            assert_eq!(status, http::StatusCode::OK);
+            let mut buf = [0; CONTENT.len()];
+            io.unwrap().read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, CONTENT);
+        }
+    };
+
+    tokio::select! {
+        _ = run_endpoint(&endpoint_address, &proxy_address) => unreachable!(),
+        _ = proxy_task => unreachable!(),
+        _ = client_task => (),
+        _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
+    }
+}
+
+/// Accept a SOCKS5 CONNECT request without authentication and send [`CONTENT`]
+/// in place of the target
+async fn serve_socks_connect(mut socket: tokio::net::TcpStream) {
+    let mut header = [0; 2];
+    socket.read_exact(&mut header).await.unwrap();
+    let mut methods = vec![0; header[1] as usize];
+    socket.read_exact(&mut methods).await.unwrap();
+    socket.write_all(&[5, 0]).await.unwrap();
+
+    // Version, command, reserved, IPv4 address type, address, port
+    let mut request = [0; 4 + 4 + 2];
+    socket.read_exact(&mut request).await.unwrap();
+    assert_eq!(request[1], 1);
+    socket
+        .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])
+        .await
+        .unwrap();
+
+    socket.write_all(CONTENT).await.unwrap();
+    let _ = socket.read(&mut [0; 1]).await;
+}
+
+/// Run an endpoint forwarding through a pool of an unreachable and a live SOCKS5 proxy
+async fn run_endpoint(listen_address: &SocketAddr, proxy_address: &SocketAddr) {
+    let settings = Settings::builder()
+        .listen_address(listen_address)
+        .unwrap()
+        .listen_protocols(ListenProtocolSettings {
+            http2: Some(Http2Settings::builder().build()),
+            ..Default::default()
+        })
+        .allow_private_network_connections(true)
+        .forwarder_settings(ForwardProtocolSettings::Socks5(
+            Socks5ForwarderSettings::builder()
+                .upstream((Ipv4Addr::LOCALHOST, 1))
+                .unwrap()
+                .upstream(proxy_address)
+                .unwrap()
+                .balancing(Socks5Balancing::RoundRobin)
+                .build()
+                .unwrap(),
+        ))
+        .build()
+        .unwrap();
+
+    let cert_key_file = common::make_cert_key_file();
+    let cert_key_path = cert_key_file.path.to_str().unwrap();
+    let hosts_settings = TlsHostsSettings::builder()
+        .main_hosts(vec![TlsHostInfo {
+            hostname: common::MAIN_DOMAIN_NAME.to_string(),
+            cert_chain_path: cert_key_path.to_string(),
+            private_key_path: cert_key_path.to_string(),
+            allowed_sni: vec![],
+            client_certificate: None,
+        }])
+        .build()
+        .unwrap();
+
+    common::run_endpoint_with_settings(settings, hosts_settings).await;
+}
@@ -0,0 +1,131 @@
+// Only Linux routes the whole 127.0.0.0/8 to the loopback interface
+#![cfg(target_os = "linux")]
+
+use futures::future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use trusttunnel::settings::{
+    DirectForwarderSettings, ForwardProtocolSettings, Http2Settings, ListenProtocolSettings,
+    Settings, TlsHostInfo, TlsHostsSettings,
+};
+
+#[allow(dead_code)]
+mod common;
+
+const EGRESS_IP: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+
+#[tokio::test]
+async fn tcp_from_source_address() {
+    common::set_up_logger();
+    let endpoint_address = common::make_endpoint_address();
+
+    let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let server_address = server.local_addr().unwrap();
+    let server_task = async move {
+        let (mut socket, peer) = server.accept().await.unwrap();
+        socket
+            .write_all(peer.ip().to_string().as_bytes())
+            .await
+            .unwrap();
+        future::pending::<()>().await;
+    };
+
+    let client_task = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let (status, io) = common::connect(&endpoint_address, server_address.to_string()).await;
+        assert_eq!(status, http::StatusCode::OK);
+        let expected = EGRESS_IP.to_string();
+        let mut buf = vec![0; expected.len()];
+        io.unwrap().read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, expected.as_bytes());
+    };
+
+    tokio::select! {
+        _ = run_endpoint(&endpoint_address) => unreachable!(),
+        _ = server_task => unreachable!(),
+        _ = client_task => (),
+        _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
+    }
+}
+
+#[tokio::test]
+async fn udp_from_source_address() {
+    common::set_up_logger();
+    let endpoint_address = common::make_endpoint_address();
+
+    let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let server_address = server.local_addr().unwrap();
+    let server_task = async move {
+        let mut buf = [0; 1024];
+        loop {
+            let (_, peer) = server.recv_from(&mut buf).await.unwrap();
+            server
+                .send_to(peer.ip().to_string().as_bytes(), peer)
+                .await
+                .unwrap();
+        }
+    };
+
+    let client_task = async {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let (status, io) = common::connect(&endpoint_address, "_udp2".to_string()).await;
+        assert_eq!(status, http::StatusCode::OK);
+        let mut io = io.unwrap();
+
+        io.write_all(&common::encode_udp_packet(&server_address, b"ping"))
+            .await
+            .unwrap();
+        io.flush().await.unwrap();
+
+        // The outgoing packet format: length, source, destination, payload
+        let mut length = [0; 4];
+        io.read_exact(&mut length).await.unwrap();
+        let mut packet = vec![0; u32::from_be_bytes(length) as usize];
+        io.read_exact(&mut packet).await.unwrap();
+        assert_eq!(&packet[2 * (16 + 2)..], EGRESS_IP.to_string().as_bytes());
+    };
+
+    tokio::select! {
+        _ = run_endpoint(&endpoint_address) => unreachable!(),
+        _ = server_task => unreachable!(),
+        _ = client_task => (),
+        _ = tokio::time::sleep(Duration::from_secs(10)) => panic!("Timed out"),
+    }
+}
+
+/// Run an endpoint binding the outbound sockets to [`EGRESS_IP`]
+async fn run_endpoint(listen_address: &SocketAddr) {
+    let settings = Settings::builder()
+        .listen_address(listen_address)
+        .unwrap()
+        .listen_protocols(ListenProtocolSettings {
+            http2: Some(Http2Settings::builder().build()),
+            ..Default::default()
+        })
+        .allow_private_network_connections(true)
+        .forwarder_settings(ForwardProtocolSettings::Direct(
+            DirectForwarderSettings::builder()
+                .source_address(EGRESS_IP.into())
+                .build()
+                .unwrap(),
+        ))
+        .build()
+        .unwrap();
+
+    let cert_key_file = common::make_cert_key_file();
+    let cert_key_path = cert_key_file.path.to_str().unwrap();
+    let hosts_settings = TlsHostsSettings::builder()
+        .main_hosts(vec![TlsHostInfo {
+            hostname: common::MAIN_DOMAIN_NAME.to_string(),
+            cert_chain_path: cert_key_path.to_string(),
+            private_key_path: cert_key_path.to_string(),
+            allowed_sni: vec![],
+            client_certificate: None,
+        }])
+        .build()
+        .unwrap();
+
+    common::run_endpoint_with_settings(settings, hosts_settings).await;
+}
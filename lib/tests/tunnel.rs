@@ -1,9 +1,8 @@
-use bytes::BufMut;
 use futures::{future, FutureExt, StreamExt};
 use http::Request;
 use log::info;
 use std::future::Future;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{Ipv4Addr, SocketAddr};
 use std::pin::Pin;
 use std::thread;
 use std::time::Duration;
@@ -137,7 +136,7 @@ async fn h2_udp_download() {
 
         let exchange = async {
             let mut io = io.await;
-            let hole_puncher = encode_udp_chunk(&server_address, &[1]);
+            let hole_puncher = common::encode_udp_packet(&server_address, &[1]);
             io.write_all(&hole_puncher).await.unwrap();
 
             let mut total = 0;
@@ -183,7 +182,7 @@ async fn h2_udp_upload() {
             let mut io = io.await;
 
             let mut content = common::make_stream_of_chunks(UDP_CONTENT_SIZE, Some(UDP_CHUNK_SIZE))
-                .map(|x| encode_udp_chunk(&server_address, x));
+                .map(|x| common::encode_udp_packet(&server_address, x));
             while let Some(chunk) = content.next().await {
                 io.write_all(&chunk).await.unwrap();
             }
@@ -306,7 +305,7 @@ async fn h3_udp_download() {
             .await;
         assert_eq!(response.status, http::StatusCode::OK);
 
-        let hole_puncher = encode_udp_chunk(&server_address, &[1]);
+        let hole_puncher = common::encode_udp_packet(&server_address, &[1]);
         conn.send(futures::stream::iter(std::iter::once(hole_puncher)))
             .await;
 
@@ -351,7 +350,7 @@ async fn h3_udp_upload() {
 
         conn.send(
             common::make_stream_of_chunks(UDP_CONTENT_SIZE, Some(UDP_CHUNK_SIZE))
-                .map(|x| encode_udp_chunk(&server_address, x)),
+                .map(|x| common::encode_udp_packet(&server_address, x)),
         )
         .await;
 
@@ -524,26 +523,3 @@ fn run_udp_server(is_download: bool) -> SocketAddr {
 
     server_addr
 }
-
-fn encode_udp_chunk(destination: &SocketAddr, payload: &[u8]) -> Vec<u8> {
-    const APP_NAME: &str = "test";
-    const SOURCE_IP: Ipv4Addr = Ipv4Addr::LOCALHOST;
-    const SOURCE_PORT: u16 = 1234;
-
-    let mut buffer = vec![];
-    buffer.put_u32((2 * (16 + 2) + 1 + APP_NAME.len() + payload.len()) as u32);
-    buffer.put_slice(&[0; 12]);
-    buffer.put_slice(&SOURCE_IP.octets());
-    buffer.put_u16(SOURCE_PORT);
-    buffer.put_slice(&[0; 12]);
-    buffer.put_slice(&match destination.ip() {
-        IpAddr::V4(ip) => ip.octets(),
-        _ => unreachable!(),
-    });
-    buffer.put_u16(destination.port());
-    buffer.put_u8(APP_NAME.len() as u8);
-    buffer.put_slice(APP_NAME.as_bytes());
-    buffer.put_slice(payload);
-
-    buffer
-}
@@ -1,9 +1,11 @@
 use crate::template_settings;
 use std::iter::once;
-use toml_edit::{value, Document};
+use toml_edit::{value, Array, Document};
 use trusttunnel::settings::{
-    ForwardProtocolSettings, Http1Settings, Http2Settings, IcmpSettings, ListenProtocolSettings,
-    MetricsSettings, QuicSettings, Settings,
+    AdminSettings, BruteForceSettings, DnsBlockingMode, DnsSettings, ForwardProtocolSettings,
+    Http1Settings, Http2Settings, HttpConnectProtocol, IcmpSettings, LimitSettings,
+    ListenProtocolSettings, MetricsSettings, QuicSettings, QuotaSettings, RateLimitSettings,
+    Settings,
 };
 use trusttunnel::utils::{IterJoin, ToTomlComment};
 
@@ -12,11 +14,20 @@ pub fn compose_document(settings: &Settings, credentials_path: &str, rules_path:
         .chain(once(compose_forward_protocol_table(
             settings.get_forward_protocol(),
         )))
+        .chain(once(template_settings::FORWARDERS_TABLE.to_toml_comment()))
+        .chain(once(compose_dns_table(settings.get_dns().as_ref())))
         .chain(once(compose_listener_protocol_table(
             settings.get_listen_protocols(),
         )))
         .chain(once(compose_icmp_table(settings.get_icmp().as_ref())))
+        .chain(once(compose_rate_limit_table(settings.get_rate_limit())))
+        .chain(once(compose_limits_table(settings.get_limits())))
+        .chain(once(compose_brute_force_table(
+            settings.get_brute_force().as_ref(),
+        )))
+        .chain(once(compose_quota_table(settings.get_quota().as_ref())))
         .chain(once(compose_metrics_table(settings.get_metrics().as_ref())))
+        .chain(once(compose_admin_table(settings.get_admin().as_ref())))
         .join("\n")
 }
 
@@ -54,6 +65,45 @@ fn compose_forward_protocol_table(settings: &ForwardProtocolSettings) -> String
             table["extended_auth"] = value(*x.get_extended_auth());
             doc.to_string()
         }
+        ForwardProtocolSettings::HttpConnect(x) => {
+            let mut doc: Document = template_settings::HTTP_CONNECT_FORWARDER_TABLE
+                .parse()
+                .unwrap();
+            let table = doc["forward_protocol"]["http_connect"]
+                .as_table_mut()
+                .unwrap();
+            table["address"] = value(x.get_address().to_string());
+            table["protocol"] = value(match x.get_protocol() {
+                HttpConnectProtocol::Http1 => "http1",
+                HttpConnectProtocol::Http2 => "http2",
+            });
+            table["tls"] = value(*x.get_tls());
+            for (key, v) in [
+                ("tls_server_name", x.get_tls_server_name()),
+                ("username", x.get_username()),
+                ("password_env", x.get_password_env()),
+            ] {
+                match v {
+                    Some(v) => table[key] = value(v.as_str()),
+                    None => {
+                        table.remove(key);
+                    }
+                }
+            }
+            table["forward_identity"] = value(*x.get_forward_identity());
+            table["direct_datagrams"] = value(*x.get_direct_datagrams());
+            doc.to_string()
+        }
+        ForwardProtocolSettings::Cascade(x) => {
+            let mut doc: Document = template_settings::CASCADE_FORWARDER_TABLE.parse().unwrap();
+            let table = doc["forward_protocol"]["cascade"].as_table_mut().unwrap();
+            table["address"] = value(x.get_address().to_string());
+            table["server_name"] = value(x.get_server_name().as_str());
+            table["username"] = value(x.get_username().as_str());
+            table["password_env"] = value(x.get_password_env().as_str());
+            table["certificate_pins"] = value(Array::from_iter(x.get_certificate_pins()));
+            doc.to_string()
+        }
     };
 
     format!(
@@ -150,6 +200,123 @@ fn compose_icmp_table(settings: Option<&IcmpSettings>) -> String {
     }
 }
 
+fn compose_rate_limit_table(settings: &RateLimitSettings) -> String {
+    if settings.get_upload_bytes_per_sec().is_none()
+        && settings.get_download_bytes_per_sec().is_none()
+    {
+        return template_settings::RATE_LIMIT_TABLE.to_toml_comment();
+    }
+
+    let mut doc: Document = template_settings::RATE_LIMIT_TABLE.parse().unwrap();
+    let table = doc["rate_limit"].as_table_mut().unwrap();
+    for (key, limit) in [
+        ("upload_bytes_per_sec", settings.get_upload_bytes_per_sec()),
+        (
+            "download_bytes_per_sec",
+            settings.get_download_bytes_per_sec(),
+        ),
+    ] {
+        match limit {
+            Some(x) => table[key] = value(*x as i64),
+            None => {
+                table.remove(key);
+            }
+        }
+    }
+
+    doc.to_string()
+}
+
+fn compose_limits_table(settings: &LimitSettings) -> String {
+    if settings == &LimitSettings::default() {
+        return template_settings::LIMITS_TABLE.to_toml_comment();
+    }
+
+    let mut doc: Document = template_settings::LIMITS_TABLE.parse().unwrap();
+    let table = doc["limits"].as_table_mut().unwrap();
+    for (key, limit) in [
+        (
+            "max_sessions_per_user",
+            settings.get_max_sessions_per_user(),
+        ),
+        ("max_sessions_per_ip", settings.get_max_sessions_per_ip()),
+        (
+            "max_tcp_connections_per_session",
+            settings.get_max_tcp_connections_per_session(),
+        ),
+        (
+            "max_udp_flows_per_multiplexer",
+            settings.get_max_udp_flows_per_multiplexer(),
+        ),
+    ] {
+        match limit {
+            Some(x) => table[key] = value(*x as i64),
+            None => {
+                table.remove(key);
+            }
+        }
+    }
+
+    doc.to_string()
+}
+
+fn compose_brute_force_table(settings: Option<&BruteForceSettings>) -> String {
+    match settings {
+        Some(x) => {
+            let mut doc: Document = template_settings::BRUTE_FORCE_TABLE.parse().unwrap();
+            let table = doc["brute_force"].as_table_mut().unwrap();
+
+            table["backoff_after_failures"] = value(*x.get_backoff_after_failures() as i64);
+            table["initial_backoff_secs"] = value(x.get_initial_backoff().as_secs() as i64);
+            table["ban_after_failures"] = value(*x.get_ban_after_failures() as i64);
+            table["ban_duration_secs"] = value(x.get_ban_duration().as_secs() as i64);
+            table["failure_window_secs"] = value(x.get_failure_window().as_secs() as i64);
+
+            doc.to_string()
+        }
+        None => template_settings::BRUTE_FORCE_TABLE.to_toml_comment(),
+    }
+}
+
+fn compose_dns_table(settings: Option<&DnsSettings>) -> String {
+    match settings {
+        Some(x) => {
+            let mut doc: Document = template_settings::DNS_TABLE.parse().unwrap();
+            let table = doc["dns"].as_table_mut().unwrap();
+
+            table["upstreams"] = value(x.get_upstreams().iter().collect::<toml_edit::Array>());
+            table["query_timeout_secs"] = value(x.get_query_timeout().as_secs() as i64);
+            table["cache_size"] = value(*x.get_cache_size() as i64);
+            table["happy_eyeballs_delay_ms"] =
+                value(x.get_happy_eyeballs_delay().as_millis() as i64);
+            table["intercept"] = value(*x.get_intercept());
+            table["blocklists"] = value(x.get_blocklists().iter().collect::<toml_edit::Array>());
+            table["blocking_mode"] = value(match x.get_blocking_mode() {
+                DnsBlockingMode::Nxdomain => "nxdomain",
+                DnsBlockingMode::Unspecified => "unspecified",
+            });
+
+            doc.to_string()
+        }
+        None => template_settings::DNS_TABLE.to_toml_comment(),
+    }
+}
+
+fn compose_quota_table(settings: Option<&QuotaSettings>) -> String {
+    match settings {
+        Some(x) => {
+            let mut doc: Document = template_settings::QUOTA_TABLE.parse().unwrap();
+            let table = doc["quota"].as_table_mut().unwrap();
+
+            table["state_file"] = value(x.get_state_file());
+            table["save_interval_secs"] = value(x.get_save_interval().as_secs() as i64);
+
+            doc.to_string()
+        }
+        None => template_settings::QUOTA_TABLE.to_toml_comment(),
+    }
+}
+
 fn compose_metrics_table(settings: Option<&MetricsSettings>) -> String {
     match settings {
         Some(x) => {
@@ -158,9 +325,27 @@ fn compose_metrics_table(settings: Option<&MetricsSettings>) -> String {
 
             table["address"] = value(x.get_address().to_string());
             table["request_timeout_secs"] = value(x.get_request_timeout().as_secs() as i64);
+            table["per_user"] = value(*x.get_per_user());
+            table["max_users"] = value(*x.get_max_users() as i64);
 
             doc.to_string()
         }
         None => template_settings::METRICS_TABLE.to_toml_comment(),
     }
 }
+
+fn compose_admin_table(settings: Option<&AdminSettings>) -> String {
+    match settings {
+        Some(x) => {
+            let mut doc: Document = template_settings::ADMIN_TABLE.parse().unwrap();
+            let table = doc["admin"].as_table_mut().unwrap();
+
+            table["address"] = value(x.get_address().to_string());
+            table["request_timeout_secs"] = value(x.get_request_timeout().as_secs() as i64);
+            table["token_env"] = value(x.get_token_env());
+
+            doc.to_string()
+        }
+        None => template_settings::ADMIN_TABLE.to_toml_comment(),
+    }
+}
@@ -4,7 +4,8 @@ use crate::user_interaction::{
 use crate::Mode;
 use std::fs;
 use toml_edit::{ArrayOfTables, Item, Key, Table};
-use trusttunnel::authentication::registry_based::Client;
+use trusttunnel::authentication::password;
+use trusttunnel::authentication::registry_based::{self, Client};
 use trusttunnel::settings::{
     Http1Settings, Http2Settings, ListenProtocolSettings, QuicSettings, Settings,
 };
@@ -62,6 +63,12 @@ fn build_credentials() -> (String, Vec<Client>) {
         ))
     {
         let clients = read_credentials_file(DEFAULT_CREDENTIALS_PATH).unwrap_or_default();
+        for warning in clients.iter().filter_map(Client::validity_warning) {
+            println!(
+                "Warning: {}, its exported client configuration will be rejected",
+                warning
+            );
+        }
         return (DEFAULT_CREDENTIALS_PATH.into(), clients);
     }
 
@@ -80,7 +87,11 @@ fn build_credentials() -> (String, Vec<Client>) {
 
     let clients = users
         .into_iter()
-        .map(|(username, password)| Client { username, password })
+        .map(|(username, password)| Client {
+            username,
+            password,
+            ..Default::default()
+        })
         .collect();
 
     (path, clients)
@@ -94,9 +105,24 @@ fn read_credentials_file(path: &str) -> Option<Vec<Client>> {
         tables
             .iter()
             .filter_map(|t| {
+                let timestamp = |key| {
+                    let value = t.get(key)?;
+                    let value = value
+                        .as_datetime()
+                        .map(ToString::to_string)
+                        .or_else(|| value.as_str().map(str::to_string))?;
+                    registry_based::parse_timestamp(&value).ok()
+                };
                 Some(Client {
                     username: t.get("username")?.as_str()?.to_string(),
                     password: t.get("password")?.as_str()?.to_string(),
+                    not_before: timestamp("not_before"),
+                    expires_at: timestamp("expires_at"),
+                    disabled: t
+                        .get("disabled")
+                        .and_then(Item::as_bool)
+                        .unwrap_or_default(),
+                    ..Default::default()
                 })
             })
             .collect(),
@@ -151,13 +177,15 @@ fn build_user_list() -> Vec<(String, String)> {
     list
 }
 
+/// Compose the credentials file content, the passwords are stored as argon2id hashes
 fn compose_credentials_content(clients: impl Iterator<Item = (String, String)>) -> String {
     let mut doc = toml_edit::Document::new();
 
     let x = clients
         .map(|(u, p)| {
             Table::from_iter(
-                std::iter::once(("username", u)).chain(std::iter::once(("password", p))),
+                std::iter::once(("username", u))
+                    .chain(std::iter::once(("password", password::hash(&p)))),
             )
         })
         .collect::<ArrayOfTables>();
@@ -185,6 +213,12 @@ fn generate_rules_toml_content(rules_config: &trusttunnel::rules::RulesConfig) -
     content.push_str(
         "#   Can optionally include a mask in format \"prefix[/mask]\" for bitwise matching\n",
     );
+    content.push_str(
+        "# - sni: TLS server name, \"example.com\", \".example.com\" or \"*.example.com\"\n",
+    );
+    content.push_str("# - username: authenticated username\n");
+    content.push_str("# - group: group of the authenticated user from the credentials file\n");
+    content.push_str("# - user_agent: substring of the request User-Agent\n");
     content.push_str("# - action: \"allow\" or \"deny\"\n");
     content.push_str("#\n");
     content.push_str("# All fields except 'action' are optional - if specified, all conditions must match for the rule to apply.\n");
@@ -200,10 +234,28 @@ fn generate_rules_toml_content(rules_config: &trusttunnel::rules::RulesConfig) -
     content.push_str(
         "#    → matches client_random where (client_random & 0xf0f0) == (0xa0b0 & 0xf0f0)\n",
     );
-    content.push_str("#    → e.g., 0xa5b5, 0xa9bf match, but 0xb0b0, 0xa0c0 don't match\n\n");
+    content.push_str("#    → e.g., 0xa5b5, 0xa9bf match, but 0xb0b0, 0xa0c0 don't match\n");
+    content.push_str("#\n");
+    content.push_str(
+        "# Outgoing connections are filtered with [[egress]] rules, which can specify:\n",
+    );
+    content.push_str("# - domain: destination host name, \"example.com\", \".example.com\" or \"*.example.com\"\n");
+    content.push_str("# - cidr: destination IP address range in CIDR notation\n");
+    content.push_str("# - ports: destination ports and port ranges, e.g. \"25,6881-6889\"\n");
+    content.push_str("# - protocol: \"tcp\" or \"udp\"\n");
+    content.push_str("# - action: \"allow\" or \"deny\"\n");
+    content.push_str("#\n");
+    content.push_str(
+        "# [[route]] rules choose a forwarder from the [forwarders] of the main settings,\n",
+    );
+    content.push_str("# they match username, sni, domain, cidr, ports and protocol and specify:\n");
+    content.push_str("# - forwarder: the forwarder name\n\n");
 
     // Serialize the actual rules (usually empty)
-    if !rules_config.rule.is_empty() {
+    if !rules_config.rule.is_empty()
+        || !rules_config.egress.is_empty()
+        || !rules_config.route.is_empty()
+    {
         content.push_str(&toml::ser::to_string(rules_config).unwrap());
         content.push('\n');
     }
@@ -75,7 +75,8 @@ fn main() {
         --hosts-settings hosts.toml
 
     # After setup, export client configuration:
-    sudo ./trusttunnel_endpoint vpn.toml hosts.toml -c admin -a 203.0.113.1
+    sudo ./trusttunnel_endpoint vpn.toml hosts.toml -c admin -a 203.0.113.1 \
+        --client-password secretpass
 
 For detailed configuration options, see:
 https://github.com/TrustTunnel/TrustTunnel/blob/master/CONFIGURATION.md
@@ -13,7 +13,7 @@ pub fn build() -> RulesConfig {
 fn build_non_interactive() -> RulesConfig {
     // In non-interactive mode, generate empty rules
     // The actual examples will be in the serialized TOML comments
-    RulesConfig { rule: vec![] }
+    RulesConfig::default()
 }
 
 fn build_interactive() -> RulesConfig {
@@ -24,7 +24,7 @@ fn build_interactive() -> RulesConfig {
     // Ask if user wants to configure rules
     if !ask_for_agreement("Do you want to configure connection filtering rules? (if not, all connections will be allowed)") {
         info!("Skipping rules configuration - all connections will be allowed.");
-        return RulesConfig { rule: vec![] };
+        return RulesConfig::default();
     }
 
     println!();
@@ -37,7 +37,11 @@ fn build_interactive() -> RulesConfig {
 
     add_custom_rules(&mut rules);
 
-    RulesConfig { rule: rules }
+    RulesConfig {
+        rule: rules,
+        egress: vec![],
+        route: vec![],
+    }
 }
 
 fn add_custom_rules(rules: &mut Vec<Rule>) {
@@ -78,6 +82,10 @@ fn add_ip_rule(rules: &mut Vec<Rule>) {
     rules.push(Rule {
         cidr: Some(cidr),
         client_random_prefix: None,
+        sni: None,
+        username: None,
+        group: None,
+        user_agent: None,
         action,
     });
 
@@ -124,6 +132,10 @@ fn add_client_random_rule(rules: &mut Vec<Rule>) {
     rules.push(Rule {
         cidr: None,
         client_random_prefix: Some(client_random_value),
+        sni: None,
+        username: None,
+        group: None,
+        user_agent: None,
         action,
     });
 
@@ -181,6 +193,10 @@ fn add_combined_rule(rules: &mut Vec<Rule>) {
     rules.push(Rule {
         cidr: Some(cidr),
         client_random_prefix: Some(client_random_value),
+        sni: None,
+        username: None,
+        group: None,
+        user_agent: None,
         action,
     });
 
@@ -1,7 +1,10 @@
 use once_cell::sync::Lazy;
 use trusttunnel::settings::{
-    ForwardProtocolSettings, Http1Settings, Http2Settings, IcmpSettings, ListenProtocolSettings,
-    MetricsSettings, QuicSettings, Settings, Socks5ForwarderSettings,
+    AdminSettings, BruteForceSettings, CascadeForwarderSettings, DirectForwarderSettings,
+    DnsSettings, ForwardProtocolSettings, Http1Settings, Http2Settings,
+    HttpConnectForwarderSettings, IcmpSettings, LimitSettings, ListenProtocolSettings,
+    MetricsSettings, QuicSettings, QuotaSettings, RateLimitSettings, Settings,
+    Socks5ForwarderSettings,
 };
 use trusttunnel::utils::ToTomlComment;
 
@@ -20,6 +23,9 @@ listen_address = ""
 # [[client]]
 # ...
 # ```
+#
+# The password may be stored as an argon2, scrypt or bcrypt hash
+# instead of the plain text.
 credentials_file = "{}"
 
 # The path to a TOML file for connection filtering rules in the following format:
@@ -99,7 +105,9 @@ pub static FORWARD_PROTOCOL_COMMON_TABLE: Lazy<String> = Lazy::new(|| {
         r#"{}.
 # Possible values:
 #   * direct: a direct forwarder routes a connection directly to its target host,
-#   * socks5: a SOCKS5 forwarder routes a connection though a SOCKS5 proxy.
+#   * socks5: a SOCKS5 forwarder routes a connection though a SOCKS5 proxy,
+#   * http_connect: an HTTP CONNECT forwarder routes a TCP connection through an HTTP proxy,
+#   * cascade: a cascade forwarder routes TCP and UDP through another endpoint.
 # Default is direct
 [forward_protocol]
 "#,
@@ -110,8 +118,19 @@ pub static FORWARD_PROTOCOL_COMMON_TABLE: Lazy<String> = Lazy::new(|| {
 pub static DIRECT_FORWARDER_TABLE: Lazy<String> = Lazy::new(|| {
     format!(
         r#"{}.
-[forward_protocol.direct]"#,
+[forward_protocol.direct]
+{}
+# source_addresses = ["203.0.113.10", "203.0.113.11"]
+{}
+# ipv6_source_prefix = "2001:db8:1:2::/64"
+{}
+# Possible values: user, session.
+# Default is user
+# source_affinity = "user""#,
         ForwardProtocolSettings::doc_direct().to_toml_comment(),
+        DirectForwarderSettings::doc_source_addresses().to_toml_comment(),
+        DirectForwarderSettings::doc_ipv6_source_prefix().to_toml_comment(),
+        DirectForwarderSettings::doc_source_affinity().to_toml_comment(),
     )
 });
 
@@ -122,10 +141,92 @@ pub static SOCKS_FORWARDER_TABLE: Lazy<String> = Lazy::new(|| {
 {}
 address = "127.0.0.1:1080"
 {}
-extended_auth = false"#,
+# upstreams = ["127.0.0.1:1080", "127.0.0.1:1081"]
+{}
+# Possible values: round_robin, least_connections, consistent_hash.
+# Default is round_robin
+# balancing = "round_robin"
+{}
+extended_auth = false
+{}
+# [forward_protocol.socks5.health_check]
+# interval_secs = 10
+# timeout_secs = 3
+# unhealthy_threshold = 3
+# healthy_threshold = 2"#,
         ForwardProtocolSettings::doc_socks5().to_toml_comment(),
         Socks5ForwarderSettings::doc_address().to_toml_comment(),
+        Socks5ForwarderSettings::doc_upstreams().to_toml_comment(),
+        Socks5ForwarderSettings::doc_balancing().to_toml_comment(),
         Socks5ForwarderSettings::doc_extended_auth().to_toml_comment(),
+        Socks5ForwarderSettings::doc_health_check().to_toml_comment(),
+    )
+});
+
+pub static HTTP_CONNECT_FORWARDER_TABLE: Lazy<String> = Lazy::new(|| {
+    format!(
+        r#"{}.
+[forward_protocol.http_connect]
+{}
+address = "127.0.0.1:3128"
+{}
+# Possible values: http1, http2
+protocol = "http1"
+{}
+tls = false
+{}
+tls_server_name = "proxy.example.com"
+{}
+username = "endpoint"
+{}
+password_env = "HTTP_PROXY_PASSWORD"
+{}
+forward_identity = false
+{}
+direct_datagrams = false"#,
+        ForwardProtocolSettings::doc_httpconnect().to_toml_comment(),
+        HttpConnectForwarderSettings::doc_address().to_toml_comment(),
+        HttpConnectForwarderSettings::doc_protocol().to_toml_comment(),
+        HttpConnectForwarderSettings::doc_tls().to_toml_comment(),
+        HttpConnectForwarderSettings::doc_tls_server_name().to_toml_comment(),
+        HttpConnectForwarderSettings::doc_username().to_toml_comment(),
+        HttpConnectForwarderSettings::doc_password_env().to_toml_comment(),
+        HttpConnectForwarderSettings::doc_forward_identity().to_toml_comment(),
+        HttpConnectForwarderSettings::doc_direct_datagrams().to_toml_comment(),
+    )
+});
+
+pub static CASCADE_FORWARDER_TABLE: Lazy<String> = Lazy::new(|| {
+    format!(
+        r#"{}.
+[forward_protocol.cascade]
+{}
+address = "198.51.100.1:443"
+{}
+server_name = "exit.example.com"
+{}
+username = "entry"
+{}
+password_env = "CASCADE_PASSWORD"
+{}
+certificate_pins = []"#,
+        ForwardProtocolSettings::doc_cascade().to_toml_comment(),
+        CascadeForwarderSettings::doc_address().to_toml_comment(),
+        CascadeForwarderSettings::doc_server_name().to_toml_comment(),
+        CascadeForwarderSettings::doc_username().to_toml_comment(),
+        CascadeForwarderSettings::doc_password_env().to_toml_comment(),
+        CascadeForwarderSettings::doc_certificate_pins().to_toml_comment(),
+    )
+});
+
+pub static FORWARDERS_TABLE: Lazy<String> = Lazy::new(|| {
+    format!(
+        r#"{}
+# Each table has the format of [forward_protocol].
+[forwarders.upstream.socks5]
+address = "127.0.0.1:1080"
+"#,
+        Settings::doc_forwarders().to_toml_comment(),
     )
 });
 
@@ -267,6 +368,120 @@ recv_message_queue_capacity = {}
     )
 });
 
+pub static RATE_LIMIT_TABLE: Lazy<String> = Lazy::new(|| {
+    format!(
+        r#"{}
+[rate_limit]
+{}
+upload_bytes_per_sec = 1000000
+{}
+download_bytes_per_sec = 1000000
+"#,
+        RateLimitSettings::doc().to_toml_comment(),
+        RateLimitSettings::doc_upload_bytes_per_sec().to_toml_comment(),
+        RateLimitSettings::doc_download_bytes_per_sec().to_toml_comment(),
+    )
+});
+
+pub static LIMITS_TABLE: Lazy<String> = Lazy::new(|| {
+    format!(
+        r#"{}
+[limits]
+{}
+max_sessions_per_user = 10
+{}
+max_sessions_per_ip = 10
+{}
+max_tcp_connections_per_session = 1000
+{}
+max_udp_flows_per_multiplexer = 1000
+"#,
+        LimitSettings::doc().to_toml_comment(),
+        LimitSettings::doc_max_sessions_per_user().to_toml_comment(),
+        LimitSettings::doc_max_sessions_per_ip().to_toml_comment(),
+        LimitSettings::doc_max_tcp_connections_per_session().to_toml_comment(),
+        LimitSettings::doc_max_udp_flows_per_multiplexer().to_toml_comment(),
+    )
+});
+
+pub static BRUTE_FORCE_TABLE: Lazy<String> = Lazy::new(|| {
+    format!(
+        r#"{}
+[brute_force]
+{}
+backoff_after_failures = {}
+{}
+initial_backoff_secs = {}
+{}
+ban_after_failures = {}
+{}
+ban_duration_secs = {}
+{}
+failure_window_secs = {}
+"#,
+        BruteForceSettings::doc().to_toml_comment(),
+        BruteForceSettings::doc_backoff_after_failures().to_toml_comment(),
+        BruteForceSettings::default_backoff_after_failures(),
+        BruteForceSettings::doc_initial_backoff().to_toml_comment(),
+        BruteForceSettings::default_initial_backoff().as_secs(),
+        BruteForceSettings::doc_ban_after_failures().to_toml_comment(),
+        BruteForceSettings::default_ban_after_failures(),
+        BruteForceSettings::doc_ban_duration().to_toml_comment(),
+        BruteForceSettings::default_ban_duration().as_secs(),
+        BruteForceSettings::doc_failure_window().to_toml_comment(),
+        BruteForceSettings::default_failure_window().as_secs(),
+    )
+});
+
+pub static DNS_TABLE: Lazy<String> = Lazy::new(|| {
+    format!(
+        r#"{}
+[dns]
+{}
+upstreams = ["https://dns.google/dns-query", "tls://1.1.1.1"]
+{}
+query_timeout_secs = {}
+{}
+cache_size = {}
+{}
+happy_eyeballs_delay_ms = {}
+{}
+intercept = false
+{}
+blocklists = []
+{}
+blocking_mode = "nxdomain"
+"#,
+        DnsSettings::doc().to_toml_comment(),
+        DnsSettings::doc_upstreams().to_toml_comment(),
+        DnsSettings::doc_query_timeout().to_toml_comment(),
+        DnsSettings::default_query_timeout().as_secs(),
+        DnsSettings::doc_cache_size().to_toml_comment(),
+        DnsSettings::default_cache_size(),
+        DnsSettings::doc_happy_eyeballs_delay().to_toml_comment(),
+        DnsSettings::default_happy_eyeballs_delay().as_millis(),
+        DnsSettings::doc_intercept().to_toml_comment(),
+        DnsSettings::doc_blocklists().to_toml_comment(),
+        DnsSettings::doc_blocking_mode().to_toml_comment(),
+    )
+});
+
+pub static QUOTA_TABLE: Lazy<String> = Lazy::new(|| {
+    format!(
+        r#"{}
+[quota]
+{}
+state_file = "quota.json"
+{}
+save_interval_secs = {}
+"#,
+        QuotaSettings::doc().to_toml_comment(),
+        QuotaSettings::doc_state_file().to_toml_comment(),
+        QuotaSettings::doc_save_interval().to_toml_comment(),
+        QuotaSettings::default_save_interval().as_secs(),
+    )
+});
+
 pub static METRICS_TABLE: Lazy<String> = Lazy::new(|| {
     format!(
         r#"{}
@@ -275,11 +490,39 @@ pub static METRICS_TABLE: Lazy<String> = Lazy::new(|| {
 address = "{}"
 {}
 request_timeout_secs = {}
+{}
+per_user = false
+{}
+max_users = {}
 "#,
         MetricsSettings::doc().to_toml_comment(),
         MetricsSettings::doc_address().to_toml_comment(),
         MetricsSettings::default_listen_address(),
         MetricsSettings::doc_request_timeout().to_toml_comment(),
         MetricsSettings::default_request_timeout().as_secs(),
+        MetricsSettings::doc_per_user().to_toml_comment(),
+        MetricsSettings::doc_max_users().to_toml_comment(),
+        MetricsSettings::default_max_users(),
+    )
+});
+
+pub static ADMIN_TABLE: Lazy<String> = Lazy::new(|| {
+    format!(
+        r#"{}
+[admin]
+{}
+address = "{}"
+{}
+request_timeout_secs = {}
+{}
+token_env = "{}"
+"#,
+        AdminSettings::doc().to_toml_comment(),
+        AdminSettings::doc_address().to_toml_comment(),
+        AdminSettings::default_listen_address(),
+        AdminSettings::doc_request_timeout().to_toml_comment(),
+        AdminSettings::default_request_timeout().as_secs(),
+        AdminSettings::doc_token_env().to_toml_comment(),
+        AdminSettings::default_token_env(),
     )
 });
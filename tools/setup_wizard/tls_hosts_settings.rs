@@ -113,6 +113,7 @@ fn build_settings_from_cert(cert: Cert) -> TlsHostsSettings {
             cert_chain_path: cert.cert_path.clone(),
             private_key_path: cert.key_path.clone(),
             allowed_sni,
+            client_certificate: None,
         }])
         .build()
         .expect("Couldn't build TLS hosts settings")